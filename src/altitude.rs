@@ -0,0 +1,167 @@
+//! Barometric altitude from the International Standard Atmosphere.
+//!
+//! The troposphere-only formula is off by hundreds of meters above 11 km,
+//! so pressure is converted layer by layer up to 71 km.
+//! A QNH other than the standard 1013.25 hPa shifts the zero point,
+//! the same way an aircraft altimeter setting does.
+//!
+//! The drivers use this too, so the maths goes through libm rather than std.
+
+/// Standard sea level pressure in pascals.
+pub const STANDARD_PRESSURE: f32 = 101_325.0;
+
+/// R / M for dry air, in J/(kg K).
+const SPECIFIC_GAS_CONSTANT: f64 = 287.05;
+
+/// g0 * M / R, in kelvin per meter.
+const GMR: f64 = 9.80665 * 0.0289644 / 8.3144598;
+
+struct Layer {
+    /// Geopotential height at the base of the layer, in meters.
+    base_height: f64,
+    /// Temperature at the base of the layer, in kelvin.
+    base_temperature: f64,
+    /// Pressure at the base of the layer, in pascals.
+    base_pressure: f64,
+    /// Temperature gradient, in kelvin per meter.
+    lapse_rate: f64,
+}
+
+const LAYERS: [Layer; 7] = [
+    // Troposphere
+    Layer { base_height: 0.0, base_temperature: 288.15, base_pressure: 101_325.0, lapse_rate: -0.0065 },
+    // Tropopause
+    Layer { base_height: 11_000.0, base_temperature: 216.65, base_pressure: 22_632.06, lapse_rate: 0.0 },
+    // Stratosphere
+    Layer { base_height: 20_000.0, base_temperature: 216.65, base_pressure: 5_474.889, lapse_rate: 0.001 },
+    Layer { base_height: 32_000.0, base_temperature: 228.65, base_pressure: 868.0187, lapse_rate: 0.0028 },
+    // Stratopause
+    Layer { base_height: 47_000.0, base_temperature: 270.65, base_pressure: 110.9063, lapse_rate: 0.0 },
+    // Mesosphere
+    Layer { base_height: 51_000.0, base_temperature: 270.65, base_pressure: 66.93887, lapse_rate: -0.0028 },
+    Layer { base_height: 71_000.0, base_temperature: 214.65, base_pressure: 3.956420, lapse_rate: -0.002 },
+];
+
+/// Height in the standard atmosphere where the pressure is `pressure` pascals.
+pub fn pressure_altitude(pressure: f32) -> f32 {
+    let pressure = pressure as f64;
+
+    // Below sea level the troposphere is simply extended downwards.
+    let layer = LAYERS
+        .iter()
+        .rev()
+        .find(|layer| pressure <= layer.base_pressure)
+        .unwrap_or(&LAYERS[0]);
+
+    let height = if layer.lapse_rate == 0.0 {
+        layer.base_height - layer.base_temperature / GMR * libm::log(pressure / layer.base_pressure)
+    } else {
+        layer.base_height
+            + layer.base_temperature / layer.lapse_rate
+                * (libm::pow(pressure / layer.base_pressure, -layer.lapse_rate / GMR) - 1.0)
+    };
+
+    height as f32
+}
+
+/// Pressure in the standard atmosphere at `height` meters.
+pub fn standard_pressure(height: f32) -> f32 {
+    let height = height as f64;
+
+    let layer = LAYERS
+        .iter()
+        .rev()
+        .find(|layer| height >= layer.base_height)
+        .unwrap_or(&LAYERS[0]);
+
+    let pressure = if layer.lapse_rate == 0.0 {
+        layer.base_pressure * libm::exp(-GMR * (height - layer.base_height) / layer.base_temperature)
+    } else {
+        let temperature = layer.base_temperature + layer.lapse_rate * (height - layer.base_height);
+        layer.base_pressure * libm::pow(temperature / layer.base_temperature, -GMR / layer.lapse_rate)
+    };
+
+    pressure as f32
+}
+
+/// Air density in the standard atmosphere at `height` meters, in kg/m³.
+pub fn standard_density(height: f32) -> f32 {
+    let layer = LAYERS
+        .iter()
+        .rev()
+        .find(|layer| height as f64 >= layer.base_height)
+        .unwrap_or(&LAYERS[0]);
+    let temperature = layer.base_temperature + layer.lapse_rate * (height as f64 - layer.base_height);
+
+    (standard_pressure(height) as f64 / (SPECIFIC_GAS_CONSTANT * temperature)) as f32
+}
+
+/// Altitude above the datum set by `qnh`, both pressures in pascals.
+pub fn altitude(pressure: f32, qnh: f32) -> f32 {
+    pressure_altitude(pressure) - pressure_altitude(qnh)
+}
+
+/// Finds the QNH that makes `pressure` read as `altitude` meters,
+/// e.g. from a GPS fix on the launch pad.
+pub fn qnh_from_altitude(pressure: f32, altitude: f32) -> f32 {
+    standard_pressure(pressure_altitude(pressure) - altitude)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pressure in pascals, height in meters and density in kg/m³ from the ISA tables.
+    const ISA: [(f32, f32, f32); 7] = [
+        (101_325.0, 0.0, 1.225),
+        (54_019.9, 5_000.0, 0.7364),
+        (22_632.1, 11_000.0, 0.3639),
+        (12_044.6, 15_000.0, 0.1937),
+        (5_474.89, 20_000.0, 0.08803),
+        (868.019, 32_000.0, 0.01322),
+        (110.906, 47_000.0, 0.001427),
+    ];
+
+    #[test]
+    fn matches_the_isa_tables() {
+        for (pressure, height, density) in ISA {
+            assert!((pressure_altitude(pressure) - height).abs() < 1.0, "{height} m: {}", pressure_altitude(pressure));
+            assert!((standard_pressure(height) / pressure - 1.0).abs() < 1e-4, "{height} m: {}", standard_pressure(height));
+            assert!((standard_density(height) / density - 1.0).abs() < 1e-3, "{height} m: {}", standard_density(height));
+        }
+    }
+
+    #[test]
+    fn layers_meet_at_their_boundaries() {
+        for boundary in [11_000.0, 20_000.0, 32_000.0, 47_000.0] {
+            let (below, above) = (boundary - 0.5, boundary + 0.5);
+
+            assert!((standard_pressure(below) / standard_pressure(above) - 1.0).abs() < 2e-4, "{boundary} m");
+            assert!((standard_density(below) / standard_density(above) - 1.0).abs() < 2e-4, "{boundary} m");
+            assert!((pressure_altitude(standard_pressure(below)) - below).abs() < 1.0, "{boundary} m");
+            assert!((pressure_altitude(standard_pressure(above)) - above).abs() < 1.0, "{boundary} m");
+        }
+    }
+
+    #[test]
+    fn extends_below_sea_level() {
+        // Dead Sea shore
+        let pressure = standard_pressure(-430.0);
+
+        assert!(pressure > STANDARD_PRESSURE);
+        assert!((pressure_altitude(pressure) + 430.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn qnh_makes_the_pressure_read_as_the_altitude() {
+        for (pressure, altitude_on_pad) in [(95_000.0, 500.0), (101_325.0, 0.0), (102_500.0, 30.0), (84_000.0, 1650.0)] {
+            let qnh = qnh_from_altitude(pressure, altitude_on_pad);
+
+            assert!((altitude(pressure, qnh) - altitude_on_pad).abs() < 0.5, "{pressure} Pa: {}", altitude(pressure, qnh));
+        }
+
+        assert_eq!(altitude(50_000.0, STANDARD_PRESSURE), pressure_altitude(50_000.0));
+        // A high QNH reads higher, like turning up an altimeter setting.
+        assert!(altitude(90_000.0, 102_000.0) > altitude(90_000.0, STANDARD_PRESSURE));
+    }
+}
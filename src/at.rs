@@ -0,0 +1,401 @@
+//! Line-oriented transport for modules driven by AT style commands.
+//!
+//! Commands are terminated with `\r\n`, and each one waits for a response line
+//! starting with a known prefix until its deadline passes.
+//! Blank lines and echoed commands are skipped along the way.
+
+use core::time::Duration;
+
+use embedded_hal::delay::DelayNs;
+use embedded_io::{Read, ReadReady, Write};
+use heapless::{String, Vec};
+use log::debug;
+use thiserror::Error;
+
+use crate::timeout::{self, CancelToken, Interrupted, Timeout};
+
+/// Time to wait between polls of the port while no data is available.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What AT modules talk at out of the box.
+const DEFAULT_BAUD_RATE: u32 = 9600;
+
+/// Longest line kept before it is treated as garbage.
+pub const MAX_LINE_LENGTH: usize = 256;
+
+/// Most that's thrown away before a command, more than the port could have buffered.
+const MAX_DISCARD: usize = 4096;
+
+/// A single line sent or received.
+pub type Line = String<MAX_LINE_LENGTH>;
+
+/// Enough of a line to tell what went wrong from the logs, without making errors huge.
+pub type Excerpt = String<32>;
+
+pub struct AtTransport<P, D> {
+    port: P,
+    delay: D,
+    /// For charging the time responses take to arrive to the timeout.
+    baud_rate: u32,
+    cancel: Option<CancelToken>,
+    /// Bytes received after the last complete line.
+    pending: Vec<u8, MAX_LINE_LENGTH>,
+}
+
+impl<P, D> AtTransport<P, D>
+where
+    P: Read + ReadReady + Write,
+    D: DelayNs,
+{
+    pub fn new(port: P, delay: D) -> Self {
+        Self {
+            port,
+            delay,
+            baud_rate: DEFAULT_BAUD_RATE,
+            cancel: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sets the baud rate the port runs at, if it's not the default of 9600.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) {
+        self.baud_rate = baud_rate;
+    }
+
+    /// Lets `cancel` abort whatever command is waiting for a response.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    pub fn port(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    pub fn delay(&mut self) -> &mut D {
+        &mut self.delay
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    /// Sends `command` and waits for a response line starting with `prefix`.
+    ///
+    /// Returns the whole response line, trimmed of surrounding whitespace.
+    pub fn command(&mut self, command: &str, prefix: &str, timeout: Duration) -> Result<Line, AtError<P::Error>> {
+        let mut timeout = Timeout::after(timeout);
+
+        // Anything left over belongs to an earlier command that was given up on.
+        self.discard()?;
+
+        debug!("AT > {command}");
+        self.port.write_all(command.as_bytes()).map_err(AtError::Port)?;
+        self.port.write_all(b"\r\n").map_err(AtError::Port)?;
+        self.port.flush().map_err(AtError::Port)?;
+
+        let mut garbage = None;
+
+        while let Some(line) = self.read_line(&mut timeout)? {
+            debug!("AT < {line}");
+
+            if line.is_empty() || line == command {
+                continue;
+            }
+
+            if line.starts_with(prefix) {
+                return Ok(line);
+            }
+
+            if line == "ERROR" || line.starts_with("+CME ERROR") || line.starts_with("ERROR:") {
+                return Err(AtError::ErrorResponse(to_line(line.as_bytes())));
+            }
+
+            garbage = Some(line);
+        }
+
+        match garbage {
+            Some(line) => Err(AtError::Garbled(to_line(line.as_bytes()))),
+            None => Err(AtError::Timeout(to_line(command.as_bytes()))),
+        }
+    }
+
+    /// Reads the next line, or `None` if `timeout` runs out first.
+    ///
+    /// A partial line is kept for the next call rather than thrown away.
+    pub fn read_line(&mut self, timeout: &mut Timeout) -> Result<Option<Line>, AtError<P::Error>> {
+        let mut buf = [0; 64];
+
+        loop {
+            if let Some(end) = self.pending.iter().position(|&byte| byte == b'\n' || byte == b'\r') {
+                let received = to_line(self.pending[..end].trim_ascii());
+                self.pending.rotate_left(end + 1);
+                self.pending.truncate(self.pending.len() - end - 1);
+
+                return Ok(Some(received));
+            }
+
+            if self.pending.is_full() {
+                let received = to_line(&self.pending);
+                self.pending.clear();
+
+                return Err(AtError::Garbled(received));
+            }
+
+            // Checked on every pass, so a module that never stops talking still runs out.
+            match timeout::check(timeout, self.cancel.as_ref()) {
+                Ok(()) => {}
+                Err(Interrupted::TimedOut) => return Ok(None),
+                Err(Interrupted::Cancelled) => return Err(AtError::Cancelled),
+            }
+
+            if self.port.read_ready().map_err(AtError::Port)? {
+                let space = (self.pending.capacity() - self.pending.len()).min(buf.len());
+                let len = self.port.read(&mut buf[..space]).map_err(AtError::Port)?;
+                // Can't fail, there was room for all of it.
+                let _ = self.pending.extend_from_slice(&buf[..len]);
+                timeout.charge(timeout::transfer_time(len, self.baud_rate));
+            } else {
+                timeout.wait(&mut self.delay, POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Drops anything already received.
+    pub fn discard(&mut self) -> Result<(), AtError<P::Error>> {
+        let mut buf = [0; 64];
+
+        self.pending.clear();
+        // Only what's already there, a port that never stops sending would keep this going forever.
+        let mut discarded = 0;
+        while discarded < MAX_DISCARD && self.port.read_ready().map_err(AtError::Port)? {
+            discarded += self.port.read(&mut buf).map_err(AtError::Port)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns received bytes into a line, replacing anything that isn't ASCII with `?`
+/// and cutting it off at the capacity of the string.
+pub fn to_line<const N: usize>(bytes: &[u8]) -> String<N> {
+    bytes
+        .iter()
+        .take(N)
+        .map(|&byte| if byte.is_ascii() { byte as char } else { '?' })
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum AtError<E> {
+    #[error("serial port error: {0}")]
+    Port(E),
+    #[error("timed out waiting for a response to {0}")]
+    Timeout(Excerpt),
+    #[error("module returned an error: {0}")]
+    ErrorResponse(Excerpt),
+    #[error("garbled response: {0:?}")]
+    Garbled(Excerpt),
+    #[error("cancelled while waiting for a response")]
+    Cancelled,
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::{collections::VecDeque, convert::Infallible};
+
+    use embedded_io::{ErrorType, Read, ReadReady, Write};
+
+    /// A fake serial port for AT modules, answering each command with the next scripted response.
+    ///
+    /// A response only shows up once the command has been flushed,
+    /// like a real module that doesn't say anything until it's asked.
+    #[derive(Debug, Clone, Default)]
+    pub struct MockPort {
+        /// Everything written to the port.
+        pub sent: Vec<u8>,
+        responses: VecDeque<Vec<u8>>,
+        received: VecDeque<u8>,
+        /// Received over and over once everything else has been.
+        noise: Vec<u8>,
+    }
+
+    impl MockPort {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Queues `response` to be received after the next command. An empty one never answers.
+        pub fn respond(&mut self, response: &[u8]) -> &mut Self {
+            self.responses.push_back(response.to_vec());
+            self
+        }
+
+        /// Makes `data` readable straight away, as if it came in unprompted.
+        pub fn receive(&mut self, data: &[u8]) -> &mut Self {
+            self.received.extend(data);
+            self
+        }
+
+        /// Keeps `data` coming in forever, like a port picking up noise.
+        pub fn repeat(&mut self, data: &[u8]) -> &mut Self {
+            self.noise = data.to_vec();
+            self
+        }
+    }
+
+    impl ErrorType for MockPort {
+        type Error = Infallible;
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            if self.received.is_empty() {
+                self.received.extend(&self.noise);
+            }
+
+            let len = buf.len().min(self.received.len());
+            for (slot, byte) in buf.iter_mut().zip(self.received.drain(..len)) {
+                *slot = byte;
+            }
+
+            Ok(len)
+        }
+    }
+
+    impl ReadReady for MockPort {
+        fn read_ready(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.received.is_empty() || !self.noise.is_empty())
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            if let Some(response) = self.responses.pop_front() {
+                self.received.extend(response);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::MockDelay;
+    use mock::MockPort;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn transport(port: MockPort) -> AtTransport<MockPort, MockDelay> {
+        AtTransport::new(port, MockDelay::default())
+    }
+
+    #[test]
+    fn command_returns_the_response_line() {
+        let mut port = MockPort::new();
+        // Echoed back, with a blank line before the answer.
+        port.respond(b"AT+DMOCONNECT\r\n\r\n+DMOCONNECT:0\r\n");
+        let mut at = transport(port);
+
+        assert_eq!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT).unwrap(), "+DMOCONNECT:0");
+        assert_eq!(at.port().sent, b"AT+DMOCONNECT\r\n");
+    }
+
+    #[test]
+    fn command_times_out_without_a_response() {
+        let mut port = MockPort::new();
+        port.respond(b"");
+        let mut at = transport(port);
+
+        assert!(matches!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT), Err(AtError::Timeout(command)) if command == "AT+DMOCONNECT"));
+        assert_eq!(at.delay.waited_ns, TIMEOUT.as_nanos() as u64);
+    }
+
+    #[test]
+    fn command_reports_the_wrong_response() {
+        let mut port = MockPort::new();
+        port.respond(b"+DMOSETGROUP:0\r\n");
+        let mut at = transport(port);
+
+        assert!(matches!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT), Err(AtError::Garbled(line)) if line == "+DMOSETGROUP:0"));
+    }
+
+    #[test]
+    fn command_reports_an_error_response() {
+        let mut port = MockPort::new();
+        port.respond(b"ERROR\r\n").respond(b"+CME ERROR: 3\r\n");
+        let mut at = transport(port);
+
+        assert!(matches!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT), Err(AtError::ErrorResponse(line)) if line == "ERROR"));
+        assert!(matches!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT), Err(AtError::ErrorResponse(line)) if line == "+CME ERROR: 3"));
+    }
+
+    #[test]
+    fn command_drops_leftovers_from_an_earlier_one() {
+        let mut port = MockPort::new();
+        port.receive(b"+DMOCONNECT:0\r\n").respond(b"+DMOSETVOLUME:0\r\n");
+        let mut at = transport(port);
+
+        assert_eq!(at.command("AT+DMOSETVOLUME=4", "+DMOSETVOLUME:", TIMEOUT).unwrap(), "+DMOSETVOLUME:0");
+    }
+
+    #[test]
+    fn read_line_keeps_a_partial_line_for_next_time() {
+        let mut port = MockPort::new();
+        port.receive(b"+DMOCON");
+        let mut at = transport(port);
+
+        assert_eq!(at.read_line(&mut Timeout::after(TIMEOUT)).unwrap(), None);
+
+        at.port().receive(b"NECT:0\r\n");
+        assert_eq!(at.read_line(&mut Timeout::after(TIMEOUT)).unwrap().unwrap(), "+DMOCONNECT:0");
+    }
+
+    #[test]
+    fn cancelling_stops_a_command() {
+        let cancel = CancelToken::new();
+        let mut port = MockPort::new();
+        port.respond(b"");
+        let mut at = transport(port);
+        at.set_cancel_token(Some(cancel.clone()));
+
+        cancel.cancel();
+        assert!(matches!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT), Err(AtError::Cancelled)));
+        assert_eq!(at.delay.waited_ns, 0);
+
+        cancel.reset();
+        at.port().respond(b"+DMOCONNECT:0\r\n");
+        assert!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT).is_ok());
+    }
+
+    #[test]
+    fn gives_up_on_a_module_that_never_stops_talking() {
+        let mut port = MockPort::new();
+        port.repeat(b"noise\r\n");
+        let mut at = transport(port);
+
+        assert!(matches!(at.command("AT+DMOCONNECT", "+DMOCONNECT:", TIMEOUT), Err(AtError::Garbled(line)) if line == "noise"));
+        // It was all arriving, so none of it was spent waiting.
+        assert_eq!(at.delay.waited_ns, 0);
+    }
+
+    #[test]
+    fn read_line_times_out_on_endless_junk_without_a_line_ending() {
+        let mut port = MockPort::new();
+        port.repeat(b"\xff\x00");
+        let mut at = transport(port);
+        let mut timeout = Timeout::after(TIMEOUT);
+
+        // A line too long to keep comes back as garbage, and then it carries on until it runs out.
+        while let Err(AtError::Garbled(_)) = at.read_line(&mut timeout) {}
+
+        assert!(matches!(at.read_line(&mut timeout), Ok(None)));
+        assert!(timeout.has_expired());
+    }
+}
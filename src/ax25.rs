@@ -54,3 +54,26 @@ pub struct Coordinate {
     minute: u8,
     second: u8,
 }
+
+/// Length of the frame check sequence in bytes.
+pub const FCS_SIZE: usize = 2;
+
+/// Computes the frame check sequence (CRC-16/X.25) for an AX.25 frame.
+///
+/// The returned bytes are in transmission order and should be appended
+/// directly after the information field.
+pub fn fcs(frame: &[u8]) -> [u8; FCS_SIZE] {
+    let mut crc: u16 = 0xffff;
+
+    for bit in frame.iter().map(|byte| (0..8).map(move |i| (byte >> i) & 0x01)).flatten() {
+        crc ^= bit as u16;
+
+        if crc & 0x01 > 0 {
+            crc = (crc >> 1) ^ 0x8408;
+        } else {
+            crc >>= 1;
+        }
+    }
+
+    return (crc ^ 0xffff).to_le_bytes();
+}
@@ -0,0 +1,218 @@
+//! Picks the right driver for whichever Bosch pressure sensor is fitted.
+//!
+//! The BMP3 family keeps its chip ID at 0x00 and the BMx280 family at 0xD0,
+//! and the BMP390 and BME280 even share an ID, so both registers are checked.
+
+use core::time::Duration;
+
+use embedded_hal::{delay::DelayNs, i2c::I2c};
+use heapless::Vec;
+
+use crate::{
+    bmp388::{self, AltimeterData, AltimeterError, Bmp388, Config, FifoConfig, TimedReading, MAX_FIFO_READINGS},
+    bmx280::{self, Bmx280},
+    bus::Registers,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Chip {
+    Bmp388,
+    Bmp390,
+    Bmp280,
+    Bme280,
+}
+
+impl Chip {
+    /// Reads the chip ID registers of the sensor at `address`.
+    pub fn detect<I2C: I2c>(i2c: &mut I2C, address: u8) -> Result<Self, AltimeterError<I2C::Error>> {
+        match i2c.read_register(address, bmp388::CHIP_ID_REGISTER)? {
+            bmp388::BMP388_CHIP_ID => return Ok(Chip::Bmp388),
+            bmp388::BMP390_CHIP_ID => return Ok(Chip::Bmp390),
+            _ => {}
+        }
+
+        match i2c.read_register(address, bmx280::CHIP_ID_REGISTER)? {
+            bmx280::BME280_CHIP_ID => Ok(Chip::Bme280),
+            id if bmx280::BMP280_CHIP_IDS.contains(&id) => Ok(Chip::Bmp280),
+            id => Err(AltimeterError::WrongChipId(id)),
+        }
+    }
+}
+
+pub enum Barometer<I2C, D> {
+    Bmp3(Bmp388<I2C, D>),
+    Bmx280(Bmx280<I2C, D>),
+}
+
+impl<I2C: I2c, D: DelayNs> Barometer<I2C, D> {
+    pub fn with_bus(mut i2c: I2C, delay: D, address: u8) -> Result<Self, AltimeterError<I2C::Error>> {
+        let barometer = match Chip::detect(&mut i2c, address)? {
+            Chip::Bmp388 | Chip::Bmp390 => Barometer::Bmp3(Bmp388::with_bus(i2c, delay, address, Config::default())?),
+            Chip::Bmp280 | Chip::Bme280 => Barometer::Bmx280(Bmx280::with_bus(i2c, delay, address)?),
+        };
+
+        Ok(barometer)
+    }
+
+    pub fn chip(&mut self) -> Chip {
+        match self {
+            Barometer::Bmp3(bmp) => match bmp.chip_id() {
+                Ok(bmp388::BMP390_CHIP_ID) => Chip::Bmp390,
+                _ => Chip::Bmp388,
+            },
+            Barometer::Bmx280(bmx) if bmx.has_humidity() => Chip::Bme280,
+            Barometer::Bmx280(_) => Chip::Bmp280,
+        }
+    }
+
+    pub fn read(&mut self) -> Result<AltimeterData, AltimeterError<I2C::Error>> {
+        match self {
+            Barometer::Bmp3(bmp) => bmp.read(),
+            Barometer::Bmx280(bmx) => bmx.read(),
+        }
+    }
+
+    /// Has the sensor keep a backlog of readings for [`Barometer::read_all`], measuring with `config`.
+    ///
+    /// Only the BMP3 family has a FIFO, so this returns false and leaves a BMx280 as it is.
+    pub fn enable_fifo(&mut self, config: Config, fifo: &FifoConfig) -> Result<bool, AltimeterError<I2C::Error>> {
+        match self {
+            Barometer::Bmp3(bmp) => {
+                bmp.configure(config)?;
+                bmp.configure_fifo(fifo)?;
+                bmp.flush_fifo()?;
+
+                Ok(true)
+            }
+            Barometer::Bmx280(_) => Ok(false),
+        }
+    }
+
+    /// Every reading taken since the last call, oldest first.
+    ///
+    /// Without a FIFO that's just the latest reading.
+    pub fn read_all(&mut self) -> Result<Vec<TimedReading, MAX_FIFO_READINGS>, AltimeterError<I2C::Error>> {
+        if let Barometer::Bmp3(bmp) = self {
+            if bmp.fifo_config().is_some() {
+                return Ok(bmp.drain_fifo()?.readings);
+            }
+        }
+
+        let mut readings = Vec::new();
+        let _ = readings.push(TimedReading {
+            age: Duration::ZERO,
+            data: self.read()?,
+        });
+
+        Ok(readings)
+    }
+
+    pub fn set_qnh(&mut self, qnh: f32) {
+        match self {
+            Barometer::Bmp3(bmp) => bmp.set_qnh(qnh),
+            Barometer::Bmx280(bmx) => bmx.set_qnh(qnh),
+        }
+    }
+
+    pub fn qnh(&self) -> f32 {
+        match self {
+            Barometer::Bmp3(bmp) => bmp.qnh(),
+            Barometer::Bmx280(bmx) => bmx.qnh(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::{MockBus, MockBusError, MockDelay};
+
+    const ADDRESS: u8 = 0x77;
+
+    /// A sensor answering with `bmp3_id` at 0x00 and `bmx280_id` at 0xD0, with made up calibration.
+    fn bus(bmp3_id: u8, bmx280_id: u8) -> MockBus {
+        let mut bus = MockBus::new();
+        bus.add_device(ADDRESS)
+            .set_register(ADDRESS, bmp388::CHIP_ID_REGISTER, bmp3_id)
+            .set_register(ADDRESS, bmx280::CHIP_ID_REGISTER, bmx280_id)
+            // BMP3 status, with the command decoder ready
+            .set_register(ADDRESS, 0x03, 0x10)
+            .set_registers(ADDRESS, 0x31, &[0x5A; 21])
+            .set_registers(ADDRESS, 0x88, &[0x5A; 26]);
+
+        bus
+    }
+
+    fn detect(bmp3_id: u8, bmx280_id: u8) -> Result<Chip, AltimeterError<MockBusError>> {
+        Chip::detect(&mut bus(bmp3_id, bmx280_id), ADDRESS)
+    }
+
+    #[test]
+    fn detects_each_chip() {
+        assert_eq!(detect(bmp388::BMP388_CHIP_ID, 0x00).unwrap(), Chip::Bmp388);
+        assert_eq!(detect(bmp388::BMP390_CHIP_ID, 0x00).unwrap(), Chip::Bmp390);
+        assert_eq!(detect(0x00, 0x58).unwrap(), Chip::Bmp280);
+        assert_eq!(detect(0x00, bmx280::BME280_CHIP_ID).unwrap(), Chip::Bme280);
+    }
+
+    #[test]
+    fn bme280_isnt_mistaken_for_a_bmp390() {
+        // Both use 0x60, but in different registers.
+        assert_eq!(detect(0x00, 0x60).unwrap(), Chip::Bme280);
+    }
+
+    #[test]
+    fn rejects_unknown_chips() {
+        assert!(matches!(detect(0x00, 0x42), Err(AltimeterError::WrongChipId(0x42))));
+    }
+
+    #[test]
+    fn picks_the_driver_for_the_chip() {
+        let mut barometer = Barometer::with_bus(bus(bmp388::BMP390_CHIP_ID, 0x00), MockDelay::default(), ADDRESS).unwrap();
+        assert!(matches!(barometer, Barometer::Bmp3(_)));
+        assert_eq!(barometer.chip(), Chip::Bmp390);
+
+        let mut barometer = Barometer::with_bus(bus(0x00, 0x57), MockDelay::default(), ADDRESS).unwrap();
+        assert!(matches!(barometer, Barometer::Bmx280(_)));
+        assert_eq!(barometer.chip(), Chip::Bmp280);
+    }
+
+    #[test]
+    fn reads_the_fifo_when_there_is_one() {
+        let mut bus = bus(bmp388::BMP388_CHIP_ID, 0x00);
+        // Every byte of a burst read comes from the same register, which the calibration doesn't mind.
+        bus.set_auto_increment(ADDRESS, false)
+            .script_reads(ADDRESS, 0x12, &[14, 0])
+            .script_reads(ADDRESS, 0x14, &[0x94, 0, 0, 0x80, 0, 0, 0x60, 0x94, 0, 0, 0x80, 0, 0, 0x60, 0xA0, 0, 1, 0]);
+        let mut barometer = Barometer::with_bus(bus, MockDelay::default(), ADDRESS).unwrap();
+        let config = Config {
+            output_data_rate: bmp388::OutputDataRate::Hz25,
+            ..Config::default()
+        };
+
+        assert!(barometer.enable_fifo(config, &FifoConfig::default()).unwrap());
+        let readings = barometer.read_all().unwrap();
+
+        let ages: std::vec::Vec<_> = readings.iter().map(|reading| reading.age).collect();
+        assert_eq!(ages, [Duration::from_millis(40), Duration::ZERO]);
+    }
+
+    #[test]
+    fn reads_once_without_a_fifo() {
+        let mut barometer = Barometer::with_bus(bus(0x00, bmx280::BME280_CHIP_ID), MockDelay::default(), ADDRESS).unwrap();
+
+        assert!(!barometer.enable_fifo(Config::default(), &FifoConfig::default()).unwrap());
+        let readings = barometer.read_all().unwrap();
+
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].age, Duration::ZERO);
+    }
+
+    #[test]
+    fn passes_qnh_through() {
+        let mut barometer = Barometer::with_bus(bus(0x00, 0x60), MockDelay::default(), ADDRESS).unwrap();
+        barometer.set_qnh(99_000.0);
+
+        assert_eq!(barometer.qnh(), 99_000.0);
+    }
+}
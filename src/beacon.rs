@@ -0,0 +1,264 @@
+//! SmartBeaconing, adapted for balloons.
+//!
+//! Classic SmartBeaconing beacons more often the faster you go and straight away after a turn.
+//! A balloon also needs to speed up when it's going up or down quickly, and near the ground
+//! on the way down, when the recovery crew needs every fix it can get. At float, drifting
+//! slowly at a steady height, it backs right off.
+
+use std::time::{Duration, Instant};
+
+/// Knots to meters per second.
+pub const KNOTS_TO_MPS: f32 = 0.514444;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SmartBeaconConfig {
+    /// Shortest interval, used at or above the fast speeds.
+    pub min_interval: Duration,
+    /// Longest interval, used at or below the slow speeds.
+    pub max_interval: Duration,
+    /// Ground speed below which we're as good as still, in m/s.
+    pub slow_speed: f32,
+    /// Ground speed at which we beacon as fast as we're allowed, in m/s.
+    pub fast_speed: f32,
+    /// Vertical rate below which the height is steady, in m/s either way.
+    pub slow_vertical_rate: f32,
+    /// Vertical rate at which we beacon as fast as we're allowed, in m/s either way.
+    pub fast_vertical_rate: f32,
+    /// Smallest heading change that counts as a turn, in degrees.
+    pub turn_min: f32,
+    /// Added to `turn_min` divided by speed in m/s, so it takes a sharper turn to count when going slowly.
+    pub turn_slope: f32,
+    /// Shortest time between turn triggered beacons.
+    pub turn_time: Duration,
+    /// Altitude below which a descending payload beacons as fast as it can, in meters.
+    pub low_altitude: f32,
+}
+
+impl Default for SmartBeaconConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(20),
+            max_interval: Duration::from_secs(300),
+            slow_speed: 2.0,
+            fast_speed: 40.0,
+            slow_vertical_rate: 1.0,
+            fast_vertical_rate: 15.0,
+            turn_min: 30.0,
+            turn_slope: 60.0,
+            turn_time: Duration::from_secs(30),
+            low_altitude: 3000.0,
+        }
+    }
+}
+
+/// Latest movement, from GPS and the flight tracker.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Motion {
+    /// Ground speed in m/s.
+    pub speed: Option<f32>,
+    /// Course over ground in degrees.
+    pub course: Option<f32>,
+    /// Vertical rate in m/s, positive going up.
+    pub vertical_rate: f32,
+    /// Altitude in meters.
+    pub altitude: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SmartBeacon {
+    config: SmartBeaconConfig,
+    motion: Motion,
+    /// Course when the last position went out.
+    beacon_course: Option<f32>,
+    last_beacon: Option<Instant>,
+}
+
+impl SmartBeacon {
+    pub fn new(config: SmartBeaconConfig) -> Self {
+        Self {
+            config,
+            motion: Motion::default(),
+            beacon_course: None,
+            last_beacon: None,
+        }
+    }
+
+    pub fn config(&self) -> &SmartBeaconConfig {
+        &self.config
+    }
+
+    pub fn update(&mut self, motion: Motion) {
+        self.motion = motion;
+    }
+
+    /// Interval to beacon at for the latest motion.
+    pub fn interval(&self) -> Duration {
+        let config = &self.config;
+        let motion = &self.motion;
+
+        let horizontal = scale(motion.speed.unwrap_or(0.0), config.slow_speed, config.fast_speed, config);
+        let vertical = scale(motion.vertical_rate.abs(), config.slow_vertical_rate, config.fast_vertical_rate, config);
+
+        let falling = motion.vertical_rate < -config.slow_vertical_rate;
+        if falling && motion.altitude.is_some_and(|altitude| altitude < config.low_altitude) {
+            return config.min_interval;
+        }
+
+        horizontal.min(vertical)
+    }
+
+    /// Whether the course has swung far enough since the last beacon to send one early.
+    pub fn turned(&self, now: Instant) -> bool {
+        let config = &self.config;
+
+        let (Some(speed), Some(course), Some(beacon_course)) = (self.motion.speed, self.motion.course, self.beacon_course) else {
+            return false;
+        };
+
+        // Courses are meaningless when barely moving.
+        if speed < config.slow_speed {
+            return false;
+        }
+
+        if self.last_beacon.is_some_and(|last| now.duration_since(last) < config.turn_time) {
+            return false;
+        }
+
+        let change = (course - beacon_course).rem_euclid(360.0);
+        let change = change.min(360.0 - change);
+
+        change > config.turn_min + config.turn_slope / speed
+    }
+
+    /// Records that a position has just gone out.
+    pub fn beaconed(&mut self, now: Instant) {
+        self.last_beacon = Some(now);
+        self.beacon_course = self.motion.course;
+    }
+}
+
+impl Default for SmartBeacon {
+    fn default() -> Self {
+        Self::new(SmartBeaconConfig::default())
+    }
+}
+
+/// Goes from the longest interval at `slow` to the shortest at `fast`, inversely with `value` in between.
+fn scale(value: f32, slow: f32, fast: f32, config: &SmartBeaconConfig) -> Duration {
+    if value <= slow {
+        config.max_interval
+    } else if value >= fast {
+        config.min_interval
+    } else {
+        config
+            .min_interval
+            .mul_f32(fast / value)
+            .clamp(config.min_interval, config.max_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(speed: f32, vertical_rate: f32, altitude: f32) -> Duration {
+        let mut beacon = SmartBeacon::default();
+        beacon.update(Motion {
+            speed: Some(speed),
+            course: None,
+            vertical_rate,
+            altitude: Some(altitude),
+        });
+
+        beacon.interval()
+    }
+
+    #[test]
+    fn speed_scales_between_the_longest_and_shortest_interval() {
+        assert_eq!(SmartBeacon::default().interval(), Duration::from_secs(300));
+        assert_eq!(interval(2.0, 0.0, 10_000.0), Duration::from_secs(300));
+        assert_eq!(interval(20.0, 0.0, 10_000.0), Duration::from_secs(40));
+        assert_eq!(interval(40.0, 0.0, 10_000.0), Duration::from_secs(20));
+        assert_eq!(interval(80.0, 0.0, 10_000.0), Duration::from_secs(20));
+
+        // Just over the slow speed would be 381s, but it's never longer than the longest.
+        assert_eq!(interval(2.1, 0.0, 10_000.0), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn vertical_rate_scales_either_way() {
+        assert_eq!(interval(0.0, 1.0, 10_000.0), Duration::from_secs(300));
+        assert_eq!(interval(0.0, 5.0, 10_000.0), Duration::from_secs(60));
+        assert_eq!(interval(0.0, -5.0, 10_000.0), Duration::from_secs(60));
+        assert_eq!(interval(0.0, 15.0, 10_000.0), Duration::from_secs(20));
+        assert_eq!(interval(0.0, -40.0, 10_000.0), Duration::from_secs(20));
+
+        // Whichever wants the shorter interval wins.
+        assert_eq!(interval(20.0, 5.0, 10_000.0), Duration::from_secs(40));
+    }
+
+    #[test]
+    fn beacons_fast_coming_down_near_the_ground() {
+        assert_eq!(interval(0.0, -3.0, 2000.0), Duration::from_secs(20));
+        assert_eq!(interval(0.0, -3.0, 5000.0), Duration::from_secs(100));
+        // Not on the way up,
+        assert_eq!(interval(0.0, 3.0, 2000.0), Duration::from_secs(100));
+        // or once it's stopped.
+        assert_eq!(interval(0.0, -0.5, 2000.0), Duration::from_secs(300));
+    }
+
+    fn turned(beacon_course: f32, course: f32, speed: f32) -> bool {
+        let now = Instant::now();
+        let mut beacon = SmartBeacon::default();
+        beacon.update(Motion {
+            speed: Some(speed),
+            course: Some(beacon_course),
+            ..Motion::default()
+        });
+        beacon.beaconed(now - Duration::from_secs(60));
+
+        beacon.update(Motion {
+            speed: Some(speed),
+            course: Some(course),
+            ..Motion::default()
+        });
+        beacon.turned(now)
+    }
+
+    #[test]
+    fn turns_count_past_the_threshold() {
+        // 30 + 60 / 10 = 36 degrees at 10 m/s.
+        assert!(!turned(90.0, 125.0, 10.0));
+        assert!(turned(90.0, 127.0, 10.0));
+        assert!(turned(90.0, 53.0, 10.0));
+        // The short way round through north.
+        assert!(turned(350.0, 27.0, 10.0));
+        assert!(!turned(350.0, 25.0, 10.0));
+
+        // Slower takes a sharper turn, 30 + 60 / 3 = 50 degrees.
+        assert!(!turned(90.0, 135.0, 3.0));
+        assert!(turned(90.0, 141.0, 3.0));
+        // And barely moving, the course means nothing.
+        assert!(!turned(90.0, 270.0, 1.5));
+    }
+
+    #[test]
+    fn turns_wait_for_the_turn_time() {
+        let now = Instant::now();
+        let mut beacon = SmartBeacon::default();
+        let motion = |course| Motion {
+            speed: Some(10.0),
+            course: Some(course),
+            ..Motion::default()
+        };
+
+        // Nothing to have turned from yet.
+        beacon.update(motion(90.0));
+        assert!(!beacon.turned(now));
+
+        beacon.beaconed(now);
+        beacon.update(motion(180.0));
+        assert!(!beacon.turned(now + Duration::from_secs(29)));
+        assert!(beacon.turned(now + Duration::from_secs(30)));
+    }
+}
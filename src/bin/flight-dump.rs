@@ -0,0 +1,150 @@
+//! Prints a flight record in readable form.
+//!
+//! `flight-dump <file or directory>`, a directory being every flight file in it in order.
+
+use std::{env, path::PathBuf, process};
+
+use chrono::DateTime;
+
+use aprs::recorder::{self, Reader, Record, RecorderError};
+
+fn main() {
+    let Some(path) = env::args().nth(1).map(PathBuf::from) else {
+        eprintln!("usage: flight-dump <file or directory>");
+        process::exit(2);
+    };
+
+    let files = if path.is_dir() {
+        match recorder::flight_files(&path) {
+            Ok(files) => files.into_iter().map(|(_, path)| path).collect(),
+            Err(err) => {
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        }
+    } else {
+        vec![path]
+    };
+
+    let mut errors = 0;
+    for file in files {
+        println!("# {}", file.display());
+
+        let reader = match Reader::open(&file) {
+            Ok(reader) => reader,
+            Err(err) => {
+                eprintln!("{err}");
+                errors += 1;
+                continue;
+            }
+        };
+
+        for entry in reader {
+            match entry {
+                Ok(entry) => println!("{} {}", format_time(entry.time), describe(&entry.record)),
+                Err(err @ RecorderError::Parse { .. }) => {
+                    eprintln!("{}: {err}", file.display());
+                    errors += 1;
+                }
+                Err(err) => {
+                    eprintln!("{}: {err}", file.display());
+                    errors += 1;
+                    break;
+                }
+            }
+        }
+    }
+
+    if errors > 0 {
+        eprintln!("{errors} bad lines skipped");
+    }
+}
+
+fn format_time(millis: u64) -> String {
+    match DateTime::from_timestamp_millis(millis as i64) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+        None => millis.to_string(),
+    }
+}
+
+fn describe(record: &Record) -> String {
+    match record {
+        Record::Gps {
+            latitude,
+            longitude,
+            altitude,
+            speed,
+            course,
+            satellites,
+        } => match (latitude, longitude) {
+            (Some(latitude), Some(longitude)) => format!(
+                "gps {latitude:.5}, {longitude:.5} alt {} m, {} kt at {}°, {} satellites",
+                or_dash(*altitude),
+                or_dash(*speed),
+                or_dash(*course),
+                or_dash(*satellites),
+            ),
+            _ => "gps no fix".to_owned(),
+        },
+        Record::Barometer {
+            pressure,
+            temperature,
+            humidity,
+            altitude,
+        } => format!(
+            "baro {:.2} hPa, {temperature:.1} °C, {} % RH, alt {altitude:.0} m",
+            pressure / 100.0,
+            or_dash(*humidity),
+        ),
+        Record::Transmission { frame, airtime, result } => {
+            let mut result = match result {
+                Ok(()) => "sent".to_owned(),
+                Err(err) => format!("failed: {err}"),
+            };
+            if let Some(airtime) = airtime {
+                result.push_str(&format!(", keyed {:.2} s", airtime.as_secs_f32()));
+            }
+
+            format!("tx {} ({result})", describe_frame(frame))
+        }
+        Record::ImageChunk { data } => format!("image chunk, {} bytes", data.len()),
+        Record::Phase(phase) => format!("phase {phase}"),
+    }
+}
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+}
+
+/// Formats a UI frame like `N0CALL-11>APRS,WIDE2-1:payload`.
+fn describe_frame(frame: &[u8]) -> String {
+    let mut addresses = Vec::new();
+    let mut rest = frame;
+
+    while rest.len() >= 7 {
+        let (address, tail) = rest.split_at(7);
+        let call: String = address[..6].iter().map(|byte| (byte >> 1) as char).collect();
+        let ssid = (address[6] >> 1) & 0x0F;
+
+        addresses.push(match ssid {
+            0 => call.trim().to_owned(),
+            ssid => format!("{}-{ssid}", call.trim()),
+        });
+        rest = tail;
+
+        if address[6] & 1 != 0 {
+            break;
+        }
+    }
+
+    // Skip the control and PID bytes.
+    let payload = String::from_utf8_lossy(rest.get(2..).unwrap_or_default());
+
+    match addresses.as_slice() {
+        [dest, source, path @ ..] => {
+            let path: String = path.iter().map(|digi| format!(",{digi}")).collect();
+            format!("{source}>{dest}{path}:{payload}")
+        }
+        _ => format!("{} bytes", frame.len()),
+    }
+}
@@ -0,0 +1,151 @@
+//! Writing the track out as KML, GPX and CSV.
+
+use std::io::{self, Write};
+
+use chrono::DateTime;
+
+use crate::{
+    track::{Flight, Point},
+    Milestones,
+};
+
+/// A KML document with the track as a line extruded down to the ground,
+/// and pins at the launch, burst and landing.
+pub fn write_kml(out: &mut impl Write, flight: &Flight, name: &str) -> io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(out, "<Document>")?;
+    writeln!(out, "<name>{}</name>", escape(name))?;
+    writeln!(out, r#"<Style id="track"><LineStyle><color>ff0000ff</color><width>3</width></LineStyle><PolyStyle><color>400000ff</color></PolyStyle></Style>"#)?;
+
+    writeln!(out, "<Placemark>")?;
+    writeln!(out, "<name>Track</name>")?;
+    writeln!(out, "<styleUrl>#track</styleUrl>")?;
+    writeln!(out, "<LineString>")?;
+    writeln!(out, "<extrude>1</extrude>")?;
+    writeln!(out, "<tessellate>1</tessellate>")?;
+    writeln!(out, "<altitudeMode>absolute</altitudeMode>")?;
+    writeln!(out, "<coordinates>")?;
+    for point in &flight.points {
+        writeln!(out, "{}", coordinates(point))?;
+    }
+    writeln!(out, "</coordinates>")?;
+    writeln!(out, "</LineString>")?;
+    writeln!(out, "</Placemark>")?;
+
+    if let Some(Milestones { launch, burst, landing }) = crate::milestones(flight) {
+        for (label, point) in [("Launch", launch), ("Burst", burst), ("Landing", landing)] {
+            writeln!(out, "<Placemark>")?;
+            writeln!(out, "<name>{label}</name>")?;
+            writeln!(out, "<description>{} at {}</description>", crate::format_altitude(point.altitude), format_time(point.time))?;
+            writeln!(out, "<Point><altitudeMode>absolute</altitudeMode><coordinates>{}</coordinates></Point>", coordinates(point))?;
+            writeln!(out, "</Placemark>")?;
+        }
+    }
+
+    writeln!(out, "</Document>")?;
+    writeln!(out, "</kml>")?;
+
+    Ok(())
+}
+
+pub fn write_gpx(out: &mut impl Write, flight: &Flight, name: &str) -> io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<gpx version="1.1" creator="hip-analyze" xmlns="http://www.topografix.com/GPX/1/1">"#)?;
+    writeln!(out, "<trk>")?;
+    writeln!(out, "<name>{}</name>", escape(name))?;
+    writeln!(out, "<trkseg>")?;
+    for point in &flight.points {
+        writeln!(
+            out,
+            r#"<trkpt lat="{:.6}" lon="{:.6}">{}<time>{}</time></trkpt>"#,
+            point.latitude,
+            point.longitude,
+            point.altitude.map(|altitude| format!("<ele>{altitude:.1}</ele>")).unwrap_or_default(),
+            format_time(point.time)
+        )?;
+    }
+    writeln!(out, "</trkseg>")?;
+    writeln!(out, "</trk>")?;
+    writeln!(out, "</gpx>")?;
+
+    Ok(())
+}
+
+/// Every point with its sensor readings, empty where there weren't any.
+pub fn write_csv(out: &mut impl Write, flight: &Flight) -> io::Result<()> {
+    writeln!(out, "time,latitude,longitude,altitude_m,pressure_pa,temperature_c,humidity_pct")?;
+    for point in &flight.points {
+        writeln!(
+            out,
+            "{},{:.6},{:.6},{},{},{},{}",
+            format_time(point.time),
+            point.latitude,
+            point.longitude,
+            point.altitude.map(|altitude| format!("{altitude:.1}")).unwrap_or_default(),
+            optional(point.pressure),
+            optional(point.temperature),
+            optional(point.humidity),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// ISO 8601 in UTC, as both KML and GPX want it.
+pub fn format_time(millis: i64) -> String {
+    match DateTime::from_timestamp_millis(millis) {
+        Some(time) => time.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        None => String::new(),
+    }
+}
+
+/// `longitude,latitude,altitude` for KML, leaving out an altitude we don't have.
+fn coordinates(point: &Point) -> String {
+    match point.altitude {
+        Some(altitude) => format!("{:.6},{:.6},{altitude:.1}", point.longitude, point.latitude),
+        None => format!("{:.6},{:.6}", point.longitude, point.latitude),
+    }
+}
+
+fn optional(value: Option<f32>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::mock;
+
+    fn export(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        write(&mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_kml() {
+        let kml = export(|out| write_kml(out, &mock::flight(), "Boulder & <back>"));
+
+        assert_eq!(kml, include_str!("testdata/flight.kml"));
+    }
+
+    #[test]
+    fn writes_gpx() {
+        let gpx = export(|out| write_gpx(out, &mock::flight(), "Boulder & <back>"));
+
+        assert_eq!(gpx, include_str!("testdata/flight.gpx"));
+    }
+
+    #[test]
+    fn writes_csv() {
+        let csv = export(|out| write_csv(out, &mock::flight()));
+
+        assert_eq!(csv, include_str!("testdata/flight.csv"));
+    }
+}
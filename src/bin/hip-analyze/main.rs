@@ -0,0 +1,232 @@
+//! Post-flight analysis.
+//!
+//! `hip-analyze <flight record or log.txt> [--kml <file>] [--gpx <file>] [--csv <file>]`
+//!
+//! Reads the track from the flight recorder's files (a `flight-NNNN.csv` or the directory
+//! holding them) or from the location packets in `log.txt`, prints a summary of the flight
+//! and exports the track for Google Earth, mapping tools or a spreadsheet.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use track::{Flight, Point};
+
+mod export;
+mod track;
+
+/// Mean radius of the Earth in meters.
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Some(input) = args.first().filter(|arg| !arg.starts_with("--")).map(PathBuf::from) else {
+        eprintln!("usage: hip-analyze <flight record or log.txt> [--kml <file>] [--gpx <file>] [--csv <file>]");
+        process::exit(2);
+    };
+
+    let flight = match track::load(&input) {
+        Ok(flight) => flight,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+
+    print_summary(&flight);
+
+    let name = input
+        .file_stem()
+        .map_or_else(|| "Flight".to_owned(), |stem| stem.to_string_lossy().into_owned());
+
+    let mut failed = false;
+    if let Some(path) = arg_value(&args, "--kml") {
+        failed |= export_to(&path, |out| export::write_kml(out, &flight, &name));
+    }
+    if let Some(path) = arg_value(&args, "--gpx") {
+        failed |= export_to(&path, |out| export::write_gpx(out, &flight, &name));
+    }
+    if let Some(path) = arg_value(&args, "--csv") {
+        failed |= export_to(&path, |out| export::write_csv(out, &flight));
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn print_summary(flight: &Flight) {
+    if flight.skipped > 0 {
+        eprintln!("warning: skipped {} unreadable lines", flight.skipped);
+    }
+
+    if let Err(err) = write_summary(&mut io::stdout().lock(), flight) {
+        eprintln!("failed to print the summary: {err}");
+    }
+}
+
+fn write_summary(out: &mut impl Write, flight: &Flight) -> io::Result<()> {
+    writeln!(out, "{} track points", flight.points.len())?;
+
+    let (Some(first), Some(last)) = (flight.points.first(), flight.points.last()) else {
+        return Ok(());
+    };
+    let Some(Milestones { launch, burst, landing }) = milestones(flight) else {
+        return Ok(());
+    };
+    let burst_time = flight.burst.unwrap_or(burst.time);
+
+    writeln!(out, "Duration: {}", format_duration(last.time - first.time))?;
+    writeln!(out, "Launch: {} at {}", export::format_time(launch.time), format_altitude(launch.altitude))?;
+    if let Some((highest, altitude)) = highest(flight) {
+        writeln!(out, "Max altitude: {altitude:.0} m ({:.0} ft) at {}", altitude / 0.3048, export::format_time(highest.time))?;
+    }
+    writeln!(out, "Burst: {}", export::format_time(burst_time))?;
+
+    if let (Some(launch_altitude), Some(burst_altitude)) = (launch.altitude, burst.altitude) {
+        if let Some(rate) = rate(burst_altitude - launch_altitude, burst_time - launch.time) {
+            writeln!(out, "Average ascent rate: {rate:.1} m/s")?;
+        }
+    }
+    if let (Some(burst_altitude), Some(landing_altitude), true) = (burst.altitude, landing.altitude, landing.time > burst_time) {
+        if let Some(rate) = rate(burst_altitude - landing_altitude, landing.time - burst_time) {
+            writeln!(out, "Average descent rate: {rate:.1} m/s")?;
+        }
+    }
+
+    writeln!(out, "Landing: {} at {:.5}, {:.5}", export::format_time(landing.time), landing.latitude, landing.longitude)?;
+    writeln!(out, "Distance from launch: {:.1} km", distance(launch, landing) / 1000.0)?;
+    writeln!(out, "Packets sent: {}, failed or refused: {}", flight.packets_sent, flight.packets_failed)
+}
+
+/// The highest point of the flight that has an altitude, and that altitude.
+fn highest(flight: &Flight) -> Option<(&Point, f32)> {
+    flight
+        .points
+        .iter()
+        .filter_map(|point| Some((point, point.altitude?)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// Where the flight launched, burst and landed.
+pub struct Milestones<'a> {
+    pub launch: &'a Point,
+    pub burst: &'a Point,
+    pub landing: &'a Point,
+}
+
+/// The last fix on the pad, the last one before the burst and the first one on the ground,
+/// going by the highest point and the ends of the track when the flight computer didn't log them.
+pub fn milestones(flight: &Flight) -> Option<Milestones<'_>> {
+    let (first, last) = (flight.points.first()?, flight.points.last()?);
+    let highest = highest(flight).map_or(last, |(point, _)| point);
+
+    Some(Milestones {
+        launch: flight.launch.and_then(|time| point_before(flight, time)).unwrap_or(first),
+        burst: flight.burst.and_then(|time| point_before(flight, time)).unwrap_or(highest),
+        landing: flight.landing.and_then(|time| point_after(flight, time)).unwrap_or(last),
+    })
+}
+
+fn point_before(flight: &Flight, time: i64) -> Option<&Point> {
+    flight.points.iter().rev().find(|point| point.time <= time)
+}
+
+fn point_after(flight: &Flight, time: i64) -> Option<&Point> {
+    flight.points.iter().find(|point| point.time >= time)
+}
+
+/// Meters per second over `millis`, if it's long enough to mean anything.
+fn rate(meters: f32, millis: i64) -> Option<f32> {
+    (millis > 0).then(|| meters / (millis as f32 / 1000.0))
+}
+
+/// Great circle distance in meters.
+fn distance(from: &Point, to: &Point) -> f64 {
+    let (lat1, lat2) = (from.latitude.to_radians(), to.latitude.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (to.longitude - from.longitude).to_radians();
+
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+fn format_altitude(altitude: Option<f32>) -> String {
+    altitude.map_or_else(|| "unknown altitude".to_owned(), |altitude| format!("{altitude:.0} m"))
+}
+
+fn format_duration(millis: i64) -> String {
+    let seconds = millis.max(0) / 1000;
+    format!("{}h {:02}m {:02}s", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Writes an export, returning whether it failed.
+fn export_to(path: &str, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> bool {
+    let result = File::create(Path::new(path)).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        write(&mut out)?;
+        out.flush()
+    });
+
+    match result {
+        Ok(()) => {
+            println!("Wrote {path}");
+            false
+        }
+        Err(err) => {
+            eprintln!("failed to write {path}: {err}");
+            true
+        }
+    }
+}
+
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_the_flight() {
+        let mut out = Vec::new();
+
+        write_summary(&mut out, &track::mock::flight()).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), include_str!("testdata/summary.txt"));
+    }
+
+    #[test]
+    fn summarizes_a_flight_without_any_fixes() {
+        let mut out = Vec::new();
+
+        write_summary(&mut out, &Flight::default()).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "0 track points\n");
+    }
+
+    #[test]
+    fn falls_back_to_the_track_for_milestones_the_flight_computer_missed() {
+        let flight = Flight {
+            launch: None,
+            burst: None,
+            landing: None,
+            ..track::mock::flight()
+        };
+
+        let Milestones { launch, burst, landing } = milestones(&flight).unwrap();
+
+        assert_eq!(launch, &flight.points[0]);
+        assert_eq!(burst, &flight.points[2]);
+        assert_eq!(landing, &flight.points[4]);
+    }
+}
@@ -0,0 +1,391 @@
+//! Loading a flight from the recorder's files or from `log.txt`.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use chrono::{NaiveDateTime, NaiveTime};
+
+use aprs::{
+    altitude,
+    recorder::{self, Reader, Record, RecorderError},
+};
+
+const FEET_TO_METERS: f32 = 0.3048;
+
+/// One position along the track, with whatever sensor readings went with it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Point {
+    /// Milliseconds since the Unix epoch.
+    pub time: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Meters, if the packet or a barometer reading gave one.
+    pub altitude: Option<f32>,
+    /// Pascals
+    pub pressure: Option<f32>,
+    /// Celsius
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Flight {
+    pub points: Vec<Point>,
+    /// When the flight computer saw the launch, burst and landing, if it did.
+    pub launch: Option<i64>,
+    pub burst: Option<i64>,
+    pub landing: Option<i64>,
+    pub packets_sent: usize,
+    pub packets_failed: usize,
+    /// Lines that couldn't be read.
+    pub skipped: usize,
+}
+
+/// Loads a recorder file, a directory of them, or a `log.txt`.
+pub fn load(path: &Path) -> Result<Flight, RecorderError> {
+    if path.is_dir() {
+        let mut flight = Flight::default();
+        for (_, file) in recorder::flight_files(path)? {
+            load_record(&file, &mut flight)?;
+        }
+
+        return Ok(flight);
+    }
+
+    let mut flight = Flight::default();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => load_record(path, &mut flight)?,
+        _ => load_log(path, &mut flight)?,
+    }
+
+    Ok(flight)
+}
+
+fn load_record(path: &Path, flight: &mut Flight) -> Result<(), RecorderError> {
+    // Barometer readings are matched up with the GPS fix after them.
+    let mut baro: Option<(f32, f32, Option<f32>, f32)> = None;
+
+    for entry in Reader::open(path)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(RecorderError::Parse { .. }) => {
+                flight.skipped += 1;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let time = entry.time as i64;
+
+        match entry.record {
+            Record::Gps {
+                latitude: Some(latitude),
+                longitude: Some(longitude),
+                altitude,
+                ..
+            } => {
+                let (pressure, temperature, humidity, baro_altitude) = match baro {
+                    Some((pressure, temperature, humidity, altitude)) => (Some(pressure), Some(temperature), humidity, Some(altitude)),
+                    None => (None, None, None, None),
+                };
+
+                flight.points.push(Point {
+                    time,
+                    latitude,
+                    longitude,
+                    // The barometer keeps going above the GPS altitude limit.
+                    altitude: baro_altitude.or(altitude),
+                    pressure,
+                    temperature,
+                    humidity,
+                });
+            }
+            Record::Gps { .. } => {}
+            Record::Barometer {
+                pressure,
+                temperature,
+                humidity,
+                altitude,
+            } => baro = Some((pressure, temperature, humidity, altitude)),
+            Record::Transmission { result: Ok(()), .. } => flight.packets_sent += 1,
+            Record::Transmission { result: Err(_), .. } => flight.packets_failed += 1,
+            Record::ImageChunk { .. } => {}
+            Record::Phase(phase) => match phase.as_str() {
+                "ascent" => flight.launch = flight.launch.or(Some(time)),
+                "descent" => flight.burst = flight.burst.or(Some(time)),
+                "landed" => flight.landing = flight.landing.or(Some(time)),
+                _ => {}
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Picks the track out of the location packets logged by the flight computer.
+fn load_log(path: &Path, flight: &mut Flight) -> Result<(), RecorderError> {
+    read_log(BufReader::new(File::open(path)?), flight)
+}
+
+fn read_log(reader: impl BufRead, flight: &mut Flight) -> Result<(), RecorderError> {
+    // Pascals, from the last telemetry packet, for location packets without an altitude.
+    let mut pressure = None;
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            // Logs can pick up junk when the power goes.
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                flight.skipped += 1;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let logged = log_time(&line);
+
+        if let Some(packet) = quoted_after(&line, "Sending APRS location packet: \"") {
+            match parse_location(packet, logged, pressure) {
+                Some(point) => flight.points.push(point),
+                None => flight.skipped += 1,
+            }
+        } else if let Some(packet) = quoted_after(&line, "Sending APRS telemetry packet: \"") {
+            pressure = parse_telemetry_pressure(packet).or(pressure);
+        } else if line.contains("Entered ascent phase") {
+            flight.launch = flight.launch.or(logged);
+        } else if line.contains("Burst detected") || line.contains("Entered descent phase") {
+            flight.burst = flight.burst.or(logged);
+        } else if line.contains("Entered landed phase") {
+            flight.landing = flight.landing.or(logged);
+        } else if line.contains("Keyed for ") {
+            flight.packets_sent += 1;
+        } else if is_radio_failure(&line) {
+            flight.packets_failed += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the line is a packet the radio failed to send or refused.
+///
+/// Packets skipped for want of a sensor reading never got as far as the radio.
+fn is_radio_failure(line: &str) -> bool {
+    line.contains("failed to transmit") || (line.contains("Skipping") && line.contains(" packet: "))
+}
+
+/// The timestamp at the start of a log line, in milliseconds since the Unix epoch.
+fn log_time(line: &str) -> Option<i64> {
+    let line = line.trim_start_matches('[');
+    let time = NaiveDateTime::parse_from_str(line.get(..19)?, "%Y-%m-%d %H:%M:%S").ok()?;
+
+    Some(time.and_utc().timestamp_millis())
+}
+
+fn quoted_after<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    let start = line.find(prefix)? + prefix.len();
+    let end = line.rfind('"').filter(|&end| end >= start)?;
+
+    Some(&line[start..end])
+}
+
+/// Parses the `/HHMMSSh DDMM.mmN/DDDMM.mmWO` position and the `/A=`, `/Pa=`, `/Ti=` and `/Hu=` fields
+/// out of a location packet, which is logged after the binary AX.25 header.
+///
+/// Packets without `/A=` get their altitude from their own pressure or else `baro`, in Pascals.
+fn parse_location(packet: &str, logged: Option<i64>, baro: Option<f32>) -> Option<Point> {
+    let (start, _) = packet
+        .char_indices()
+        .find(|&(i, c)| c == '/' && packet.get(i + 7..i + 8) == Some("h") && packet.get(i + 1..i + 7).is_some_and(is_digits))?;
+    let report = &packet[start + 1..];
+
+    let time = NaiveTime::parse_from_str(report.get(..6)?, "%H%M%S").ok()?;
+    // Older builds didn't zero pad the longitude minutes, so go by the hemisphere letters rather than fixed offsets.
+    let position = report.get(7..)?;
+    // Some of them also wrote the latitude hemisphere as E or W.
+    let lat_end = position.find(['N', 'S', 'E', 'W'])? + 1;
+    let latitude = parse_coordinate(&position[..lat_end], 2)?;
+    let position = position.get(lat_end + 1..)?;
+    let long_end = position.find(['E', 'W'])? + 1;
+    let longitude = parse_coordinate(&position[..long_end], 3)?;
+
+    let pressure = field(report, "/Pa=").or(baro);
+    let altitude = match field(report, "/A=") {
+        Some(feet) => Some(feet * FEET_TO_METERS),
+        None => pressure.map(altitude::pressure_altitude),
+    };
+
+    // Without a date in the log line, all we know is the time of day.
+    let time = match logged {
+        Some(logged) => logged,
+        None => time.signed_duration_since(NaiveTime::MIN).num_milliseconds(),
+    };
+
+    Some(Point {
+        time,
+        latitude,
+        longitude,
+        altitude,
+        pressure,
+        temperature: field(report, "/Ti="),
+        humidity: field(report, "/Hu="),
+    })
+}
+
+/// The pressure in a `T#NNN,hPa,...` telemetry packet, in Pascals.
+fn parse_telemetry_pressure(packet: &str) -> Option<f32> {
+    let report = &packet[packet.find("T#")?..];
+    let hectopascals: f32 = report.split(',').nth(1)?.trim().parse().ok()?;
+
+    Some(hectopascals * 100.0)
+}
+
+/// Parses `DDMM.mmN` or `DDDMM.mmW` into signed degrees.
+fn parse_coordinate(text: &str, degree_digits: usize) -> Option<f64> {
+    let degrees: f64 = text.get(..degree_digits)?.parse().ok()?;
+    let minutes: f64 = text.get(degree_digits..text.len() - 1)?.parse().ok()?;
+    let value = degrees + minutes / 60.0;
+
+    match text.chars().last()? {
+        'N' | 'E' => Some(value),
+        'S' | 'W' => Some(-value),
+        _ => None,
+    }
+}
+
+fn field(report: &str, name: &str) -> Option<f32> {
+    let start = report.find(name)? + name.len();
+    let value = &report[start..];
+    let end = value.find('/').unwrap_or(value.len());
+
+    value[..end].trim().parse().ok()
+}
+
+fn is_digits(text: &str) -> bool {
+    text.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    /// 2024-06-10T06:13:20Z
+    pub const START: i64 = 1_718_000_000_000;
+
+    /// A short flight with a fix missing its altitude and readings missing here and there.
+    pub fn flight() -> Flight {
+        Flight {
+            points: vec![
+                point(0, 40.015, -105.2705, Some((1650.0, 83412.0, 21.5, Some(40.0)))),
+                point(600, 40.02, -105.25, Some((4650.0, 57000.0, -5.25, None))),
+                point(5400, 40.1, -104.9, Some((28950.0, 1100.0, -56.5, None))),
+                point(6600, 40.15, -104.75, None),
+                point(7800, 40.2, -104.6, Some((2400.0, 75000.0, 18.0, Some(55.5)))),
+            ],
+            launch: Some(START + 60_000),
+            burst: Some(START + 5_430_000),
+            landing: Some(START + 7_800_000),
+            packets_sent: 120,
+            packets_failed: 3,
+            skipped: 1,
+        }
+    }
+
+    fn point(seconds: i64, latitude: f64, longitude: f64, readings: Option<(f32, f32, f32, Option<f32>)>) -> Point {
+        Point {
+            time: START + seconds * 1000,
+            latitude,
+            longitude,
+            altitude: readings.map(|readings| readings.0),
+            pressure: readings.map(|readings| readings.1),
+            temperature: readings.map(|readings| readings.2),
+            humidity: readings.and_then(|readings| readings.3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_location_packet() {
+        let point = parse_location("\u{7e}header/182657h4001.13N/10516.23WO 090/012/A=005413/Pa=083412/Ti=21.50", None, None).unwrap();
+
+        assert_eq!(point.time, (18 * 3600 + 26 * 60 + 57) * 1000);
+        assert!((point.latitude - (40.0 + 1.13 / 60.0)).abs() < 1e-9);
+        assert!((point.longitude + (105.0 + 16.23 / 60.0)).abs() < 1e-9);
+        assert!((point.altitude.unwrap() - 1650.0).abs() < 0.5);
+        assert_eq!(point.pressure, Some(83412.0));
+        assert_eq!(point.temperature, Some(21.5));
+        assert_eq!(point.humidity, None);
+    }
+
+    #[test]
+    fn parses_legacy_packets_with_the_latitude_hemisphere_as_e() {
+        let point = parse_location("header/182657h3311.13E/11720.53WO", None, None).unwrap();
+
+        assert!((point.latitude - (33.0 + 11.13 / 60.0)).abs() < 1e-9);
+        assert!((point.longitude + (117.0 + 20.53 / 60.0)).abs() < 1e-9);
+        assert_eq!(point.altitude, None);
+    }
+
+    #[test]
+    fn takes_the_altitude_from_the_barometer_without_a_gps_one() {
+        let packet = "header/182657h4001.13N/10516.23WO";
+
+        let point = parse_location(packet, None, parse_telemetry_pressure("header T#012,1013.2,21.5,40,0.0,3,00000000")).unwrap();
+        assert!(point.altitude.unwrap().abs() < 10.0, "{point:?}");
+
+        let point = parse_location(&format!("{packet}/Pa=054020"), None, Some(101_325.0)).unwrap();
+        assert!((point.altitude.unwrap() - 5000.0).abs() < 10.0, "{point:?}");
+    }
+
+    #[test]
+    fn counts_only_packets_the_radio_failed_to_send() {
+        let log = "\
+2024-06-10 06:13:20 INFO Keyed for 1.20s; 1.2s used of the 60.0s budget, 1.2s over 1 transmissions in total
+2024-06-10 06:13:50 WARN Skipping location packet without both a GPS and a barometer reading
+2024-06-10 06:14:20 WARN Skipping telemetry packet without a barometer reading
+2024-06-10 06:14:50 WARN Skipping location packet: duty cycle exceeded, retry in 12s
+2024-06-10 06:15:20 WARN Skipping image packet: duty cycle exceeded, retry in 40s
+2024-06-10 06:15:50 WARN failed to transmit telemetry: radio not responding
+2024-06-10 06:16:20 INFO Keyed for 1.10s; 2.3s used of the 60.0s budget, 2.3s over 2 transmissions in total
+";
+        let mut flight = Flight::default();
+
+        read_log(log.as_bytes(), &mut flight).unwrap();
+
+        assert_eq!(flight.packets_sent, 2);
+        assert_eq!(flight.packets_failed, 3);
+        assert_eq!(flight.skipped, 0);
+    }
+
+    #[test]
+    fn reads_the_track_and_milestones_from_a_log() {
+        let log = "\
+2024-06-10 06:13:20 INFO Sending APRS telemetry packet: \"header T#012,834.1,21.5,40,0.0,3,00000000\"
+2024-06-10 06:14:20 INFO Entered ascent phase
+2024-06-10 06:15:20 INFO Sending APRS location packet: \"header/061520h4001.13N/10516.23WO 090/012/A=005413\"
+2024-06-10 07:43:20 INFO Burst detected at 28950 m
+2024-06-10 07:45:20 INFO Sending APRS location packet: \"header/074520h4006.00N/10454.00WO\"
+2024-06-10 07:45:50 INFO Sending APRS location packet: \"header/0745\"
+";
+        let mut flight = Flight::default();
+
+        read_log(log.as_bytes(), &mut flight).unwrap();
+
+        assert_eq!(flight.points.len(), 2);
+        assert_eq!(flight.points[0].time, mock::START + 120_000);
+        assert!((flight.points[0].altitude.unwrap() - 1650.0).abs() < 0.5);
+        // Its altitude comes from the last telemetry packet's pressure.
+        assert_eq!(flight.points[1].pressure, Some(83410.0));
+        assert_eq!(flight.points[1].altitude, Some(altitude::pressure_altitude(83410.0)));
+        assert_eq!(flight.launch, Some(mock::START + 60_000));
+        assert_eq!(flight.burst, Some(mock::START + 5_400_000));
+        assert_eq!(flight.landing, None);
+        assert_eq!(flight.skipped, 1);
+    }
+}
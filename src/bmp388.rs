@@ -1,191 +1,1124 @@
-use std::{
-    mem::{self, MaybeUninit},
-    thread,
-    time::Duration,
-};
+use core::time::Duration;
+
+use embedded_hal::{delay::DelayNs, i2c::I2c};
+use heapless::Vec;
+use thiserror::Error;
 
-use rpi_embedded::i2c::{self, I2c};
+use crate::{
+    altitude::{self, STANDARD_PRESSURE},
+    bus::Registers,
+};
 
-const CHIP_ID_REGISTER: u8 = 0x00;
+pub const CHIP_ID_REGISTER: u8 = 0x00;
 const ERROR_REGISTER: u8 = 0x02;
 const STATUS_REGISTER: u8 = 0x03;
 const PRESSURE_REGISTER: u8 = 0x04;
 const TEMPERATURE_REGISTER: u8 = 0x07;
+const SENSOR_TIME_REGISTER: u8 = 0x0C;
+const INT_STATUS_REGISTER: u8 = 0x11;
+const FIFO_LENGTH_REGISTER: u8 = 0x12;
+const FIFO_DATA_REGISTER: u8 = 0x14;
+const FIFO_WATERMARK_REGISTER: u8 = 0x15;
+const FIFO_CONFIG_1_REGISTER: u8 = 0x17;
+const FIFO_CONFIG_2_REGISTER: u8 = 0x18;
+const INT_CTRL_REGISTER: u8 = 0x19;
 const POWER_CTRL_REGISTER: u8 = 0x1B;
-const BMP388_ADDRESS: u16 = 0x77;
+const OSR_REGISTER: u8 = 0x1C;
+const ODR_REGISTER: u8 = 0x1D;
+const CONFIG_REGISTER: u8 = 0x1F;
+/// Address with SDO pulled high
+pub const BMP388_ADDRESS: u8 = 0x77;
+/// Address with SDO pulled low
+pub const BMP388_ALT_ADDRESS: u8 = 0x76;
+
+pub const BMP388_CHIP_ID: u8 = 0x50;
+pub const BMP390_CHIP_ID: u8 = 0x60;
 const COMMAND_REGISTER: u8 = 0x7E;
+const CALIBRATION_REGISTER: u8 = 0x31;
+const CALIBRATION_LENGTH: usize = 21;
 
 const SOFT_RESET_COMMAND: u8 = 0xB6;
+const FIFO_FLUSH_COMMAND: u8 = 0xB0;
+
+/// Size of the FIFO in bytes.
+const FIFO_SIZE: usize = 512;
+/// Most readings a full FIFO can hold, all in 4 byte pressure only frames.
+pub const MAX_FIFO_READINGS: usize = FIFO_SIZE / 4;
+
+const FIFO_PRESSURE_TEMPERATURE_FRAME: u8 = 0x94;
+const FIFO_TEMPERATURE_FRAME: u8 = 0x90;
+const FIFO_PRESSURE_FRAME: u8 = 0x84;
+const FIFO_SENSOR_TIME_FRAME: u8 = 0xA0;
+const FIFO_EMPTY_FRAME: u8 = 0x80;
+const FIFO_CONFIG_CHANGE_FRAME: u8 = 0x48;
+const FIFO_ERROR_FRAME: u8 = 0x44;
 
 const CMD_READY_MASK: u8 = 0x10;
+const PRESSURE_READY_MASK: u8 = 0x20;
+const TEMPERATURE_READY_MASK: u8 = 0x40;
 const COMMAND_ERROR_MASK: u8 = 0x02;
+const CONFIG_ERROR_MASK: u8 = 0x04;
 
-const SEA_LEVEL_PRESSURE: f32 = 101219.16;
+const PRESSURE_ENABLE: u8 = 0x01;
+const TEMPERATURE_ENABLE: u8 = 0x02;
 
-pub struct Bmp388 {
-    i2c: I2c,
+pub struct Bmp388<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+    calibration: Calibration,
+    config: Config,
+    fifo: Option<FifoConfig>,
+    /// Pressure at the altitude datum, in pascals.
+    qnh: f32,
 }
 
-impl Bmp388 {
-    pub fn new() -> Result<Self, AltimeterError> {
-        let mut i2c = I2c::with_bus(1)?;
-        i2c.set_slave_address(BMP388_ADDRESS)?;
-
-        let this = Self { i2c };
+impl<I2C: I2c, D: DelayNs> Bmp388<I2C, D> {
+    pub fn with_bus(i2c: I2C, delay: D, address: u8, config: Config) -> Result<Self, AltimeterError<I2C::Error>> {
+        let mut this = Self {
+            i2c,
+            delay,
+            address,
+            calibration: Calibration::default(),
+            config,
+            fifo: None,
+            qnh: STANDARD_PRESSURE,
+        };
         this.init()?;
 
         Ok(this)
     }
 
-    pub fn read(&mut self) -> rpi_embedded::i2c::Result<AltimeterData> {
-        let mut data = [0; 6];
+    /// Reads the latest measurement.
+    ///
+    /// Only returns fresh data in normal mode, use [`Bmp388::read_forced`] otherwise.
+    pub fn read(&mut self) -> Result<AltimeterData, AltimeterError<I2C::Error>> {
+        let (raw_pressure, raw_temperature) = self.read_raw()?;
 
-        self.i2c.write_read(&[PRESSURE_REGISTER], &mut data)?;
+        Ok(self.compensate(raw_pressure, raw_temperature))
+    }
+
+    /// Triggers a single measurement and waits for it to finish.
+    ///
+    /// The sensor goes back to sleep afterwards, so this is meant for forced mode.
+    pub fn read_forced(&mut self) -> Result<AltimeterData, AltimeterError<I2C::Error>> {
+        self.i2c.write_register(self.address, POWER_CTRL_REGISTER, self.config.power_control(PowerMode::Forced))?;
+
+        let measurement_time = self.config.measurement_time();
+        self.delay.delay_us(measurement_time.as_micros() as u32);
+
+        // Give up once it's taken twice as long as it should have.
+        let mut polls = measurement_time.as_millis() + 1;
+        let ready = PRESSURE_READY_MASK | TEMPERATURE_READY_MASK;
+        while self.i2c.read_register(self.address, STATUS_REGISTER)? & ready != ready {
+            if polls == 0 {
+                return Err(AltimeterError::NotReady);
+            }
 
-        // Yeah this is stupid but it works :3
-        let mut coef_uninit: MaybeUninit<IntCoefficients> = MaybeUninit::uninit();
-        let coef_slice: &mut [u8; 21] = unsafe { mem::transmute(coef_uninit.as_mut_ptr()) };
-        self.i2c.write_read(&[0x31], coef_slice)?;
-        let coef = unsafe { coef_uninit.assume_init() };
+            polls -= 1;
+            self.delay.delay_ms(1);
+        }
+
+        let (raw_pressure, raw_temperature) = self.read_raw()?;
 
-        let raw_temperature = u32::from_be_bytes([0x00, data[5], data[4], data[3]]);
-        let temperature = self.compensate_temperature(raw_temperature as f32, coef)?;
+        Ok(self.compensate(raw_pressure, raw_temperature))
+    }
 
-        let raw_pressure = u32::from_be_bytes([0x00, data[2], data[1], data[0]]);
-        let pressure = self.compensate_pressure(raw_pressure as f32, temperature, coef)?;
+    /// Applies a new configuration, going through sleep mode as the datasheet requires.
+    pub fn configure(&mut self, config: Config) -> Result<(), AltimeterError<I2C::Error>> {
+        config.validate()?;
 
-        let altitude = ((SEA_LEVEL_PRESSURE / pressure).powf(0.190223) - 1.0)
-            * (temperature + 273.15)
-            / 0.0065;
+        self.i2c.write_register(self.address, POWER_CTRL_REGISTER, config.power_control(PowerMode::Sleep))?;
+        self.i2c.write_register(self.address, OSR_REGISTER, config.osr())?;
+        self.i2c.write_register(self.address, ODR_REGISTER, config.output_data_rate as u8)?;
+        self.i2c.write_register(self.address, CONFIG_REGISTER, (config.iir_filter as u8) << 1)?;
+        self.i2c.write_register(self.address, POWER_CTRL_REGISTER, config.power_control(config.mode))?;
 
-        Ok(AltimeterData {
+        if self.i2c.read_register(self.address, ERROR_REGISTER)? & CONFIG_ERROR_MASK > 0 {
+            return Err(AltimeterError::InvalidConfig("rejected by the sensor"));
+        }
+
+        self.config = config;
+
+        Ok(())
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Sets the pressure altitudes are measured from, in pascals.
+    pub fn set_qnh(&mut self, qnh: f32) {
+        self.qnh = qnh;
+    }
+
+    pub fn qnh(&self) -> f32 {
+        self.qnh
+    }
+
+    fn compensate(&self, raw_pressure: u32, raw_temperature: u32) -> AltimeterData {
+        let temperature = self.calibration.compensate_temperature(raw_temperature);
+        let pressure = self.calibration.compensate_pressure(raw_pressure, temperature);
+
+        let temperature = temperature as f32;
+        let pressure = pressure as f32;
+
+        let altitude = altitude::altitude(pressure, self.qnh);
+
+        AltimeterData {
             pressure,
             temperature,
             altitude,
+            humidity: None,
+        }
+    }
+
+    /// Reads the pressure and temperature using the datasheet's 64-bit integer compensation,
+    /// returned in hundredths of a pascal and hundredths of a degree Celcius.
+    pub fn read_fixed(&mut self) -> Result<(u64, i64), AltimeterError<I2C::Error>> {
+        let (raw_pressure, raw_temperature) = self.read_raw()?;
+
+        let (t_lin, temperature) = self.calibration.compensate_temperature_fixed(raw_temperature);
+        let pressure = self.calibration.compensate_pressure_fixed(raw_pressure, t_lin);
+
+        Ok((pressure, temperature))
+    }
+
+    /// Sets up the FIFO. Frames are only collected in normal mode.
+    pub fn configure_fifo(&mut self, config: &FifoConfig) -> Result<(), AltimeterError<I2C::Error>> {
+        config.validate()?;
+
+        let [watermark_low, watermark_high] = config.watermark.to_le_bytes();
+        // Burst writes need address/data pairs, so it's simpler to write these one at a time.
+        self.i2c.write_register(self.address, FIFO_WATERMARK_REGISTER, watermark_low)?;
+        self.i2c.write_register(self.address, FIFO_WATERMARK_REGISTER + 1, watermark_high & 0x01)?;
+        self.i2c.write_register(self.address, FIFO_CONFIG_2_REGISTER, (config.filtered as u8) << 3 | config.subsampling)?;
+        self.i2c.write_register(self.address, FIFO_CONFIG_1_REGISTER, config.register())?;
+
+        if self.i2c.read_register(self.address, ERROR_REGISTER)? & CONFIG_ERROR_MASK > 0 {
+            return Err(AltimeterError::InvalidConfig("FIFO configuration rejected by the sensor"));
+        }
+
+        self.fifo = Some(*config);
+
+        Ok(())
+    }
+
+    /// How the FIFO is set up, if [`Bmp388::configure_fifo`] has been called.
+    pub fn fifo_config(&self) -> Option<&FifoConfig> {
+        self.fifo.as_ref()
+    }
+
+    pub fn configure_interrupts(&mut self, config: &InterruptConfig) -> Result<(), AltimeterError<I2C::Error>> {
+        self.i2c.write_register(self.address, INT_CTRL_REGISTER, config.register())?;
+
+        Ok(())
+    }
+
+    /// Reads and clears the interrupt status.
+    pub fn interrupt_status(&mut self) -> Result<InterruptStatus, AltimeterError<I2C::Error>> {
+        let status = self.i2c.read_register(self.address, INT_STATUS_REGISTER)?;
+
+        Ok(InterruptStatus {
+            fifo_watermark: status & 0x01 > 0,
+            fifo_full: status & 0x02 > 0,
+            data_ready: status & 0x08 > 0,
         })
     }
 
-    fn init(&self) -> Result<(), AltimeterError> {
+    /// Number of bytes waiting in the FIFO.
+    pub fn fifo_length(&mut self) -> Result<usize, AltimeterError<I2C::Error>> {
+        let mut length = [0; 2];
+        self.i2c.read_registers(self.address, FIFO_LENGTH_REGISTER, &mut length)?;
+
+        Ok(u16::from_le_bytes([length[0], length[1] & 0x01]) as usize)
+    }
+
+    pub fn flush_fifo(&mut self) -> Result<(), AltimeterError<I2C::Error>> {
+        self.i2c.write_register(self.address, COMMAND_REGISTER, FIFO_FLUSH_COMMAND)?;
+
+        Ok(())
+    }
+
+    /// Reads the sensor time, in ticks of 1/25600 s.
+    pub fn sensor_time(&mut self) -> Result<u32, AltimeterError<I2C::Error>> {
+        let mut time = [0; 3];
+        self.i2c.read_registers(self.address, SENSOR_TIME_REGISTER, &mut time)?;
+
+        Ok(u32::from_le_bytes([time[0], time[1], time[2], 0x00]))
+    }
+
+    /// Empties the FIFO, returning every complete reading with an estimate of how long ago it was taken.
+    pub fn drain_fifo(&mut self) -> Result<FifoBatch, AltimeterError<I2C::Error>> {
+        let config = self.fifo.ok_or(AltimeterError::InvalidConfig("FIFO hasn't been configured"))?;
+
+        let length = self.fifo_length()?;
+
+        // The sensor time frame only shows up once the FIFO has been read past its end.
+        let extra = if config.sensor_time { 4 } else { 0 };
+        let mut buf = [0; FIFO_SIZE + 4];
+        let data = &mut buf[..(length + extra).min(FIFO_SIZE + extra)];
+        if !data.is_empty() {
+            self.i2c.read_registers(self.address, FIFO_DATA_REGISTER, data)?;
+        }
+
+        let mut batch = FifoBatch::default();
+        let mut readings: Vec<AltimeterData, MAX_FIFO_READINGS> = Vec::new();
+        let mut temperature = None;
+
+        // There can't be more readings than fit in the FIFO, so pushes never fail.
+        for frame in parse_fifo(data) {
+            match frame {
+                FifoFrame::Measurement { pressure, temperature: Some(raw_temperature) } => {
+                    temperature = Some(raw_temperature);
+                    if let Some(raw_pressure) = pressure {
+                        let _ = readings.push(self.compensate(raw_pressure, raw_temperature));
+                    }
+                }
+                FifoFrame::Measurement { pressure: Some(raw_pressure), temperature: None } => {
+                    // Pressure only frames are compensated with the last temperature seen.
+                    if let Some(raw_temperature) = temperature {
+                        let _ = readings.push(self.compensate(raw_pressure, raw_temperature));
+                    }
+                }
+                FifoFrame::Measurement { pressure: None, temperature: None } => {}
+                FifoFrame::SensorTime(time) => batch.sensor_time = Some(time),
+                FifoFrame::ConfigChange => batch.config_changed = true,
+                FifoFrame::Error => batch.errors += 1,
+                FifoFrame::Empty => break,
+            }
+        }
+
+        // Readings are evenly spaced, with the newest one taken just before the FIFO was read.
+        let interval = self.config.output_data_rate.period() * (1 << config.subsampling);
+        let count = readings.len() as u32;
+        batch.readings = readings
+            .into_iter()
+            .zip(1..)
+            .map(|(data, i)| TimedReading {
+                age: interval * (count - i),
+                data,
+            })
+            .collect();
+
+        Ok(batch)
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    /// Reads the uncompensated pressure and temperature.
+    fn read_raw(&mut self) -> Result<(u32, u32), AltimeterError<I2C::Error>> {
+        let mut data = [0; 6];
+        self.i2c.read_registers(self.address, PRESSURE_REGISTER, &mut data)?;
+
+        let raw_pressure = u32::from_le_bytes([data[0], data[1], data[2], 0x00]);
+        let raw_temperature = u32::from_le_bytes([data[3], data[4], data[5], 0x00]);
+
+        Ok((raw_pressure, raw_temperature))
+    }
+
+    /// The chip ID, telling a BMP388 and BMP390 apart.
+    pub fn chip_id(&mut self) -> Result<u8, AltimeterError<I2C::Error>> {
+        Ok(self.i2c.read_register(self.address, CHIP_ID_REGISTER)?)
+    }
+
+    fn init(&mut self) -> Result<(), AltimeterError<I2C::Error>> {
+        let chip_id = self.chip_id()?;
+        if chip_id != BMP388_CHIP_ID && chip_id != BMP390_CHIP_ID {
+            return Err(AltimeterError::WrongChipId(chip_id));
+        }
+
         self.reset()?;
+        self.configure(self.config)?;
 
-        let chip_id = self.i2c.smbus_read_byte(CHIP_ID_REGISTER)?;
-        self.i2c.smbus_write_byte(POWER_CTRL_REGISTER, 0b00110011)?;
-        self.i2c.smbus_write_byte(0x1C, 0)?;
-        self.i2c.smbus_write_byte(0x1D, 0)?;
+        // The calibration never changes, so it only needs reading once.
+        let mut nvm = [0; CALIBRATION_LENGTH];
+        self.i2c.read_registers(self.address, CALIBRATION_REGISTER, &mut nvm)?;
+        check_nvm(&nvm)?;
+        self.calibration = Calibration::parse(&nvm);
 
         Ok(())
     }
 
-    pub fn reset(&self) -> Result<(), AltimeterError> {
-        let status = self.i2c.smbus_read_byte(STATUS_REGISTER)?;
+    pub fn reset(&mut self) -> Result<(), AltimeterError<I2C::Error>> {
+        let status = self.i2c.read_register(self.address, STATUS_REGISTER)?;
         if status & CMD_READY_MASK == 0 {
             return Err(AltimeterError::CommandFailed);
         }
 
-        self.i2c
-            .smbus_write_byte(COMMAND_REGISTER, SOFT_RESET_COMMAND)?;
-        thread::sleep(Duration::from_micros(2000));
-        let result = self.i2c.smbus_read_byte(ERROR_REGISTER)?;
+        self.i2c.write_register(self.address, COMMAND_REGISTER, SOFT_RESET_COMMAND)?;
+        self.delay.delay_us(2000);
+        let result = self.i2c.read_register(self.address, ERROR_REGISTER)?;
         if result & COMMAND_ERROR_MASK > 0 {
             return Err(AltimeterError::CommandFailed);
         }
 
         return Ok(());
     }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Oversampling {
+    X1 = 0,
+    X2 = 1,
+    X4 = 2,
+    X8 = 3,
+    X16 = 4,
+    X32 = 5,
+}
+
+impl Oversampling {
+    pub fn samples(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IirFilter {
+    Bypass = 0,
+    Coef1 = 1,
+    Coef3 = 2,
+    Coef7 = 3,
+    Coef15 = 4,
+    Coef31 = 5,
+    Coef63 = 6,
+    Coef127 = 7,
+}
+
+/// Output data rate in normal mode, each step halving the one before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputDataRate {
+    Hz200 = 0,
+    Hz100 = 1,
+    Hz50 = 2,
+    Hz25 = 3,
+    Hz12_5 = 4,
+    Hz6_25 = 5,
+    Hz3_1 = 6,
+    Hz1_5 = 7,
+    Hz0_78 = 8,
+    Hz0_39 = 9,
+    Hz0_2 = 10,
+    Hz0_1 = 11,
+    Hz0_05 = 12,
+    Hz0_02 = 13,
+    Hz0_01 = 14,
+    Hz0_006 = 15,
+    Hz0_003 = 16,
+    Hz0_0015 = 17,
+}
+
+impl OutputDataRate {
+    pub fn period(&self) -> Duration {
+        Duration::from_millis(5) * (1 << (*self as u32))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PowerMode {
+    Sleep,
+    /// Take one measurement then go back to sleep.
+    Forced,
+    /// Measure continuously at the output data rate.
+    Normal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Config {
+    pub pressure_oversampling: Oversampling,
+    pub temperature_oversampling: Oversampling,
+    pub iir_filter: IirFilter,
+    pub output_data_rate: OutputDataRate,
+    pub mode: PowerMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pressure_oversampling: Oversampling::X1,
+            temperature_oversampling: Oversampling::X1,
+            iir_filter: IirFilter::Bypass,
+            output_data_rate: OutputDataRate::Hz200,
+            mode: PowerMode::Normal,
+        }
+    }
+}
+
+impl Config {
+    /// Maximum time a single measurement takes (datasheet section 3.9.2).
+    pub fn measurement_time(&self) -> Duration {
+        let micros = 234
+            + 392 + self.pressure_oversampling.samples() * 2020
+            + 163 + self.temperature_oversampling.samples() * 2020;
+
+        Duration::from_micros(micros as u64)
+    }
+
+    /// Checks the configuration against the datasheet's constraints.
+    ///
+    /// In normal mode every measurement has to fit within one output data period,
+    /// otherwise the sensor flags a configuration error.
+    pub fn validate<E>(&self) -> Result<(), AltimeterError<E>> {
+        if self.mode == PowerMode::Normal && self.measurement_time() > self.output_data_rate.period() {
+            return Err(AltimeterError::InvalidConfig("measurement takes longer than the output data period"));
+        }
+
+        Ok(())
+    }
+
+    fn osr(&self) -> u8 {
+        (self.temperature_oversampling as u8) << 3 | self.pressure_oversampling as u8
+    }
+
+    fn power_control(&self, mode: PowerMode) -> u8 {
+        let mode = match mode {
+            PowerMode::Sleep => 0b00,
+            PowerMode::Forced => 0b01,
+            PowerMode::Normal => 0b11,
+        };
+
+        mode << 4 | TEMPERATURE_ENABLE | PRESSURE_ENABLE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FifoConfig {
+    pub pressure: bool,
+    pub temperature: bool,
+    /// Append a sensor time frame when the FIFO is read empty.
+    pub sensor_time: bool,
+    /// Stop collecting when full instead of overwriting the oldest frames.
+    pub stop_on_full: bool,
+    /// Only store every 2^`subsampling` measurements, from 0 to 7.
+    pub subsampling: u8,
+    /// Store IIR filtered data instead of the raw measurements.
+    pub filtered: bool,
+    /// Fill level in bytes that triggers the watermark interrupt, up to 511.
+    pub watermark: u16,
+}
+
+impl Default for FifoConfig {
+    fn default() -> Self {
+        Self {
+            pressure: true,
+            temperature: true,
+            sensor_time: true,
+            stop_on_full: false,
+            subsampling: 0,
+            filtered: true,
+            // Room for 64 pressure and temperature frames
+            watermark: 448,
+        }
+    }
+}
+
+impl FifoConfig {
+    fn validate<E>(&self) -> Result<(), AltimeterError<E>> {
+        if self.subsampling > 7 {
+            return Err(AltimeterError::InvalidConfig("FIFO subsampling must be between 0 and 7"));
+        }
 
-    fn compensate_temperature(&self, raw_temp: f32, coef: IntCoefficients) -> i2c::Result<f32> {
-        let nvm_t1 = coef.t1 as f32;
-        let t1 = nvm_t1 / 2.0f32.powi(-8);
+        if self.watermark as usize >= FIFO_SIZE {
+            return Err(AltimeterError::InvalidConfig("FIFO watermark must be below 512 bytes"));
+        }
 
-        let nvm_t2 = coef.t2 as f32;
-        let t2 = nvm_t2 / 2.0f32.powi(30);
+        Ok(())
+    }
 
-        let nvm_t3 = coef.t3 as f32;
-        let t3 = nvm_t3 / 2.0f32.powi(48);
+    fn register(&self) -> u8 {
+        (self.temperature as u8) << 4
+            | (self.pressure as u8) << 3
+            | (self.sensor_time as u8) << 2
+            | (self.stop_on_full as u8) << 1
+            | 0x01
+    }
+}
 
-        let partial1 = raw_temp as f32 - t1;
-        let partial2 = partial1 * t2 as f32;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct InterruptConfig {
+    /// Open drain output instead of push-pull.
+    pub open_drain: bool,
+    pub active_high: bool,
+    /// Hold the pin until the status is read.
+    pub latch: bool,
+    pub fifo_watermark: bool,
+    pub fifo_full: bool,
+    pub data_ready: bool,
+}
 
-        return Ok(partial2 + (partial1 * partial1) * t3);
+impl InterruptConfig {
+    fn register(&self) -> u8 {
+        (self.data_ready as u8) << 6
+            | (self.fifo_full as u8) << 4
+            | (self.fifo_watermark as u8) << 3
+            | (self.latch as u8) << 2
+            | (self.active_high as u8) << 1
+            | self.open_drain as u8
     }
+}
 
-    fn compensate_pressure(
-        &self,
-        raw_press: f32,
-        temp: f32,
-        coef: IntCoefficients,
-    ) -> i2c::Result<f32> {
-        // Absolute garbage from the BMP388 datasheet (pg. 56)
-        let p1 = ((coef.p1 as f32) - 2.0f32.powi(14)) / 2.0f32.powi(20);
-        let p2 = ((coef.p2 as f32) - 2.0f32.powi(14)) / 2.0f32.powi(29);
-        let p3 = (coef.p3 as f32) / 2.0f32.powi(32);
-        let p4 = (coef.p4 as f32) / 2.0f32.powi(37);
-        let p5 = (coef.p5 as f32) / 2.0f32.powi(-3);
-        let p6 = (coef.p6 as f32) / 2.0f32.powi(6);
-        let p7 = (coef.p7 as f32) / 2.0f32.powi(8);
-        let p8 = (coef.p8 as f32) / 2.0f32.powi(15);
-        let p9 = (coef.p9 as f32) / 2.0f32.powi(48);
-        let p10 = (coef.p10 as f32) / 2.0f32.powi(48);
-        let p11 = (coef.p11 as f32) / 2.0f32.powi(65);
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct InterruptStatus {
+    pub fifo_watermark: bool,
+    pub fifo_full: bool,
+    pub data_ready: bool,
+}
 
-        let partial1 = p6 * temp;
-        let partial2 = p7 * (temp * temp);
-        let partial3 = p8 * (temp * temp * temp);
-        let partial_out1 = p5 + partial1 + partial2 + partial3;
+/// A single frame from the FIFO, still uncompensated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FifoFrame {
+    Measurement {
+        pressure: Option<u32>,
+        temperature: Option<u32>,
+    },
+    SensorTime(u32),
+    /// The FIFO configuration changed since the last frame.
+    ConfigChange,
+    Error,
+    /// No more data, everything after this is padding.
+    Empty,
+}
 
-        let partial4 = p2 * temp;
-        let partial5 = p3 * temp;
-        let partial6 = p4 * temp;
-        let partial_out2 = raw_press * (p1 + partial4 + partial5 + partial6);
+/// Splits raw FIFO data into frames, stopping at the first empty or truncated one.
+pub fn parse_fifo(data: &[u8]) -> FifoFrames<'_> {
+    FifoFrames { data }
+}
 
-        let partial7 = raw_press * raw_press;
-        let partial8 = p9 + p10 * temp;
-        let partial9 = partial7 * partial8;
-        let partial_out3 = partial9 + (raw_press * raw_press * raw_press) * p11;
+/// Iterator over the frames in raw FIFO data, from [`parse_fifo`].
+#[derive(Debug, Clone)]
+pub struct FifoFrames<'a> {
+    /// Whatever hasn't been parsed yet.
+    data: &'a [u8],
+}
 
-        let comp_press = partial_out1 + partial_out2 + partial_out3;
+impl Iterator for FifoFrames<'_> {
+    type Item = FifoFrame;
 
-        return Ok(comp_press);
+    fn next(&mut self) -> Option<FifoFrame> {
+        let read_u24 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0x00]);
+
+        let (&header, rest) = self.data.split_first()?;
+        let length = match header {
+            FIFO_PRESSURE_TEMPERATURE_FRAME => 6,
+            FIFO_TEMPERATURE_FRAME | FIFO_PRESSURE_FRAME | FIFO_SENSOR_TIME_FRAME => 3,
+            // The config change and error frames carry a single byte of padding.
+            FIFO_CONFIG_CHANGE_FRAME | FIFO_ERROR_FRAME => 1,
+            FIFO_EMPTY_FRAME => {
+                self.data = &[];
+                return Some(FifoFrame::Empty);
+            }
+            // Anything else means we've lost track of the frame boundaries.
+            _ => {
+                self.data = &[];
+                return None;
+            }
+        };
+
+        let Some(payload) = rest.get(..length) else {
+            self.data = &[];
+            return None;
+        };
+        self.data = &rest[length..];
+
+        Some(match header {
+            // Temperature comes first in combined frames.
+            FIFO_PRESSURE_TEMPERATURE_FRAME => FifoFrame::Measurement {
+                pressure: Some(read_u24(&payload[3..])),
+                temperature: Some(read_u24(payload)),
+            },
+            FIFO_TEMPERATURE_FRAME => FifoFrame::Measurement {
+                pressure: None,
+                temperature: Some(read_u24(payload)),
+            },
+            FIFO_PRESSURE_FRAME => FifoFrame::Measurement {
+                pressure: Some(read_u24(payload)),
+                temperature: None,
+            },
+            FIFO_SENSOR_TIME_FRAME => FifoFrame::SensorTime(read_u24(payload)),
+            FIFO_CONFIG_CHANGE_FRAME => FifoFrame::ConfigChange,
+            _ => FifoFrame::Error,
+        })
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct TimedReading {
+    /// Estimated time between the measurement being taken and the FIFO being read.
+    pub age: Duration,
+    pub data: AltimeterData,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FifoBatch {
+    pub readings: Vec<TimedReading, MAX_FIFO_READINGS>,
+    /// Sensor time when the FIFO was read, in ticks of 1/25600 s.
+    pub sensor_time: Option<u32>,
+    pub config_changed: bool,
+    pub errors: usize,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct AltimeterData {
     // Pressure in Pascals
     pub pressure: f32,
     // Temperature in Celcius
     pub temperature: f32,
-    // Altitude in meters
+    // Barometric altitude above the QNH datum in meters
     pub altitude: f32,
+    // Relative humidity in percent, if the sensor has one
+    pub humidity: Option<f32>,
 }
 
-#[derive(Debug)]
-pub enum AltimeterError {
-    I2C(rpi_embedded::i2c::Error),
+#[derive(Debug, Error)]
+pub enum AltimeterError<E> {
+    #[error("I2C error: {0}")]
+    I2C(#[from] E),
+    #[error("sensor reported a command error")]
     CommandFailed,
+    #[error("unexpected chip ID {0:#04x}")]
+    WrongChipId(u8),
+    #[error("calibration data is invalid (NVM reads as all {0:#04x})")]
+    CalibrationInvalid(u8),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(&'static str),
+    #[error("measurement not ready in time")]
+    NotReady,
 }
 
-impl From<rpi_embedded::i2c::Error> for AltimeterError {
-    fn from(value: rpi_embedded::i2c::Error) -> Self {
-        AltimeterError::I2C(value)
+/// Rejects calibration data that is all zeros or all ones,
+/// which is what a blank NVM or a floating bus reads back as.
+pub fn check_nvm<E>(nvm: &[u8]) -> Result<(), AltimeterError<E>> {
+    for blank in [0x00, 0xFF] {
+        if nvm.iter().all(|&byte| byte == blank) {
+            return Err(AltimeterError::CalibrationInvalid(blank));
+        }
     }
+
+    Ok(())
 }
 
-#[repr(packed)]
-#[derive(Debug, Clone, Copy)]
-struct IntCoefficients {
-    t1: u16,
-    t2: u16,
-    t3: i8,
-    p1: i16,
-    p2: i16,
-    p3: i8,
-    p4: i8,
-    p5: u16,
-    p6: u16,
-    p7: i8,
-    p8: i8,
-    p9: i16,
-    p10: i8,
-    p11: i8,
+/// Trimming coefficients stored in the sensor's NVM, exactly as read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Calibration {
+    pub t1: u16,
+    pub t2: u16,
+    pub t3: i8,
+    pub p1: i16,
+    pub p2: i16,
+    pub p3: i8,
+    pub p4: i8,
+    pub p5: u16,
+    pub p6: u16,
+    pub p7: i8,
+    pub p8: i8,
+    pub p9: i16,
+    pub p10: i8,
+    pub p11: i8,
+}
+
+impl Calibration {
+    /// Parses the 21 little-endian bytes starting at `NVM_PAR_T1` (0x31).
+    pub fn parse(nvm: &[u8; CALIBRATION_LENGTH]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([nvm[i], nvm[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([nvm[i], nvm[i + 1]]);
+        let i8_at = |i: usize| nvm[i] as i8;
+
+        Self {
+            t1: u16_at(0),
+            t2: u16_at(2),
+            t3: i8_at(4),
+            p1: i16_at(5),
+            p2: i16_at(7),
+            p3: i8_at(9),
+            p4: i8_at(10),
+            p5: u16_at(11),
+            p6: u16_at(13),
+            p7: i8_at(15),
+            p8: i8_at(16),
+            p9: i16_at(17),
+            p10: i8_at(19),
+            p11: i8_at(20),
+        }
+    }
+
+    /// Floating point temperature compensation (datasheet section 9.2), in degrees Celcius.
+    pub fn compensate_temperature(&self, raw_temp: u32) -> f64 {
+        // Yes, the datasheet really does divide by 2^-8 here.
+        let t1 = self.t1 as f64 / pow2(-8);
+        let t2 = self.t2 as f64 / pow2(30);
+        let t3 = self.t3 as f64 / pow2(48);
+
+        let partial1 = raw_temp as f64 - t1;
+        let partial2 = partial1 * t2;
+
+        return partial2 + (partial1 * partial1) * t3;
+    }
+
+    /// Floating point pressure compensation (datasheet section 9.3), in pascals.
+    ///
+    /// `temp` is the compensated temperature from [`Calibration::compensate_temperature`].
+    pub fn compensate_pressure(&self, raw_press: u32, temp: f64) -> f64 {
+        let p1 = ((self.p1 as f64) - pow2(14)) / pow2(20);
+        let p2 = ((self.p2 as f64) - pow2(14)) / pow2(29);
+        let p3 = (self.p3 as f64) / pow2(32);
+        let p4 = (self.p4 as f64) / pow2(37);
+        let p5 = (self.p5 as f64) / pow2(-3);
+        let p6 = (self.p6 as f64) / pow2(6);
+        let p7 = (self.p7 as f64) / pow2(8);
+        let p8 = (self.p8 as f64) / pow2(15);
+        let p9 = (self.p9 as f64) / pow2(48);
+        let p10 = (self.p10 as f64) / pow2(48);
+        let p11 = (self.p11 as f64) / pow2(65);
+
+        let raw_press = raw_press as f64;
+        let temp2 = temp * temp;
+        let temp3 = temp2 * temp;
+
+        let partial_out1 = p5 + p6 * temp + p7 * temp2 + p8 * temp3;
+        let partial_out2 = raw_press * (p1 + p2 * temp + p3 * temp2 + p4 * temp3);
+
+        let partial1 = raw_press * raw_press;
+        let partial2 = p9 + p10 * temp;
+        let partial3 = partial1 * partial2;
+        let partial_out3 = partial3 + (raw_press * raw_press * raw_press) * p11;
+
+        return partial_out1 + partial_out2 + partial_out3;
+    }
+
+    /// 64-bit integer temperature compensation from Bosch's reference driver.
+    ///
+    /// Returns `t_lin`, needed for pressure compensation,
+    /// and the temperature in hundredths of a degree Celcius.
+    pub fn compensate_temperature_fixed(&self, raw_temp: u32) -> (i64, i64) {
+        let partial1 = raw_temp as i64 - 256 * self.t1 as i64;
+        let partial2 = self.t2 as i64 * partial1;
+        let partial3 = partial1 * partial1;
+        let partial4 = partial3 * self.t3 as i64;
+        let partial5 = partial2 * 262144 + partial4;
+        let t_lin = partial5 / 4294967296;
+
+        return (t_lin, t_lin * 25 / 16384);
+    }
+
+    /// 64-bit integer pressure compensation from Bosch's reference driver,
+    /// in hundredths of a pascal.
+    pub fn compensate_pressure_fixed(&self, raw_press: u32, t_lin: i64) -> u64 {
+        let raw_press = raw_press as i64;
+
+        let partial1 = t_lin * t_lin;
+        let partial2 = partial1 / 64;
+        let partial3 = partial2 * t_lin / 256;
+        let partial4 = self.p8 as i64 * partial3 / 32;
+        let partial5 = self.p7 as i64 * partial1 * 16;
+        let partial6 = self.p6 as i64 * t_lin * 4194304;
+        let offset = self.p5 as i64 * 140737488355328 + partial4 + partial5 + partial6;
+
+        let partial2 = self.p4 as i64 * partial3 / 32;
+        let partial4 = self.p3 as i64 * partial1 * 4;
+        let partial5 = (self.p2 as i64 - 16384) * t_lin * 2097152;
+        let sensitivity = (self.p1 as i64 - 16384) * 70368744177664 + partial2 + partial4 + partial5;
+
+        let partial1 = sensitivity / 16777216 * raw_press;
+        let partial2 = self.p10 as i64 * t_lin;
+        let partial3 = partial2 + 65536 * self.p9 as i64;
+        let partial4 = partial3 * raw_press / 8192;
+        // Dividing by 10 first keeps this from overflowing
+        let partial5 = raw_press * (partial4 / 10) / 512 * 10;
+        let partial6 = raw_press * raw_press;
+        let partial2 = self.p11 as i64 * partial6 / 65536;
+        let partial3 = partial2 * raw_press / 128;
+        let partial4 = offset / 4 + partial1 + partial5 + partial3;
+
+        return partial4 as u64 * 25 / 1099511627776;
+    }
+}
+
+/// 2^`exp`, exactly. `powi` needs std, and these are all well within range of an f64.
+fn pow2(exp: i32) -> f64 {
+    f64::from_bits(((1023 + exp) as u64) << 52)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::bus::mock::{MockBus, MockDelay};
+
+    const ADDRESS: u8 = BMP388_ADDRESS;
+
+    /// Calibration read off a BMP388.
+    const NVM: [u8; CALIBRATION_LENGTH] = [
+        0x00, 0x6c, 0x42, 0x4b, 0xf9, 0xfd, 0xff, 0xfa, 0xff, 0x23, 0x01, 0x36, 0x4d, 0xfb, 0x74, 0x03, 0xfc, 0x0c, 0x40,
+        0x09, 0xc4,
+    ];
+
+    fn bus() -> MockBus {
+        let mut bus = MockBus::new();
+        bus.add_device(ADDRESS)
+            .set_register(ADDRESS, CHIP_ID_REGISTER, BMP388_CHIP_ID)
+            .set_register(ADDRESS, STATUS_REGISTER, CMD_READY_MASK | PRESSURE_READY_MASK | TEMPERATURE_READY_MASK)
+            .set_registers(ADDRESS, CALIBRATION_REGISTER, &NVM);
+
+        bus
+    }
+
+    fn set_raw(bus: &mut MockBus, raw_pressure: u32, raw_temperature: u32) {
+        let [p0, p1, p2, _] = raw_pressure.to_le_bytes();
+        let [t0, t1, t2, _] = raw_temperature.to_le_bytes();
+        bus.set_registers(ADDRESS, PRESSURE_REGISTER, &[p0, p1, p2, t0, t1, t2]);
+    }
+
+    /// Raw temperature and pressure ADC values, with what Bosch's reference compensation gives for [`NVM`]:
+    /// the floating point °C and Pa, then the integer `t_lin`, hundredths of a °C and hundredths of a Pa.
+    const KNOWN_ANSWERS: [(u32, u32, f64, f64, i64, i64, u64); 3] = [
+        (8_300_000, 4_250_000, 21.891042, 100_073.788, 1_434_651, 2189, 10_007_378),
+        (8_400_000, 4_200_000, 23.679001, 101_479.714, 1_551_827, 2367, 10_147_971),
+        // A quarter of sea level pressure, around 10 km up, where the higher order terms count for more.
+        (8_000_000, 8_950_000, 16.524181, 25_040.978, 1_082_928, 1652, 2_504_097),
+    ];
+
+    #[test]
+    fn calibration_parses_the_nvm_layout() {
+        let calibration = Calibration::parse(&NVM);
+
+        assert_eq!(
+            calibration,
+            Calibration {
+                t1: 27648,
+                t2: 19266,
+                t3: -7,
+                p1: -3,
+                p2: -6,
+                p3: 35,
+                p4: 1,
+                p5: 19766,
+                p6: 29947,
+                p7: 3,
+                p8: -4,
+                p9: 16396,
+                p10: 9,
+                p11: -60,
+            }
+        );
+    }
+
+    #[test]
+    fn floating_point_compensation_matches_reference() {
+        let calibration = Calibration::parse(&NVM);
+
+        for (raw_temp, raw_press, temperature, pressure, ..) in KNOWN_ANSWERS {
+            let t = calibration.compensate_temperature(raw_temp);
+            let p = calibration.compensate_pressure(raw_press, t);
+
+            assert!((t - temperature).abs() < 1e-5, "{raw_temp}: {t} °C, expected {temperature} °C");
+            assert!((p - pressure).abs() < 1e-2, "{raw_press}: {p} Pa, expected {pressure} Pa");
+        }
+    }
+
+    #[test]
+    fn integer_compensation_matches_reference() {
+        let calibration = Calibration::parse(&NVM);
+
+        for (raw_temp, raw_press, _, _, t_lin, temperature, pressure) in KNOWN_ANSWERS {
+            assert_eq!(calibration.compensate_temperature_fixed(raw_temp), (t_lin, temperature));
+            assert_eq!(calibration.compensate_pressure_fixed(raw_press, t_lin), pressure);
+        }
+    }
+
+    #[test]
+    fn integer_and_floating_point_compensation_agree() {
+        let calibration = Calibration::parse(&NVM);
+
+        for (raw_temp, raw_press, ..) in KNOWN_ANSWERS {
+            let t = calibration.compensate_temperature(raw_temp);
+            let p = calibration.compensate_pressure(raw_press, t);
+            let (t_lin, t_fixed) = calibration.compensate_temperature_fixed(raw_temp);
+            let p_fixed = calibration.compensate_pressure_fixed(raw_press, t_lin);
+
+            assert!((t - t_fixed as f64 / 100.0).abs() <= 0.01);
+            assert!((p - p_fixed as f64 / 100.0).abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn init_resets_configures_and_reads_calibration() {
+        let bmp = Bmp388::with_bus(bus(), MockDelay::default(), ADDRESS, Config::default()).unwrap();
+
+        assert_eq!(
+            bmp.i2c.writes(ADDRESS),
+            [
+                vec![COMMAND_REGISTER, SOFT_RESET_COMMAND],
+                vec![POWER_CTRL_REGISTER, 0x03],
+                vec![OSR_REGISTER, 0x00],
+                vec![ODR_REGISTER, 0x00],
+                vec![CONFIG_REGISTER, 0x00],
+                vec![POWER_CTRL_REGISTER, 0x33],
+            ]
+        );
+        assert_eq!(*bmp.calibration(), Calibration::parse(&NVM));
+        assert_eq!(bmp.calibration().t1, 27648);
+        assert_eq!(bmp.calibration().p11, -60);
+    }
+
+    #[test]
+    fn init_accepts_bmp390() {
+        let mut bus = bus();
+        bus.set_register(ADDRESS, CHIP_ID_REGISTER, BMP390_CHIP_ID);
+
+        assert!(Bmp388::with_bus(bus, MockDelay::default(), ADDRESS, Config::default()).is_ok());
+    }
+
+    #[test]
+    fn init_rejects_other_chips() {
+        let mut bus = bus();
+        bus.set_register(ADDRESS, CHIP_ID_REGISTER, 0x58);
+
+        let result = Bmp388::with_bus(bus, MockDelay::default(), ADDRESS, Config::default());
+        assert!(matches!(result, Err(AltimeterError::WrongChipId(0x58))));
+    }
+
+    #[test]
+    fn init_rejects_blank_calibration() {
+        let mut bus = bus();
+        bus.set_registers(ADDRESS, CALIBRATION_REGISTER, &[0xFF; CALIBRATION_LENGTH]);
+
+        let result = Bmp388::with_bus(bus, MockDelay::default(), ADDRESS, Config::default());
+        assert!(matches!(result, Err(AltimeterError::CalibrationInvalid(0xFF))));
+    }
+
+    #[test]
+    fn init_fails_when_busy() {
+        let mut bus = bus();
+        bus.set_register(ADDRESS, STATUS_REGISTER, 0x00);
+
+        let result = Bmp388::with_bus(bus, MockDelay::default(), ADDRESS, Config::default());
+        assert!(matches!(result, Err(AltimeterError::CommandFailed)));
+    }
+
+    #[test]
+    fn read_compensates_the_data_registers() {
+        let mut bmp = Bmp388::with_bus(bus(), MockDelay::default(), ADDRESS, Config::default()).unwrap();
+        set_raw(&mut bmp.i2c, 4_250_000, 8_300_000);
+        bmp.set_qnh(100_000.0);
+
+        let data = bmp.read().unwrap();
+
+        let temperature = bmp.calibration().compensate_temperature(8_300_000);
+        assert_eq!(data.temperature, temperature as f32);
+        assert_eq!(data.pressure, bmp.calibration().compensate_pressure(4_250_000, temperature) as f32);
+        assert_eq!(data.altitude, altitude::altitude(data.pressure, 100_000.0));
+        assert_eq!(data.humidity, None);
+    }
+
+    #[test]
+    fn read_forced_gives_up_when_not_ready() {
+        let config = Config { mode: PowerMode::Forced, ..Config::default() };
+        let mut bmp = Bmp388::with_bus(bus(), MockDelay::default(), ADDRESS, config).unwrap();
+        bmp.i2c.set_register(ADDRESS, STATUS_REGISTER, CMD_READY_MASK);
+
+        assert!(matches!(bmp.read_forced(), Err(AltimeterError::NotReady)));
+        // The reset, the measurement time and then a millisecond for each poll.
+        assert_eq!(bmp.delay.waited_ns, 2_000_000 + 4_829_000 + 5_000_000);
+    }
+
+    #[test]
+    fn configure_rejects_measurements_longer_than_the_period() {
+        let config = Config {
+            pressure_oversampling: Oversampling::X32,
+            ..Config::default()
+        };
+
+        assert!(matches!(config.validate::<()>(), Err(AltimeterError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn parse_fifo_handles_every_frame_type() {
+        let data = [
+            0x94, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+            0x90, 0x11, 0x12, 0x13,
+            0x84, 0x21, 0x22, 0x23,
+            0xA0, 0x31, 0x32, 0x33,
+            0x48, 0x00,
+            0x44, 0x00,
+            0x80, 0x00,
+            // Padding after the empty frame is never looked at.
+            0x94, 0xff,
+        ];
+
+        let frames: Vec<_> = parse_fifo(&data).collect();
+
+        assert_eq!(
+            frames,
+            [
+                FifoFrame::Measurement {
+                    pressure: Some(0x060504),
+                    temperature: Some(0x030201),
+                },
+                FifoFrame::Measurement {
+                    pressure: None,
+                    temperature: Some(0x131211),
+                },
+                FifoFrame::Measurement {
+                    pressure: Some(0x232221),
+                    temperature: None,
+                },
+                FifoFrame::SensorTime(0x333231),
+                FifoFrame::ConfigChange,
+                FifoFrame::Error,
+                FifoFrame::Empty,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_fifo_stops_at_truncated_or_unknown_frames() {
+        assert_eq!(parse_fifo(&[0x94, 0x01, 0x02, 0x03]).count(), 0);
+        assert_eq!(parse_fifo(&[0xA0, 0x01, 0x02, 0x03, 0x42, 0x84, 0x01, 0x02, 0x03]).count(), 1);
+        assert_eq!(parse_fifo(&[]).count(), 0);
+    }
+
+    #[test]
+    fn drain_fifo_compensates_and_spaces_readings() {
+        let mut bmp = Bmp388::with_bus(bus(), MockDelay::default(), ADDRESS, Config::default()).unwrap();
+        bmp.configure_fifo(&FifoConfig::default()).unwrap();
+
+        // The data register doesn't move on as it's read.
+        #[rustfmt::skip]
+        let data = [
+            FIFO_PRESSURE_TEMPERATURE_FRAME, 0xe0, 0xa5, 0x7e, 0x90, 0xd9, 0x40,
+            FIFO_PRESSURE_FRAME, 0x40, 0x16, 0x40,
+            FIFO_SENSOR_TIME_FRAME, 0x56, 0x34, 0x12,
+        ];
+        bmp.i2c
+            .set_auto_increment(ADDRESS, false)
+            .script_reads(ADDRESS, FIFO_LENGTH_REGISTER, &[11, 0])
+            .script_reads(ADDRESS, FIFO_DATA_REGISTER, &data);
+
+        let batch = bmp.drain_fifo().unwrap();
+
+        let temperature = bmp.calibration().compensate_temperature(8_300_000);
+        let pressures: Vec<f32> = batch.readings.iter().map(|reading| reading.data.pressure).collect();
+        assert_eq!(
+            pressures,
+            [
+                bmp.calibration().compensate_pressure(4_250_000, temperature) as f32,
+                bmp.calibration().compensate_pressure(4_200_000, temperature) as f32,
+            ]
+        );
+        let ages: Vec<Duration> = batch.readings.iter().map(|reading| reading.age).collect();
+        assert_eq!(ages, [Duration::from_millis(5), Duration::ZERO]);
+        assert_eq!(batch.sensor_time, Some(0x123456));
+        assert!(!batch.config_changed);
+        assert_eq!(batch.errors, 0);
+    }
+
+    #[test]
+    fn drain_fifo_needs_configuring_first() {
+        let mut bmp = Bmp388::with_bus(bus(), MockDelay::default(), ADDRESS, Config::default()).unwrap();
+
+        assert!(matches!(bmp.drain_fifo(), Err(AltimeterError::InvalidConfig(_))));
+    }
 }
@@ -0,0 +1,311 @@
+//! Driver for the older BMP280 and BME280, which share a register map.
+//!
+//! The BME280 adds a humidity sensor, everything else is the same.
+
+use embedded_hal::{delay::DelayNs, i2c::I2c};
+
+use crate::{
+    altitude::{self, STANDARD_PRESSURE},
+    bmp388::{self, AltimeterData, AltimeterError},
+    bus::Registers,
+};
+
+pub const CHIP_ID_REGISTER: u8 = 0xD0;
+const RESET_REGISTER: u8 = 0xE0;
+const CTRL_HUM_REGISTER: u8 = 0xF2;
+const CTRL_MEAS_REGISTER: u8 = 0xF4;
+const CONFIG_REGISTER: u8 = 0xF5;
+const DATA_REGISTER: u8 = 0xF7;
+const CALIBRATION_REGISTER: u8 = 0x88;
+const CALIBRATION_LENGTH: usize = 26;
+const HUMIDITY_CALIBRATION_REGISTER: u8 = 0xE1;
+const HUMIDITY_CALIBRATION_LENGTH: usize = 7;
+
+const SOFT_RESET_COMMAND: u8 = 0xB6;
+
+pub const BMP280_CHIP_IDS: [u8; 3] = [0x56, 0x57, 0x58];
+pub const BME280_CHIP_ID: u8 = 0x60;
+
+/// x1 temperature and pressure oversampling (0b001 each) in normal mode (0b11).
+const CTRL_MEAS: u8 = 0b0010_0111;
+/// x1 humidity oversampling.
+const CTRL_HUM: u8 = 0b001;
+
+pub struct Bmx280<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+    calibration: Calibration,
+    humidity: bool,
+    qnh: f32,
+}
+
+impl<I2C: I2c, D: DelayNs> Bmx280<I2C, D> {
+    pub fn with_bus(mut i2c: I2C, delay: D, address: u8) -> Result<Self, AltimeterError<I2C::Error>> {
+        let chip_id = i2c.read_register(address, CHIP_ID_REGISTER)?;
+        let humidity = match chip_id {
+            BME280_CHIP_ID => true,
+            id if BMP280_CHIP_IDS.contains(&id) => false,
+            id => return Err(AltimeterError::WrongChipId(id)),
+        };
+
+        let mut this = Self {
+            i2c,
+            delay,
+            address,
+            calibration: Calibration::default(),
+            humidity,
+            qnh: STANDARD_PRESSURE,
+        };
+        this.init()?;
+
+        Ok(this)
+    }
+
+    /// Whether this is a BME280, with a humidity sensor.
+    pub fn has_humidity(&self) -> bool {
+        self.humidity
+    }
+
+    pub fn set_qnh(&mut self, qnh: f32) {
+        self.qnh = qnh;
+    }
+
+    pub fn qnh(&self) -> f32 {
+        self.qnh
+    }
+
+    pub fn read(&mut self) -> Result<AltimeterData, AltimeterError<I2C::Error>> {
+        let mut data = [0; 8];
+        let len = if self.humidity { 8 } else { 6 };
+        self.i2c.read_registers(self.address, DATA_REGISTER, &mut data[..len])?;
+
+        let raw_pressure = (data[0] as u32) << 12 | (data[1] as u32) << 4 | (data[2] as u32) >> 4;
+        let raw_temperature = (data[3] as u32) << 12 | (data[4] as u32) << 4 | (data[5] as u32) >> 4;
+
+        let t_fine = self.calibration.t_fine(raw_temperature);
+        let temperature = (t_fine / 5120.0) as f32;
+        let pressure = self.calibration.compensate_pressure(raw_pressure, t_fine) as f32;
+
+        let humidity = if self.humidity {
+            let raw_humidity = (data[6] as u32) << 8 | data[7] as u32;
+            Some(self.calibration.compensate_humidity(raw_humidity, t_fine) as f32)
+        } else {
+            None
+        };
+
+        Ok(AltimeterData {
+            pressure,
+            temperature,
+            altitude: altitude::altitude(pressure, self.qnh),
+            humidity,
+        })
+    }
+
+    fn init(&mut self) -> Result<(), AltimeterError<I2C::Error>> {
+        self.i2c.write_register(self.address, RESET_REGISTER, SOFT_RESET_COMMAND)?;
+        self.delay.delay_ms(10);
+
+        let mut nvm = [0; CALIBRATION_LENGTH];
+        self.i2c.read_registers(self.address, CALIBRATION_REGISTER, &mut nvm)?;
+        bmp388::check_nvm(&nvm)?;
+
+        let mut humidity_nvm = [0; HUMIDITY_CALIBRATION_LENGTH];
+        if self.humidity {
+            self.i2c.read_registers(self.address, HUMIDITY_CALIBRATION_REGISTER, &mut humidity_nvm)?;
+        }
+
+        self.calibration = Calibration::parse(&nvm, &humidity_nvm);
+
+        // Humidity settings only take effect after CTRL_MEAS is written.
+        if self.humidity {
+            self.i2c.write_register(self.address, CTRL_HUM_REGISTER, CTRL_HUM)?;
+        }
+        self.i2c.write_register(self.address, CONFIG_REGISTER, 0)?;
+        self.i2c.write_register(self.address, CTRL_MEAS_REGISTER, CTRL_MEAS)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Calibration {
+    pub t1: u16,
+    pub t2: i16,
+    pub t3: i16,
+    pub p1: u16,
+    pub p2: i16,
+    pub p3: i16,
+    pub p4: i16,
+    pub p5: i16,
+    pub p6: i16,
+    pub p7: i16,
+    pub p8: i16,
+    pub p9: i16,
+    pub h1: u8,
+    pub h2: i16,
+    pub h3: u8,
+    pub h4: i16,
+    pub h5: i16,
+    pub h6: i8,
+}
+
+impl Calibration {
+    /// Parses the little-endian blocks at 0x88 and, for the BME280, 0xE1.
+    pub fn parse(nvm: &[u8; CALIBRATION_LENGTH], humidity: &[u8; HUMIDITY_CALIBRATION_LENGTH]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([nvm[i], nvm[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([nvm[i], nvm[i + 1]]);
+
+        Self {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p2: i16_at(8),
+            p3: i16_at(10),
+            p4: i16_at(12),
+            p5: i16_at(14),
+            p6: i16_at(16),
+            p7: i16_at(18),
+            p8: i16_at(20),
+            p9: i16_at(22),
+            h1: nvm[25],
+            h2: i16::from_le_bytes([humidity[0], humidity[1]]),
+            h3: humidity[2],
+            // These two are 12 bit values sharing a nibble.
+            h4: (humidity[3] as i8 as i16) << 4 | (humidity[4] & 0x0F) as i16,
+            h5: (humidity[5] as i8 as i16) << 4 | (humidity[4] >> 4) as i16,
+            h6: humidity[6] as i8,
+        }
+    }
+
+    /// Fine resolution temperature shared by all the compensation formulas (datasheet section 4.2.3).
+    pub fn t_fine(&self, raw_temp: u32) -> f64 {
+        let raw_temp = raw_temp as f64;
+        let t1 = self.t1 as f64;
+
+        let var1 = (raw_temp / 16384.0 - t1 / 1024.0) * self.t2 as f64;
+        let var2 = raw_temp / 131072.0 - t1 / 8192.0;
+        let var2 = var2 * var2 * self.t3 as f64;
+
+        return var1 + var2;
+    }
+
+    /// Pressure in pascals.
+    pub fn compensate_pressure(&self, raw_press: u32, t_fine: f64) -> f64 {
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * self.p6 as f64 / 32768.0;
+        var2 += var1 * self.p5 as f64 * 2.0;
+        var2 = var2 / 4.0 + self.p4 as f64 * 65536.0;
+        var1 = (self.p3 as f64 * var1 * var1 / 524288.0 + self.p2 as f64 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * self.p1 as f64;
+
+        if var1 == 0.0 {
+            // Avoid dividing by zero with an unprogrammed NVM
+            return 0.0;
+        }
+
+        let mut pressure = 1048576.0 - raw_press as f64;
+        pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        var1 = self.p9 as f64 * pressure * pressure / 2147483648.0;
+        var2 = pressure * self.p8 as f64 / 32768.0;
+
+        return pressure + (var1 + var2 + self.p7 as f64) / 16.0;
+    }
+
+    /// Relative humidity in percent.
+    pub fn compensate_humidity(&self, raw_hum: u32, t_fine: f64) -> f64 {
+        let var = t_fine - 76800.0;
+        let var = (raw_hum as f64 - (self.h4 as f64 * 64.0 + self.h5 as f64 / 16384.0 * var))
+            * (self.h2 as f64 / 65536.0
+                * (1.0 + self.h6 as f64 / 67108864.0 * var * (1.0 + self.h3 as f64 / 67108864.0 * var)));
+        let var = var * (1.0 - self.h1 as f64 * var / 524288.0);
+
+        return var.clamp(0.0, 100.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::{MockBus, MockDelay};
+
+    const ADDRESS: u8 = 0x76;
+
+    /// The calibration from the compensation example in the BMP280 datasheet, with H1 at the end.
+    const NVM: [u8; CALIBRATION_LENGTH] = [
+        0x70, 0x6b, 0x43, 0x67, 0x18, 0xfc, 0x7d, 0x8e, 0x43, 0xd6, 0xd0, 0x0b, 0x27, 0x0b, 0x8c, 0x00, 0xf9, 0xff,
+        0x8c, 0x3c, 0xf8, 0xc6, 0x70, 0x17, 0x00, 0x4b,
+    ];
+    /// H2 = 362, H3 = 0, H4 = 313, H5 = 50 and H6 = 30.
+    const HUMIDITY_NVM: [u8; HUMIDITY_CALIBRATION_LENGTH] = [0x6a, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1e];
+
+    fn bus(chip_id: u8) -> MockBus {
+        let mut bus = MockBus::new();
+        bus.add_device(ADDRESS)
+            .set_register(ADDRESS, CHIP_ID_REGISTER, chip_id)
+            .set_registers(ADDRESS, CALIBRATION_REGISTER, &NVM)
+            .set_registers(ADDRESS, HUMIDITY_CALIBRATION_REGISTER, &HUMIDITY_NVM)
+            // The datasheet's raw pressure and temperature, then a raw humidity of 30000.
+            .set_registers(ADDRESS, DATA_REGISTER, &[0x65, 0x5a, 0xc0, 0x7e, 0xed, 0x00, 0x75, 0x30]);
+
+        bus
+    }
+
+    #[test]
+    fn bmp280_matches_the_datasheet_example() {
+        let mut bmp = Bmx280::with_bus(bus(0x58), MockDelay::default(), ADDRESS).unwrap();
+        assert!(!bmp.has_humidity());
+
+        let data = bmp.read().unwrap();
+
+        assert!((data.temperature - 25.08).abs() < 0.01, "{}", data.temperature);
+        assert!((data.pressure - 100_653.27).abs() < 0.01, "{}", data.pressure);
+        assert_eq!(data.humidity, None);
+        assert_eq!(data.altitude, altitude::altitude(data.pressure, STANDARD_PRESSURE));
+    }
+
+    #[test]
+    fn bmp280_skips_the_humidity_registers() {
+        let bmp = Bmx280::with_bus(bus(0x56), MockDelay::default(), ADDRESS).unwrap();
+
+        assert_eq!(
+            bmp.i2c.writes(ADDRESS),
+            [[RESET_REGISTER, SOFT_RESET_COMMAND], [CONFIG_REGISTER, 0], [CTRL_MEAS_REGISTER, CTRL_MEAS]]
+        );
+        assert_eq!(bmp.delay.waited_ns, 10_000_000);
+    }
+
+    #[test]
+    fn bme280_parses_the_split_humidity_calibration() {
+        let bme = Bmx280::with_bus(bus(BME280_CHIP_ID), MockDelay::default(), ADDRESS).unwrap();
+        assert!(bme.has_humidity());
+
+        let calibration = bme.calibration;
+        assert_eq!(
+            (calibration.h1, calibration.h2, calibration.h3, calibration.h4, calibration.h5, calibration.h6),
+            (75, 362, 0, 313, 50, 30)
+        );
+    }
+
+    #[test]
+    fn bme280_reads_humidity() {
+        let mut bme = Bmx280::with_bus(bus(BME280_CHIP_ID), MockDelay::default(), ADDRESS).unwrap();
+
+        // Humidity has to be set before CTRL_MEAS for it to take effect.
+        let writes = bme.i2c.writes(ADDRESS);
+        let ctrl_hum = writes.iter().position(|write| *write == [CTRL_HUM_REGISTER, CTRL_HUM]).unwrap();
+        let ctrl_meas = writes.iter().position(|write| *write == [CTRL_MEAS_REGISTER, CTRL_MEAS]).unwrap();
+        assert!(ctrl_hum < ctrl_meas);
+
+        let humidity = bme.read().unwrap().humidity.unwrap();
+        assert!((humidity - 55.0).abs() < 0.01, "{humidity}");
+    }
+
+    #[test]
+    fn rejects_other_chips() {
+        let result = Bmx280::with_bus(bus(0x50), MockDelay::default(), ADDRESS);
+
+        assert!(matches!(result, Err(AltimeterError::WrongChipId(0x50))));
+    }
+}
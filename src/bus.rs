@@ -0,0 +1,247 @@
+//! Register level access to I2C devices.
+//!
+//! Drivers are written against embedded-hal's [`I2c`] trait rather than the Pi's bus,
+//! so they can be driven by [`PiI2c`](crate::pi::PiI2c) on the payload or by the mock bus in the tests.
+
+use embedded_hal::i2c::I2c;
+
+/// Reading and writing the registers of devices that take the register address as the first byte written.
+pub trait Registers: I2c {
+    fn read_register(&mut self, address: u8, register: u8) -> Result<u8, Self::Error> {
+        let mut value = [0];
+        self.write_read(address, &[register], &mut value)?;
+
+        Ok(value[0])
+    }
+
+    /// Burst reads `buf.len()` bytes starting at `register`.
+    fn read_registers(&mut self, address: u8, register: u8, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.write_read(address, &[register], buf)
+    }
+
+    fn write_register(&mut self, address: u8, register: u8, value: u8) -> Result<(), Self::Error> {
+        self.write(address, &[register, value])
+    }
+}
+
+impl<T: I2c> Registers for T {}
+
+#[cfg(test)]
+pub mod mock {
+    use std::collections::{HashMap, VecDeque};
+
+    use embedded_hal::{
+        delay::DelayNs,
+        i2c::{self as hal, ErrorKind, I2c, NoAcknowledgeSource, Operation},
+    };
+    use thiserror::Error;
+
+    /// A transfer seen by [`MockBus`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Transaction {
+        Write { address: u8, data: Vec<u8> },
+        Read { address: u8, data: Vec<u8> },
+    }
+
+    #[derive(Debug, Clone)]
+    struct Device {
+        registers: [u8; 256],
+        /// Register the next read or write goes to.
+        pointer: u8,
+        auto_increment: bool,
+        /// Values handed out by reads of a register before falling back to the register map.
+        scripted: HashMap<u8, VecDeque<u8>>,
+    }
+
+    /// A fake I2C bus serving register maps, for exercising the drivers without hardware.
+    ///
+    /// Every device works like a typical sensor: the first byte written sets the register pointer,
+    /// following bytes are written from there and reads carry on from wherever it points.
+    /// Every transfer is recorded so the exact bus traffic can be checked afterwards.
+    #[derive(Debug, Clone, Default)]
+    pub struct MockBus {
+        devices: HashMap<u8, Device>,
+        transactions: Vec<Transaction>,
+        /// Errors to fail upcoming transfers with, in order.
+        failures: VecDeque<ErrorKind>,
+    }
+
+    impl MockBus {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Adds a device with all its registers zeroed. Anything else on the bus doesn't ACK.
+        pub fn add_device(&mut self, address: u8) -> &mut Self {
+            self.devices.insert(
+                address,
+                Device {
+                    registers: [0; 256],
+                    pointer: 0,
+                    auto_increment: true,
+                    scripted: HashMap::new(),
+                },
+            );
+
+            self
+        }
+
+        /// Sets whether the register pointer moves on after each byte, which it does by default.
+        pub fn set_auto_increment(&mut self, address: u8, auto_increment: bool) -> &mut Self {
+            self.device(address).auto_increment = auto_increment;
+            self
+        }
+
+        pub fn set_register(&mut self, address: u8, register: u8, value: u8) -> &mut Self {
+            self.set_registers(address, register, &[value])
+        }
+
+        pub fn set_registers(&mut self, address: u8, register: u8, values: &[u8]) -> &mut Self {
+            let device = self.device(address);
+            for (i, value) in values.iter().enumerate() {
+                device.registers[register.wrapping_add(i as u8) as usize] = *value;
+            }
+
+            self
+        }
+
+        /// Queues values for successive reads of `register`, like a status register
+        /// changing between polls or a FIFO data register.
+        pub fn script_reads(&mut self, address: u8, register: u8, values: &[u8]) -> &mut Self {
+            self.device(address)
+                .scripted
+                .entry(register)
+                .or_default()
+                .extend(values);
+
+            self
+        }
+
+        /// Makes the next transfer fail with `kind`.
+        pub fn fail_next(&mut self, kind: ErrorKind) -> &mut Self {
+            self.failures.push_back(kind);
+            self
+        }
+
+        pub fn register(&self, address: u8, register: u8) -> u8 {
+            self.devices[&address].registers[register as usize]
+        }
+
+        pub fn transactions(&self) -> &[Transaction] {
+            &self.transactions
+        }
+
+        /// Every byte written to `address`, in order.
+        pub fn written(&self, address: u8) -> Vec<u8> {
+            self.transactions
+                .iter()
+                .filter_map(|transaction| match transaction {
+                    Transaction::Write { address: to, data } if *to == address => Some(data.as_slice()),
+                    _ => None,
+                })
+                .flatten()
+                .copied()
+                .collect()
+        }
+
+        /// Every write to `address` that carried data, leaving out the ones that only set the pointer for a read.
+        pub fn writes(&self, address: u8) -> Vec<Vec<u8>> {
+            self.transactions
+                .iter()
+                .filter_map(|transaction| match transaction {
+                    Transaction::Write { address: to, data } if *to == address && data.len() > 1 => Some(data.clone()),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        pub fn clear_transactions(&mut self) {
+            self.transactions.clear();
+        }
+
+        fn device(&mut self, address: u8) -> &mut Device {
+            self.devices
+                .get_mut(&address)
+                .unwrap_or_else(|| panic!("no mock device at {address:#04x}"))
+        }
+    }
+
+    impl hal::ErrorType for MockBus {
+        type Error = MockBusError;
+    }
+
+    impl I2c for MockBus {
+        fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+            if let Some(kind) = self.failures.pop_front() {
+                return Err(MockBusError(kind));
+            }
+
+            let Some(device) = self.devices.get_mut(&address) else {
+                return Err(MockBusError(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)));
+            };
+
+            for operation in operations {
+                match operation {
+                    Operation::Write(data) => {
+                        if let Some((pointer, values)) = data.split_first() {
+                            device.pointer = *pointer;
+                            for value in values {
+                                device.registers[device.pointer as usize] = *value;
+                                if device.auto_increment {
+                                    device.pointer = device.pointer.wrapping_add(1);
+                                }
+                            }
+                        }
+
+                        self.transactions.push(Transaction::Write {
+                            address,
+                            data: data.to_vec(),
+                        });
+                    }
+                    Operation::Read(buf) => {
+                        for byte in buf.iter_mut() {
+                            let scripted = device
+                                .scripted
+                                .get_mut(&device.pointer)
+                                .and_then(|values| values.pop_front());
+                            *byte = scripted.unwrap_or(device.registers[device.pointer as usize]);
+
+                            if device.auto_increment {
+                                device.pointer = device.pointer.wrapping_add(1);
+                            }
+                        }
+
+                        self.transactions.push(Transaction::Read {
+                            address,
+                            data: buf.to_vec(),
+                        });
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+    #[error("{0}")]
+    pub struct MockBusError(pub ErrorKind);
+
+    impl hal::Error for MockBusError {
+        fn kind(&self) -> ErrorKind {
+            self.0
+        }
+    }
+
+    /// A delay that returns straight away, keeping track of how long it should have waited.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct MockDelay {
+        pub waited_ns: u64,
+    }
+
+    impl DelayNs for MockDelay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.waited_ns += ns as u64;
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Points in time to give up at, for the flight computer and the Pi adapters.
+//!
+//! The drivers themselves don't have a clock, they use [`Timeout`](crate::timeout::Timeout) instead.
+
+use std::time::{Duration, Instant};
+
+/// A point in time to give up waiting at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Deadline {
+    /// `None` for waiting forever.
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// Waits forever. Best kept for things that really can't go missing.
+    pub const NEVER: Deadline = Deadline { at: None };
+
+    pub fn at(instant: Instant) -> Self {
+        Self { at: Some(instant) }
+    }
+
+    pub fn after(timeout: Duration) -> Self {
+        Self {
+            at: Instant::now().checked_add(timeout),
+        }
+    }
+
+    pub fn has_passed(&self) -> bool {
+        self.at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// Time left, or `None` if there's no limit.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whichever of the two deadlines comes first.
+    pub fn min(self, other: Deadline) -> Deadline {
+        match (self.at, other.at) {
+            (Some(a), Some(b)) => Deadline::at(a.min(b)),
+            (Some(_), None) => self,
+            (None, _) => other,
+        }
+    }
+}
+
+impl From<Duration> for Deadline {
+    fn from(timeout: Duration) -> Self {
+        Deadline::after(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_at_its_instant() {
+        let now = Instant::now();
+
+        assert!(Deadline::at(now).has_passed());
+        assert_eq!(Deadline::at(now).remaining(), Some(Duration::ZERO));
+
+        let later = Deadline::after(Duration::from_secs(60));
+        assert!(!later.has_passed());
+        assert!(later.remaining().unwrap() > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn never_passes() {
+        assert!(!Deadline::NEVER.has_passed());
+        assert_eq!(Deadline::NEVER.remaining(), None);
+    }
+
+    #[test]
+    fn min_is_the_earlier_one() {
+        let now = Instant::now();
+        let (soon, later) = (Deadline::at(now), Deadline::at(now + Duration::from_secs(1)));
+
+        assert_eq!(soon.min(later), soon);
+        assert_eq!(later.min(soon), soon);
+        assert_eq!(Deadline::NEVER.min(later), later);
+        assert_eq!(later.min(Deadline::NEVER), later);
+    }
+}
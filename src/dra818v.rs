@@ -1,78 +1,469 @@
-use std::{thread, time::Duration};
+use core::{
+    fmt::{self, Write as _},
+    ops::RangeInclusive,
+    time::Duration,
+};
 
+use embedded_hal::delay::DelayNs;
+use embedded_io::{Read, ReadReady, Write};
 use log::warn;
-use rpi_embedded::uart::{self, Uart};
+use thiserror::Error;
 
-pub struct Dra818V {
-    uart: Uart,
+use crate::at::{self, AtError, AtTransport, Excerpt, Line};
+
+/// Tuning range of the VHF module, in MHz.
+const FREQUENCY_RANGE: RangeInclusive<f32> = 134.0..=174.0;
+
+/// Deadline for most commands.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Retuning the synthesizer takes noticeably longer than anything else.
+const SET_GROUP_TIMEOUT: Duration = Duration::from_millis(2000);
+
+pub struct Dra818V<S, D> {
+    at: AtTransport<S, D>,
 }
 
-impl Dra818V {
-    pub fn new(uart: Uart) -> Self {
-        return Self { uart }
+impl<S, D> Dra818V<S, D>
+where
+    S: Read + ReadReady + Write,
+    D: DelayNs,
+{
+    pub fn with_port(port: S, delay: D) -> Self {
+        Self { at: AtTransport::new(port, delay) }
     }
 
-    pub fn init(&mut self) -> Result<(), Error> {
-        self.uart.set_read_mode(0, Duration::ZERO)?;
-        self.uart.set_write_mode(true)?;
+    /// Connects to the module and tunes it to `frequency`, in MHz.
+    pub fn init(&mut self, frequency: f32) -> Result<(), Error<S::Error>> {
         self.handshake()?;
-        self.set_group(144.390, 144.390)?;
+        self.set_group(&Group::simplex(frequency))?;
 
         Ok(())
     }
 
-    pub fn set_group(&mut self, tfv: f32, rfv: f32) -> Result<(), Error> {
-        self.uart.write(format!("AT+DMOSETGROUP=0,{:.4},{:.4},0000,0,0000\r\n", tfv, rfv))?;
-        println!("AT+DMOSETGROUP=0,{:.4},{:.4},0000,4,0000\r\n", tfv, rfv);
-        self.terrible_timeout()?;
-        let mut line = self.uart.read_line()?;
-        if line.is_empty() {
-            line = self.uart.read_line()?;
+    pub fn handshake(&mut self) -> Result<(), Error<S::Error>> {
+        let line = self.at.command("AT+DMOCONNECT", "+DMOCONNECT", COMMAND_TIMEOUT)?;
+        if let Err(err) = parse_status(&line, "+DMOCONNECT") {
+            warn!("transceiver confirmation not recieved: {line}");
+            return Err(err);
         }
 
-        if line != "+DMOSETGROUP:0\r" {
-            return Err(Error::NoConnect);
+        Ok(())
+    }
+
+    /// Sets the bandwidth, frequencies, tones and squelch level.
+    pub fn set_group(&mut self, group: &Group) -> Result<(), Error<S::Error>> {
+        group.validate()?;
+
+        let line = self.at.command(&command(format_args!("AT+DMOSETGROUP={group}")), "+DMOSETGROUP", SET_GROUP_TIMEOUT)?;
+        parse_status(&line, "+DMOSETGROUP")
+    }
+
+    /// Reads back the current group settings.
+    pub fn read_group(&mut self) -> Result<Group, Error<S::Error>> {
+        let line = self.at.command("AT+DMOREADGROUP", "+DMOREADGROUP", COMMAND_TIMEOUT)?;
+        let fields = line
+            .strip_prefix("+DMOREADGROUP:")
+            .ok_or_else(|| Error::mismatch("+DMOREADGROUP", &line))?;
+
+        Group::parse(fields).ok_or_else(|| Error::mismatch("+DMOREADGROUP", &line))
+    }
+
+    /// Sets the speaker volume, from 1 to 8.
+    pub fn set_volume(&mut self, volume: u8) -> Result<(), Error<S::Error>> {
+        if !(1..=8).contains(&volume) {
+            return Err(Error::InvalidParameter("volume must be between 1 and 8"));
         }
 
-        Ok(())
+        let line = self.at.command(&command(format_args!("AT+DMOSETVOLUME={volume}")), "+DMOSETVOLUME", COMMAND_TIMEOUT)?;
+        parse_status(&line, "+DMOSETVOLUME")
+    }
+
+    /// Enables or bypasses the audio filters.
+    pub fn set_filters(&mut self, filters: Filters) -> Result<(), Error<S::Error>> {
+        let line = self.at.command(&command(format_args!("AT+SETFILTER={filters}")), "+DMOSETFILTER", COMMAND_TIMEOUT)?;
+        parse_status(&line, "+DMOSETFILTER")
     }
 
-    pub fn handshake(&mut self) -> Result<(), Error> {
-        self.uart.write_bytes(b"AT+DMOCONNECT\r\n")?;
-        self.terrible_timeout()?;
-        let mut line = self.uart.read_line()?;
-        if line.is_empty() {
-            line = self.uart.read_line()?;
+    /// Enables or disables the tail tone sent when PTT is released.
+    pub fn set_tail(&mut self, enabled: bool) -> Result<(), Error<S::Error>> {
+        let line = self.at.command(&command(format_args!("AT+SETTAIL={}", enabled as u8)), "+DMOSETTAIL", COMMAND_TIMEOUT)?;
+        parse_status(&line, "+DMOSETTAIL")
+    }
+
+    /// Reads the received signal strength, from 0 to 255.
+    pub fn rssi(&mut self) -> Result<u8, Error<S::Error>> {
+        let line = self.at.command("RSSI?", "RSSI", COMMAND_TIMEOUT)?;
+
+        // The format varies between firmware versions.
+        line.strip_prefix("RSSI=")
+            .or_else(|| line.strip_prefix("RSSI:"))
+            .and_then(|rssi| rssi.trim().parse().ok())
+            .ok_or_else(|| Error::mismatch("RSSI", &line))
+    }
+}
+
+/// Formats a command, none of which come anywhere near the length of a line.
+fn command(args: fmt::Arguments<'_>) -> Line {
+    let mut command = Line::new();
+    let _ = command.write_fmt(args);
+
+    command
+}
+
+/// Parses a `<prefix>:<status>` response, where a status of 0 means success.
+fn parse_status<E>(line: &Line, prefix: &'static str) -> Result<(), Error<E>> {
+    match line.strip_prefix(prefix).and_then(|rest| rest.strip_prefix(':')) {
+        Some("0") => Ok(()),
+        Some("1") => Err(Error::Rejected(prefix)),
+        _ => Err(Error::mismatch(prefix, line)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bandwidth {
+    /// 12.5 kHz
+    Narrow,
+    /// 25 kHz
+    Wide,
+}
+
+/// Sub-audible tone used for selective calling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tone {
+    None,
+    /// CTCSS tone index, from 1 to 38.
+    Ctcss(u8),
+    /// CDCSS code, written in octal as it is on the radio (e.g. 023).
+    Cdcss { code: u16, inverted: bool },
+}
+
+impl Tone {
+    fn parse(s: &str) -> Option<Self> {
+        if s == "0000" {
+            return Some(Tone::None);
         }
 
-        if line != "+DMOCONNECT:0\r" {
-            warn!("transceiver confirmation not recieved: {line}");
-            return Err(Error::NoConnect);
+        match s.as_bytes() {
+            [.., b'N'] => Some(Tone::Cdcss { code: s[..s.len() - 1].parse().ok()?, inverted: false }),
+            [.., b'I'] => Some(Tone::Cdcss { code: s[..s.len() - 1].parse().ok()?, inverted: true }),
+            _ => Some(Tone::Ctcss(s.parse().ok()?)),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match self {
+            Tone::None => true,
+            Tone::Ctcss(index) => (1..=38).contains(index),
+            // Written in decimal digits, each of which has to be an octal one.
+            Tone::Cdcss { code, .. } => *code <= 777 && [code / 100, code / 10 % 10, code % 10].iter().all(|&digit| digit <= 7),
+        }
+    }
+}
+
+impl fmt::Display for Tone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tone::None => write!(f, "0000"),
+            Tone::Ctcss(index) => write!(f, "{index:04}"),
+            Tone::Cdcss { code, inverted: false } => write!(f, "{code:03}N"),
+            Tone::Cdcss { code, inverted: true } => write!(f, "{code:03}I"),
+        }
+    }
+}
+
+/// Settings applied together by `AT+DMOSETGROUP`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Group {
+    pub bandwidth: Bandwidth,
+    /// Transmit frequency in MHz
+    pub tx_frequency: f32,
+    /// Receive frequency in MHz
+    pub rx_frequency: f32,
+    pub tx_tone: Tone,
+    /// Squelch level, from 0 (open) to 8.
+    pub squelch: u8,
+    pub rx_tone: Tone,
+}
+
+impl Group {
+    /// Narrowband simplex operation on a single frequency with no tones and squelch open.
+    pub fn simplex(frequency: f32) -> Self {
+        Self {
+            bandwidth: Bandwidth::Narrow,
+            tx_frequency: frequency,
+            rx_frequency: frequency,
+            tx_tone: Tone::None,
+            squelch: 0,
+            rx_tone: Tone::None,
+        }
+    }
+
+    fn validate<E>(&self) -> Result<(), Error<E>> {
+        if !FREQUENCY_RANGE.contains(&self.tx_frequency) || !FREQUENCY_RANGE.contains(&self.rx_frequency) {
+            return Err(Error::InvalidParameter("frequency must be between 134 and 174 MHz"));
+        }
+
+        if self.squelch > 8 {
+            return Err(Error::InvalidParameter("squelch must be between 0 and 8"));
+        }
+
+        if !self.tx_tone.is_valid() || !self.rx_tone.is_valid() {
+            return Err(Error::InvalidParameter("invalid CTCSS/CDCSS tone"));
         }
 
         Ok(())
     }
 
-    fn terrible_timeout(&self) -> Result<(), Error> {
-        if self.uart.input_len()? == 0 {
-            thread::sleep(Duration::from_millis(750));
-            if self.uart.input_len()? == 0 {
-                return Err(Error::NoConnect);
-            }
+    /// Parses the comma separated fields of a `+DMOREADGROUP` response.
+    fn parse(fields: &str) -> Option<Self> {
+        let mut fields = fields.split(',').map(str::trim);
+
+        let bandwidth = match fields.next()? {
+            "0" => Bandwidth::Narrow,
+            "1" => Bandwidth::Wide,
+            _ => return None,
+        };
+
+        let group = Self {
+            bandwidth,
+            tx_frequency: fields.next()?.parse().ok()?,
+            rx_frequency: fields.next()?.parse().ok()?,
+            tx_tone: Tone::parse(fields.next()?)?,
+            squelch: fields.next()?.parse().ok()?,
+            rx_tone: Tone::parse(fields.next()?)?,
+        };
+
+        return Some(group);
+    }
+}
+
+impl fmt::Display for Group {
+    /// Formats the group as the arguments of `AT+DMOSETGROUP`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bandwidth = match self.bandwidth {
+            Bandwidth::Narrow => 0,
+            Bandwidth::Wide => 1,
+        };
+
+        write!(
+            f,
+            "{},{:.4},{:.4},{},{},{}",
+            bandwidth, self.tx_frequency, self.rx_frequency, self.tx_tone, self.squelch, self.rx_tone
+        )
+    }
+}
+
+/// Audio filter settings, `true` meaning the filter is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Filters {
+    /// Pre-emphasis on transmit and de-emphasis on receive.
+    pub emphasis: bool,
+    pub high_pass: bool,
+    pub low_pass: bool,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self {
+            emphasis: true,
+            high_pass: true,
+            low_pass: true,
         }
+    }
+}
 
-        return Ok(());
+impl fmt::Display for Filters {
+    /// Formats the filters as the arguments of `AT+SETFILTER`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The module takes 0 for an active filter and 1 for bypass.
+        write!(f, "{},{},{}", !self.emphasis as u8, !self.high_pass as u8, !self.low_pass as u8)
     }
 }
 
-#[derive(Debug)]
-pub enum Error {
-    Uart(uart::Error),
-    NoConnect,
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error(transparent)]
+    At(#[from] AtError<E>),
+    #[error("transceiver rejected {0}")]
+    Rejected(&'static str),
+    #[error("expected a {expected} response, received {received:?}")]
+    ResponseMismatch {
+        expected: &'static str,
+        received: Excerpt,
+    },
+    #[error("invalid parameter: {0}")]
+    InvalidParameter(&'static str),
+}
+
+impl<E> Error<E> {
+    fn mismatch(expected: &'static str, received: &Line) -> Self {
+        Self::ResponseMismatch {
+            expected,
+            received: at::to_line(received.as_bytes()),
+        }
+    }
 }
 
-impl From<uart::Error> for Error {
-    fn from(value: uart::Error) -> Self {
-        return Self::Uart(value);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{at::mock::MockPort, bus::mock::MockDelay};
+
+    fn transceiver(responses: &[&[u8]]) -> Dra818V<MockPort, MockDelay> {
+        let mut port = MockPort::new();
+        for response in responses {
+            port.respond(response);
+        }
+
+        Dra818V::with_port(port, MockDelay::default())
+    }
+
+    fn sent(transceiver: &mut Dra818V<MockPort, MockDelay>) -> &[u8] {
+        &transceiver.at.port().sent
+    }
+
+    #[test]
+    fn set_group_sends_the_exact_command() {
+        let mut transceiver = transceiver(&[b"+DMOSETGROUP:0\r\n"]);
+
+        transceiver.set_group(&Group::simplex(144.8)).unwrap();
+
+        assert_eq!(sent(&mut transceiver), b"AT+DMOSETGROUP=0,144.8000,144.8000,0000,0,0000\r\n");
+    }
+
+    #[test]
+    fn set_group_formats_tones_and_bandwidth() {
+        let mut transceiver = transceiver(&[b"+DMOSETGROUP:0\r\n"]);
+        let group = Group {
+            bandwidth: Bandwidth::Wide,
+            tx_frequency: 145.825,
+            rx_frequency: 144.39,
+            tx_tone: Tone::Ctcss(12),
+            squelch: 4,
+            rx_tone: Tone::Cdcss { code: 23, inverted: true },
+        };
+
+        transceiver.set_group(&group).unwrap();
+
+        assert_eq!(sent(&mut transceiver), b"AT+DMOSETGROUP=1,145.8250,144.3900,0012,4,023I\r\n");
+    }
+
+    #[test]
+    fn set_filters_sends_the_exact_command() {
+        let mut transceiver = transceiver(&[b"+DMOSETFILTER:0\r\n"]);
+        let filters = Filters {
+            emphasis: true,
+            high_pass: false,
+            low_pass: true,
+        };
+
+        transceiver.set_filters(filters).unwrap();
+
+        // 0 turns a filter on, 1 bypasses it.
+        assert_eq!(sent(&mut transceiver), b"AT+SETFILTER=0,1,0\r\n");
+    }
+
+    #[test]
+    fn set_tail_sends_the_exact_command() {
+        let mut transceiver = transceiver(&[b"+DMOSETTAIL:0\r\n", b"+DMOSETTAIL:0\r\n"]);
+
+        transceiver.set_tail(false).unwrap();
+        transceiver.set_tail(true).unwrap();
+
+        assert_eq!(sent(&mut transceiver), b"AT+SETTAIL=0\r\nAT+SETTAIL=1\r\n");
+    }
+
+    #[test]
+    fn status_one_is_a_rejection() {
+        let mut transceiver = transceiver(&[b"+DMOSETGROUP:1\r\n", b"+DMOSETFILTER:1\r\n", b"+DMOSETTAIL:1\r\n"]);
+
+        assert!(matches!(transceiver.set_group(&Group::simplex(144.8)), Err(Error::Rejected("+DMOSETGROUP"))));
+        assert!(matches!(transceiver.set_filters(Filters::default()), Err(Error::Rejected("+DMOSETFILTER"))));
+        assert!(matches!(transceiver.set_tail(false), Err(Error::Rejected("+DMOSETTAIL"))));
+    }
+
+    #[test]
+    fn unexpected_status_is_a_mismatch() {
+        let mut transceiver = transceiver(&[b"+DMOSETTAIL:2\r\n"]);
+
+        match transceiver.set_tail(false) {
+            Err(Error::ResponseMismatch { expected, received }) => {
+                assert_eq!(expected, "+DMOSETTAIL");
+                assert_eq!(received, "+DMOSETTAIL:2");
+            }
+            other => panic!("expected a mismatch, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn silence_times_out() {
+        let mut transceiver = transceiver(&[b""]);
+
+        assert!(matches!(transceiver.handshake(), Err(Error::At(AtError::Timeout(command))) if command == "AT+DMOCONNECT"));
+        assert_eq!(transceiver.at.delay().waited_ns, COMMAND_TIMEOUT.as_nanos() as u64);
+    }
+
+    #[test]
+    fn set_group_waits_longer() {
+        let mut transceiver = transceiver(&[b""]);
+
+        assert!(matches!(transceiver.set_group(&Group::simplex(144.8)), Err(Error::At(AtError::Timeout(_)))));
+        assert_eq!(transceiver.at.delay().waited_ns, SET_GROUP_TIMEOUT.as_nanos() as u64);
+    }
+
+    #[test]
+    fn echo_blank_lines_and_stale_data_are_skipped() {
+        let mut transceiver = transceiver(&[b"AT+DMOCONNECT\r\n\r\n+DMOCONNECT:0\r\n"]);
+        transceiver.at.port().receive(b"+DMOSETTAIL:1\r\n");
+
+        transceiver.handshake().unwrap();
+    }
+
+    #[test]
+    fn rssi_accepts_either_format() {
+        let mut transceiver = transceiver(&[b"RSSI=123\r\n", b"RSSI:45\r\n", b"RSSI=loud\r\n"]);
+
+        assert_eq!(transceiver.rssi().unwrap(), 123);
+        assert_eq!(transceiver.rssi().unwrap(), 45);
+        assert!(matches!(transceiver.rssi(), Err(Error::ResponseMismatch { expected: "RSSI", .. })));
+        assert_eq!(sent(&mut transceiver), b"RSSI?\r\nRSSI?\r\nRSSI?\r\n");
+    }
+
+    #[test]
+    fn read_group_parses_the_response() {
+        let mut transceiver = transceiver(&[b"+DMOREADGROUP:1,145.8250,144.3900,0012,4,023N\r\n"]);
+
+        let group = transceiver.read_group().unwrap();
+
+        assert_eq!(group.bandwidth, Bandwidth::Wide);
+        assert_eq!(group.tx_frequency, 145.825);
+        assert_eq!(group.rx_frequency, 144.39);
+        assert_eq!(group.tx_tone, Tone::Ctcss(12));
+        assert_eq!(group.squelch, 4);
+        assert_eq!(group.rx_tone, Tone::Cdcss { code: 23, inverted: false });
+    }
+
+    #[test]
+    fn invalid_settings_are_never_sent() {
+        let mut transceiver = transceiver(&[]);
+
+        assert!(matches!(transceiver.set_group(&Group::simplex(440.0)), Err(Error::InvalidParameter(_))));
+        assert!(matches!(transceiver.set_volume(9), Err(Error::InvalidParameter(_))));
+        assert!(sent(&mut transceiver).is_empty());
+    }
+
+    #[test]
+    fn cdcss_codes_must_be_octal() {
+        let mut transceiver = transceiver(&[b"+DMOSETGROUP:0\r\n"]);
+        let mut group = Group::simplex(144.8);
+
+        for code in [89, 199, 778] {
+            group.tx_tone = Tone::Cdcss { code, inverted: false };
+            assert!(matches!(transceiver.set_group(&group), Err(Error::InvalidParameter(_))), "{code:03}");
+        }
+        assert!(sent(&mut transceiver).is_empty());
+
+        group.tx_tone = Tone::Cdcss { code: 754, inverted: false };
+        transceiver.set_group(&group).unwrap();
     }
 }
@@ -0,0 +1,106 @@
+//! Altitude from the barometer, kept honest by GPS.
+
+use std::time::{Duration, Instant};
+
+/// Blends barometric and GPS altitude.
+///
+/// The barometer is smooth and always there but drifts with the weather,
+/// while GPS is unbiased but noisy and drops out (or hits its altitude limit).
+/// The output follows the barometer, with an offset that slowly tracks GPS.
+#[derive(Debug, Clone)]
+pub struct AltitudeEstimator {
+    /// Time constant the offset converges towards GPS with.
+    time_constant: Duration,
+    offset: f32,
+    last_gps: Option<Instant>,
+}
+
+impl AltitudeEstimator {
+    pub fn new(time_constant: Duration) -> Self {
+        Self {
+            time_constant,
+            offset: 0.0,
+            last_gps: None,
+        }
+    }
+
+    /// Updates the estimate with a new barometric altitude and, if there's a fix, a GPS altitude.
+    pub fn update(&mut self, baro: f32, gps: Option<f32>) -> f32 {
+        self.update_at(baro, gps, Instant::now())
+    }
+
+    pub fn update_at(&mut self, baro: f32, gps: Option<f32>, now: Instant) -> f32 {
+        if let Some(gps) = gps {
+            let error = gps - baro;
+
+            match self.last_gps {
+                Some(last) => {
+                    let dt = now.duration_since(last).as_secs_f32();
+                    let alpha = dt / (self.time_constant.as_secs_f32() + dt);
+                    self.offset += alpha * (error - self.offset);
+                }
+                // Snap straight to the first fix.
+                None => self.offset = error,
+            }
+
+            self.last_gps = Some(now);
+        }
+
+        self.estimate(baro)
+    }
+
+    pub fn estimate(&self, baro: f32) -> f32 {
+        baro + self.offset
+    }
+
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+}
+
+impl Default for AltitudeEstimator {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(300))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snaps_to_the_first_fix() {
+        let mut estimator = AltitudeEstimator::default();
+        assert_eq!(estimator.update_at(1000.0, None, Instant::now()), 1000.0);
+
+        assert_eq!(estimator.update_at(1000.0, Some(1050.0), Instant::now()), 1050.0);
+        assert_eq!(estimator.offset(), 50.0);
+    }
+
+    #[test]
+    fn follows_gps_over_the_time_constant() {
+        let start = Instant::now();
+        let mut estimator = AltitudeEstimator::new(Duration::from_secs(300));
+        estimator.update_at(1000.0, Some(1000.0), start);
+
+        // One time constant later the offset is halfway to the new error.
+        let altitude = estimator.update_at(2000.0, Some(2100.0), start + Duration::from_secs(300));
+        assert!((estimator.offset() - 50.0).abs() < 1e-3, "{}", estimator.offset());
+        assert!((altitude - 2050.0).abs() < 1e-3);
+
+        // A fix straight after barely moves it.
+        estimator.update_at(2000.0, Some(2100.0), start + Duration::from_millis(300_100));
+        assert!((estimator.offset() - 50.0).abs() < 0.1, "{}", estimator.offset());
+    }
+
+    #[test]
+    fn keeps_the_offset_without_gps() {
+        let start = Instant::now();
+        let mut estimator = AltitudeEstimator::default();
+        estimator.update_at(1000.0, Some(1020.0), start);
+
+        // Above the GPS altitude limit, the barometer carries on with the last offset.
+        assert_eq!(estimator.update_at(20_000.0, None, start + Duration::from_secs(3600)), 20_020.0);
+        assert_eq!(estimator.estimate(25_000.0), 25_020.0);
+    }
+}
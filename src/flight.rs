@@ -0,0 +1,471 @@
+//! Working out which part of the flight we're in.
+//!
+//! Altitude is run through a median of the last three readings, which throws out single spikes,
+//! and then an alpha-beta filter to get a smoothed altitude and vertical rate.
+//! The phase only moves on once a condition has held for a while,
+//! so a bad reading can't call a burst or a landing.
+//! Each phase has its own [`PhaseBehavior`], deciding how often to beacon,
+//! whether to take pictures, which digipeater path to ask for and how loud to transmit.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlightPhase {
+    /// Sitting on the ground waiting to be let go.
+    Prelaunch,
+    Ascent,
+    /// Floating at a roughly constant altitude.
+    Float,
+    /// Falling after the balloon burst or was cut down.
+    Descent,
+    Landed,
+}
+
+impl fmt::Display for FlightPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FlightPhase::Prelaunch => "prelaunch",
+            FlightPhase::Ascent => "ascent",
+            FlightPhase::Float => "float",
+            FlightPhase::Descent => "descent",
+            FlightPhase::Landed => "landed",
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TxPower {
+    /// 0.5 W
+    Low,
+    /// 1 W
+    High,
+}
+
+/// What to do while in a phase.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseBehavior {
+    /// Time between location beacons.
+    pub beacon_interval: Duration,
+    /// Whether to capture and send SSDV images.
+    pub imaging: bool,
+    /// Digipeater path, e.g. `WIDE2-1`.
+    pub path: &'static [&'static str],
+    pub power: TxPower,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlightConfig {
+    pub prelaunch: PhaseBehavior,
+    pub ascent: PhaseBehavior,
+    pub float: PhaseBehavior,
+    pub descent: PhaseBehavior,
+    pub landed: PhaseBehavior,
+    /// Alpha-beta filter gains, how much of each reading's error goes into the altitude and the rate.
+    pub alpha: f32,
+    pub beta: f32,
+    /// Climb rate that counts as launched, in m/s.
+    pub launch_rate: f32,
+    /// Height above the pad that counts as launched whatever the climb rate, in meters.
+    pub launch_height: f32,
+    /// Vertical rate below which a flight is floating, in m/s either way.
+    pub float_rate: f32,
+    /// Sink rate that counts as falling, in m/s.
+    pub descent_rate: f32,
+    /// Drop from the highest altitude that counts as a burst whatever the sink rate, in meters.
+    pub burst_drop: f32,
+    /// Vertical rate below which a falling payload has landed, in m/s either way.
+    pub landed_rate: f32,
+    /// How long a condition has to hold before the phase changes.
+    pub confirm_time: Duration,
+    /// How long to sit still before calling it landed.
+    pub landed_time: Duration,
+}
+
+impl FlightConfig {
+    pub fn behavior(&self, phase: FlightPhase) -> &PhaseBehavior {
+        match phase {
+            FlightPhase::Prelaunch => &self.prelaunch,
+            FlightPhase::Ascent => &self.ascent,
+            FlightPhase::Float => &self.float,
+            FlightPhase::Descent => &self.descent,
+            FlightPhase::Landed => &self.landed,
+        }
+    }
+}
+
+impl Default for FlightConfig {
+    fn default() -> Self {
+        Self {
+            // Nothing to see on the pad, and ground stations can hear us directly.
+            prelaunch: PhaseBehavior {
+                beacon_interval: Duration::from_secs(120),
+                imaging: false,
+                path: &["WIDE1-1"],
+                power: TxPower::Low,
+            },
+            ascent: PhaseBehavior {
+                beacon_interval: Duration::from_secs(58),
+                imaging: true,
+                path: &[],
+                power: TxPower::High,
+            },
+            float: PhaseBehavior {
+                beacon_interval: Duration::from_secs(120),
+                imaging: true,
+                path: &[],
+                power: TxPower::High,
+            },
+            // Beacon fast to pin down where it comes down.
+            descent: PhaseBehavior {
+                beacon_interval: Duration::from_secs(30),
+                imaging: false,
+                path: &[],
+                power: TxPower::High,
+            },
+            // Down in a field, the recovery team needs all the help getting heard it can get.
+            landed: PhaseBehavior {
+                beacon_interval: Duration::from_secs(300),
+                imaging: false,
+                path: &["WIDE1-1", "WIDE2-1"],
+                power: TxPower::High,
+            },
+            alpha: 0.5,
+            beta: 0.1,
+            launch_rate: 2.0,
+            launch_height: 100.0,
+            float_rate: 1.0,
+            descent_rate: 5.0,
+            burst_drop: 300.0,
+            landed_rate: 0.5,
+            confirm_time: Duration::from_secs(60),
+            landed_time: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Tracks the flight phase from a stream of altitudes.
+#[derive(Debug, Clone)]
+pub struct FlightTracker {
+    config: FlightConfig,
+    phase: FlightPhase,
+    /// Filtered altitude in meters, `None` before the first sample.
+    altitude: Option<f32>,
+    /// Filtered vertical rate in m/s, positive going up.
+    vertical_rate: f32,
+    /// The last two altitudes fed in, before filtering.
+    recent: [Option<f32>; 2],
+    last_update: Option<Instant>,
+    launch_altitude: Option<f32>,
+    max_altitude: f32,
+    /// When the condition for the next phase started holding.
+    pending_since: Option<Instant>,
+}
+
+impl FlightTracker {
+    pub fn new(config: FlightConfig) -> Self {
+        Self {
+            config,
+            phase: FlightPhase::Prelaunch,
+            altitude: None,
+            vertical_rate: 0.0,
+            recent: [None; 2],
+            last_update: None,
+            launch_altitude: None,
+            max_altitude: f32::MIN,
+            pending_since: None,
+        }
+    }
+
+    pub fn phase(&self) -> FlightPhase {
+        self.phase
+    }
+
+    pub fn config(&self) -> &FlightConfig {
+        &self.config
+    }
+
+    pub fn behavior(&self) -> &PhaseBehavior {
+        self.config.behavior(self.phase)
+    }
+
+    pub fn altitude(&self) -> Option<f32> {
+        self.altitude
+    }
+
+    pub fn vertical_rate(&self) -> f32 {
+        self.vertical_rate
+    }
+
+    /// Altitude of the launch site, the first one we saw.
+    pub fn launch_altitude(&self) -> Option<f32> {
+        self.launch_altitude
+    }
+
+    pub fn max_altitude(&self) -> Option<f32> {
+        self.altitude.map(|_| self.max_altitude)
+    }
+
+    /// Moves everything tracked by `shift` meters, for when the altitude scale changes
+    /// rather than the payload moving, like the altimeter being calibrated against GPS.
+    pub fn rebase(&mut self, shift: f32) {
+        let Some(altitude) = &mut self.altitude else {
+            return;
+        };
+
+        *altitude += shift;
+        self.max_altitude += shift;
+        for recent in self.recent.iter_mut().flatten() {
+            *recent += shift;
+        }
+        if let Some(launch_altitude) = &mut self.launch_altitude {
+            *launch_altitude += shift;
+        }
+    }
+
+    /// Feeds in a new altitude, returning the new phase if it changed.
+    pub fn update(&mut self, altitude: f32) -> Option<FlightPhase> {
+        self.update_at(altitude, Instant::now())
+    }
+
+    pub fn update_at(&mut self, altitude: f32, now: Instant) -> Option<FlightPhase> {
+        let altitude = self.despike(altitude);
+        self.filter(altitude, now);
+
+        let altitude = self.altitude?;
+        self.max_altitude = self.max_altitude.max(altitude);
+        let launch_altitude = *self.launch_altitude.get_or_insert(altitude);
+
+        let config = &self.config;
+        let rate = self.vertical_rate;
+        let burst = rate < -config.descent_rate || altitude < self.max_altitude - config.burst_drop;
+
+        let (next, hold) = match self.phase {
+            FlightPhase::Prelaunch => {
+                let launched = rate > config.launch_rate || altitude > launch_altitude + config.launch_height;
+                (launched.then_some(FlightPhase::Ascent), config.confirm_time)
+            }
+            FlightPhase::Ascent if burst => (Some(FlightPhase::Descent), config.confirm_time),
+            FlightPhase::Ascent => ((rate.abs() < config.float_rate).then_some(FlightPhase::Float), config.confirm_time),
+            FlightPhase::Float => (burst.then_some(FlightPhase::Descent), config.confirm_time),
+            FlightPhase::Descent => ((rate.abs() < config.landed_rate).then_some(FlightPhase::Landed), config.landed_time),
+            FlightPhase::Landed => (None, Duration::ZERO),
+        };
+
+        let Some(next) = next else {
+            self.pending_since = None;
+            return None;
+        };
+
+        let since = *self.pending_since.get_or_insert(now);
+        if now.duration_since(since) < hold {
+            return None;
+        }
+
+        self.phase = next;
+        self.pending_since = None;
+
+        Some(next)
+    }
+
+    /// The median of this altitude and the last two. A spike would otherwise live on as the
+    /// highest altitude seen, and the drop from there be taken for a burst.
+    fn despike(&mut self, altitude: f32) -> f32 {
+        let [older, newer] = self.recent;
+        self.recent = [newer, Some(altitude)];
+
+        match (older, newer) {
+            (Some(older), Some(newer)) => older.min(newer).max(older.max(newer).min(altitude)),
+            _ => altitude,
+        }
+    }
+
+    fn filter(&mut self, altitude: f32, now: Instant) {
+        let Some(last) = self.last_update.replace(now) else {
+            self.altitude = Some(altitude);
+            return;
+        };
+
+        let dt = now.duration_since(last).as_secs_f32();
+        let Some(estimate) = self.altitude.filter(|_| dt > 0.0) else {
+            return;
+        };
+
+        let predicted = estimate + self.vertical_rate * dt;
+        let residual = altitude - predicted;
+
+        self.altitude = Some(predicted + self.config.alpha * residual);
+        self.vertical_rate += self.config.beta * residual / dt;
+    }
+}
+
+impl Default for FlightTracker {
+    fn default() -> Self {
+        Self::new(FlightConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds in one altitude a second for `seconds`, returning the last phase change.
+    fn hold(tracker: &mut FlightTracker, start: Instant, from: u64, seconds: u64, altitude: impl Fn(u64) -> f32) -> Option<FlightPhase> {
+        (from..from + seconds)
+            .filter_map(|second| tracker.update_at(altitude(second), start + Duration::from_secs(second)))
+            .last()
+    }
+
+    #[test]
+    fn recalibrating_on_the_pad_is_not_a_launch() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+
+        assert_eq!(hold(&mut tracker, start, 0, 30, |_| 20.0), None);
+
+        // QNH calibration moves the pad from 20 m to 520 m.
+        tracker.rebase(500.0);
+        assert_eq!(tracker.launch_altitude(), Some(520.0));
+        assert_eq!(tracker.max_altitude(), Some(520.0));
+
+        assert_eq!(hold(&mut tracker, start, 30, 300, |_| 520.0), None);
+        assert_eq!(tracker.phase(), FlightPhase::Prelaunch);
+        assert!(tracker.vertical_rate().abs() < 0.1);
+
+        // A real climb is still a launch.
+        assert_eq!(hold(&mut tracker, start, 330, 120, |second| 520.0 + 5.0 * (second - 330) as f32), Some(FlightPhase::Ascent));
+    }
+
+    #[test]
+    fn the_same_jump_without_a_rebase_is_a_launch() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+
+        assert_eq!(hold(&mut tracker, start, 0, 30, |_| 20.0), None);
+        assert_eq!(hold(&mut tracker, start, 30, 70, |_| 520.0), Some(FlightPhase::Ascent));
+    }
+
+    #[test]
+    fn rebasing_before_the_first_altitude_does_nothing() {
+        let mut tracker = FlightTracker::default();
+        tracker.rebase(500.0);
+
+        tracker.update(20.0);
+        assert_eq!(tracker.altitude(), Some(20.0));
+        assert_eq!(tracker.launch_altitude(), Some(20.0));
+    }
+
+    /// Climbs off the pad at 5 m/s for 100 s, which is a launch.
+    fn launch(tracker: &mut FlightTracker, start: Instant) {
+        assert_eq!(hold(tracker, start, 0, 100, |second| 5.0 * second as f32), Some(FlightPhase::Ascent));
+    }
+
+    #[test]
+    fn launches_on_climb_rate() {
+        // Too high up for the height above the pad to count.
+        let mut tracker = FlightTracker::new(FlightConfig {
+            launch_height: f32::INFINITY,
+            ..FlightConfig::default()
+        });
+        let start = Instant::now();
+
+        // Only once the climb has held for a minute.
+        assert_eq!(hold(&mut tracker, start, 0, 55, |second| 5.0 * second as f32), None);
+        assert_eq!(hold(&mut tracker, start, 55, 20, |second| 5.0 * second as f32), Some(FlightPhase::Ascent));
+    }
+
+    #[test]
+    fn launches_on_height_above_the_pad() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+
+        // Climbing at 1 m/s is slower than the launch rate, but 100 m up is still a launch.
+        assert_eq!(hold(&mut tracker, start, 0, 150, |second| second as f32), None);
+        assert_eq!(hold(&mut tracker, start, 150, 30, |second| second as f32), Some(FlightPhase::Ascent));
+        assert!(tracker.vertical_rate() < tracker.config().launch_rate);
+    }
+
+    #[test]
+    fn levelling_off_is_a_float() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+        launch(&mut tracker, start);
+
+        assert_eq!(hold(&mut tracker, start, 100, 90, |_| 500.0), Some(FlightPhase::Float));
+    }
+
+    #[test]
+    fn bursts_on_sink_rate() {
+        // Too far down for the drop from the top to count.
+        let mut tracker = FlightTracker::new(FlightConfig {
+            burst_drop: f32::INFINITY,
+            ..FlightConfig::default()
+        });
+        let start = Instant::now();
+        launch(&mut tracker, start);
+
+        assert_eq!(hold(&mut tracker, start, 100, 50, |second| 500.0 - 10.0 * (second - 100) as f32), None);
+        assert_eq!(hold(&mut tracker, start, 150, 30, |second| 500.0 - 10.0 * (second - 100) as f32), Some(FlightPhase::Descent));
+    }
+
+    #[test]
+    fn bursts_on_the_drop_from_the_top() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+        launch(&mut tracker, start);
+
+        // Sinking at 4 m/s is slower than the descent rate, but 300 m down is still a burst.
+        let altitude = |second: u64| 500.0 - 4.0 * (second - 100) as f32;
+        assert_eq!(hold(&mut tracker, start, 100, 120, altitude), None);
+        assert_eq!(hold(&mut tracker, start, 220, 60, altitude), Some(FlightPhase::Descent));
+        assert!(tracker.vertical_rate() > -tracker.config().descent_rate);
+    }
+
+    #[test]
+    fn falling_from_a_float_is_a_descent() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+        launch(&mut tracker, start);
+        assert_eq!(hold(&mut tracker, start, 100, 90, |_| 500.0), Some(FlightPhase::Float));
+
+        assert_eq!(hold(&mut tracker, start, 190, 90, |second| 500.0 - 10.0 * (second - 190) as f32), Some(FlightPhase::Descent));
+    }
+
+    #[test]
+    fn lands_once_still_for_the_landed_time() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+        launch(&mut tracker, start);
+        assert_eq!(hold(&mut tracker, start, 100, 80, |second| 500.0 - 10.0 * (second - 100) as f32), Some(FlightPhase::Descent));
+
+        // Sitting still for a minute isn't enough.
+        assert_eq!(hold(&mut tracker, start, 180, 240, |_| -300.0), None);
+        assert_eq!(hold(&mut tracker, start, 420, 90, |_| -300.0), Some(FlightPhase::Landed));
+
+        assert_eq!(hold(&mut tracker, start, 510, 60, |second| 5.0 * second as f32), None);
+        assert_eq!(tracker.phase(), FlightPhase::Landed);
+    }
+
+    #[test]
+    fn a_single_spike_changes_nothing() {
+        let mut tracker = FlightTracker::default();
+        let start = Instant::now();
+
+        // On the pad,
+        let pad = |second| if second == 30 { 2000.0 } else { 20.0 };
+        assert_eq!(hold(&mut tracker, start, 0, 200, pad), None);
+        assert_eq!(tracker.phase(), FlightPhase::Prelaunch);
+        // It isn't taken for the top of the flight either, or the climb would look like a fall from there.
+        assert_eq!(tracker.max_altitude(), Some(20.0));
+
+        // and on the way up.
+        let climb = |second| if second == 300 { 0.0 } else { 5.0 * (second - 200) as f32 + 20.0 };
+        assert_eq!(hold(&mut tracker, start, 200, 90, |second| 5.0 * (second - 200) as f32 + 20.0), Some(FlightPhase::Ascent));
+        assert_eq!(hold(&mut tracker, start, 290, 200, climb), None);
+        assert_eq!(tracker.phase(), FlightPhase::Ascent);
+    }
+}
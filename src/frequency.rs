@@ -0,0 +1,389 @@
+//! Regional APRS frequencies, chosen from the GPS position.
+//!
+//! Regions are made of rough latitude/longitude boxes checked in order, so smaller
+//! regions (and no-TX zones) have to come before the larger ones they sit in.
+//! A region is only left once the position is clear of it by
+//! [`HYSTERESIS_DEGREES`], to avoid flapping back and forth along a border.
+
+/// Frequency used outside of every listed region, in MHz.
+pub const DEFAULT_FREQUENCY: f32 = 144.390;
+
+/// Distance past a region's edge before it is considered left, in degrees.
+pub const HYSTERESIS_DEGREES: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+impl Bounds {
+    pub const fn new(min_latitude: f64, max_latitude: f64, min_longitude: f64, max_longitude: f64) -> Self {
+        Self {
+            min_latitude,
+            max_latitude,
+            min_longitude,
+            max_longitude,
+        }
+    }
+
+    /// Checks whether the position is inside the bounds grown by `margin` degrees on every side.
+    pub fn contains(&self, latitude: f64, longitude: f64, margin: f64) -> bool {
+        latitude >= self.min_latitude - margin
+            && latitude <= self.max_latitude + margin
+            && longitude >= self.min_longitude - margin
+            && longitude <= self.max_longitude + margin
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub name: &'static str,
+    /// Boxes that together make up the region.
+    pub bounds: &'static [Bounds],
+    /// APRS frequency in MHz, or `None` where we aren't allowed to transmit at all.
+    pub frequency: Option<f32>,
+}
+
+impl Region {
+    /// Checks whether the position is inside any of the region's boxes, grown by `margin` degrees.
+    pub fn contains(&self, latitude: f64, longitude: f64, margin: f64) -> bool {
+        self.bounds.iter().any(|bounds| bounds.contains(latitude, longitude, margin))
+    }
+}
+
+/// Regions in priority order.
+pub const REGIONS: &[Region] = &[
+    // Airborne amateur operation isn't permitted here.
+    Region {
+        name: "United Kingdom",
+        bounds: &[
+            // England, Wales and southern Scotland, staying east of the Irish Sea.
+            Bounds::new(51.3, 55.4, -5.8, 1.8),
+            // South coast, short of Normandy and Picardy.
+            Bounds::new(50.0, 51.3, -5.8, 1.4),
+            // Rest of Scotland, north of Ireland.
+            Bounds::new(55.4, 60.9, -7.7, -0.7),
+            Bounds::new(54.0, 55.3, -8.2, -5.4), // Northern Ireland
+        ],
+        frequency: None,
+    },
+    Region {
+        name: "North Korea",
+        bounds: &[
+            // Kaesong and Haeju, north of Seoul and Ganghwa.
+            Bounds::new(37.8, 38.3, 124.6, 126.9),
+            Bounds::new(38.3, 40.0, 124.2, 128.0),
+            // East coast, north of Goseong.
+            Bounds::new(38.6, 41.0, 128.0, 129.8),
+            // Along the Yalu, which runs northeast from Dandong.
+            Bounds::new(40.0, 40.5, 124.5, 129.8),
+            Bounds::new(40.5, 41.4, 125.3, 129.8),
+            // Along the Tumen, up to the Russian border.
+            Bounds::new(41.4, 42.0, 128.2, 130.7),
+            Bounds::new(42.0, 42.3, 129.0, 130.7),
+            Bounds::new(42.3, 42.95, 129.7, 130.4),
+        ],
+        frequency: None,
+    },
+    Region {
+        name: "Yemen",
+        bounds: &[
+            // Red Sea coast, short of Jizan and Eritrea.
+            Bounds::new(14.0, 16.4, 42.6, 48.0),
+            Bounds::new(12.6, 14.0, 43.2, 48.0),
+            // Saada and al-Jawf, short of Najran.
+            Bounds::new(16.4, 17.3, 43.2, 47.0),
+            // Hadramawt, short of the Saudi border across the Empty Quarter.
+            Bounds::new(14.0, 17.7, 48.0, 52.0),
+            // Al-Mahrah, short of Dhofar.
+            Bounds::new(15.6, 16.6, 52.0, 53.1),
+            Bounds::new(12.1, 12.8, 52.0, 54.5), // Socotra
+        ],
+        frequency: None,
+    },
+
+    Region {
+        name: "South Korea",
+        bounds: &[
+            Bounds::new(35.0, 38.6, 124.5, 130.0),
+            // The south coast and Jeju, stopping west of the Korea Strait before Tsushima and Kyushu.
+            Bounds::new(33.0, 35.0, 124.5, 129.0),
+        ],
+        frequency: Some(144.620),
+    },
+    Region {
+        name: "Japan",
+        bounds: &[
+            Bounds::new(24.0, 30.9, 122.9, 131.5), // Ryukyu Islands
+            Bounds::new(30.9, 34.8, 128.5, 134.8), // Kyushu and Shikoku
+            // Honshu, east of Korea.
+            Bounds::new(33.4, 41.5, 130.8, 142.2),
+            // Hokkaido, east of Primorye.
+            Bounds::new(41.5, 45.6, 139.3, 146.0),
+        ],
+        frequency: Some(144.660),
+    },
+    Region {
+        name: "China",
+        bounds: &[
+            // From Yunnan to Beijing, east of Myanmar and north of Vietnam and Laos.
+            Bounds::new(23.0, 41.5, 98.0, 122.5),
+            // Guangxi, Guangdong and Hainan.
+            Bounds::new(18.0, 23.0, 108.0, 117.5),
+            // Tibet, north of the Himalaya.
+            Bounds::new(28.3, 36.5, 85.5, 98.0),
+            Bounds::new(30.5, 36.5, 79.0, 85.5),
+            // Tarim basin and Qinghai.
+            Bounds::new(36.5, 40.5, 75.5, 98.0),
+            // Xinjiang and Gansu, short of Kazakhstan and Mongolia.
+            Bounds::new(40.5, 45.0, 80.2, 96.0),
+            Bounds::new(40.5, 42.5, 96.0, 101.0),
+            Bounds::new(45.0, 47.0, 82.6, 89.5),
+            // Inner Mongolia, Liaoning and Jilin, south of Mongolia.
+            Bounds::new(41.5, 43.6, 112.0, 130.3),
+            Bounds::new(38.7, 41.5, 122.5, 126.3),
+            // Manchuria, between Mongolia and Primorye.
+            Bounds::new(43.6, 46.3, 117.0, 131.0),
+            Bounds::new(46.3, 50.5, 119.9, 127.0),
+            Bounds::new(46.3, 47.7, 127.0, 133.5),
+            Bounds::new(50.5, 53.4, 121.0, 125.5),
+        ],
+        frequency: Some(144.640),
+    },
+    // Boxes can't follow the Mekong or the hills along Myanmar, so towns just across it
+    // like Savannakhet, Mawlamyine and Kota Bharu still come out as Thailand. The capitals don't.
+    Region {
+        name: "Thailand",
+        bounds: &[
+            // The centre and south, west of Cambodia.
+            Bounds::new(5.6, 14.3, 97.3, 102.9),
+            // Isan, north of Cambodia and south of Vientiane.
+            Bounds::new(14.3, 17.9, 97.3, 105.7),
+            // The north, west of Laos.
+            Bounds::new(17.9, 20.5, 97.3, 101.2),
+        ],
+        frequency: Some(145.525),
+    },
+    Region { name: "New Zealand", bounds: &[Bounds::new(-47.5, -34.0, 166.0, 179.0)], frequency: Some(144.575) },
+    Region { name: "Australia", bounds: &[Bounds::new(-44.0, -10.0, 112.0, 154.0)], frequency: Some(145.175) },
+    Region { name: "Argentina", bounds: &[Bounds::new(-55.1, -21.8, -73.6, -53.6)], frequency: Some(144.930) },
+    Region { name: "Brazil", bounds: &[Bounds::new(-33.8, 5.3, -74.0, -34.8)], frequency: Some(145.570) },
+    Region { name: "North America", bounds: &[Bounds::new(14.5, 72.0, -170.0, -52.0)], frequency: Some(144.390) },
+    // IARU Region 1
+    Region { name: "Europe and Africa", bounds: &[Bounds::new(-35.0, 72.0, -25.0, 60.0)], frequency: Some(144.800) },
+    Region {
+        name: "Russia",
+        bounds: &[
+            // North of Kazakhstan and Mongolia.
+            Bounds::new(55.0, 78.0, 60.0, 180.0),
+            Bounds::new(52.2, 55.0, 87.0, 180.0),
+            Bounds::new(50.5, 52.2, 102.0, 180.0),
+            // Amur and Primorye, east of China.
+            Bounds::new(42.3, 50.5, 127.0, 180.0),
+        ],
+        frequency: Some(144.800),
+    },
+];
+
+/// Tracks which region we're in as the position changes.
+#[derive(Debug, Clone)]
+pub struct FrequencyPlan {
+    regions: &'static [Region],
+    /// Index into `regions`, `None` meaning outside all of them.
+    current: Option<usize>,
+}
+
+impl FrequencyPlan {
+    pub fn new(regions: &'static [Region]) -> Self {
+        Self {
+            regions,
+            current: None,
+        }
+    }
+
+    /// Updates the region from a new position, returning whether it changed.
+    pub fn update(&mut self, latitude: f64, longitude: f64) -> bool {
+        let candidate = self
+            .regions
+            .iter()
+            .position(|region| region.contains(latitude, longitude, 0.0));
+
+        if candidate == self.current {
+            return false;
+        }
+
+        // Only stick to the current region if it would otherwise lose to a lower priority one,
+        // so higher priority regions (like no-TX zones) are always entered straight away.
+        if let Some(current) = self.current {
+            let outranks = candidate.is_none_or(|candidate| current < candidate);
+            if outranks && self.regions[current].contains(latitude, longitude, HYSTERESIS_DEGREES) {
+                return false;
+            }
+        }
+
+        self.current = candidate;
+
+        true
+    }
+
+    pub fn region(&self) -> Option<&Region> {
+        self.current.map(|current| &self.regions[current])
+    }
+
+    /// Frequency to use in the current region, or `None` if transmitting isn't allowed.
+    pub fn frequency(&self) -> Option<f32> {
+        match self.region() {
+            Some(region) => region.frequency,
+            None => Some(DEFAULT_FREQUENCY),
+        }
+    }
+
+    pub fn transmit_allowed(&self) -> bool {
+        self.frequency().is_some()
+    }
+}
+
+impl Default for FrequencyPlan {
+    fn default() -> Self {
+        Self::new(REGIONS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region_at(latitude: f64, longitude: f64) -> Option<&'static str> {
+        let mut plan = FrequencyPlan::default();
+        plan.update(latitude, longitude);
+        plan.region().map(|region| region.name)
+    }
+
+    #[test]
+    fn cities_are_in_their_own_region() {
+        let cities = [
+            ("London", 51.51, -0.13, "United Kingdom"),
+            ("Belfast", 54.60, -5.93, "United Kingdom"),
+            ("Edinburgh", 55.95, -3.19, "United Kingdom"),
+            ("Pyongyang", 39.03, 125.75, "North Korea"),
+            ("Kaesong", 37.97, 126.55, "North Korea"),
+            ("Chongjin", 41.80, 129.78, "North Korea"),
+            ("Sana'a", 15.35, 44.21, "Yemen"),
+            ("Aden", 12.79, 45.02, "Yemen"),
+            ("Mukalla", 14.54, 49.13, "Yemen"),
+            ("Seoul", 37.57, 126.98, "South Korea"),
+            ("Busan", 35.18, 129.08, "South Korea"),
+            ("Jeju", 33.50, 126.53, "South Korea"),
+            ("Tokyo", 35.68, 139.69, "Japan"),
+            ("Tsushima", 34.20, 129.29, "Japan"),
+            ("Iki", 33.75, 129.69, "Japan"),
+            ("Sasebo", 33.16, 129.72, "Japan"),
+            ("Karatsu", 33.45, 129.97, "Japan"),
+            ("Sapporo", 43.06, 141.35, "Japan"),
+            ("Naha", 26.21, 127.68, "Japan"),
+            ("Beijing", 39.90, 116.40, "China"),
+            ("Lhasa", 29.65, 91.10, "China"),
+            ("Urumqi", 43.83, 87.62, "China"),
+            ("Harbin", 45.75, 126.63, "China"),
+            ("Shenyang", 41.80, 123.43, "China"),
+            ("Dandong", 40.13, 124.38, "China"),
+            ("Yanji", 42.90, 129.51, "China"),
+            ("Guangzhou", 23.13, 113.26, "China"),
+            ("Bangkok", 13.75, 100.50, "Thailand"),
+            ("Chiang Mai", 18.79, 98.98, "Thailand"),
+            ("Nong Khai", 17.88, 102.74, "Thailand"),
+            ("Ubon Ratchathani", 15.24, 104.85, "Thailand"),
+            ("Hat Yai", 7.01, 100.47, "Thailand"),
+            ("Vladivostok", 43.12, 131.89, "Russia"),
+            ("Blagoveshchensk", 50.26, 127.53, "Russia"),
+        ];
+
+        for (city, latitude, longitude, region) in cities {
+            assert_eq!(region_at(latitude, longitude), Some(region), "{city}");
+        }
+    }
+
+    #[test]
+    fn neighbours_are_left_out_of_no_tx_zones() {
+        let cities = [
+            ("Dublin", 53.35, -6.26, "Europe and Africa"),
+            ("Cork", 51.90, -8.47, "Europe and Africa"),
+            ("Calais", 50.95, 1.86, "Europe and Africa"),
+            ("Dieppe", 49.92, 1.08, "Europe and Africa"),
+            ("Najran", 17.49, 44.13, "Europe and Africa"),
+            ("Jizan", 16.89, 42.55, "Europe and Africa"),
+            ("Salalah", 17.02, 54.09, "Europe and Africa"),
+            ("Assab", 13.01, 42.74, "Europe and Africa"),
+        ];
+
+        for (city, latitude, longitude, region) in cities {
+            assert_eq!(region_at(latitude, longitude), Some(region), "{city}");
+        }
+    }
+
+    #[test]
+    fn china_stops_at_its_borders() {
+        let cities = [
+            ("Kathmandu", 27.72, 85.32),
+            ("Delhi", 28.61, 77.21),
+            ("Dhaka", 23.81, 90.41),
+            ("Mandalay", 21.97, 96.08),
+            ("Hanoi", 21.03, 105.85),
+            ("Ulaanbaatar", 47.92, 106.92),
+            ("Almaty", 43.24, 76.89),
+        ];
+
+        for (city, latitude, longitude) in cities {
+            assert_eq!(region_at(latitude, longitude), None, "{city}");
+        }
+    }
+
+    #[test]
+    fn thailand_leaves_out_its_neighbours_capitals() {
+        let cities = [("Vientiane", 17.97, 102.60), ("Phnom Penh", 11.56, 104.92), ("Siem Reap", 13.36, 103.86)];
+
+        for (city, latitude, longitude) in cities {
+            assert_eq!(region_at(latitude, longitude), None, "{city}");
+        }
+    }
+
+    #[test]
+    fn no_tx_zones_block_transmitting() {
+        let mut plan = FrequencyPlan::default();
+        assert_eq!(plan.frequency(), Some(DEFAULT_FREQUENCY));
+
+        assert!(plan.update(39.03, 125.75));
+        assert!(!plan.transmit_allowed());
+
+        assert!(plan.update(45.75, 126.63));
+        assert_eq!(plan.frequency(), Some(144.640));
+    }
+
+    #[test]
+    fn regions_are_left_only_past_the_hysteresis() {
+        let mut plan = FrequencyPlan::default();
+        // Out over the Atlantic, just off the edge of Europe.
+        assert!(plan.update(40.0, -24.9));
+        assert_eq!(plan.region().map(|region| region.name), Some("Europe and Africa"));
+
+        assert!(!plan.update(40.0, -25.1));
+        assert_eq!(plan.region().map(|region| region.name), Some("Europe and Africa"));
+
+        assert!(plan.update(40.0, -25.5));
+        assert_eq!(plan.region(), None);
+    }
+
+    #[test]
+    fn higher_priority_regions_are_entered_straight_away() {
+        let mut plan = FrequencyPlan::default();
+        assert!(plan.update(37.57, 126.98));
+        assert_eq!(plan.region().map(|region| region.name), Some("South Korea"));
+
+        // Just over the border, well within South Korea's hysteresis.
+        assert!(plan.update(37.97, 126.55));
+        assert!(!plan.transmit_allowed());
+    }
+}
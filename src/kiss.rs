@@ -0,0 +1,661 @@
+//! KISS framing for talking to standard TNCs over serial or TCP.
+//!
+//! See <http://www.ax25.net/kiss.aspx> for the protocol description.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::{
+    ax25,
+    modulator::{Modulator, ModulatorError},
+};
+
+/// Frame end
+pub const FEND: u8 = 0xC0;
+/// Frame escape
+pub const FESC: u8 = 0xDB;
+/// Transposed frame end
+pub const TFEND: u8 = 0xDC;
+/// Transposed frame escape
+pub const TFESC: u8 = 0xDD;
+
+/// Largest frame the decoder will buffer before giving up on it.
+const MAX_FRAME_SIZE: usize = 1024;
+
+/// Longest a client gets to take a frame before it's dropped, so one that stops reading can't hold up the flight loop.
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    /// The rest of the frame is data to be sent on the HDLC channel.
+    Data,
+    /// Keyup delay, in 10 ms units.
+    TxDelay,
+    /// Persistence parameter `p`, scaled to 0-255.
+    Persistence,
+    /// Slot interval, in 10 ms units.
+    SlotTime,
+    /// Time to hold up the TX after the FCS has been sent, in 10 ms units.
+    TxTail,
+    /// 0 for half duplex, anything else for full duplex.
+    FullDuplex,
+    /// TNC specific configuration.
+    SetHardware,
+    /// Exit KISS mode. Applies to all ports.
+    Return,
+}
+
+impl Command {
+    fn code(&self) -> u8 {
+        match self {
+            Command::Data => 0x00,
+            Command::TxDelay => 0x01,
+            Command::Persistence => 0x02,
+            Command::SlotTime => 0x03,
+            Command::TxTail => 0x04,
+            Command::FullDuplex => 0x05,
+            Command::SetHardware => 0x06,
+            Command::Return => 0x0F,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x00 => Some(Command::Data),
+            0x01 => Some(Command::TxDelay),
+            0x02 => Some(Command::Persistence),
+            0x03 => Some(Command::SlotTime),
+            0x04 => Some(Command::TxTail),
+            0x05 => Some(Command::FullDuplex),
+            0x06 => Some(Command::SetHardware),
+            0x0F => Some(Command::Return),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// TNC port, 0-15.
+    pub port: u8,
+    pub command: Command,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn data(port: u8, data: &[u8]) -> Self {
+        Self {
+            port,
+            command: Command::Data,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Builds the on-the-wire representation of the frame, including both `FEND`s.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.data.len() + 3);
+        encode_into(&mut buf, self.port, self.command, &self.data);
+
+        return buf;
+    }
+}
+
+/// Appends a complete, escaped KISS frame to `buf`.
+pub fn encode_into(buf: &mut Vec<u8>, port: u8, command: Command, data: &[u8]) {
+    buf.push(FEND);
+
+    // Return is the only command that doesn't carry a port number.
+    match command {
+        Command::Return => buf.push(0xFF),
+        _ => buf.push((port & 0x0F) << 4 | command.code()),
+    }
+
+    for &byte in data {
+        match byte {
+            FEND => buf.extend_from_slice(&[FESC, TFEND]),
+            FESC => buf.extend_from_slice(&[FESC, TFESC]),
+            _ => buf.push(byte),
+        }
+    }
+
+    buf.push(FEND);
+}
+
+/// Incremental KISS decoder.
+///
+/// Bytes can be fed in whatever chunks they arrive in;
+/// a frame is returned once its closing `FEND` has been seen.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    in_frame: bool,
+    escaped: bool,
+    overflowed: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a single byte into the decoder.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame, KissError>> {
+        if byte == FEND {
+            let result = if self.in_frame && !self.buf.is_empty() {
+                Some(self.finish())
+            } else {
+                // Back to back FENDs are allowed and just mark a frame boundary.
+                None
+            };
+
+            self.buf.clear();
+            self.in_frame = true;
+            self.escaped = false;
+            self.overflowed = false;
+
+            return result;
+        }
+
+        if !self.in_frame {
+            // Garbage between frames
+            return None;
+        }
+
+        if self.overflowed {
+            return None;
+        }
+
+        let byte = if self.escaped {
+            self.escaped = false;
+            match byte {
+                TFEND => FEND,
+                TFESC => FESC,
+                _ => {
+                    // Drop the rest of the frame.
+                    self.in_frame = false;
+                    self.buf.clear();
+                    return Some(Err(KissError::InvalidEscape(byte)));
+                }
+            }
+        } else if byte == FESC {
+            self.escaped = true;
+            return None;
+        } else {
+            byte
+        };
+
+        if self.buf.len() >= MAX_FRAME_SIZE {
+            self.overflowed = true;
+            self.buf.clear();
+            return Some(Err(KissError::TooLong));
+        }
+
+        self.buf.push(byte);
+
+        None
+    }
+
+    /// Feeds a chunk of bytes into the decoder, returning every frame completed by it.
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<Result<Frame, KissError>> {
+        bytes.iter().filter_map(|&byte| self.push(byte)).collect()
+    }
+
+    fn finish(&mut self) -> Result<Frame, KissError> {
+        let type_byte = self.buf[0];
+
+        if type_byte == 0xFF {
+            return Ok(Frame {
+                port: 0,
+                command: Command::Return,
+                data: Vec::new(),
+            });
+        }
+
+        let command = Command::from_code(type_byte & 0x0F).ok_or(KissError::UnknownCommand(type_byte))?;
+
+        Ok(Frame {
+            port: type_byte >> 4,
+            command,
+            data: self.buf[1..].to_vec(),
+        })
+    }
+}
+
+/// Channel access parameters sent to the TNC on startup.
+///
+/// All times are in 10 ms units, as they are on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Parameters {
+    pub tx_delay: u8,
+    pub persistence: u8,
+    pub slot_time: u8,
+    pub tx_tail: u8,
+    pub full_duplex: bool,
+}
+
+impl Default for Parameters {
+    /// Defaults recommended by the KISS specification.
+    fn default() -> Self {
+        Self {
+            tx_delay: 50,
+            persistence: 63,
+            slot_time: 10,
+            tx_tail: 0,
+            full_duplex: false,
+        }
+    }
+}
+
+impl Parameters {
+    pub fn frames(&self, port: u8) -> [Frame; 5] {
+        let frame = |command, value| Frame {
+            port,
+            command,
+            data: vec![value],
+        };
+
+        [
+            frame(Command::TxDelay, self.tx_delay),
+            frame(Command::Persistence, self.persistence),
+            frame(Command::SlotTime, self.slot_time),
+            frame(Command::TxTail, self.tx_tail),
+            frame(Command::FullDuplex, self.full_duplex as u8),
+        ]
+    }
+}
+
+/// A TNC speaking KISS over any byte stream.
+pub struct KissTnc<T> {
+    io: T,
+    port: u8,
+    decoder: Decoder,
+    received: VecDeque<Frame>,
+    /// Last parameters sent to the TNC, assumed to be the defaults until then.
+    parameters: Parameters,
+}
+
+impl<T> KissTnc<T> {
+    pub fn new(io: T, port: u8) -> Self {
+        Self {
+            io,
+            port: port & 0x0F,
+            decoder: Decoder::new(),
+            received: VecDeque::new(),
+            parameters: Parameters::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl KissTnc<TcpStream> {
+    /// Connects to a network TNC, such as Direwolf's KISS port (8001 by default).
+    pub fn connect<A: ToSocketAddrs>(addr: A, port: u8) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self::new(stream, port))
+    }
+}
+
+impl KissTnc<File> {
+    /// Opens a serial TNC.
+    ///
+    /// The line settings are left as they are,
+    /// so the device should already be configured with `stty`.
+    pub fn open<P: AsRef<Path>>(path: P, port: u8) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        Ok(Self::new(file, port))
+    }
+}
+
+impl<T: Write> KissTnc<T> {
+    pub fn configure(&mut self, parameters: &Parameters) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for frame in parameters.frames(self.port) {
+            encode_into(&mut buf, frame.port, frame.command, &frame.data);
+        }
+
+        self.io.write_all(&buf)?;
+        self.io.flush()?;
+
+        self.parameters = *parameters;
+
+        Ok(())
+    }
+
+    pub fn send(&mut self, command: Command, data: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(data.len() + 3);
+        encode_into(&mut buf, self.port, command, data);
+
+        self.io.write_all(&buf)?;
+        self.io.flush()
+    }
+}
+
+impl<T: Read> KissTnc<T> {
+    /// Blocks until a complete frame has been received from the TNC.
+    ///
+    /// Malformed frames are skipped.
+    pub fn receive(&mut self) -> io::Result<Frame> {
+        let mut buf = [0; 256];
+
+        loop {
+            if let Some(frame) = self.received.pop_front() {
+                return Ok(frame);
+            }
+
+            let len = self.io.read(&mut buf)?;
+            if len == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "TNC closed the connection"));
+            }
+
+            self.received.extend(self.decoder.decode(&buf[..len]).into_iter().filter_map(Result::ok));
+        }
+    }
+}
+
+impl<T: Write> Modulator for KissTnc<T> {
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), ModulatorError> {
+        self.send(Command::Data, frame)?;
+
+        Ok(())
+    }
+
+    fn framing_overhead(&self) -> usize {
+        // The TNC sends flags for the whole of TXDELAY and TXTAIL, which are in 10 ms units,
+        // so 1.5 bytes each at 1200 baud, and a closing flag after the FCS.
+        let flags = (self.parameters.tx_delay as usize + self.parameters.tx_tail as usize) * 3 / 2;
+
+        flags + ax25::FCS_SIZE + 1
+    }
+
+    fn keys_transmitter(&self) -> bool {
+        true
+    }
+}
+
+/// A local KISS-over-TCP server that hands every transmitted frame to its clients,
+/// so APRS clients like Direwolf, Xastir or YAAC can be tested without RF.
+///
+/// Anything the clients send is ignored.
+pub struct KissServer {
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+    port: u8,
+    local_addr: SocketAddr,
+}
+
+impl KissServer {
+    /// Binds the server and starts accepting clients in the background.
+    pub fn bind<A: ToSocketAddrs>(addr: A, port: u8) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = Arc::clone(&clients);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)) {
+                            warn!("failed to set a write timeout for KISS client, turning it away: {err}");
+                            continue;
+                        }
+
+                        if let Ok(peer) = stream.peer_addr() {
+                            info!("KISS client connected from {peer}");
+                        }
+
+                        let _ = stream.set_nodelay(true);
+                        accepted.lock().unwrap().push(stream);
+                    }
+                    Err(err) => warn!("failed to accept KISS client: {err}"),
+                }
+            }
+        });
+
+        Ok(Self {
+            clients,
+            port: port & 0x0F,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Sends a data frame to every connected client, dropping any that have gone away
+    /// or haven't taken it within [`CLIENT_WRITE_TIMEOUT`].
+    ///
+    /// Returns the number of clients the frame was delivered to.
+    pub fn broadcast(&mut self, data: &[u8]) -> usize {
+        let mut buf = Vec::with_capacity(data.len() + 3);
+        encode_into(&mut buf, self.port, Command::Data, data);
+
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain_mut(|client| match client.write_all(&buf) {
+            Ok(()) => true,
+            // A timed out write may have sent part of the frame, so the client is out of step either way.
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                warn!("Dropping KISS client that stopped reading: {err}");
+                false
+            }
+            Err(err) => {
+                info!("KISS client disconnected: {err}");
+                false
+            }
+        });
+
+        clients.len()
+    }
+}
+
+impl Modulator for KissServer {
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), ModulatorError> {
+        let clients = self.broadcast(frame);
+        info!("Served frame to {clients} KISS client(s)");
+
+        Ok(())
+    }
+
+    /// Nothing goes on the air in test mode.
+    fn framing_overhead(&self) -> usize {
+        0
+    }
+
+    fn keys_transmitter(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum KissError {
+    #[error("unknown KISS command byte: {0:#04x}")]
+    UnknownCommand(u8),
+    #[error("invalid byte after FESC: {0:#04x}")]
+    InvalidEscape(u8),
+    #[error("frame exceeds the maximum of {MAX_FRAME_SIZE} bytes")]
+    TooLong,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Shutdown, time::Instant};
+
+    use super::*;
+
+    fn round_trip(frame: &Frame) -> Frame {
+        let mut decoder = Decoder::new();
+        let mut frames = decoder.decode(&frame.encode());
+
+        assert_eq!(frames.len(), 1);
+        frames.remove(0).unwrap()
+    }
+
+    #[test]
+    fn escapes_fend_and_fesc() {
+        let frame = Frame::data(3, &[0x01, FEND, 0x02, FESC, TFEND, TFESC]);
+
+        assert_eq!(
+            frame.encode(),
+            [FEND, 0x30, 0x01, FESC, TFEND, 0x02, FESC, TFESC, TFEND, TFESC, FEND]
+        );
+        assert_eq!(round_trip(&frame), frame);
+    }
+
+    #[test]
+    fn round_trips_every_byte_value() {
+        let data: Vec<u8> = (0..=255).collect();
+        let frame = Frame::data(15, &data);
+
+        assert_eq!(round_trip(&frame), frame);
+    }
+
+    #[test]
+    fn round_trips_commands() {
+        for command in [Command::TxDelay, Command::Persistence, Command::SlotTime, Command::TxTail, Command::FullDuplex] {
+            let frame = Frame {
+                port: 2,
+                command,
+                data: vec![FEND],
+            };
+
+            assert_eq!(round_trip(&frame), frame);
+        }
+
+        let frame = Frame {
+            port: 0,
+            command: Command::Return,
+            data: Vec::new(),
+        };
+        assert_eq!(frame.encode(), [FEND, 0xFF, FEND]);
+        assert_eq!(round_trip(&frame), frame);
+    }
+
+    #[test]
+    fn decodes_several_frames_from_one_read() {
+        let frames = [Frame::data(0, b"first"), Frame::data(1, &[FESC, FEND]), Frame::data(0, b"third")];
+        let mut bytes = Vec::new();
+        for frame in &frames {
+            bytes.extend(frame.encode());
+        }
+
+        let decoded: Vec<Frame> = Decoder::new().decode(&bytes).into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn decodes_frames_split_across_reads() {
+        let frames = [Frame::data(0, &[0x01, FEND, 0x02]), Frame::data(0, &[FESC; 3])];
+        let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+
+        // Every split point, including between FESC and what it escapes.
+        for split in 0..=bytes.len() {
+            let mut decoder = Decoder::new();
+            let mut decoded = decoder.decode(&bytes[..split]);
+            decoded.extend(decoder.decode(&bytes[split..]));
+
+            let decoded: Vec<Frame> = decoded.into_iter().map(Result::unwrap).collect();
+            assert_eq!(decoded, frames, "split at {split}");
+        }
+    }
+
+    #[test]
+    fn skips_garbage_and_shared_fends() {
+        // Leading noise, then two frames sharing a single FEND between them.
+        let bytes = [0x55, 0xAA, FEND, FEND, 0x00, b'a', FEND, 0x00, b'b', FEND];
+
+        let decoded: Vec<Frame> = Decoder::new().decode(&bytes).into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(decoded, [Frame::data(0, b"a"), Frame::data(0, b"b")]);
+    }
+
+    #[test]
+    fn recovers_after_a_bad_escape() {
+        let bytes = [FEND, 0x00, FESC, 0x42, 0x01, FEND, 0x00, b'o', b'k', FEND];
+
+        let decoded = Decoder::new().decode(&bytes);
+
+        assert!(matches!(decoded[0], Err(KissError::InvalidEscape(0x42))));
+        assert_eq!(decoded[1].as_ref().unwrap(), &Frame::data(0, b"ok"));
+        assert_eq!(decoded.len(), 2);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut bytes = vec![FEND, 0x00];
+        bytes.extend(std::iter::repeat_n(0x01, MAX_FRAME_SIZE));
+        bytes.extend([FEND, 0x00, b'x', FEND]);
+
+        let decoded = Decoder::new().decode(&bytes);
+
+        assert!(matches!(decoded[0], Err(KissError::TooLong)));
+        assert_eq!(decoded[1].as_ref().unwrap(), &Frame::data(0, b"x"));
+    }
+
+    /// Waits for the server's accept thread to pick up `count` clients.
+    fn wait_for_clients(server: &KissServer, count: usize) {
+        let start = Instant::now();
+        while server.clients.lock().unwrap().len() < count {
+            assert!(start.elapsed() < Duration::from_secs(5), "clients never connected");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn server_broadcasts_to_clients_and_drops_closed_ones() {
+        let mut server = KissServer::bind("127.0.0.1:0", 0).unwrap();
+        let client = TcpStream::connect(server.local_addr()).unwrap();
+        let closed = TcpStream::connect(server.local_addr()).unwrap();
+        wait_for_clients(&server, 2);
+
+        closed.shutdown(Shutdown::Both).unwrap();
+        drop(closed);
+
+        // Writes to a closed socket only start failing once the reset has come back.
+        let start = Instant::now();
+        while server.broadcast(b"frame") > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5), "closed client was never dropped");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut tnc = KissTnc::new(client, 0);
+        assert_eq!(tnc.receive().unwrap(), Frame::data(0, b"frame"));
+    }
+
+    #[test]
+    fn server_drops_clients_that_stop_reading() {
+        let mut server = KissServer::bind("127.0.0.1:0", 0).unwrap();
+        let _stalled = TcpStream::connect(server.local_addr()).unwrap();
+        wait_for_clients(&server, 1);
+
+        // Keep going until the socket buffers are full and a write times out.
+        let frame = vec![0x55; 1 << 20];
+        let start = Instant::now();
+        while server.broadcast(&frame) > 0 {
+            assert!(start.elapsed() < Duration::from_secs(30), "stalled client was never dropped");
+        }
+
+        let started = Instant::now();
+        server.broadcast(&frame);
+        assert!(started.elapsed() < CLIENT_WRITE_TIMEOUT);
+    }
+}
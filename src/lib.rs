@@ -0,0 +1,33 @@
+//! Drivers and flight logic for the APRS balloon tracker.
+//!
+//! The flight computer itself is `src/main.rs`, and the tools in `src/bin` for going
+//! through a flight afterwards share the same code from here.
+
+extern crate alloc;
+
+pub mod altitude;
+pub mod aprs;
+pub mod at;
+pub mod ax25;
+pub mod barometer;
+pub mod beacon;
+pub mod bmp388;
+pub mod bmx280;
+pub mod bus;
+pub mod deadline;
+pub mod dra818v;
+pub mod estimator;
+pub mod flight;
+pub mod frequency;
+pub mod kiss;
+pub mod modulator;
+pub mod neo6m;
+pub mod pi;
+pub mod predict;
+pub mod radio;
+pub mod recorder;
+pub mod replay;
+pub mod sc16is752;
+pub mod scheduler;
+pub mod signal;
+pub mod timeout;
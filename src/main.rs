@@ -1,28 +1,40 @@
 use std::{
-    fs::{self, File}, io::{self, stdout, Read, Write}, iter, process::{Command, ExitStatus}, thread, time::Duration
+    env, fs::{self, File}, io::{self, stdout, Read, Write}, iter, process::{self, Command, ExitStatus}, thread, time::{Duration, Instant}
 };
 
-use bmp388::Bmp388;
-use dra818v::Dra818V;
+use aprs::{
+    altitude,
+    ax25,
+    barometer::Barometer,
+    beacon::{Motion, SmartBeacon, KNOTS_TO_MPS},
+    bmp388::{AltimeterData, AltimeterError, Config, FifoConfig, IirFilter, OutputDataRate, Oversampling, PowerMode, TimedReading},
+    deadline::Deadline,
+    dra818v::{self, Dra818V, Group},
+    estimator::AltitudeEstimator,
+    flight::{FlightPhase, FlightTracker, TxPower},
+    frequency::{self, FrequencyPlan},
+    kiss::{self, KissServer, KissTnc},
+    modulator::Modulator,
+    neo6m::{self, Neo6M},
+    pi::{Delay, PiI2c, PiI2cError, PiOutputPin},
+    predict::{Fix, LandingPredictor, Prediction},
+    radio::{Radio, RadioConfig, RadioError},
+    recorder::{Record, Recorder, RecorderConfig},
+    replay::Replay,
+    sc16is752::{self, DataLength, GpioPin, Parity, StopLength, UartChannel, SC16IS752},
+    scheduler::{PacketKind, Scheduler, SchedulerConfig, TimeSlot},
+    signal::SignalGenerator,
+};
 use ftail::Ftail;
 use log::{error, info, warn};
-use neo6m::Neo6M;
+use nmea::Nmea;
 use num_bigint::BigUint;
-use rpi_embedded::{gpio::{Gpio, Level, OutputPin}, i2c, uart::{Parity, Uart}};
-use sc16is752::{Channel, SC16IS752};
-use signal::SignalGenerator;
+use embedded_hal::digital::OutputPin;
+use rpi_embedded::gpio::Gpio;
 use thiserror::Error;
 use ssdv::{encoder::{EncodeError, Encoder}, Quality};
 use chrono::Timelike;
 
-mod aprs;
-mod ax25;
-mod bmp388;
-mod neo6m;
-mod sc16is752;
-mod signal;
-mod dra818v;
-
 /// [Balloon SSID](http://www.aprs.org/aprs11/SSIDs.txt)
 const SSID: u8 = 11;
 /// Destination callsign
@@ -33,16 +45,41 @@ const DEST_SSID: u8 = 0;
 const SYMBOL: u8 = b'O';
 
 const SC16IS752_FREQ: u32 = 1_843_200;
-const SC16IS752_ID: u16 = 0x4D;
+const SC16IS752_ID: u8 = 0x4D;
+const GPS_BAUD_RATE: u32 = 9600;
+const TRANSCEIVER_BAUD_RATE: u32 = 9600;
+
+/// Bridge GPIO wired to the transceiver's H/L input, high for full power.
+const POWER_PIN: usize = 0;
+
+/// I2C address of the barometer, 0x77 with SDO high or 0x76 with it low.
+const BAROMETER_ADDRESS: u8 = 0x77;
+/// Oversampled pressure at 0.78 Hz, slow enough for the FIFO to hold over a minute and a half of readings.
+const BAROMETER_CONFIG: Config = Config {
+    pressure_oversampling: Oversampling::X8,
+    temperature_oversampling: Oversampling::X1,
+    iir_filter: IirFilter::Coef3,
+    output_data_rate: OutputDataRate::Hz0_78,
+    mode: PowerMode::Normal,
+};
 
 const MAX_RETRIES: usize = 20;
 
+/// Where the made up flight in test mode launches from, without `--replay`.
+const SIMULATED_LAUNCH_SITE: (f64, f64) = (33.1855, -117.3422);
+
+/// The UART bridge's channels and pins, as wired up on the Pi.
+type BridgeChannel = UartChannel<PiI2c, Delay>;
+type BridgePin = GpioPin<PiI2c, Delay>;
+type BridgeError = sc16is752::Error<PiI2cError>;
+
 const METERS_TO_FEET: f32 = 3.280839895;
 
-const GPS_LEVEL: Level = Level::Low;
-const TRANSCEIVER_LEVEL: Level = Level::High; 
+/// Lowest altitude worth taking pictures from, 20 000 ft.
+const IMAGING_ALTITUDE: f32 = 20_000.0 / METERS_TO_FEET;
 
-const FLAG_SIZE: usize = 20;
+/// Port used on the TNC when transmitting over KISS.
+const KISS_PORT: u8 = 0;
 
 fn main() -> ! {
     let mut call = File::open("/home/aprs/Documents/callsign").expect("failed to open callsign file");
@@ -55,274 +92,799 @@ fn main() -> ! {
 
     info!("Starting APRS service!");
 
-    let gpio = Gpio::new().expect("Should be able to capture GPIO");
-    let mut uart_select = gpio.get(26).expect("Should be able to capture UART select pin").into_output();
-    let mut radio_enable = gpio.get(21).expect("Should be able to capture radio enable pin").into_output();
-    
-    radio_enable.set_high();
-    uart_select.write(TRANSCEIVER_LEVEL);
+    let time_slot = match time_slot() {
+        Ok(time_slot) => time_slot,
+        Err(err) => {
+            error!("{err}");
+            process::exit(2);
+        }
+    };
 
-    let trans_uart = Uart::new(9600, Parity::None, 8, 1).unwrap();
-    thread::sleep(Duration::from_millis(5000));
-    // let _ = trans_uart.flush(rpi_embedded::uart::Queue::Both);
-    let mut transceiver = Dra818V::new(trans_uart);
-    
-    info!("initializing transciever");
-    
-    // Retry initialization of tranceiver until success
-    while let Err(err) = transceiver.init() {
-        warn!("Failed to initialize tranceiver (retrying in 1s): {err:?}");
-        thread::sleep(Duration::from_millis(1000));
-    }
+    let kiss_server = arg_value("--kiss-server");
+    let mut flight = FlightTracker::default();
 
-    info!("Initialized transceiver!");
+    // In test mode nothing needs to be attached: frames are only served over KISS,
+    // and a recorded or made up flight stands in for the GPS and barometer.
+    let (mut sensors, mut power_pin, radio_enable, mut transceiver) = match kiss_server {
+        Some(_) => {
+            let replay = match arg_value("--replay") {
+                Some(path) => Replay::open(&path).expect("Should be able to read the flight to replay"),
+                None => Replay::simulated(SIMULATED_LAUNCH_SITE.0, SIMULATED_LAUNCH_SITE.1),
+            };
 
-    radio_enable.set_low();
+            (Sensors::Replay(replay), None, None, None)
+        }
+        None => {
+            let hardware = init_hardware(flight.behavior().power);
+            let sensors = Sensors::Hardware {
+                gps: hardware.gps,
+                altimeter: hardware.altimeter,
+            };
+
+            (sensors, Some(hardware.power_pin), Some(hardware.radio_enable), Some(hardware.transceiver))
+        }
+    };
 
-    // Retry initialization of signal generator until success
-    let mut generator;
-    loop {
-        match SignalGenerator::new() {
-            Ok(gen) => {
-                generator = gen;
-                break;
+    let modulator: Box<dyn Modulator> = match (kiss_server, arg_value("--tnc")) {
+        (Some(addr), _) => {
+            let server = KissServer::bind(&addr, KISS_PORT).expect("Should be able to bind the KISS server");
+            info!("Test mode: serving frames over KISS on {}", server.local_addr());
+            Box::new(server)
+        }
+        (None, Some(target)) => {
+            // Retry connecting to the TNC until success
+            loop {
+                match connect_tnc(&target) {
+                    Ok(tnc) => break tnc,
+                    Err(err) => {
+                        warn!("Failed to connect to TNC at {target} (retrying in 1s): {err}");
+                        thread::sleep(Duration::from_millis(1000));
+                    }
+                }
             }
-            Err(err) => {
-                warn!("Failed to initialize signal generator (retrying in 1s): {err}");
-                thread::sleep(Duration::from_millis(1000));
+        }
+        (None, None) => {
+            // Retry initialization of signal generator until success
+            loop {
+                match SignalGenerator::new() {
+                    Ok(gen) => break Box::new(gen),
+                    Err(err) => {
+                        warn!("Failed to initialize signal generator (retrying in 1s): {err}");
+                        thread::sleep(Duration::from_millis(1000));
+                    }
+                }
             }
         }
-    }
-
-    // yeah i broke the altimeter so this is commented out until i fix it
+    };
 
-    // Retry initialization of altimeter until success
-    let mut altimeter;
-    loop {
-        match Bmp388::new() {
-            Ok(alt) => {
-                altimeter = alt;
-                break;
-            }
-            Err(err) => {
-                warn!("Failed to initialize altimeter (retrying in 1s): {err:?}");
-                thread::sleep(Duration::from_millis(1000));
-            }
+    // Flying without a record beats not flying.
+    let recorder = match Recorder::open(RecorderConfig::default()) {
+        Ok(recorder) => {
+            info!("Recording flight data to {}", recorder.path().display());
+            Some(recorder)
         }
-    }
+        Err(err) => {
+            error!("Failed to open the flight recorder, flying without one: {err}");
+            None
+        }
+    };
 
-    uart_select.write(GPS_LEVEL);
-    let gps_uart = Uart::new(9600, Parity::None, 8, 1).unwrap();
-    let mut gps = Neo6M::new(gps_uart);
+    let mut radio = Radio::<PiOutputPin>::new(radio_enable, modulator, RadioConfig::default());
+    radio.set_recorder(recorder.clone());
 
     let mut transmitting_image = false;
     let mut packet_num = 0;
     let mut image_packet_num = 0;
     let mut image_packet_data: Option<[u8; 256]> = None;
     let mut ssdv_iter: Box<dyn Iterator<Item = Result<[u8; 256], EncodeError>>> = Box::new(iter::empty());
+    let mut frequency_plan = FrequencyPlan::default();
+    let mut tuned_frequency = frequency::DEFAULT_FREQUENCY;
+    let mut qnh_calibrated = false;
+    let mut altitude_estimator = AltitudeEstimator::default();
+    let mut smart_beacon = SmartBeacon::default();
+    let mut predictor = LandingPredictor::default();
+    let mut prediction: Option<Prediction> = None;
+    let mut scheduler = Scheduler::new(SchedulerConfig {
+        time_slot,
+        ..SchedulerConfig::default()
+    });
+    // Newest barometer reading and the altitude estimated from it, kept while the FIFO has nothing new.
+    let mut baro: Option<(AltimeterData, f32)> = None;
     loop {
+        let slotted = scheduler.config().time_slot.is_some();
+        // Slots only line up with everyone else's once they're based on GPS time,
+        // so until then there's no point waiting for one or transmitting at all.
+        let waiting_for_sync = slotted && !scheduler.is_synced();
+
+        // Going up or down, the phase's beacon interval is the slowest we'll go and smart beaconing can only speed it up.
+        // Otherwise it's free to back off too, like drifting at float.
+        let position_interval = match flight.phase() {
+            FlightPhase::Ascent | FlightPhase::Descent => smart_beacon.interval().min(flight.behavior().beacon_interval),
+            _ => smart_beacon.interval(),
+        };
+        scheduler.set_position_interval(position_interval);
+        // Sensors are read once the slot comes around, so what goes out is fresh.
+        let slot = scheduler.next(Instant::now(), transmitting_image);
+        if !waiting_for_sync {
+            thread::sleep(slot.start.saturating_duration_since(Instant::now()));
+        }
+        // Without a window the slots only pace things out, so a slow GPS read can't cost a packet.
+        if slotted {
+            radio.set_slot_end(Deadline::at(slot.end));
+        }
+
         let mut retries = 0;
+        let mut speed = None;
+        let mut course = None;
+        let mut position = None;
+        let mut gps = None;
+
+        // After sleeping, what's waiting in the bridge FIFO is long out of date, or cut off where it overflowed.
+        if let Err(err) = sensors.flush_gps() {
+            warn!("failed to flush GPS: {err}");
+        }
 
-        if transmitting_image && image_packet_num != 4 {
-            if image_packet_num % 2 == 0 {
-                loop {
-                    match ssdv_iter.next() {
-                        Some(Ok(data)) => {
-                            info!("Successfully generated SSDV packet");
-                            image_packet_data = Some(data);
-                            break;
+        match sensors.read_gps() {
+            Ok(reading) => {
+                record(&recorder, Record::Gps {
+                    latitude: reading.latitude(),
+                    longitude: reading.longitude(),
+                    altitude: reading.altitude(),
+                    speed: reading.speed_over_ground,
+                    course: reading.true_course,
+                    satellites: reading.fix_satellites(),
+                });
+
+                speed = reading.speed_over_ground.map(|knots| knots * KNOTS_TO_MPS);
+                course = reading.true_course;
+
+                if let Some(time) = reading.fix_timestamp() {
+                    scheduler.sync(time);
+                }
+
+                if let (Some(latitude), Some(longitude)) = (reading.latitude(), reading.longitude()) {
+                    position = Some((latitude, longitude));
+                    if frequency_plan.update(latitude, longitude) {
+                        info!("Entered frequency region: {}", frequency_plan.region().map_or("default", |region| region.name));
+                    }
+                }
+
+                // Calibrate the altimeter against the first GPS altitude, normally on the launch pad.
+                if !qnh_calibrated {
+                    if let Some(gps_altitude) = reading.altitude() {
+                        match sensors.read_altimeter() {
+                            Ok(data) => {
+                                let qnh = altitude::qnh_from_altitude(data.pressure, gps_altitude);
+                                sensors.set_qnh(qnh);
+                                // The launch altitude has probably been latched already, against the wrong QNH.
+                                flight.rebase(gps_altitude - data.altitude);
+                                qnh_calibrated = true;
+                                info!("Set QNH to {:.2} hPa from GPS altitude {gps_altitude:.0} m", qnh / 100.0);
+                            }
+                            Err(err) => warn!("failed to read altimeter for QNH calibration: {err}"),
                         }
-                        Some(Err(err)) => {
-                            warn!("Failed to generate SSDV packet: {err:?}");
+                    }
+                }
+
+                gps = Some(reading);
+            }
+            Err(err) => warn!("failed to read GPS: {err}"),
+        }
+        let gps_altitude = gps.as_ref().and_then(Nmea::altitude);
+
+        match sensors.read_all_altimeter() {
+            Ok(readings) => {
+                let now = Instant::now();
+                let newest = readings.len().saturating_sub(1);
+                // Everything the FIFO collected while we were busy, so the tracker sees the whole climb.
+                for (index, reading) in readings.into_iter().enumerate() {
+                    let data = reading.data;
+                    // The GPS fix is from just now, so it only goes with the newest reading.
+                    let offset = altitude_estimator.offset();
+                    let altitude = altitude_estimator.update(data.altitude, if index == newest { gps_altitude } else { None });
+                    // Pulling the barometer towards GPS isn't the payload moving.
+                    flight.rebase(altitude_estimator.offset() - offset);
+                    baro = Some((data, altitude));
+                    record(&recorder, Record::Barometer {
+                        pressure: data.pressure,
+                        temperature: data.temperature,
+                        humidity: data.humidity,
+                        altitude,
+                    });
+
+                    let at = now.checked_sub(reading.age).unwrap_or(now);
+                    if let Some(phase) = flight.update_at(altitude, at) {
+                        info!("Entered {phase} phase at {altitude:.0} m, {:+.1} m/s", flight.vertical_rate());
+                        record(&recorder, Record::Phase(phase.to_string()));
+                        match phase {
+                            FlightPhase::Ascent => predictor.set_ground_altitude(flight.launch_altitude().unwrap_or(0.0)),
+                            FlightPhase::Descent => info!("Burst detected, peak altitude {:.0} m", flight.max_altitude().unwrap_or(altitude)),
+                            _ => {}
                         }
-                        None => {
-                            info!("Reached the end of the image");
-                            image_packet_data = None;
-                            break;
+
+                        if let Some(power_pin) = &mut power_pin {
+                            if let Err(err) = set_power(power_pin, flight.behavior().power) {
+                                warn!("failed to set transmit power: {err}");
+                            }
                         }
                     }
                 }
             }
+            Err(err) => {
+                warn!("failed to read altimeter: {err}");
+                baro = None;
+            }
+        }
+        let behavior = *flight.behavior();
+
+        if let (Some((latitude, longitude)), Some(altitude)) = (position, flight.altitude()) {
+            let fix = Fix {
+                at: Instant::now(),
+                latitude,
+                longitude,
+                altitude,
+            };
+
+            match flight.phase() {
+                FlightPhase::Ascent | FlightPhase::Float => predictor.wind.add(fix),
+                FlightPhase::Descent => {
+                    predictor.observe_descent(flight.vertical_rate(), altitude);
+                    prediction = predictor.predict(&fix);
+                    if let Some(prediction) = &prediction {
+                        info!(
+                            "Predicted landing at {:.5}, {:.5} in {:.0} min, descending at {:.1} m/s at sea level",
+                            prediction.latitude,
+                            prediction.longitude,
+                            prediction.time_to_landing.as_secs_f32() / 60.0,
+                            predictor.sea_level_rate(),
+                        );
+                    }
+                }
+                FlightPhase::Prelaunch | FlightPhase::Landed => prediction = None,
+            }
+        }
+        scheduler.set_predicting(prediction.is_some());
+
+        match frequency_plan.frequency() {
+            Some(frequency) if frequency != tuned_frequency => {
+                let result = match &mut transceiver {
+                    Some(transceiver) => retune(transceiver, &mut radio, frequency),
+                    None => Ok(()),
+                };
+
+                match result {
+                    Ok(()) => {
+                        info!("Retuned transceiver to {frequency:.3} MHz");
+                        tuned_frequency = frequency;
+                    }
+                    Err(err) => warn!("failed to retune transceiver to {frequency:.3} MHz: {err}"),
+                }
+            }
+            Some(_) => {},
+            None => {
+                info!("Not transmitting inside no-TX zone");
+                thread::sleep(Duration::from_secs(58));
+                continue;
+            }
+        }
 
-            if let Some(ref data) = image_packet_data {
+        // Even once this read has synced, the slot was picked before that.
+        if waiting_for_sync {
+            if scheduler.is_synced() {
+                info!("Time slots synced to GPS time");
+            } else {
+                thread::sleep(Duration::from_secs(1));
+            }
+            continue;
+        }
+
+        if !transmitting_image && behavior.imaging {
+            if let Some(IMAGING_ALTITUDE..) = flight.altitude() {
                 let mut image_retries = 0;
                 while image_retries < MAX_RETRIES {
-                    match transmit_image_packet(packet_num, data, image_packet_num % 2 == 0, &mut radio_enable, &callsign, &mut generator) {
-                        Ok(_) => break,
+                    match capture_image() {
+                        Ok(image) => {
+                            ssdv_iter = Box::new(Encoder::new(callsign, 1, ssdv::Quality::Q1, image));
+                            transmitting_image = true;
+                            image_packet_num = 0;
+                            break;
+                        },
                         Err(err) => {
-                            warn!("Failed to transmit image packet: {err}");
+                            warn!("failed to capture image: {err}");
                             image_retries += 1;
                         }
                     }
                 }
             }
-            
-            image_packet_num += 1;
-        } else {
-            image_packet_num = 0;
-            while retries < MAX_RETRIES {
-                match transmit_location(packet_num, &mut gps, &mut altimeter, &mut radio_enable, &callsign, &mut generator) {
-                    Ok(_) => break,
-                    Err(err) => {
-                        warn!("failed to transmit location: {err}");
-                        retries += 1;
-                        thread::sleep(Duration::from_millis(1000));
-                    },
+        }
+
+        smart_beacon.update(Motion {
+            speed,
+            course,
+            vertical_rate: flight.vertical_rate(),
+            altitude: flight.altitude(),
+        });
+        if smart_beacon.turned(Instant::now()) {
+            info!("Course changed, sending position early");
+            scheduler.expedite(PacketKind::Position);
+        }
+        let mut overran = false;
+
+        match slot.kind {
+            PacketKind::Position => match (&gps, &baro) {
+                (Some(location), Some((altimeter_data, altitude))) => {
+                    while retries < MAX_RETRIES {
+                        match transmit_location(packet_num, location, altimeter_data, *altitude, &callsign, behavior.path, &mut radio) {
+                            Ok(_) => break,
+                            Err(err @ Error::Radio(RadioError::DutyCycleExceeded { .. })) => {
+                                warn!("Skipping location packet: {err}");
+                                break;
+                            }
+                            Err(err @ Error::Radio(RadioError::SlotOverrun { .. })) => {
+                                info!("Holding location packet for the next slot: {err}");
+                                overran = true;
+                                break;
+                            }
+                            Err(err) => {
+                                warn!("failed to transmit location: {err}");
+                                retries += 1;
+                                thread::sleep(Duration::from_millis(1000));
+                            },
+                        }
+                    }
+                }
+                _ => warn!("Skipping location packet without both a GPS and a barometer reading"),
+            },
+            PacketKind::Prediction => {
+                let time = scheduler.time_of_day(Instant::now());
+                match prediction.as_ref().map(|prediction| transmit_prediction(packet_num, prediction, time, &callsign, behavior.path, &mut radio)) {
+                    Some(Err(Error::Radio(RadioError::SlotOverrun { .. }))) => overran = true,
+                    Some(Err(err)) => warn!("failed to transmit landing prediction: {err}"),
+                    _ => {}
                 }
             }
-        }
+            PacketKind::Telemetry => match &baro {
+                Some((altimeter_data, _)) => match transmit_telemetry(packet_num, altimeter_data, &flight, &callsign, behavior.path, &mut radio) {
+                    Ok(()) => {}
+                    Err(Error::Radio(RadioError::SlotOverrun { .. })) => overran = true,
+                    Err(err) => warn!("failed to transmit telemetry: {err}"),
+                },
+                None => warn!("Skipping telemetry packet without a barometer reading"),
+            },
+            PacketKind::Status => {
+                match transmit_status(packet_num, &flight, tuned_frequency, &callsign, behavior.path, &mut radio) {
+                    Ok(()) => {}
+                    Err(Error::Radio(RadioError::SlotOverrun { .. })) => overran = true,
+                    Err(err) => warn!("failed to transmit status: {err}"),
+                }
+            }
+            PacketKind::Image => {
+                // Each SSDV packet goes out in two halves.
+                if image_packet_num % 2 == 0 {
+                    loop {
+                        match ssdv_iter.next() {
+                            Some(Ok(data)) => {
+                                info!("Successfully generated SSDV packet");
+                                record(&recorder, Record::ImageChunk { data: data.to_vec() });
+                                image_packet_data = Some(data);
+                                break;
+                            }
+                            Some(Err(err)) => {
+                                warn!("Failed to generate SSDV packet: {err:?}");
+                            }
+                            None => {
+                                info!("Reached the end of the image");
+                                image_packet_data = None;
+                                transmitting_image = false;
+                                break;
+                            }
+                        }
+                    }
+                }
 
-        if !transmitting_image {
-            match gps.read().map(|reading| reading.altitude()) {
-                Ok(alt) => if let Some(20_000.0..) = alt {
+                if let Some(ref data) = image_packet_data {
                     let mut image_retries = 0;
                     while image_retries < MAX_RETRIES {
-                        match capture_image() {
-                            Ok(image) => {
-                                ssdv_iter = Box::new(Encoder::new(callsign, 1, ssdv::Quality::Q1, image));
-                                transmitting_image = true;
+                        match transmit_image_packet(packet_num, data, image_packet_num % 2 == 0, &callsign, behavior.path, &mut radio) {
+                            Ok(_) => break,
+                            // Retrying won't help until the budget recovers
+                            Err(err @ Error::Radio(RadioError::DutyCycleExceeded { .. })) => {
+                                warn!("Skipping image packet: {err}");
                                 break;
-                            },
+                            }
+                            Err(Error::Radio(RadioError::SlotOverrun { .. })) => {
+                                overran = true;
+                                break;
+                            }
                             Err(err) => {
-                                warn!("failed to capture image: {err}");
+                                warn!("Failed to transmit image packet: {err}");
                                 image_retries += 1;
                             }
                         }
                     }
                 }
-                Err(err) => warn!("failed to check altimeter for image capture: {err}"),
+
+                if !overran {
+                    image_packet_num += 1;
+                }
             }
         }
 
+        // Whatever didn't fit goes first in the next window.
+        if overran {
+            scheduler.close_window(Instant::now());
+            continue;
+        }
+
+        scheduler.sent(slot.kind, Instant::now());
+        if slot.kind == PacketKind::Position {
+            smart_beacon.beaconed(Instant::now());
+        }
         packet_num += 1;
-        thread::sleep(Duration::from_secs(58));
     }
 }
 
-fn transmit_location(packet_num: usize, gps: &mut Neo6M, altimeter: &mut Bmp388, radio_enable: &mut OutputPin, callsign: &[u8; 6], generator: &mut SignalGenerator) -> Result<(), Error> {
-    let location = gps.read()?;
-    let altimeter_data = altimeter.read().map_err(|err| Error::Altimeter(err))?;
+/// Everything attached to the Pi.
+struct Hardware {
+    gps: Neo6M<BridgeChannel, Delay>,
+    altimeter: Barometer<PiI2c, Delay>,
+    power_pin: BridgePin,
+    radio_enable: PiOutputPin,
+    transceiver: Dra818V<BridgeChannel, Delay>,
+}
+
+/// Brings up the UART bridge, transceiver and barometer, retrying each until it works.
+fn init_hardware(power: TxPower) -> Hardware {
+    let gpio = Gpio::new().expect("Should be able to capture GPIO");
+
+    // The GPS is on channel A of the UART bridge and the transceiver on channel B.
+    let bridge = loop {
+        match SC16IS752::begin(SC16IS752_ID, GPS_BAUD_RATE, TRANSCEIVER_BAUD_RATE, SC16IS752_FREQ, DataLength::D8, Parity::None, StopLength::One) {
+            Ok(bridge) => break bridge,
+            Err(err) => {
+                warn!("Failed to initialize UART bridge (retrying in 1s): {err}");
+                thread::sleep(Duration::from_millis(1000));
+            }
+        }
+    };
+    let bridge = bridge.split();
+
+    let mut power_pin = bridge.pins.into_iter().nth(POWER_PIN).expect("Bridge should have the power level pin");
+    if let Err(err) = set_power(&mut power_pin, power) {
+        warn!("failed to set transmit power: {err}");
+    }
+
+    let mut radio_enable = gpio.get(21).expect("Should be able to capture radio enable pin").into_output();
 
+    radio_enable.set_high();
+
+    thread::sleep(Duration::from_millis(5000));
+    let mut transceiver = Dra818V::with_port(bridge.b, Delay);
+
+    info!("initializing transciever");
+
+    // Retry initialization of tranceiver until success
+    while let Err(err) = transceiver.init(frequency::DEFAULT_FREQUENCY) {
+        warn!("Failed to initialize tranceiver (retrying in 1s): {err}");
+        thread::sleep(Duration::from_millis(1000));
+    }
+
+    info!("Initialized transceiver!");
+
+    radio_enable.set_low();
+
+    // Retry initialization of altimeter until success
+    let altimeter = loop {
+        match Barometer::new(BAROMETER_ADDRESS) {
+            Ok(mut alt) => {
+                info!("Found {:?} barometer", alt.chip());
+                match alt.enable_fifo(BAROMETER_CONFIG, &FifoConfig::default()) {
+                    Ok(true) => info!("Buffering barometer readings in its FIFO"),
+                    Ok(false) => {}
+                    Err(err) => warn!("failed to set up the barometer FIFO, reading it directly instead: {err}"),
+                }
+                break alt;
+            }
+            Err(err) => {
+                warn!("Failed to initialize altimeter (retrying in 1s): {err}");
+                thread::sleep(Duration::from_millis(1000));
+            }
+        }
+    };
+
+    let mut gps = Neo6M::with_port(bridge.a, Delay);
+    gps.set_baud_rate(GPS_BAUD_RATE);
+
+    Hardware {
+        gps,
+        altimeter,
+        power_pin,
+        radio_enable: PiOutputPin::new(radio_enable),
+        transceiver,
+    }
+}
+
+/// Where positions and pressures come from.
+enum Sensors {
+    Hardware {
+        gps: Neo6M<BridgeChannel, Delay>,
+        altimeter: Barometer<PiI2c, Delay>,
+    },
+    /// A flight played back in test mode.
+    Replay(Replay),
+}
+
+impl Sensors {
+    /// Drops whatever the GPS sent while nobody was reading it.
+    fn flush_gps(&mut self) -> Result<(), Error> {
+        match self {
+            Sensors::Hardware { gps, .. } => Ok(gps.flush()?),
+            Sensors::Replay(_) => Ok(()),
+        }
+    }
+
+    fn read_gps(&mut self) -> Result<Nmea, Error> {
+        match self {
+            Sensors::Hardware { gps, .. } => Ok(gps.read()?),
+            Sensors::Replay(replay) => replay.gps().ok_or(Error::GpsData),
+        }
+    }
+
+    fn read_altimeter(&mut self) -> Result<AltimeterData, Error> {
+        match self {
+            Sensors::Hardware { altimeter, .. } => Ok(altimeter.read()?),
+            Sensors::Replay(replay) => replay.read().ok_or(Error::Altimeter(AltimeterError::NotReady)),
+        }
+    }
+
+    /// Every barometer reading since the last call, oldest first.
+    fn read_all_altimeter(&mut self) -> Result<Vec<TimedReading>, Error> {
+        match self {
+            Sensors::Hardware { altimeter, .. } => Ok(altimeter.read_all()?.into_iter().collect()),
+            Sensors::Replay(replay) => Ok(replay.read_all()),
+        }
+    }
+
+    fn set_qnh(&mut self, qnh: f32) {
+        match self {
+            Sensors::Hardware { altimeter, .. } => altimeter.set_qnh(qnh),
+            Sensors::Replay(replay) => replay.set_qnh(qnh),
+        }
+    }
+}
+
+/// Sends a position report, `altitude` being the estimate from `altimeter_data`.
+fn transmit_location(packet_num: usize, location: &Nmea, altimeter_data: &AltimeterData, altitude: f32, callsign: &[u8; 6], path: &[&str], radio: &mut Radio<PiOutputPin>) -> Result<(), Error> {
     let (longitude, latitude, time) = match (location.longitude(), location.latitude(), location.fix_timestamp()) {
         (Some(long), Some(lat), Some(time)) => (long, lat, time),
         _ => return Err(Error::GpsData),
     };
 
     let mut data = Vec::new();
-    write_header(&mut data, callsign, packet_num);
+    write_header(&mut data, callsign, path, packet_num);
     data.push(b'/');
     data.extend(format!("{:02}{:02}{:02}h", time.hour(), time.minute(), time.second()).bytes());
 
-    let (lat_deg, lat_min, lat_sign) = if latitude < 0.0 {
-        let deg = latitude.abs().floor();
-        (deg as usize, (latitude.abs() - deg) * 60.0, 'S')
-    } else {
-        let deg = latitude.floor();
-        (deg as usize, (latitude - deg) * 60.0, 'N')
-    };
-
-    let (long_deg, long_min, long_sign) = if longitude < 0.0 {
-        let deg = longitude.abs().floor();
-        (deg as usize, (longitude.abs() - deg) * 60.0, 'W')
-    } else {
-        let deg = longitude.floor();
-        (deg as usize, (longitude - deg) * 60.0, 'E')
-    };
-
-    data.extend(format!("{:0>2}{:0>5.2}{}/{:0>3}{:0>2.2}{}", lat_deg, lat_min, lat_sign, long_deg, long_min, long_sign).bytes());
-
     // Balloon symbol code
-    data.push(b'O');
+    data.extend(format_position(latitude, longitude, b'/', b'O').bytes());
     data.push(b' ');
 
     if let (Some(speed), Some(course)) = (location.speed_over_ground, location.true_course) {
         data.extend(format!("{:0>3}/{:0>3}", course.ceil() as isize, speed.ceil() as isize).bytes());
     }
 
-    data.extend(format!("/A={:0>6}", (altimeter_data.altitude * METERS_TO_FEET).round().min(999999.0) as usize).bytes());
+    data.extend(format!("/A={:0>6}", (altitude * METERS_TO_FEET).round().min(999999.0) as usize).bytes());
     data.extend(format!("/Pa={:0>6}", altimeter_data.pressure.round() as usize).bytes());
     data.extend(format!("/Ti={:.2}", altimeter_data.temperature).bytes());
+    if let Some(humidity) = altimeter_data.humidity {
+        data.extend(format!("/Hu={:.0}", humidity).bytes());
+    }
     
-    let mut crc: u16 = 0xffff;
+    info!("Sending APRS location packet: \"{}\"", String::from_utf8_lossy(&data));
+    // Only kept for debugging, so it's not worth missing a beacon over.
+    if let Err(err) = fs::write("/home/aprs/Documents/packet.bin", [&data[..], &ax25::fcs(&data)].concat()) {
+        warn!("failed to save location packet: {err}");
+    }
 
-    for bit in data.iter().skip(FLAG_SIZE).map(|byte| (0..8).map(move |i| (byte >> i) & 0x01)).flatten() {
-        crc ^= bit as u16;
+    radio.transmit(&data)?;
 
-        if crc & 0x01 > 0 {
-            crc = (crc >> 1) ^ 0x8408;
-        } else {
-            crc >>= 1;
-        }
+    Ok(())
+}
+
+fn transmit_image_packet(packet_num: usize, packet_data: &[u8], second: bool, callsign: &[u8; 6], path: &[&str], radio: &mut Radio<PiOutputPin>) -> Result<(), Error> {
+    let mut data = Vec::new();
+
+    write_header(&mut data, callsign, path, packet_num);
+
+    data.extend_from_slice(b"{{I");
+
+    data.extend_from_slice(&packet_data[0..ssdv::encoder::HEADER_SIZE]);
+    if second {
+        // yes this is bs shush
+        data.append(&mut b91_encode(&packet_data[ssdv::encoder::HEADER_SIZE+ssdv::encoder::PAYLOAD_SIZE/2..packet_data.len()-ssdv::encoder::CRC_SIZE]));
+    } else {
+        data.append(&mut b91_encode(&packet_data[ssdv::encoder::HEADER_SIZE.. ssdv::encoder::HEADER_SIZE+ssdv::encoder::PAYLOAD_SIZE/2]));
     }
 
-    data.push((crc ^ 0xFF) as u8);
-    data.push(((crc >> 8) ^ 0xFF) as u8);
+    radio.transmit(&data)?;
+    
+    Ok(())
+}
 
-    data.extend_from_slice(&[0x7e; FLAG_SIZE]);
+/// Sends the predicted landing site as an APRS object named after our callsign, e.g. `N0CALL-LP`.
+fn transmit_prediction(packet_num: usize, prediction: &Prediction, time: Option<Duration>, callsign: &[u8; 6], path: &[&str], radio: &mut Radio<PiOutputPin>) -> Result<(), Error> {
+    let mut data = Vec::new();
+    write_header(&mut data, callsign, path, packet_num);
 
-    info!("Sending APRS location packet: \"{}\"", String::from_utf8_lossy(&data));
-    fs::write("/home/aprs/Documents/packet.bin", &data[FLAG_SIZE..data.len()-FLAG_SIZE]).unwrap();
+    let name = format!("{}-LP", String::from_utf8_lossy(callsign).trim());
+    data.extend(format!(";{name:<9.9}*").bytes());
 
-    radio_enable.set_high();
-    thread::sleep(Duration::from_millis(1000));
+    let seconds = time.unwrap_or_default().as_secs();
+    data.extend(format!("{:02}{:02}{:02}h", seconds / 3600 % 24, seconds / 60 % 60, seconds % 60).bytes());
+    data.extend(format_position(prediction.latitude, prediction.longitude, b'/', b'/').bytes());
+    data.extend(format!("Predicted landing in {:.0} min", prediction.time_to_landing.as_secs_f32() / 60.0).bytes());
 
-    generator.write(&data).map_err(|err| Error::Generator(err))?;
+    info!("Sending APRS landing prediction: \"{}\"", String::from_utf8_lossy(&data));
+    radio.transmit(&data)?;
 
-    thread::sleep(Duration::from_millis(1000));
-    radio_enable.set_low();
+    Ok(())
+}
+
+/// Sends sensor readings as an APRS telemetry report: pressure in hPa, temperature,
+/// humidity, vertical rate and seconds of airtime used in the duty-cycle window.
+fn transmit_telemetry(packet_num: usize, altimeter_data: &AltimeterData, flight: &FlightTracker, callsign: &[u8; 6], path: &[&str], radio: &mut Radio<PiOutputPin>) -> Result<(), Error> {
+    let mut data = Vec::new();
+    write_header(&mut data, callsign, path, packet_num);
+    data.extend(format!(
+        "T#{:03},{:.1},{:.1},{:.0},{:.1},{},00000000",
+        packet_num % 1000,
+        altimeter_data.pressure / 100.0,
+        altimeter_data.temperature,
+        altimeter_data.humidity.unwrap_or(0.0),
+        flight.vertical_rate(),
+        radio.used_airtime().as_secs(),
+    ).bytes());
+
+    info!("Sending APRS telemetry packet: \"{}\"", String::from_utf8_lossy(&data));
+    radio.transmit(&data)?;
 
     Ok(())
 }
 
-fn transmit_image_packet(packet_num: usize, packet_data: &[u8], second: bool, radio_enable: &mut OutputPin, callsign: &[u8; 6], generator: &mut SignalGenerator) -> Result<(), Error> {
+fn transmit_status(packet_num: usize, flight: &FlightTracker, frequency: f32, callsign: &[u8; 6], path: &[&str], radio: &mut Radio<PiOutputPin>) -> Result<(), Error> {
     let mut data = Vec::new();
+    write_header(&mut data, callsign, path, packet_num);
+    data.push(b'>');
+    data.extend(format!("{} phase", flight.phase()).bytes());
+    if let Some(max_altitude) = flight.max_altitude() {
+        data.extend(format!(", max {:.0} ft", max_altitude * METERS_TO_FEET).bytes());
+    }
+    data.extend(format!(", {frequency:.3} MHz, {} frames sent", radio.stats().transmissions).bytes());
 
-    write_header(&mut data, callsign, packet_num);
+    info!("Sending APRS status packet: \"{}\"", String::from_utf8_lossy(&data));
+    radio.transmit(&data)?;
 
-    data.extend_from_slice(b"{{I");
+    Ok(())
+}
 
-    data.extend_from_slice(&packet_data[0..ssdv::encoder::HEADER_SIZE]);
-    if second {
-        // yes this is bs shush
-        data.append(&mut b91_encode(&packet_data[ssdv::encoder::HEADER_SIZE+ssdv::encoder::PAYLOAD_SIZE/2..packet_data.len()-ssdv::encoder::CRC_SIZE]));
+/// Wakes the transceiver up and changes its frequency.
+fn retune(transceiver: &mut Dra818V<BridgeChannel, Delay>, radio: &mut Radio<PiOutputPin>, frequency: f32) -> Result<(), Error> {
+    let result = radio.keyed(Duration::from_millis(500), || transceiver.set_group(&Group::simplex(frequency)))?;
+
+    Ok(result?)
+}
+
+/// Formats a position as `DDMM.mmN/DDDMM.mmW` with the symbol table and code in between and after.
+fn format_position(latitude: f64, longitude: f64, table: u8, symbol: u8) -> String {
+    let (lat_deg, lat_min, lat_sign) = if latitude < 0.0 {
+        let deg = latitude.abs().floor();
+        (deg as usize, (latitude.abs() - deg) * 60.0, 'S')
     } else {
-        data.append(&mut b91_encode(&packet_data[ssdv::encoder::HEADER_SIZE.. ssdv::encoder::HEADER_SIZE+ssdv::encoder::PAYLOAD_SIZE/2]));
+        let deg = latitude.floor();
+        (deg as usize, (latitude - deg) * 60.0, 'N')
+    };
+
+    let (long_deg, long_min, long_sign) = if longitude < 0.0 {
+        let deg = longitude.abs().floor();
+        (deg as usize, (longitude.abs() - deg) * 60.0, 'W')
+    } else {
+        let deg = longitude.floor();
+        (deg as usize, (longitude - deg) * 60.0, 'E')
+    };
+
+    format!("{:0>2}{:0>5.2}{}{}{:0>3}{:0>5.2}{}{}", lat_deg, lat_min, lat_sign, table as char, long_deg, long_min, long_sign, symbol as char)
+}
+
+/// Adds to the flight record, if there is one. A failed write isn't worth stopping for.
+fn record(recorder: &Option<Recorder>, record: Record) {
+    if let Some(recorder) = recorder {
+        if let Err(err) = recorder.record(record) {
+            warn!("failed to write flight record: {err}");
+        }
     }
+}
 
-    let mut crc: u16 = 0xffff;
+/// Switches the transceiver between its low and high power levels.
+fn set_power(power_pin: &mut BridgePin, power: TxPower) -> Result<(), BridgeError> {
+    power_pin.set_output()?;
 
-    for bit in data.iter().skip(FLAG_SIZE).map(|byte| (0..8).map(move |i| (byte >> i) & 0x01)).flatten() {
-        crc ^= bit as u16;
+    match power {
+        TxPower::Low => power_pin.set_low(),
+        TxPower::High => power_pin.set_high(),
+    }
+}
 
-        if crc & 0x01 > 0 {
-            crc = (crc >> 1) ^ 0x8408;
-        } else {
-            crc >>= 1;
+/// Returns the value following `name` on the command line, if any.
+///
+/// * `--tnc <device or address>` transmits through a KISS TNC instead of the signal generator.
+/// * `--kiss-server <address>` runs in test mode without any hardware, serving frames to KISS clients instead of transmitting them.
+/// * `--replay <flight file>` plays back a flight recorder file in test mode, instead of a made up flight.
+/// * `--slot <seconds>` only transmits in a window starting that many seconds past each minute of GPS time.
+/// * `--slot-length <seconds>` sets the length of that window, 10 s by default.
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
         }
     }
 
-    data.push((crc ^ 0xFF) as u8);
-    data.push(((crc >> 8) ^ 0xFF) as u8);
+    None
+}
 
-    data.extend_from_slice(&[0x7e; FLAG_SIZE]);
+/// The transmit window set with `--slot`, if any.
+fn time_slot() -> Result<Option<TimeSlot>, UsageError> {
+    let seconds = |flag: &'static str, value: String| value.parse::<u64>().map_err(|_| UsageError::NotSeconds { flag, value });
 
-    radio_enable.set_high();
-    thread::sleep(Duration::from_millis(1000));
+    let Some(offset) = arg_value("--slot").map(|offset| seconds("--slot", offset)).transpose()? else {
+        return Ok(None);
+    };
+    let length = arg_value("--slot-length").map(|length| seconds("--slot-length", length)).transpose()?.unwrap_or(10);
+
+    let time_slot = TimeSlot::new(Duration::from_secs(offset), Duration::from_secs(length));
+    if !time_slot.is_valid() {
+        return Err(UsageError::SlotOutsideMinute { offset, length });
+    }
 
-    generator.write(&data).map_err(|err| Error::Generator(err))?;
+    info!("Transmitting {offset}-{}s past each minute", offset + length);
 
-    thread::sleep(Duration::from_millis(1000));
-    radio_enable.set_low();
-    
-    Ok(())
+    Ok(Some(time_slot))
 }
 
-fn write_header(buf: &mut Vec<u8>, callsign: &[u8; 6], packet_num: usize) {
-    buf.extend_from_slice(&[0x7e; FLAG_SIZE]);
+/// Connects to a KISS TNC, either a serial device (`/dev/ttyUSB0`)
+/// or a TCP address (`127.0.0.1:8001`).
+fn connect_tnc(target: &str) -> io::Result<Box<dyn Modulator>> {
+    let tnc: Box<dyn Modulator> = if target.starts_with('/') {
+        let mut tnc = KissTnc::open(target, KISS_PORT)?;
+        tnc.configure(&kiss::Parameters::default())?;
+        Box::new(tnc)
+    } else {
+        let mut tnc = KissTnc::connect(target, KISS_PORT)?;
+        tnc.configure(&kiss::Parameters::default())?;
+        Box::new(tnc)
+    };
+
+    info!("Connected to KISS TNC at {target}");
+
+    Ok(tnc)
+}
+
+fn write_header(buf: &mut Vec<u8>, callsign: &[u8; 6], path: &[&str], packet_num: usize) {
     buf.extend(DEST_CALLSIGN.iter().map(|byte| byte << 1));
     buf.push((DEST_SSID + b'0') << 1);
     buf.extend(callsign.iter().map(|byte| byte << 1));
-    buf.push ((SSID + b'0') << 1 | 1);
+    buf.push((SSID + b'0') << 1);
+
+    for digipeater in path {
+        let (call, ssid) = digipeater.split_once('-').unwrap_or((digipeater, "0"));
+        let ssid: u8 = ssid.parse().unwrap_or(0);
+
+        buf.extend(format!("{:<6.6}", call.to_ascii_uppercase()).bytes().map(|byte| byte << 1));
+        buf.push((ssid + b'0') << 1);
+    }
+
+    // The last address is marked by the low bit.
+    *buf.last_mut().unwrap() |= 1;
     buf.push(0x03);
     buf.push(0xf0);
 }
@@ -366,14 +928,25 @@ fn b91_encode(buf: &[u8]) -> Vec<u8> {
     return output;
 }
 
+/// Command line arguments that don't make sense.
+#[derive(Debug, Error)]
+enum UsageError {
+    #[error("{flag} should be a number of seconds, not {value:?}")]
+    NotSeconds { flag: &'static str, value: String },
+    #[error("--slot window of {length}s starting {offset}s past the minute has to fit inside the minute")]
+    SlotOutsideMinute { offset: u64, length: u64 },
+}
+
 #[derive(Debug, Error)]
 enum Error {
     #[error("Failed to read GPS data: {0}")]
-    Gps(#[from] neo6m::GpsError),
+    Gps(#[from] neo6m::GpsError<BridgeError>),
     #[error("GPS data contains no location")]
     GpsData,
     #[error("Failed to read altimeter data: {0}")]
-    Altimeter(i2c::Error),
-    #[error("Failed to transmit to signal generator: {0}")]
-    Generator(i2c::Error),
+    Altimeter(#[from] AltimeterError<PiI2cError>),
+    #[error("Transceiver error: {0}")]
+    Transceiver(#[from] dra818v::Error<BridgeError>),
+    #[error("Failed to transmit frame: {0}")]
+    Radio(#[from] RadioError),
 }
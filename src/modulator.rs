@@ -0,0 +1,47 @@
+//! Backends that turn finished AX.25 frames into something on the air.
+
+use std::{error, io};
+
+use embedded_hal::i2c::I2c;
+use thiserror::Error;
+
+use crate::signal::{self, SignalGenerator};
+
+pub trait Modulator {
+    /// Transmits a single AX.25 frame.
+    ///
+    /// `frame` holds the address, control, PID and information fields only.
+    /// Flags, FCS and any transport framing are added by the backend.
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), ModulatorError>;
+
+    /// Bytes the backend puts on the air around every frame, for estimating airtime.
+    fn framing_overhead(&self) -> usize;
+
+    /// Whether the backend keys its own transmitter, like a TNC does.
+    /// Frames are only queued up then, so the airtime has to be estimated rather than timed.
+    fn keys_transmitter(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ModulatorError {
+    #[error("failed to write to the signal generator: {0}")]
+    Generator(#[source] Box<dyn error::Error + Send + Sync>),
+    #[error("failed to write to the TNC: {0}")]
+    Tnc(#[from] io::Error),
+}
+
+impl<I2C> Modulator for SignalGenerator<I2C>
+where
+    I2C: I2c,
+    I2C::Error: error::Error + Send + Sync + 'static,
+{
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), ModulatorError> {
+        self.send_frame(frame).map_err(|err| ModulatorError::Generator(Box::new(err)))
+    }
+
+    fn framing_overhead(&self) -> usize {
+        signal::FRAMING_OVERHEAD
+    }
+}
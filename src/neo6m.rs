@@ -1,56 +1,264 @@
+use core::{fmt::Write as _, time::Duration};
+
+use embedded_hal::delay::DelayNs;
+use embedded_io::{Read, ReadReady};
+use heapless::{String, Vec};
 use nmea::{Nmea, SentenceType};
-use rpi_embedded::uart::{Queue, Uart};
 use thiserror::Error;
 
-use crate::sc16is752::{Channel, SC16IS752};
+use crate::timeout::{self, CancelToken, Interrupted, Timeout};
+
+/// Longest line kept before it is thrown away, NMEA sentences are at most 82 bytes.
+const MAX_LINE_LENGTH: usize = 128;
+
+/// The module sends a burst of sentences every second, so this is several missed fixes.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
 
-pub struct Neo6M {
-    uart: Uart,
+/// The module's factory setting.
+const DEFAULT_BAUD_RATE: u32 = 9600;
+
+/// Most that's thrown away by a flush, more than the port could have buffered.
+const MAX_DISCARD: usize = 4096;
+
+/// A single NMEA sentence, without the line ending.
+pub type Sentence = String<MAX_LINE_LENGTH>;
+
+pub struct Neo6M<S, D> {
+    serial: S,
+    delay: D,
+    timeout: Duration,
+    /// For charging the time data takes to arrive to the timeout.
+    baud_rate: u32,
+    cancel: Option<CancelToken>,
+    /// Bytes received after the last complete line.
+    pending: Vec<u8, MAX_LINE_LENGTH>,
+    /// Everything parsed so far. Each sentence only carries part of a fix,
+    /// position and altitude come from GGA while speed and course come from RMC.
+    nmea: Nmea,
 }
 
-impl Neo6M {
-    pub fn new(uart: Uart) -> Self {
-        return Self { uart };
+impl<S: Read + ReadReady, D: DelayNs> Neo6M<S, D> {
+    pub fn with_port(serial: S, delay: D) -> Self {
+        return Self {
+            serial,
+            delay,
+            timeout: DEFAULT_TIMEOUT,
+            baud_rate: DEFAULT_BAUD_RATE,
+            cancel: None,
+            pending: Vec::new(),
+            nmea: Nmea::create_for_navigation(&[SentenceType::RMC, SentenceType::GGA]).expect("Should have sentences to navigate with"),
+        };
+    }
+
+    /// Sets how long [`Neo6M::read`] waits for a sentence before giving up.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the baud rate the module has been set up for, if it's not the default of 9600.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) {
+        self.baud_rate = baud_rate;
+    }
+
+    /// Lets `cancel` stop a read that is waiting on the module.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    pub fn is_available(&mut self) -> Result<bool, GpsError<S::Error>> {
+        Ok(!self.pending.is_empty() || self.serial.read_ready().map_err(GpsError::Uart)?)
     }
 
-    pub fn is_available(&self) -> Result<usize, GpsError> {
-        Ok(self.uart.input_len()?)
+    pub fn read(&mut self) -> Result<Nmea, GpsError<S::Error>> {
+        self.read_with_timeout(self.timeout)
     }
 
-    pub fn read(&mut self) -> Result<Nmea, GpsError> {
-        let mut sentence = String::new();
+    /// Reads sentences until every one making up a fix has arrived for the same time,
+    /// failing with [`GpsError::NoFix`] or [`GpsError::TimedOut`] if that doesn't happen within `timeout`.
+    ///
+    /// A sentence cut off by the timeout isn't lost, the next read picks it up.
+    pub fn read_with_timeout(&mut self, timeout: Duration) -> Result<Nmea, GpsError<S::Error>> {
+        let mut timeout = Timeout::after(timeout);
+        let mut heard = false;
+
         loop {
-            sentence = self.uart.read_line()?;
-            if sentence.starts_with('$') {
-                break;
+            let line = match self.read_line(&mut timeout) {
+                Ok(line) => line,
+                Err(GpsError::TimedOut) if heard => return Err(GpsError::NoFix),
+                Err(err) => return Err(err),
+            };
+            if !line.starts_with('$') {
+                continue;
+            }
+
+            // A garbled sentence is only a gap in this fix, the next one will do.
+            match self.nmea.parse_for_fix(&line) {
+                Ok(fix) if fix.is_valid() => return Ok(self.nmea.clone()),
+                Ok(_) => heard = true,
+                Err(_) => {}
             }
         }
+    }
 
-        let mut nmea = Nmea::create_for_navigation(&[SentenceType::TXT, SentenceType::GGA])?;
-        nmea.parse(&sentence)?;
+    /// Waits for a whole line to be received, returning it without the line ending.
+    fn read_line(&mut self, timeout: &mut Timeout) -> Result<Sentence, GpsError<S::Error>> {
+        let mut buf = [0; 64];
+
+        loop {
+            if let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+                // Sentences are plain ASCII, anything else is line noise.
+                let line = self.pending[..end]
+                    .trim_ascii_end()
+                    .iter()
+                    .map(|&byte| if byte.is_ascii() { byte as char } else { '?' })
+                    .collect();
+                self.pending.rotate_left(end + 1);
+                self.pending.truncate(self.pending.len() - end - 1);
+
+                return Ok(line);
+            }
+
+            // Lost the start of a sentence somewhere, wait for the next one.
+            if self.pending.is_full() {
+                self.pending.clear();
+            }
+
+            // Checked on every pass, a port that never stops sending junk has to run out too.
+            timeout::check(timeout, self.cancel.as_ref())?;
+
+            // Only read once something has arrived, so the port itself never blocks.
+            if !self.serial.read_ready().map_err(GpsError::Uart)? {
+                timeout.wait(&mut self.delay, POLL_INTERVAL);
+                continue;
+            }
+
+            let space = (self.pending.capacity() - self.pending.len()).min(buf.len());
+            let len = self.serial.read(&mut buf[..space]).map_err(GpsError::Uart)?;
+            if len == 0 {
+                return Err(GpsError::DataUnavailable);
+            }
+            timeout.charge(timeout::transfer_time(len, self.baud_rate));
 
-        return Ok(nmea);
+            // Can't fail, there was room for all of it.
+            let _ = self.pending.extend_from_slice(&buf[..len]);
+        }
     }
 
-    pub fn flush(&mut self) -> Result<(), GpsError> {
-        self.uart.flush(Queue::Input)?;
+    /// Drops everything received so far, so the next read gets a fresh sentence.
+    pub fn flush(&mut self) -> Result<(), GpsError<S::Error>> {
+        let mut buf = [0; 64];
+
+        self.pending.clear();
+        // Only what's already there, a port that never stops sending would keep this going forever.
+        let mut discarded = 0;
+        while discarded < MAX_DISCARD && self.serial.read_ready().map_err(GpsError::Uart)? {
+            discarded += self.serial.read(&mut buf).map_err(GpsError::Uart)?;
+        }
 
         Ok(())
     }
 }
 
 #[derive(Debug, Error)]
-pub enum GpsError {
+pub enum GpsError<E> {
     #[error("failed to recieve data from the serial bus: {0}")]
-    Uart(#[from] rpi_embedded::uart::Error),
+    Uart(E),
     #[error("no data available from the serial bus")]
     DataUnavailable,
+    #[error("timed out waiting for a sentence")]
+    TimedOut,
+    #[error("no fix yet")]
+    NoFix,
+    #[error("cancelled while waiting for a sentence")]
+    Cancelled,
     #[error("failed to parse NMEA sentence: {0}")]
-    Nmea(String),
+    Nmea(String<64>),
+}
+
+impl<E> From<Interrupted> for GpsError<E> {
+    fn from(value: Interrupted) -> Self {
+        match value {
+            Interrupted::TimedOut => GpsError::TimedOut,
+            Interrupted::Cancelled => GpsError::Cancelled,
+        }
+    }
 }
 
-impl<'a> From<nmea::Error<'a>> for GpsError {
+impl<'a, E> From<nmea::Error<'a>> for GpsError<E> {
     fn from(value: nmea::Error<'a>) -> Self {
-        GpsError::Nmea(value.to_string())
+        // The message is only for the logs, so a long one can be cut short.
+        let mut message = String::new();
+        let _ = write!(message, "{value}");
+
+        GpsError::Nmea(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{at::mock::MockPort, bus::mock::MockDelay};
+
+    fn gps(port: MockPort) -> Neo6M<MockPort, MockDelay> {
+        let mut gps = Neo6M::with_port(port, MockDelay::default());
+        gps.set_timeout(Duration::from_millis(500));
+        gps
+    }
+
+    #[test]
+    fn times_out_without_any_data() {
+        let mut gps = gps(MockPort::new());
+
+        assert!(matches!(gps.read(), Err(GpsError::TimedOut)));
+        assert_eq!(gps.delay.waited_ns, 500_000_000);
+    }
+
+    #[test]
+    fn times_out_on_endless_junk_without_a_line_ending() {
+        let mut port = MockPort::new();
+        port.repeat(b"\xff\x00noise");
+        let mut gps = gps(port);
+
+        assert!(matches!(gps.read(), Err(GpsError::TimedOut)));
+        // It was all arriving, so none of it was spent waiting.
+        assert_eq!(gps.delay.waited_ns, 0);
+
+        // Flushing doesn't go on forever either.
+        gps.flush().unwrap();
+    }
+
+    #[test]
+    fn times_out_on_endless_lines_that_arent_sentences() {
+        let mut port = MockPort::new();
+        port.repeat(b"noise\r\n");
+        let mut gps = gps(port);
+
+        assert!(matches!(gps.read(), Err(GpsError::TimedOut)));
+    }
+
+    #[test]
+    fn keeps_a_sentence_cut_off_by_the_timeout() {
+        let mut port = MockPort::new();
+        port.receive(b"$GPTXT,01,01,02,ANTSTATUS=OK*3B");
+        let mut gps = gps(port);
+
+        assert!(matches!(gps.read(), Err(GpsError::TimedOut)));
+        assert_eq!(gps.pending, b"$GPTXT,01,01,02,ANTSTATUS=OK*3B".as_slice());
+
+        gps.flush().unwrap();
+        assert!(gps.pending.is_empty());
+    }
+
+    #[test]
+    fn cancelling_stops_the_read() {
+        let cancel = CancelToken::new();
+        let mut gps = gps(MockPort::new());
+        gps.set_cancel_token(Some(cancel.clone()));
+
+        cancel.cancel();
+        assert!(matches!(gps.read(), Err(GpsError::Cancelled)));
+        assert_eq!(gps.delay.waited_ns, 0);
     }
 }
@@ -0,0 +1,297 @@
+//! Adapters putting the Pi's peripherals behind the embedded-hal and embedded-io traits.
+//!
+//! The drivers only know about the traits, so the same code can run on another flight computer
+//! by swapping these out for that board's HAL. The constructors for the drivers as they're
+//! wired up on the Pi live here too.
+
+use std::{convert::Infallible, io, thread, time::Duration};
+
+use embedded_hal::{
+    delay::DelayNs,
+    digital,
+    i2c::{self as hal, ErrorKind, I2c, NoAcknowledgeSource, Operation},
+};
+use rpi_embedded::{
+    gpio::OutputPin,
+    i2c,
+    uart::{self, Uart},
+};
+use thiserror::Error;
+
+use crate::{
+    barometer::Barometer,
+    bmp388::{self, AltimeterError, Bmp388, Config},
+    bmx280::Bmx280,
+    deadline::Deadline,
+    dra818v::Dra818V,
+    neo6m::Neo6M,
+    sc16is752::{self, DataLength, Parity, StopLength, SC16IS752},
+    signal::SignalGenerator,
+};
+
+/// Time to wait between polls of the UART while no data is available.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The Pi's I2C bus.
+pub struct PiI2c {
+    i2c: i2c::I2c,
+    /// Address the kernel is currently talking to.
+    address: Option<u8>,
+}
+
+impl PiI2c {
+    /// Opens bus 1, the one broken out on the header.
+    pub fn new() -> Result<Self, PiI2cError> {
+        Self::with_bus(1)
+    }
+
+    pub fn with_bus(bus: u8) -> Result<Self, PiI2cError> {
+        Ok(Self {
+            i2c: i2c::I2c::with_bus(bus)?,
+            address: None,
+        })
+    }
+
+    fn select(&mut self, address: u8) -> Result<(), PiI2cError> {
+        if self.address != Some(address) {
+            self.i2c.set_slave_address(address as u16)?;
+            self.address = Some(address);
+        }
+
+        Ok(())
+    }
+}
+
+impl hal::ErrorType for PiI2c {
+    type Error = PiI2cError;
+}
+
+impl I2c for PiI2c {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        self.select(address)?;
+
+        let mut operations = operations.iter_mut().peekable();
+        while let Some(operation) = operations.next() {
+            match operation {
+                // A write followed by a read has to be done with a repeated start,
+                // otherwise the register pointer can be lost in between.
+                Operation::Write(write) if matches!(operations.peek(), Some(Operation::Read(_))) => {
+                    let Some(Operation::Read(read)) = operations.next() else {
+                        unreachable!()
+                    };
+                    self.i2c.write_read(write, read)?;
+                }
+                Operation::Write(write) => {
+                    self.i2c.write(write)?;
+                }
+                Operation::Read(read) => {
+                    self.i2c.read(read)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct PiI2cError(#[from] i2c::Error);
+
+impl hal::Error for PiI2cError {
+    fn kind(&self) -> ErrorKind {
+        match &self.0 {
+            // ENXIO and EREMOTEIO are what the kernel returns for a missing ACK.
+            i2c::Error::Io(err) if matches!(err.raw_os_error(), Some(6) | Some(121)) => {
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown)
+            }
+            i2c::Error::Io(err) if err.kind() == io::ErrorKind::TimedOut => ErrorKind::Bus,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// One of the Pi's UARTs.
+///
+/// Reads only ever ask the kernel for bytes that have already arrived,
+/// so this works whatever read mode the port is in.
+pub struct PiUart {
+    uart: Uart,
+    read_timeout: Option<Duration>,
+}
+
+impl PiUart {
+    pub fn new(uart: Uart) -> Self {
+        Self {
+            uart,
+            read_timeout: None,
+        }
+    }
+
+    /// Sets how long a read waits for the first byte, by default it waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn into_inner(self) -> Uart {
+        self.uart
+    }
+}
+
+impl embedded_io::ErrorType for PiUart {
+    type Error = PiUartError;
+}
+
+impl embedded_io::Read for PiUart {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = self.read_timeout.map_or(Deadline::NEVER, Deadline::after);
+
+        loop {
+            let available = self.uart.input_len()?;
+            if available > 0 {
+                let len = available.min(buf.len());
+                return Ok(self.uart.read(&mut buf[..len])?);
+            }
+
+            if deadline.has_passed() {
+                return Err(uart::Error::Io(io::ErrorKind::TimedOut.into()).into());
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl embedded_io::ReadReady for PiUart {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.uart.input_len()? > 0)
+    }
+}
+
+impl embedded_io::Write for PiUart {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(self.uart.write_bytes(buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(self.uart.drain()?)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct PiUartError(#[from] uart::Error);
+
+impl embedded_io::Error for PiUartError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match &self.0 {
+            uart::Error::Io(err) => err.kind().into(),
+            _ => embedded_io::ErrorKind::Other,
+        }
+    }
+}
+
+/// A GPIO output on the Pi's header.
+pub struct PiOutputPin {
+    pin: OutputPin,
+}
+
+impl PiOutputPin {
+    pub fn new(pin: OutputPin) -> Self {
+        Self { pin }
+    }
+}
+
+impl digital::ErrorType for PiOutputPin {
+    type Error = Infallible;
+}
+
+impl digital::OutputPin for PiOutputPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.pin.set_low();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.pin.set_high();
+        Ok(())
+    }
+}
+
+/// Delays by sleeping the thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delay;
+
+impl DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        thread::sleep(Duration::from_nanos(ns as u64));
+    }
+}
+
+impl SC16IS752<PiI2c, Delay> {
+    /// Sets up both channels of the bridge at `addr` on the Pi's I2C bus.
+    pub fn begin(
+        addr: u8,
+        baud_a: u32,
+        baud_b: u32,
+        crystal_freq: u32,
+        data_length: DataLength,
+        parity: Parity,
+        stop_length: StopLength,
+    ) -> Result<Self, sc16is752::Error<PiI2cError>> {
+        // Use 0x4D when both A0 and A1 are connected to ground
+        SC16IS752::with_bus(PiI2c::new()?, Delay, addr, baud_a, baud_b, crystal_freq, data_length, parity, stop_length)
+    }
+}
+
+impl Bmp388<PiI2c, Delay> {
+    pub fn new() -> Result<Self, AltimeterError<PiI2cError>> {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Result<Self, AltimeterError<PiI2cError>> {
+        Self::with_address(bmp388::BMP388_ADDRESS, config)
+    }
+
+    /// Connects to a BMP388 or the register compatible BMP390 at `address` on the Pi's I2C bus.
+    pub fn with_address(address: u8, config: Config) -> Result<Self, AltimeterError<PiI2cError>> {
+        Bmp388::with_bus(PiI2c::new()?, Delay, address, config)
+    }
+}
+
+impl Bmx280<PiI2c, Delay> {
+    /// Connects to the sensor at `address` on the Pi's I2C bus.
+    pub fn new(address: u8) -> Result<Self, AltimeterError<PiI2cError>> {
+        Bmx280::with_bus(PiI2c::new()?, Delay, address)
+    }
+}
+
+impl Barometer<PiI2c, Delay> {
+    /// Detects and initializes the sensor at `address` on the Pi's I2C bus,
+    /// usually 0x76 or 0x77 depending on SDO.
+    pub fn new(address: u8) -> Result<Self, AltimeterError<PiI2cError>> {
+        Barometer::with_bus(PiI2c::new()?, Delay, address)
+    }
+}
+
+impl Neo6M<PiUart, Delay> {
+    pub fn new(uart: Uart) -> Self {
+        Self::with_port(PiUart::new(uart), Delay)
+    }
+}
+
+impl Dra818V<PiUart, Delay> {
+    pub fn new(uart: Uart) -> Self {
+        Self::with_port(PiUart::new(uart), Delay)
+    }
+}
+
+impl SignalGenerator<PiI2c> {
+    pub fn new() -> Result<Self, PiI2cError> {
+        Ok(Self::with_bus(PiI2c::new()?))
+    }
+}
@@ -0,0 +1,369 @@
+//! Predicting where the payload will land.
+//!
+//! On the way up the GPS track gives the wind at every altitude we pass through,
+//! which is binned into a [`WindProfile`]. After burst the descent is stepped down
+//! through that profile, with the fall rate under the parachute scaled by air density
+//! and calibrated against the descent rate actually seen so far.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::altitude;
+
+/// Mean radius of the Earth in meters.
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// Fixes closer together than this make for noisy wind.
+const MIN_WIND_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A GPS position with the best altitude estimate we have for it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    pub at: Instant,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Altitude in meters.
+    pub altitude: f32,
+}
+
+/// Wind velocity in m/s, the direction it's blowing towards.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Wind {
+    pub east: f32,
+    pub north: f32,
+}
+
+/// Average wind in altitude bands.
+#[derive(Debug, Clone)]
+pub struct WindProfile {
+    /// Height of each band in meters.
+    band: f32,
+    /// Summed east and north velocity and number of samples, by band index.
+    bands: BTreeMap<i32, (f32, f32, usize)>,
+    last: Option<Fix>,
+}
+
+impl WindProfile {
+    pub fn new(band: f32) -> Self {
+        Self {
+            band,
+            bands: BTreeMap::new(),
+            last: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bands.is_empty()
+    }
+
+    /// Adds the drift between the previous fix and this one at the altitude halfway between them.
+    pub fn add(&mut self, fix: Fix) {
+        let Some(last) = self.last else {
+            self.last = Some(fix);
+            return;
+        };
+
+        let dt = fix.at.saturating_duration_since(last.at);
+        if dt < MIN_WIND_INTERVAL {
+            return;
+        }
+
+        let (east, north) = offset_between(&last, &fix);
+        let dt = dt.as_secs_f64();
+        let band = self.band_of((last.altitude + fix.altitude) / 2.0);
+
+        let entry = self.bands.entry(band).or_insert((0.0, 0.0, 0));
+        entry.0 += (east / dt) as f32;
+        entry.1 += (north / dt) as f32;
+        entry.2 += 1;
+
+        self.last = Some(fix);
+    }
+
+    /// Wind at `altitude`, taken from the closest band there's data for.
+    pub fn wind_at(&self, altitude: f32) -> Option<Wind> {
+        let band = self.band_of(altitude);
+
+        let below = self.bands.range(..=band).next_back();
+        let above = self.bands.range(band..).next();
+        let (_, &(east, north, count)) = match (below, above) {
+            (Some(below), Some(above)) => {
+                if band - below.0 <= above.0 - band {
+                    below
+                } else {
+                    above
+                }
+            }
+            (Some(closest), None) | (None, Some(closest)) => closest,
+            (None, None) => return None,
+        };
+
+        Some(Wind {
+            east: east / count as f32,
+            north: north / count as f32,
+        })
+    }
+
+    fn band_of(&self, altitude: f32) -> i32 {
+        (altitude / self.band).floor() as i32
+    }
+}
+
+impl Default for WindProfile {
+    fn default() -> Self {
+        Self::new(500.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prediction {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Time left until landing.
+    pub time_to_landing: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct LandingPredictor {
+    pub wind: WindProfile,
+    /// Descent rate under the parachute at sea level, in m/s.
+    sea_level_rate: f32,
+    /// Weight given to each observed descent rate when calibrating.
+    calibration_gain: f32,
+    /// Altitude of the ground we'll land on, in meters.
+    ground_altitude: f32,
+    step: Duration,
+}
+
+impl LandingPredictor {
+    /// `sea_level_rate` is the expected descent rate at sea level, from the parachute size and payload weight.
+    pub fn new(sea_level_rate: f32) -> Self {
+        Self {
+            wind: WindProfile::default(),
+            sea_level_rate,
+            calibration_gain: 0.2,
+            ground_altitude: 0.0,
+            step: Duration::from_secs(10),
+        }
+    }
+
+    /// Assumes we land at the same height as we launched from.
+    pub fn set_ground_altitude(&mut self, altitude: f32) {
+        self.ground_altitude = altitude;
+    }
+
+    pub fn sea_level_rate(&self) -> f32 {
+        self.sea_level_rate
+    }
+
+    /// Descent rate at `altitude` in m/s. Terminal velocity goes with one over the square root of air density.
+    pub fn descent_rate(&self, altitude: f32) -> f32 {
+        let density = altitude::standard_density(altitude.max(0.0));
+
+        self.sea_level_rate * (altitude::standard_density(0.0) / density).sqrt()
+    }
+
+    /// Nudges the sea level descent rate towards one seen at `altitude`.
+    pub fn observe_descent(&mut self, rate: f32, altitude: f32) {
+        let rate = rate.abs();
+        if rate <= 0.0 {
+            return;
+        }
+
+        let density = altitude::standard_density(altitude.max(0.0));
+        let sea_level_rate = rate * (density / altitude::standard_density(0.0)).sqrt();
+
+        self.sea_level_rate += self.calibration_gain * (sea_level_rate - self.sea_level_rate);
+    }
+
+    /// Steps the descent from `from` down to the ground, drifting with the wind on the way.
+    pub fn predict(&self, from: &Fix) -> Option<Prediction> {
+        if self.wind.is_empty() || self.sea_level_rate <= 0.0 {
+            return None;
+        }
+
+        let step = self.step.as_secs_f32();
+        let mut latitude = from.latitude;
+        let mut longitude = from.longitude;
+        let mut altitude = from.altitude;
+        let mut elapsed = 0.0;
+
+        while altitude > self.ground_altitude {
+            let rate = self.descent_rate(altitude);
+            // Don't overshoot the ground on the last step.
+            let dt = step.min((altitude - self.ground_altitude) / rate);
+            let wind = self.wind.wind_at(altitude)?;
+
+            (latitude, longitude) = offset_by(latitude, longitude, (wind.east * dt) as f64, (wind.north * dt) as f64);
+            altitude -= rate * dt;
+            elapsed += dt;
+        }
+
+        Some(Prediction {
+            latitude,
+            longitude,
+            time_to_landing: Duration::from_secs_f32(elapsed),
+        })
+    }
+}
+
+impl Default for LandingPredictor {
+    fn default() -> Self {
+        Self::new(5.0)
+    }
+}
+
+/// Distance east and north from `from` to `to` in meters, fine over the few km between fixes.
+fn offset_between(from: &Fix, to: &Fix) -> (f64, f64) {
+    let latitude = ((from.latitude + to.latitude) / 2.0).to_radians();
+    // Going the short way round across the antimeridian.
+    let longitude = (to.longitude - from.longitude + 540.0).rem_euclid(360.0) - 180.0;
+
+    let east = longitude.to_radians() * EARTH_RADIUS * latitude.cos();
+    let north = (to.latitude - from.latitude).to_radians() * EARTH_RADIUS;
+
+    (east, north)
+}
+
+/// Moves `east` and `north` meters from a position.
+pub(crate) fn offset_by(latitude: f64, longitude: f64, east: f64, north: f64) -> (f64, f64) {
+    let latitude = latitude + (north / EARTH_RADIUS).to_degrees();
+    let longitude = longitude + (east / (EARTH_RADIUS * latitude.to_radians().cos())).to_degrees();
+
+    (latitude, (longitude + 540.0).rem_euclid(360.0) - 180.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAUNCH_ALTITUDE: f32 = 1650.0;
+    const BURST_ALTITUDE: f32 = 28_950.0;
+
+    /// A made-up ascent at 5 m/s with a fix every 30 s, drifting with `wind` at each altitude.
+    fn ascent(wind: impl Fn(f32) -> Wind) -> Vec<Fix> {
+        let start = Instant::now();
+        let mut fix = Fix {
+            at: start,
+            latitude: 40.015,
+            longitude: -105.2705,
+            altitude: LAUNCH_ALTITUDE,
+        };
+        let mut fixes = vec![fix];
+
+        while fix.altitude < BURST_ALTITUDE {
+            let wind = wind(fix.altitude + 75.0);
+            (fix.latitude, fix.longitude) = offset_by(fix.latitude, fix.longitude, wind.east as f64 * 30.0, wind.north as f64 * 30.0);
+            fix.altitude += 150.0;
+            fix.at += Duration::from_secs(30);
+            fixes.push(fix);
+        }
+
+        fixes
+    }
+
+    /// Learns the wind the way the flight loop does.
+    fn predictor(fixes: &[Fix]) -> LandingPredictor {
+        let mut predictor = LandingPredictor::default();
+        predictor.set_ground_altitude(LAUNCH_ALTITUDE);
+        for fix in fixes {
+            predictor.wind.add(*fix);
+        }
+
+        predictor
+    }
+
+    /// Time to fall from `top` to `bottom`, adding up each meter on the way down.
+    fn fall_time(predictor: &LandingPredictor, top: f32, bottom: f32) -> f32 {
+        (0..(top - bottom) as usize)
+            .map(|meter| 1.0 / predictor.descent_rate(bottom + meter as f32 + 0.5))
+            .sum()
+    }
+
+    /// Meters east and north from `from` to the predicted landing.
+    fn drift(from: &Fix, prediction: &Prediction) -> (f32, f32) {
+        let landing = Fix {
+            latitude: prediction.latitude,
+            longitude: prediction.longitude,
+            ..*from
+        };
+        let (east, north) = offset_between(from, &landing);
+
+        (east as f32, north as f32)
+    }
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() <= expected.abs() * 0.01 + 50.0, "{value} != {expected}");
+    }
+
+    #[test]
+    fn drifts_with_a_constant_wind_for_the_whole_fall() {
+        let wind = Wind { east: 12.0, north: -4.0 };
+        let fixes = ascent(|_| wind);
+        let predictor = predictor(&fixes);
+        let burst = fixes.last().unwrap();
+
+        let prediction = predictor.predict(burst).unwrap();
+
+        let time = fall_time(&predictor, burst.altitude, LAUNCH_ALTITUDE);
+        assert_close(prediction.time_to_landing.as_secs_f32(), time);
+        // About 40 minutes, so about 30 km east and 10 km south.
+        let (east, north) = drift(burst, &prediction);
+        assert_close(east, wind.east * time);
+        assert_close(north, wind.north * time);
+    }
+
+    #[test]
+    fn drifts_with_each_layer_of_wind_for_as_long_as_it_falls_through_it() {
+        // East below 10 km and north above.
+        let fixes = ascent(|altitude| match altitude < 10_000.0 {
+            true => Wind { east: 20.0, north: 0.0 },
+            false => Wind { east: 0.0, north: 10.0 },
+        });
+        let predictor = predictor(&fixes);
+        let burst = fixes.last().unwrap();
+
+        let prediction = predictor.predict(burst).unwrap();
+
+        let (east, north) = drift(burst, &prediction);
+        assert_close(east, 20.0 * fall_time(&predictor, 10_000.0, LAUNCH_ALTITUDE));
+        assert_close(north, 10.0 * fall_time(&predictor, burst.altitude, 10_000.0));
+    }
+
+    #[test]
+    fn predicts_from_partway_up_with_the_wind_seen_so_far() {
+        let wind = Wind { east: 12.0, north: -4.0 };
+        let fixes = ascent(|_| wind);
+        let climbed = fixes.iter().position(|fix| fix.altitude >= 15_000.0).unwrap();
+        let predictor = predictor(&fixes[..=climbed]);
+        let now = fixes[climbed];
+
+        let prediction = predictor.predict(&now).unwrap();
+
+        let time = fall_time(&predictor, now.altitude, LAUNCH_ALTITUDE);
+        assert_close(prediction.time_to_landing.as_secs_f32(), time);
+        let (east, north) = drift(&now, &prediction);
+        assert_close(east, wind.east * time);
+        assert_close(north, wind.north * time);
+
+        // Above the highest band seen, the wind is taken from the top of the climb.
+        let top = predictor.wind.wind_at(now.altitude).unwrap();
+        assert_eq!(predictor.wind.wind_at(25_000.0), Some(top));
+    }
+
+    #[test]
+    fn nothing_to_predict_without_wind() {
+        let fixes = ascent(|_| Wind { east: 12.0, north: -4.0 });
+        let burst = fixes.last().unwrap();
+
+        let mut predictor = LandingPredictor::default();
+        assert_eq!(predictor.predict(burst), None);
+
+        // A single fix isn't any drift yet.
+        predictor.wind.add(fixes[10]);
+        assert!(predictor.wind.is_empty());
+        assert_eq!(predictor.predict(burst), None);
+    }
+}
@@ -0,0 +1,497 @@
+//! Keying the transmitter and keeping track of how long it has been on the air.
+//!
+//! All transmissions go through [`Radio`], which owns the PTT line unless the modulator
+//! keys the transmitter itself, refuses frames that would key up for too long or blow
+//! the duty-cycle budget, and keeps airtime statistics. The budget matters at altitude,
+//! where there's very little air to carry heat away from the PA.
+
+use std::{
+    collections::VecDeque,
+    thread,
+    time::{Duration, Instant},
+};
+
+use embedded_hal::digital::{self, OutputPin};
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::{
+    deadline::Deadline,
+    modulator::{Modulator, ModulatorError},
+    recorder::{Record, Recorder},
+};
+
+/// AFSK bit rate
+const BAUD_RATE: f32 = 1200.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RadioConfig {
+    /// Time between keying up and starting the frame.
+    pub tx_delay: Duration,
+    /// Time between the end of the frame and unkeying.
+    pub tx_tail: Duration,
+    /// Longest the transmitter may be keyed for in one go.
+    pub max_key_down: Duration,
+    /// Fraction of `duty_window` the transmitter may be keyed for.
+    pub duty_cycle: f32,
+    pub duty_window: Duration,
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        Self {
+            tx_delay: Duration::from_millis(1000),
+            tx_tail: Duration::from_millis(1000),
+            max_key_down: Duration::from_secs(10),
+            duty_cycle: 0.25,
+            duty_window: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AirtimeStats {
+    pub transmissions: usize,
+    /// Frames refused because of the key-down limit or the duty-cycle budget.
+    pub refused: usize,
+    pub total: Duration,
+    pub longest: Duration,
+}
+
+pub struct Radio<P> {
+    ptt: Option<P>,
+    modulator: Box<dyn Modulator>,
+    config: RadioConfig,
+    /// Start and length of every transmission inside the duty-cycle window.
+    history: VecDeque<(Instant, Duration)>,
+    /// End of the time slot we're allowed to transmit in.
+    slot_end: Deadline,
+    recorder: Option<Recorder>,
+    stats: AirtimeStats,
+}
+
+impl<P: OutputPin> Radio<P> {
+    /// `ptt` is `None` when nothing needs keying, like in test mode.
+    ///
+    /// Any output pin will do, whether it's on the Pi itself or the UART bridge.
+    pub fn new(ptt: Option<P>, modulator: Box<dyn Modulator>, config: RadioConfig) -> Self {
+        Self {
+            ptt,
+            modulator,
+            config,
+            history: VecDeque::new(),
+            slot_end: Deadline::NEVER,
+            recorder: None,
+            stats: AirtimeStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &AirtimeStats {
+        &self.stats
+    }
+
+    /// Keys the transmitter around `f`, for the rare cases where the transceiver has to be enabled
+    /// outside of a transmission, like retuning it. `settle` is how long to wait before calling `f`.
+    ///
+    /// The time keyed is charged to the duty-cycle budget, but isn't refused when over it.
+    pub fn keyed<R>(&mut self, settle: Duration, f: impl FnOnce() -> R) -> Result<R, RadioError> {
+        let Some(ptt) = &mut self.ptt else {
+            return Ok(f());
+        };
+
+        let start = Instant::now();
+        ptt.set_high().map_err(RadioError::ptt)?;
+        thread::sleep(settle);
+
+        let result = f();
+
+        let unkeyed = ptt.set_low().map_err(RadioError::ptt);
+        self.charge(start, start.elapsed());
+        unkeyed?;
+
+        Ok(result)
+    }
+
+    /// Logs every frame and how it went to the flight record, including ones that were refused.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Refuses frames that wouldn't be finished by `end`, so we don't run into someone else's slot.
+    pub fn set_slot_end(&mut self, end: Deadline) {
+        self.slot_end = end;
+    }
+
+    /// Estimates how long the transmitter will be keyed to send `frame`.
+    pub fn estimate_airtime(&self, frame: &[u8]) -> Duration {
+        let bits = (frame.len() + self.modulator.framing_overhead()) * 8;
+        // Worst case bit stuffing adds one bit for every five.
+        let frame_time = Duration::from_secs_f32(bits as f32 * 1.2 / BAUD_RATE);
+
+        // A modulator keying its own transmitter counts its TX delay and tail as framing.
+        if self.drives_ptt() {
+            self.config.tx_delay + frame_time + self.config.tx_tail
+        } else {
+            frame_time
+        }
+    }
+
+    /// Whether the PTT is ours to key for a transmission.
+    fn drives_ptt(&self) -> bool {
+        self.ptt.is_some() && !self.modulator.keys_transmitter()
+    }
+
+    /// Airtime used within the current duty-cycle window.
+    pub fn used_airtime(&mut self) -> Duration {
+        self.expire_history();
+        self.history.iter().map(|(_, length)| *length).sum()
+    }
+
+    pub fn transmit(&mut self, frame: &[u8]) -> Result<(), RadioError> {
+        let mut airtime = None;
+        let result = self.try_transmit(frame, &mut airtime);
+
+        if let Some(recorder) = &self.recorder {
+            let record = Record::Transmission {
+                frame: frame.to_vec(),
+                airtime,
+                result: result.as_ref().map(|_| ()).map_err(|err| err.to_string()),
+            };
+
+            if let Err(err) = recorder.record(record) {
+                warn!("failed to record transmission: {err}");
+            }
+        }
+
+        result
+    }
+
+    /// Sets `airtime` to how long the transmitter was actually keyed, if it got that far.
+    fn try_transmit(&mut self, frame: &[u8], airtime: &mut Option<Duration>) -> Result<(), RadioError> {
+        let estimate = self.estimate_airtime(frame);
+
+        if estimate > self.config.max_key_down {
+            self.stats.refused += 1;
+            return Err(RadioError::KeyDownTooLong {
+                estimate,
+                max: self.config.max_key_down,
+            });
+        }
+
+        let budget = self.config.duty_window.mul_f32(self.config.duty_cycle);
+        if self.used_airtime() + estimate > budget {
+            self.stats.refused += 1;
+            return Err(RadioError::DutyCycleExceeded {
+                retry_in: self.budget_available_in(budget.saturating_sub(estimate)),
+            });
+        }
+
+        if let Some(left) = self.slot_end.remaining() {
+            if estimate > left {
+                self.stats.refused += 1;
+                return Err(RadioError::SlotOverrun { estimate, left });
+            }
+        }
+
+        let start = Instant::now();
+
+        // The frame is only queued, the TNC keys up when it gets round to sending it.
+        if self.modulator.keys_transmitter() {
+            self.modulator.transmit(frame)?;
+            *airtime = Some(estimate);
+            self.record(start, estimate);
+
+            return Ok(());
+        }
+
+        if let Some(ptt) = &mut self.ptt {
+            ptt.set_high().map_err(RadioError::ptt)?;
+            thread::sleep(self.config.tx_delay);
+        }
+
+        let result = self.modulator.transmit(frame);
+
+        let unkeyed = match &mut self.ptt {
+            Some(ptt) => {
+                thread::sleep(self.config.tx_tail);
+                ptt.set_low().map_err(RadioError::ptt)
+            }
+            None => Ok(()),
+        };
+
+        let keyed = start.elapsed();
+        *airtime = Some(keyed);
+        self.record(start, keyed);
+
+        if keyed > self.config.max_key_down {
+            warn!("Transmitter was keyed for {:.1}s, longer than the {:.1}s limit", keyed.as_secs_f32(), self.config.max_key_down.as_secs_f32());
+        }
+
+        // Getting stuck keyed up matters more than a failed frame.
+        unkeyed?;
+        result?;
+
+        Ok(())
+    }
+
+    fn record(&mut self, start: Instant, airtime: Duration) {
+        self.stats.transmissions += 1;
+        self.charge(start, airtime);
+
+        let used = self.used_airtime();
+        info!(
+            "Keyed for {:.2}s; {:.1}s used of the {:.1}s budget, {:.1}s over {} transmissions in total",
+            airtime.as_secs_f32(),
+            used.as_secs_f32(),
+            self.config.duty_window.mul_f32(self.config.duty_cycle).as_secs_f32(),
+            self.stats.total.as_secs_f32(),
+            self.stats.transmissions,
+        );
+    }
+
+    /// Counts time keyed against the duty-cycle budget.
+    fn charge(&mut self, start: Instant, airtime: Duration) {
+        self.history.push_back((start, airtime));
+
+        self.stats.total += airtime;
+        self.stats.longest = self.stats.longest.max(airtime);
+    }
+
+    /// Drops transmissions that have fallen out of the duty-cycle window.
+    fn expire_history(&mut self) {
+        while let Some((start, _)) = self.history.front() {
+            if start.elapsed() > self.config.duty_window {
+                self.history.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Time until no more than `allowed` airtime has been used within the window.
+    fn budget_available_in(&mut self, allowed: Duration) -> Duration {
+        let mut used = self.used_airtime();
+
+        for (start, length) in &self.history {
+            if used <= allowed {
+                break;
+            }
+
+            used = used.saturating_sub(*length);
+            if used <= allowed {
+                return self.config.duty_window.saturating_sub(start.elapsed());
+            }
+        }
+
+        Duration::ZERO
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RadioError {
+    #[error("{0}")]
+    Modulator(#[from] ModulatorError),
+    #[error("frame would key the transmitter for {:.1}s, over the {:.1}s limit", .estimate.as_secs_f32(), .max.as_secs_f32())]
+    KeyDownTooLong { estimate: Duration, max: Duration },
+    #[error("duty-cycle budget exhausted, available again in {:.0}s", .retry_in.as_secs_f32())]
+    DutyCycleExceeded { retry_in: Duration },
+    #[error("frame needs {:.1}s but only {:.1}s of the time slot is left", .estimate.as_secs_f32(), .left.as_secs_f32())]
+    SlotOverrun { estimate: Duration, left: Duration },
+    #[error("failed to switch PTT: {0}")]
+    Ptt(digital::ErrorKind),
+}
+
+impl RadioError {
+    pub fn ptt<E: digital::Error>(err: E) -> Self {
+        RadioError::Ptt(err.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Remembers every level it was set to.
+    struct MockPin(Rc<RefCell<Vec<bool>>>);
+
+    impl digital::ErrorType for MockPin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockPin {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.borrow_mut().push(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.borrow_mut().push(true);
+            Ok(())
+        }
+    }
+
+    struct NullModulator {
+        overhead: usize,
+        keys_transmitter: bool,
+    }
+
+    impl Modulator for NullModulator {
+        fn transmit(&mut self, _frame: &[u8]) -> Result<(), ModulatorError> {
+            Ok(())
+        }
+
+        fn framing_overhead(&self) -> usize {
+            self.overhead
+        }
+
+        fn keys_transmitter(&self) -> bool {
+            self.keys_transmitter
+        }
+    }
+
+    fn radio(ptt: Option<MockPin>, overhead: usize) -> Radio<MockPin> {
+        let config = RadioConfig {
+            tx_delay: Duration::ZERO,
+            tx_tail: Duration::ZERO,
+            ..RadioConfig::default()
+        };
+
+        Radio::new(ptt, Box::new(NullModulator { overhead, keys_transmitter: false }), config)
+    }
+
+    fn ago(time: Duration) -> Instant {
+        Instant::now().checked_sub(time).unwrap()
+    }
+
+    #[test]
+    fn airtime_estimate_uses_the_backend_overhead() {
+        let frame = [0; 58];
+
+        // 100 bytes with worst case bit stuffing at 1200 baud.
+        let estimate = radio(None, 42).estimate_airtime(&frame);
+        assert!((estimate.as_secs_f32() - 0.8).abs() < 1e-3, "{estimate:?}");
+
+        let estimate = radio(None, 0).estimate_airtime(&frame);
+        assert!((estimate.as_secs_f32() - 0.464).abs() < 1e-3, "{estimate:?}");
+    }
+
+    #[test]
+    fn keying_outside_a_transmission_is_charged_to_the_budget() {
+        let levels = Rc::new(RefCell::new(Vec::new()));
+        let mut radio = radio(Some(MockPin(levels.clone())), 42);
+
+        let result = radio.keyed(Duration::from_millis(20), || {
+            assert_eq!(*levels.borrow(), [true]);
+            7
+        });
+
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(*levels.borrow(), [true, false]);
+        assert!(radio.used_airtime() >= Duration::from_millis(20));
+        assert_eq!(radio.stats().transmissions, 0);
+    }
+
+    #[test]
+    fn transmissions_record_how_long_they_were_keyed() {
+        let levels = Rc::new(RefCell::new(Vec::new()));
+        let mut radio = radio(Some(MockPin(levels.clone())), 42);
+
+        radio.transmit(b"test").unwrap();
+
+        assert_eq!(*levels.borrow(), [true, false]);
+        assert_eq!(radio.stats().transmissions, 1);
+        assert_eq!(radio.used_airtime(), radio.stats().total);
+        assert_eq!(radio.stats().longest, radio.stats().total);
+    }
+
+    #[test]
+    fn nothing_is_keyed_without_a_ptt() {
+        let mut radio = radio(None, 0);
+
+        assert_eq!(radio.keyed(Duration::from_secs(10), || 7).unwrap(), 7);
+        assert_eq!(radio.used_airtime(), Duration::ZERO);
+    }
+
+    #[test]
+    fn a_tnc_keys_itself_and_is_charged_the_estimate() {
+        let levels = Rc::new(RefCell::new(Vec::new()));
+        let config = RadioConfig {
+            tx_delay: Duration::from_secs(5),
+            tx_tail: Duration::from_secs(5),
+            ..RadioConfig::default()
+        };
+        let modulator = NullModulator { overhead: 42, keys_transmitter: true };
+        let mut radio = Radio::new(Some(MockPin(levels.clone())), Box::new(modulator), config);
+        let frame = [0; 58];
+
+        // The TNC's own TX delay and tail are in its overhead, ours aren't added on top.
+        let estimate = radio.estimate_airtime(&frame);
+        assert!((estimate.as_secs_f32() - 0.8).abs() < 1e-3, "{estimate:?}");
+
+        let start = Instant::now();
+        radio.transmit(&frame).unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(levels.borrow().is_empty());
+        assert_eq!(radio.used_airtime(), estimate);
+        assert_eq!(radio.stats().transmissions, 1);
+    }
+
+    #[test]
+    fn frames_too_long_to_key_for_are_refused() {
+        let levels = Rc::new(RefCell::new(Vec::new()));
+        let mut radio = radio(Some(MockPin(levels.clone())), 42);
+
+        // Over 10s at 1200 baud.
+        let result = radio.transmit(&[0; 1500]);
+
+        assert!(matches!(result, Err(RadioError::KeyDownTooLong { max, .. }) if max == Duration::from_secs(10)), "{result:?}");
+        assert!(levels.borrow().is_empty());
+        assert_eq!(radio.stats().refused, 1);
+        assert_eq!(radio.stats().transmissions, 0);
+    }
+
+    #[test]
+    fn frames_over_the_duty_cycle_budget_are_refused() {
+        let mut radio = radio(None, 42);
+        // The whole 150s budget, used up 100s ago.
+        radio.history.push_back((ago(Duration::from_secs(100)), Duration::from_secs(150)));
+
+        let result = radio.transmit(b"test");
+
+        // Back once that falls out of the 600s window.
+        let Err(RadioError::DutyCycleExceeded { retry_in }) = result else {
+            panic!("{result:?}");
+        };
+        assert!(retry_in.abs_diff(Duration::from_secs(500)) < Duration::from_secs(1), "{retry_in:?}");
+        assert_eq!(radio.stats().refused, 1);
+    }
+
+    #[test]
+    fn airtime_falls_out_of_the_duty_cycle_window() {
+        let mut radio = radio(None, 42);
+        radio.history.push_back((ago(Duration::from_secs(601)), Duration::from_secs(150)));
+        radio.history.push_back((ago(Duration::from_secs(300)), Duration::from_secs(20)));
+
+        assert_eq!(radio.used_airtime(), Duration::from_secs(20));
+        assert_eq!(radio.history.len(), 1);
+        radio.transmit(b"test").unwrap();
+    }
+
+    #[test]
+    fn frames_that_would_run_past_the_slot_are_refused() {
+        let mut radio = radio(None, 42);
+        radio.set_slot_end(Deadline::after(Duration::from_millis(500)));
+
+        // About 0.8s on the air.
+        let result = radio.transmit(&[0; 58]);
+
+        assert!(matches!(result, Err(RadioError::SlotOverrun { left, .. }) if left <= Duration::from_millis(500)), "{result:?}");
+        assert_eq!(radio.stats().refused, 1);
+
+        radio.set_slot_end(Deadline::NEVER);
+        radio.transmit(&[0; 58]).unwrap();
+    }
+}
@@ -0,0 +1,719 @@
+//! Flight data recorder.
+//!
+//! Everything worth looking at after the flight goes into CSV files, one record per line:
+//! milliseconds since the Unix epoch, the record type, then its fields. Empty fields are
+//! readings we didn't have, and frames and image data are hex encoded.
+//!
+//! ```text
+//! 1718000000000,gps,51.500000,-0.120000,1234.5,12.3,270.0,8
+//! 1718000000250,baro,87654.3,-12.50,,1230.1
+//! 1718000001000,tx,82a0a4a6...,2.347,ok
+//! ```
+//!
+//! Each record is written in a single `write` so a power cut can only lose the tail of the
+//! last line, which [`Reader`] reports as an error and carries on from. Files are rolled over
+//! at a size limit and never overwritten, a reboot mid-flight just starts the next one.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+
+const FILE_PREFIX: &str = "flight-";
+const FILE_EXTENSION: &str = "csv";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Gps {
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        /// Meters
+        altitude: Option<f32>,
+        /// Knots
+        speed: Option<f32>,
+        /// Degrees
+        course: Option<f32>,
+        satellites: Option<u32>,
+    },
+    Barometer {
+        /// Pascals
+        pressure: f32,
+        /// Celsius
+        temperature: f32,
+        /// Percent
+        humidity: Option<f32>,
+        /// Estimated altitude in meters.
+        altitude: f32,
+    },
+    /// A frame handed to the radio, and what came of it.
+    /// `airtime` is how long the transmitter was keyed, `None` if it never was.
+    Transmission { frame: Vec<u8>, airtime: Option<Duration>, result: Result<(), String> },
+    /// An SSDV packet generated from a picture.
+    ImageChunk { data: Vec<u8> },
+    /// The flight phase changed.
+    Phase(String),
+}
+
+impl Record {
+    fn kind(&self) -> &'static str {
+        match self {
+            Record::Gps { .. } => "gps",
+            Record::Barometer { .. } => "baro",
+            Record::Transmission { .. } => "tx",
+            Record::ImageChunk { .. } => "image",
+            Record::Phase(_) => "phase",
+        }
+    }
+
+    fn write_fields(&self, line: &mut String) {
+        match self {
+            Record::Gps {
+                latitude,
+                longitude,
+                altitude,
+                speed,
+                course,
+                satellites,
+            } => {
+                let _ = write!(
+                    line,
+                    "{},{},{},{},{},{}",
+                    optional(latitude.map(|latitude| format!("{latitude:.6}"))),
+                    optional(longitude.map(|longitude| format!("{longitude:.6}"))),
+                    optional(altitude.map(|altitude| format!("{altitude:.1}"))),
+                    optional(speed.map(|speed| format!("{speed:.1}"))),
+                    optional(course.map(|course| format!("{course:.1}"))),
+                    optional(*satellites),
+                );
+            }
+            Record::Barometer {
+                pressure,
+                temperature,
+                humidity,
+                altitude,
+            } => {
+                let _ = write!(
+                    line,
+                    "{pressure:.1},{temperature:.2},{},{altitude:.1}",
+                    optional(humidity.map(|humidity| format!("{humidity:.1}")))
+                );
+            }
+            Record::Transmission { frame, airtime, result } => {
+                line.push_str(&hex_encode(frame));
+                line.push(',');
+                if let Some(airtime) = airtime {
+                    let _ = write!(line, "{:.3}", airtime.as_secs_f32());
+                }
+                match result {
+                    Ok(()) => line.push_str(",ok"),
+                    // The error is the last field, so it can contain commas but not newlines.
+                    Err(err) => {
+                        let _ = write!(line, ",{}", err.replace(['\r', '\n'], " "));
+                    }
+                }
+            }
+            Record::ImageChunk { data } => line.push_str(&hex_encode(data)),
+            Record::Phase(phase) => line.push_str(phase),
+        }
+    }
+
+    fn parse(kind: &str, fields: &str) -> Result<Self, String> {
+        match kind {
+            "gps" => {
+                let fields = split_fields::<6>(fields)?;
+
+                Ok(Record::Gps {
+                    latitude: parse_optional(fields[0])?,
+                    longitude: parse_optional(fields[1])?,
+                    altitude: parse_optional(fields[2])?,
+                    speed: parse_optional(fields[3])?,
+                    course: parse_optional(fields[4])?,
+                    satellites: parse_optional(fields[5])?,
+                })
+            }
+            "baro" => {
+                let fields = split_fields::<4>(fields)?;
+
+                Ok(Record::Barometer {
+                    pressure: parse(fields[0])?,
+                    temperature: parse(fields[1])?,
+                    humidity: parse_optional(fields[2])?,
+                    altitude: parse(fields[3])?,
+                })
+            }
+            "tx" => {
+                let (frame, fields) = fields.split_once(',').ok_or("missing transmission airtime")?;
+                let (airtime, result) = fields.split_once(',').ok_or("missing transmission result")?;
+
+                Ok(Record::Transmission {
+                    frame: hex_decode(frame)?,
+                    airtime: parse_optional::<f32>(airtime)?.map(Duration::from_secs_f32),
+                    result: match result {
+                        "ok" => Ok(()),
+                        err => Err(err.to_owned()),
+                    },
+                })
+            }
+            "image" => Ok(Record::ImageChunk {
+                data: hex_decode(fields)?,
+            }),
+            "phase" => Ok(Record::Phase(fields.to_owned())),
+            kind => Err(format!("unknown record type {kind:?}")),
+        }
+    }
+}
+
+/// A record and when it was made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Milliseconds since the Unix epoch, by the Pi's clock.
+    pub time: u64,
+    pub record: Record,
+}
+
+impl Entry {
+    pub fn now(record: Record) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        Self { time, record }
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = format!("{},{},", self.time, self.record.kind());
+        self.record.write_fields(&mut line);
+        line.push('\n');
+
+        line
+    }
+
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut parts = line.trim_end_matches(['\r', '\n']).splitn(3, ',');
+        let time = parts.next().ok_or("empty line")?;
+        let kind = parts.next().ok_or("missing record type")?;
+        let fields = parts.next().ok_or("missing fields")?;
+
+        Ok(Self {
+            time: parse(time)?,
+            record: Record::parse(kind, fields)?,
+        })
+    }
+}
+
+/// How often to make sure records have actually hit the SD card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every record. Safest, but wears the card.
+    Always,
+    /// With the first record written at least this long after the last sync.
+    Every(Duration),
+    /// Whenever the kernel gets round to it.
+    Never,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    /// Size a file is rolled over at, in bytes.
+    pub max_file_size: u64,
+    pub sync: SyncPolicy,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("/home/aprs/Documents/flight"),
+            max_file_size: 16 * 1024 * 1024,
+            sync: SyncPolicy::Every(Duration::from_secs(5)),
+        }
+    }
+}
+
+struct Inner {
+    config: RecorderConfig,
+    file: File,
+    index: u32,
+    size: u64,
+    last_sync: Instant,
+    unsynced: bool,
+}
+
+/// Writes records to the current flight file. Clones share the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Recorder {
+    /// Starts a new file after any left over from earlier runs.
+    pub fn open(config: RecorderConfig) -> Result<Self, RecorderError> {
+        fs::create_dir_all(&config.dir)?;
+
+        let index = flight_files(&config.dir)?.last().map_or(0, |(index, _)| index + 1);
+        let file = create_file(&config.dir, index)?;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                config,
+                file,
+                index,
+                size: 0,
+                last_sync: Instant::now(),
+                unsynced: false,
+            })),
+        })
+    }
+
+    /// Path of the file currently being written.
+    pub fn path(&self) -> PathBuf {
+        let inner = self.lock();
+        file_path(&inner.config.dir, inner.index)
+    }
+
+    pub fn record(&self, record: Record) -> Result<(), RecorderError> {
+        let line = Entry::now(record).to_line();
+        let mut inner = self.lock();
+
+        if inner.size > 0 && inner.size + line.len() as u64 > inner.config.max_file_size {
+            inner.file.sync_data()?;
+            inner.index += 1;
+            inner.file = create_file(&inner.config.dir, inner.index)?;
+            inner.size = 0;
+        }
+
+        inner.file.write_all(line.as_bytes())?;
+        inner.size += line.len() as u64;
+        inner.unsynced = true;
+
+        let due = match inner.config.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(interval) => inner.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            inner.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes everything written so far to the card.
+    pub fn sync(&self) -> Result<(), RecorderError> {
+        self.lock().sync()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Inner {
+    fn sync(&mut self) -> Result<(), RecorderError> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+}
+
+/// Reads back the records in a flight file.
+///
+/// Bad lines, like one cut short by a power cut, come out as errors without stopping the rest.
+pub struct Reader<R> {
+    lines: io::Lines<R>,
+    line: usize,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RecorderError> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Entry, RecorderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            self.line += 1;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            return Some(Entry::parse(&line).map_err(|message| RecorderError::Parse {
+                line: self.line,
+                message,
+            }));
+        }
+    }
+}
+
+/// Flight files in `dir` in the order they were written, with their index.
+pub fn flight_files(dir: impl AsRef<Path>) -> Result<Vec<(u32, PathBuf)>, RecorderError> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let index = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(FILE_PREFIX))
+            .and_then(|index| index.parse().ok());
+
+        if let (Some(index), Some(FILE_EXTENSION)) = (index, path.extension().and_then(|ext| ext.to_str())) {
+            files.push((index, path));
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+fn file_path(dir: &Path, index: u32) -> PathBuf {
+    dir.join(format!("{FILE_PREFIX}{index:04}.{FILE_EXTENSION}"))
+}
+
+fn create_file(dir: &Path, index: u32) -> Result<File, RecorderError> {
+    let file = OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(file_path(dir, index))?;
+
+    // The new directory entry has to survive a power cut too.
+    File::open(dir)?.sync_all()?;
+
+    Ok(file)
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn parse<T: std::str::FromStr>(field: &str) -> Result<T, String> {
+    field.parse().map_err(|_| format!("invalid field {field:?}"))
+}
+
+fn parse_optional<T: std::str::FromStr>(field: &str) -> Result<Option<T>, String> {
+    match field {
+        "" => Ok(None),
+        field => parse(field).map(Some),
+    }
+}
+
+fn split_fields<const N: usize>(fields: &str) -> Result<[&str; N], String> {
+    let fields: Vec<&str> = fields.split(',').collect();
+
+    fields
+        .try_into()
+        .map_err(|fields: Vec<&str>| format!("expected {N} fields, got {}", fields.len()))
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn hex_decode(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("invalid hex {hex:?}"));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("invalid hex {:?}", &hex[i..i + 2])))
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum RecorderError {
+    #[error("failed to access the flight record: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line} of the flight record is invalid: {message}")]
+    Parse { line: usize, message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch directory that's removed again when the test is done.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("recorder-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            Self(dir)
+        }
+
+        fn config(&self, max_file_size: u64, sync: SyncPolicy) -> RecorderConfig {
+            RecorderConfig {
+                dir: self.0.clone(),
+                max_file_size,
+                sync,
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn assert_round_trip(record: Record) {
+        let entry = Entry {
+            time: 1718000000250,
+            record,
+        };
+        let line = entry.to_line();
+
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'), "{line:?}");
+        assert_eq!(Entry::parse(&line), Ok(entry));
+    }
+
+    fn read_all(dir: &Path) -> Vec<Result<Entry, RecorderError>> {
+        flight_files(dir)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, path)| Reader::open(path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn gps_records_round_trip() {
+        assert_round_trip(Record::Gps {
+            latitude: Some(51.5),
+            longitude: Some(-0.12),
+            altitude: Some(1234.5),
+            speed: Some(12.3),
+            course: Some(270.0),
+            satellites: Some(8),
+        });
+        assert_round_trip(Record::Gps {
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            speed: None,
+            course: None,
+            satellites: Some(0),
+        });
+    }
+
+    #[test]
+    fn barometer_records_round_trip() {
+        assert_round_trip(Record::Barometer {
+            pressure: 87654.3,
+            temperature: -12.5,
+            humidity: Some(41.2),
+            altitude: 1230.1,
+        });
+        assert_round_trip(Record::Barometer {
+            pressure: 1100.0,
+            temperature: -56.25,
+            humidity: None,
+            altitude: 30512.7,
+        });
+    }
+
+    #[test]
+    fn transmission_records_round_trip() {
+        assert_round_trip(Record::Transmission {
+            frame: vec![0x82, 0xa0, 0xa4, 0xa6, 0x00, 0xff],
+            airtime: Some(Duration::from_secs_f32(2.347)),
+            result: Ok(()),
+        });
+        assert_round_trip(Record::Transmission {
+            frame: vec![],
+            airtime: None,
+            result: Err("duty cycle exceeded, retry in 12s".to_owned()),
+        });
+    }
+
+    #[test]
+    fn transmission_errors_stay_on_one_line() {
+        let entry = Entry {
+            time: 1,
+            record: Record::Transmission {
+                frame: vec![1],
+                airtime: None,
+                result: Err("radio said:\r\nno".to_owned()),
+            },
+        };
+
+        let parsed = Entry::parse(&entry.to_line()).unwrap();
+        assert!(matches!(parsed.record, Record::Transmission { result: Err(err), .. } if err == "radio said:  no"));
+    }
+
+    #[test]
+    fn image_chunk_records_round_trip() {
+        assert_round_trip(Record::ImageChunk {
+            data: (0..=255).collect(),
+        });
+    }
+
+    #[test]
+    fn phase_records_round_trip() {
+        assert_round_trip(Record::Phase("ascent".to_owned()));
+    }
+
+    #[test]
+    fn bad_lines_are_errors() {
+        assert!(Entry::parse("").is_err());
+        assert!(Entry::parse("1718000000000,gps,51.500000,-0.120000").is_err());
+        assert!(Entry::parse("1718000000000,baro,87654.3,-12.50,,").is_err());
+        assert!(Entry::parse("1718000000000,tx,82a").is_err());
+        assert!(Entry::parse("1718000000000,image,0g").is_err());
+        assert!(Entry::parse("1718000000000,wind,1,2").is_err());
+        assert!(Entry::parse("soon,phase,ascent").is_err());
+    }
+
+    #[test]
+    fn rolls_over_to_a_new_file_at_the_size_limit() {
+        let dir = TempDir::new("rotation");
+        let recorder = Recorder::open(dir.config(100, SyncPolicy::Never)).unwrap();
+        let first = recorder.path();
+
+        for i in 0..10 {
+            recorder.record(Record::Phase(format!("phase {i}"))).unwrap();
+        }
+
+        let files = flight_files(&dir.0).unwrap();
+        assert!(files.len() > 1);
+        assert_eq!(files[0].1, first);
+        assert_eq!(files.last().unwrap().1, recorder.path());
+        for (_, path) in &files {
+            assert!(fs::metadata(path).unwrap().len() <= 100);
+        }
+
+        // Nothing lost or reordered across files.
+        let phases: Vec<Record> = read_all(&dir.0).into_iter().map(|entry| entry.unwrap().record).collect();
+        let expected: Vec<Record> = (0..10).map(|i| Record::Phase(format!("phase {i}"))).collect();
+        assert_eq!(phases, expected);
+    }
+
+    #[test]
+    fn a_record_bigger_than_a_file_still_gets_written() {
+        let dir = TempDir::new("oversized");
+        let recorder = Recorder::open(dir.config(10, SyncPolicy::Never)).unwrap();
+
+        recorder.record(Record::ImageChunk { data: vec![0; 64] }).unwrap();
+        recorder.record(Record::ImageChunk { data: vec![1; 64] }).unwrap();
+
+        // One record per file rather than an empty file each time.
+        assert_eq!(flight_files(&dir.0).unwrap().len(), 2);
+        assert_eq!(read_all(&dir.0).len(), 2);
+    }
+
+    #[test]
+    fn reads_past_a_line_cut_short_by_a_power_cut() {
+        let dir = TempDir::new("power-cut");
+        let recorder = Recorder::open(dir.config(1024 * 1024, SyncPolicy::Always)).unwrap();
+        recorder.record(Record::Phase("ascent".to_owned())).unwrap();
+        recorder
+            .record(Record::Barometer {
+                pressure: 87654.3,
+                temperature: -12.5,
+                humidity: None,
+                altitude: 1230.1,
+            })
+            .unwrap();
+        let path = recorder.path();
+        drop(recorder);
+
+        // Lose the end of the last line.
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 8).unwrap();
+
+        let entries = read_all(&dir.0);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].as_ref().unwrap().record, Record::Phase("ascent".to_owned()));
+        assert!(matches!(entries[1], Err(RecorderError::Parse { line: 2, .. })));
+
+        // After the reboot the damaged file is left alone.
+        let recorder = Recorder::open(dir.config(1024 * 1024, SyncPolicy::Always)).unwrap();
+        recorder.record(Record::Phase("descent".to_owned())).unwrap();
+        assert_ne!(recorder.path(), path);
+        assert_eq!(fs::metadata(&path).unwrap().len(), length - 8);
+
+        let entries = read_all(&dir.0);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].as_ref().unwrap().record, Record::Phase("descent".to_owned()));
+    }
+
+    #[test]
+    fn flight_files_are_in_numeric_order() {
+        let dir = TempDir::new("order");
+        for name in [
+            "flight-0010.csv",
+            "flight-0002.csv",
+            "flight-100.csv",
+            "flight-0003.txt",
+            "flight-abc.csv",
+            "notes.csv",
+        ] {
+            File::create(dir.0.join(name)).unwrap();
+        }
+
+        let files = flight_files(&dir.0).unwrap();
+
+        assert_eq!(
+            files,
+            vec![
+                (2, dir.0.join("flight-0002.csv")),
+                (10, dir.0.join("flight-0010.csv")),
+                (100, dir.0.join("flight-100.csv")),
+            ]
+        );
+
+        // A new recorder carries on after the highest one.
+        let recorder = Recorder::open(dir.config(1024, SyncPolicy::Never)).unwrap();
+        assert_eq!(recorder.path(), dir.0.join("flight-0101.csv"));
+    }
+
+    #[test]
+    fn sync_policy_decides_when_records_hit_the_card() {
+        let unsynced_after_record = |name, sync| {
+            let dir = TempDir::new(name);
+            let recorder = Recorder::open(dir.config(1024, sync)).unwrap();
+            recorder.record(Record::Phase("ascent".to_owned())).unwrap();
+            let unsynced = recorder.lock().unsynced;
+            recorder.sync().unwrap();
+            assert!(!recorder.lock().unsynced);
+
+            unsynced
+        };
+
+        assert!(!unsynced_after_record("always", SyncPolicy::Always));
+        assert!(unsynced_after_record("never", SyncPolicy::Never));
+        assert!(unsynced_after_record("not-yet", SyncPolicy::Every(Duration::from_secs(3600))));
+        assert!(!unsynced_after_record("due", SyncPolicy::Every(Duration::ZERO)));
+    }
+}
@@ -0,0 +1,387 @@
+//! Plays a flight back in real time in place of the GPS and barometer,
+//! so the flight loop can run on a desk with nothing attached.
+//!
+//! Files from the flight recorder play back as they were recorded,
+//! and [`Replay::simulated`] makes up a flight for when there isn't one to hand.
+
+use std::{
+    collections::VecDeque,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use log::warn;
+use nmea::Nmea;
+
+use crate::{
+    altitude::{self, STANDARD_PRESSURE},
+    beacon::KNOTS_TO_MPS,
+    bmp388::{AltimeterData, TimedReading, MAX_FIFO_READINGS},
+    predict,
+    recorder::{Entry, Reader, Record, RecorderError},
+};
+
+/// Time between samples in a simulated flight, in milliseconds.
+const SIMULATED_INTERVAL: u64 = 1000;
+/// Time spent on the pad before and on the ground after a simulated flight, in seconds.
+const SIMULATED_GROUND_TIME: u64 = 120;
+const SIMULATED_LAUNCH_ALTITUDE: f32 = 100.0;
+const SIMULATED_BURST_ALTITUDE: f32 = 30_000.0;
+/// Meters per second
+const SIMULATED_ASCENT_RATE: f32 = 5.0;
+/// Descent rate under the parachute at sea level, in meters per second.
+const SIMULATED_DESCENT_RATE: f32 = 5.0;
+/// Meters per second
+const SIMULATED_WIND_SPEED: f32 = 10.0;
+/// Degrees, the direction the wind blows towards.
+const SIMULATED_WIND_COURSE: f32 = 70.0;
+
+/// A barometer reading as recorded, before it's turned into an altitude.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    pressure: f32,
+    temperature: f32,
+    humidity: Option<f32>,
+}
+
+pub struct Replay {
+    /// GPS and barometer records still to come, oldest first.
+    entries: VecDeque<Entry>,
+    /// When playback started.
+    started: Instant,
+    /// Recording time, in milliseconds, that lines up with `started`.
+    start_time: u64,
+    gps: Option<Nmea>,
+    latest: Option<Sample>,
+    /// Barometer readings played back since they were last read, with when they were taken.
+    ///
+    /// Like the sensor's FIFO, the oldest ones are dropped once it's full.
+    pending: VecDeque<(Instant, Sample)>,
+    /// Pressure at the altitude datum, in pascals.
+    qnh: f32,
+}
+
+impl Replay {
+    /// Plays back `entries`, starting from the first one now.
+    pub fn new(entries: impl IntoIterator<Item = Entry>) -> Self {
+        let entries: VecDeque<Entry> = entries
+            .into_iter()
+            .filter(|entry| matches!(entry.record, Record::Gps { .. } | Record::Barometer { .. }))
+            .collect();
+
+        Self {
+            start_time: entries.front().map_or(0, |entry| entry.time),
+            entries,
+            started: Instant::now(),
+            gps: None,
+            latest: None,
+            pending: VecDeque::new(),
+            qnh: STANDARD_PRESSURE,
+        }
+    }
+
+    /// Plays back a flight recorder file, skipping any lines that can't be read.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RecorderError> {
+        let mut entries = Vec::new();
+        let mut skipped = 0;
+
+        for entry in Reader::open(path)? {
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(RecorderError::Parse { .. }) => skipped += 1,
+                Err(err) => return Err(err),
+            }
+        }
+
+        if skipped > 0 {
+            warn!("Skipped {skipped} unreadable lines in the recording");
+        }
+
+        Ok(Self::new(entries))
+    }
+
+    /// Makes up a flight launched from `latitude`, `longitude`: a couple of minutes on the pad,
+    /// a steady climb to burst at 30 km, then down under the parachute, drifting with a constant wind.
+    pub fn simulated(latitude: f64, longitude: f64) -> Self {
+        let mut entries = Vec::new();
+        let (mut latitude, mut longitude) = (latitude, longitude);
+        let mut altitude = SIMULATED_LAUNCH_ALTITUDE;
+        let mut ascending = true;
+        let mut landed_at = None;
+        let dt = SIMULATED_INTERVAL as f32 / 1000.0;
+
+        for time in (0..).step_by(SIMULATED_INTERVAL as usize) {
+            let flying = time >= SIMULATED_GROUND_TIME * 1000 && landed_at.is_none();
+
+            if flying {
+                if ascending {
+                    altitude += SIMULATED_ASCENT_RATE * dt;
+                    ascending = altitude < SIMULATED_BURST_ALTITUDE;
+                } else {
+                    // Falls faster in thinner air, with the drag balancing the same weight.
+                    let density = altitude::standard_density(altitude) / altitude::standard_density(0.0);
+                    altitude -= SIMULATED_DESCENT_RATE / density.sqrt() * dt;
+
+                    if altitude <= SIMULATED_LAUNCH_ALTITUDE {
+                        altitude = SIMULATED_LAUNCH_ALTITUDE;
+                        landed_at = Some(time);
+                    }
+                }
+
+                let drift = (SIMULATED_WIND_SPEED * dt) as f64;
+                let course = (SIMULATED_WIND_COURSE as f64).to_radians();
+                (latitude, longitude) = predict::offset_by(latitude, longitude, drift * course.sin(), drift * course.cos());
+            }
+
+            entries.push(Entry {
+                time,
+                record: Record::Gps {
+                    latitude: Some(latitude),
+                    longitude: Some(longitude),
+                    altitude: Some(altitude),
+                    speed: Some(if flying { SIMULATED_WIND_SPEED / KNOTS_TO_MPS } else { 0.0 }),
+                    course: flying.then_some(SIMULATED_WIND_COURSE),
+                    satellites: Some(8),
+                },
+            });
+            entries.push(Entry {
+                time,
+                record: Record::Barometer {
+                    pressure: altitude::standard_pressure(altitude),
+                    // The standard atmosphere's lapse rate, down to the tropopause
+                    temperature: (15.0 - 0.0065 * altitude).max(-56.5),
+                    humidity: None,
+                    altitude,
+                },
+            });
+
+            if landed_at.is_some_and(|landed_at| time >= landed_at + SIMULATED_GROUND_TIME * 1000) {
+                break;
+            }
+        }
+
+        Self::new(entries)
+    }
+
+    /// The latest GPS fix, or `None` before the first one.
+    ///
+    /// Fixes are timestamped now rather than when they were recorded,
+    /// so transmit slots still line up with the clock.
+    pub fn gps(&mut self) -> Option<Nmea> {
+        self.advance(Instant::now());
+
+        let mut fix = self.gps.clone()?;
+        let now = Utc::now();
+        fix.fix_time = Some(now.time());
+        fix.fix_date = Some(now.date_naive());
+
+        Some(fix)
+    }
+
+    /// The latest barometer reading, or `None` before the first one.
+    pub fn read(&mut self) -> Option<AltimeterData> {
+        self.advance(Instant::now());
+
+        self.latest.map(|sample| self.altimeter_data(sample))
+    }
+
+    /// Every barometer reading played back since the last call, oldest first.
+    pub fn read_all(&mut self) -> Vec<TimedReading> {
+        let now = Instant::now();
+        self.advance(now);
+
+        let pending: Vec<_> = self.pending.drain(..).collect();
+        pending
+            .into_iter()
+            .map(|(at, sample)| TimedReading {
+                age: now.saturating_duration_since(at),
+                data: self.altimeter_data(sample),
+            })
+            .collect()
+    }
+
+    /// Sets the pressure altitudes are measured from, in pascals.
+    pub fn set_qnh(&mut self, qnh: f32) {
+        self.qnh = qnh;
+    }
+
+    pub fn qnh(&self) -> f32 {
+        self.qnh
+    }
+
+    /// Whether everything has been played back. The last readings carry on being returned after that.
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Plays back everything recorded up to `now`.
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.started).as_millis() as u64;
+
+        while let Some(entry) = self.entries.front() {
+            let offset = entry.time.saturating_sub(self.start_time);
+            if offset > elapsed {
+                break;
+            }
+
+            let at = self.started + Duration::from_millis(offset);
+            match self.entries.pop_front().map(|entry| entry.record) {
+                Some(Record::Gps {
+                    latitude,
+                    longitude,
+                    altitude,
+                    speed,
+                    course,
+                    satellites,
+                }) => {
+                    let mut fix = Nmea::default();
+                    fix.latitude = latitude;
+                    fix.longitude = longitude;
+                    fix.altitude = altitude;
+                    fix.speed_over_ground = speed;
+                    fix.true_course = course;
+                    fix.num_of_fix_satellites = satellites;
+
+                    self.gps = Some(fix);
+                }
+                Some(Record::Barometer {
+                    pressure,
+                    temperature,
+                    humidity,
+                    ..
+                }) => {
+                    let sample = Sample {
+                        pressure,
+                        temperature,
+                        humidity,
+                    };
+
+                    self.latest = Some(sample);
+                    if self.pending.len() == MAX_FIFO_READINGS {
+                        self.pending.pop_front();
+                    }
+                    self.pending.push_back((at, sample));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn altimeter_data(&self, sample: Sample) -> AltimeterData {
+        AltimeterData {
+            pressure: sample.pressure,
+            temperature: sample.temperature,
+            altitude: altitude::altitude(sample.pressure, self.qnh),
+            humidity: sample.humidity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gps(time: u64, latitude: f64) -> Entry {
+        Entry {
+            time,
+            record: Record::Gps {
+                latitude: Some(latitude),
+                longitude: Some(-117.0),
+                altitude: Some(100.0),
+                speed: None,
+                course: None,
+                satellites: Some(7),
+            },
+        }
+    }
+
+    fn baro(time: u64, pressure: f32) -> Entry {
+        Entry {
+            time,
+            record: Record::Barometer {
+                pressure,
+                temperature: 20.0,
+                humidity: None,
+                altitude: 0.0,
+            },
+        }
+    }
+
+    #[test]
+    fn plays_records_back_as_they_come_due() {
+        let start = 1_718_000_000_000;
+        let mut replay = Replay::new([
+            gps(start, 33.0),
+            baro(start, 100_000.0),
+            Entry {
+                time: start + 200,
+                record: Record::Phase("ascent".to_owned()),
+            },
+            baro(start + 500, 99_990.0),
+            baro(start + 1000, 99_980.0),
+            gps(start + 2000, 33.1),
+        ]);
+
+        replay.advance(replay.started + Duration::from_millis(1000));
+        assert_eq!(replay.gps().unwrap().latitude, Some(33.0));
+        let pressures: Vec<f32> = replay.read_all().iter().map(|reading| reading.data.pressure).collect();
+        assert_eq!(pressures, [100_000.0, 99_990.0, 99_980.0]);
+        assert!(replay.read_all().is_empty());
+        assert!(!replay.is_finished());
+
+        replay.advance(replay.started + Duration::from_millis(2000));
+        assert_eq!(replay.gps().unwrap().latitude, Some(33.1));
+        assert_eq!(replay.read().unwrap().pressure, 99_980.0);
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn nothing_without_records() {
+        let mut replay = Replay::new([]);
+
+        assert!(replay.gps().is_none());
+        assert!(replay.read().is_none());
+        assert!(replay.read_all().is_empty());
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn altitudes_follow_qnh() {
+        let mut replay = Replay::new([baro(0, 95_000.0)]);
+
+        let standard = replay.read().unwrap().altitude;
+        replay.set_qnh(95_000.0);
+
+        assert!((standard - altitude::pressure_altitude(95_000.0)).abs() < 0.01);
+        assert_eq!(replay.read().unwrap().altitude, 0.0);
+    }
+
+    #[test]
+    fn simulated_flight_goes_up_and_comes_down_downwind() {
+        let replay = Replay::simulated(33.0, -117.0);
+
+        let fixes: Vec<(f64, f64, f32)> = replay
+            .entries
+            .iter()
+            .filter_map(|entry| match entry.record {
+                Record::Gps {
+                    latitude: Some(latitude),
+                    longitude: Some(longitude),
+                    altitude: Some(altitude),
+                    ..
+                } => Some((latitude, longitude, altitude)),
+                _ => None,
+            })
+            .collect();
+
+        let peak = fixes.iter().map(|fix| fix.2).fold(0.0, f32::max);
+        let (latitude, longitude, altitude) = *fixes.last().unwrap();
+
+        assert!(peak >= SIMULATED_BURST_ALTITUDE);
+        assert_eq!(altitude, SIMULATED_LAUNCH_ALTITUDE);
+        // Blown east-northeast
+        assert!(latitude > 33.0 && longitude > -117.0);
+        assert!(longitude + 117.0 > latitude - 33.0);
+    }
+}
@@ -1,41 +1,111 @@
-use std::{
-    hint,
-    io::{self, ErrorKind, Read, Write},
-    time::{Duration, SystemTime},
+use alloc::rc::Rc;
+use core::{
+    array,
+    cell::{RefCell, RefMut},
+    fmt,
+    time::Duration,
 };
 
-use rpi_embedded::i2c::{self, I2c};
+use embedded_hal::{delay::DelayNs, digital, i2c::I2c};
+use embedded_io::{ErrorKind, ReadReady};
+use heapless::Vec;
+use log::debug;
+use thiserror::Error;
 
-const LCR: u8 = 0x05;
+use crate::{
+    bus::Registers,
+    timeout::{self, CancelToken, Interrupted, Timeout},
+};
+
+// General registers
 const THR_RHR: u8 = 0x00;
-const RX_LVL: u8 = 0x09;
-const IOCONTROL: u8 = 0x0E;
+const IER: u8 = 0x01;
 const FCR: u8 = 0x02;
+const IIR: u8 = 0x02;
+const LCR: u8 = 0x03;
 const MCR: u8 = 0x04;
+const LSR: u8 = 0x05;
+const SPR: u8 = 0x07;
+const TX_LVL: u8 = 0x08;
+const RX_LVL: u8 = 0x09;
+const IODIR: u8 = 0x0A;
+const IOSTATE: u8 = 0x0B;
+const IOINTENA: u8 = 0x0C;
+const IOCONTROL: u8 = 0x0E;
+// Only while LCR[7] is set
 const DLL: u8 = 0x00;
 const DLH: u8 = 0x01;
-const SPR: u8 = 0x07;
+// Only while MCR[2] and EFR[4] are set
+const TCR: u8 = 0x06;
+const TLR: u8 = 0x07;
+// Only while LCR is 0xBF
+const EFR: u8 = 0x02;
+const XON1: u8 = 0x04;
+const XON2: u8 = 0x05;
+const XOFF1: u8 = 0x06;
+const XOFF2: u8 = 0x07;
+
+/// LCR value that opens up the enhanced register set.
+const ENHANCED_ACCESS: u8 = 0xBF;
+const LCR_DIVISOR_LATCH: u8 = 0x80;
+const MCR_TCR_TLR_ENABLE: u8 = 0x04;
+const MCR_CLOCK_DIVISOR: u8 = 0x80;
+const EFR_ENHANCED_FUNCTIONS: u8 = 0x10;
+const EFR_AUTO_RTS: u8 = 0x40;
+const EFR_AUTO_CTS: u8 = 0x80;
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_RX_FIFO_RESET: u8 = 0x02;
+const FCR_TX_FIFO_RESET: u8 = 0x04;
+const IOCONTROL_SOFT_RESET: u8 = 0x08;
+/// GPIO[7:4] are channel A's modem pins instead of GPIOs.
+const IOCONTROL_MODEM_A: u8 = 0x02;
+/// GPIO[3:0] are channel B's modem pins instead of GPIOs.
+const IOCONTROL_MODEM_B: u8 = 0x04;
 
-pub struct SC16IS752 {
-    i2c: I2c,
+/// Number of GPIO pins on the bridge.
+pub const GPIO_PINS: u8 = 8;
+
+/// Size of each channel's RX and TX FIFO.
+const FIFO_SIZE: usize = 64;
+/// RX FIFO level flow control holds off the sender at, by deasserting RTS or sending XOFF, in multiples of 4.
+const HALT_LEVEL: u8 = 48;
+/// RX FIFO level the sender is let go again at.
+const RESUME_LEVEL: u8 = 16;
+
+/// Time to wait between polls of the FIFO levels while there's nothing to do.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Longest a write waits for room in the TX FIFO, e.g. while CTS is held off.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct SC16IS752<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+    /// Of channels A and B, for charging the time received data takes to arrive to read timeouts.
+    baud_rates: [u32; 2],
 }
 
-impl SC16IS752 {
-    pub fn begin(
-        addr: u16,
+impl<I2C: I2c, D: DelayNs> SC16IS752<I2C, D> {
+    /// Sets up both channels of the bridge at `addr`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_bus(
+        i2c: I2C,
+        delay: D,
+        addr: u8,
         baud_a: u32,
         baud_b: u32,
         crystal_freq: u32,
         data_length: DataLength,
         parity: Parity,
         stop_length: StopLength,
-    ) -> i2c::Result<Self> {
-        let mut i2c = I2c::with_bus(1)?;
-        // Use 0x4D when both A0 and A1 are connected to ground
-        i2c.set_slave_address(addr)?;
-
-        let mut this = Self { i2c };
-        this.ping();
+    ) -> Result<Self, Error<I2C::Error>> {
+        let mut this = Self {
+            i2c,
+            delay,
+            address: addr,
+            baud_rates: [baud_a, baud_b],
+        };
+        this.ping()?;
         this.reset()?;
         this.fifo_enable(Channel::A)?;
         this.fifo_enable(Channel::B)?;
@@ -47,52 +117,52 @@ impl SC16IS752 {
         Ok(this)
     }
 
-    pub fn reset(&mut self) -> i2c::Result<()> {
+    pub fn reset(&mut self) -> Result<(), Error<I2C::Error>> {
         let mut reg = self.read_reg(Channel::Both, IOCONTROL)?;
-        reg |= 0x08;
-        self.write_reg(Channel::Both, IOCONTROL, reg)?;
+        reg |= IOCONTROL_SOFT_RESET;
+        // The bridge resets before it gets to ACK this, so an error is expected.
+        let _ = self.write_reg(Channel::Both, IOCONTROL, reg);
 
         Ok(())
     }
 
-    fn fifo_enable(&mut self, channel: Channel) -> i2c::Result<()> {
-        let mut fcr = self.read_reg(channel, FCR)?;
-        fcr |= 0x01;
-        self.write_reg(channel, FCR, fcr)?;
+    /// Enables and clears both FIFOs. FCR is write only, so this also resets the trigger levels.
+    fn fifo_enable(&mut self, channel: Channel) -> Result<(), Error<I2C::Error>> {
+        self.write_reg(channel, FCR, FCR_FIFO_ENABLE | FCR_RX_FIFO_RESET | FCR_TX_FIFO_RESET)?;
 
         Ok(())
     }
 
-    pub fn set_baudrate(&mut self, channel: Channel, baud: u32, crystal_freq: u32) -> i2c::Result<()> {
-        let prescaler = if self.read_reg(channel, MCR)? & 0x80 == 0 {
+    pub fn set_baudrate(&mut self, channel: Channel, baud: u32, crystal_freq: u32) -> Result<(), Error<I2C::Error>> {
+        let prescaler = if self.read_reg(channel, MCR)? & MCR_CLOCK_DIVISOR == 0 {
             1
         } else {
             4
         };
-        let divisor1 = crystal_freq / prescaler;
-        let divisor2 = baud * 16;
-
-        if divisor2 > divisor1 {
-            return Err(i2c::Error::Io(io::Error::new(
-                ErrorKind::InvalidInput,
-                "the specified baud rate is not valid",
-            )));
+
+        // Round to the nearest divisor rather than up, to keep the error as small as possible.
+        let clock = crystal_freq / prescaler;
+        let divisor = (clock + baud * 8) / (baud * 16);
+        if divisor == 0 || divisor > u16::MAX as u32 {
+            return Err(Error::InvalidBaudRate(baud));
         }
 
-        let wk = (divisor1 as f64) / (divisor2 as f64);
-        let divisor = wk.ceil() as u16;
+        debug!("{channel:?} baud rate divisor: {divisor}");
 
-        println!("divisor: {divisor}");
+        let lcr = self.read_reg(channel, LCR)?;
+        self.write_reg(channel, LCR, lcr | LCR_DIVISOR_LATCH)?;
 
-        let mut lcr = self.read_reg(channel, LCR)?;
-        lcr |= 0x80;
-        self.write_reg(channel, LCR, lcr)?;
+        let [low, high] = (divisor as u16).to_le_bytes();
+        self.write_reg(channel, DLL, low)?;
+        self.write_reg(channel, DLH, high)?;
 
-        self.write_reg(channel, DLL, (divisor & 0xFF) as u8)?;
-        self.write_reg(channel, DLH, (divisor << 8) as u8)?;
+        self.write_reg(channel, LCR, lcr & !LCR_DIVISOR_LATCH)?;
 
-        lcr &= 0x7F;
-        self.write_reg(channel, LCR, lcr)?;
+        match channel {
+            Channel::A => self.baud_rates[0] = baud,
+            Channel::B => self.baud_rates[1] = baud,
+            Channel::Both => self.baud_rates = [baud; 2],
+        }
 
         Ok(())
     }
@@ -103,7 +173,7 @@ impl SC16IS752 {
         data_length: DataLength,
         parity: Parity,
         stop_length: StopLength,
-    ) -> i2c::Result<()> {
+    ) -> Result<(), Error<I2C::Error>> {
         let mut lcr = self.read_reg(channel, LCR)?;
         lcr &= 0xC0;
 
@@ -123,8 +193,8 @@ impl SC16IS752 {
             Parity::None => {},
             Parity::Odd => lcr |= 0x08,
             Parity::Even => lcr |= 0x18,
-            Parity::One => lcr |= 0x03,
-            Parity::Zero => {},
+            Parity::One => lcr |= 0x28,
+            Parity::Zero => lcr |= 0x38,
         }
 
         self.write_reg(channel, LCR, lcr)?;
@@ -132,107 +202,624 @@ impl SC16IS752 {
         Ok(())
     }
 
-    pub fn write_byte(&mut self, channel: Channel, byte: u8) -> i2c::Result<()> {
-        while self.read_reg(channel, LCR)? & 0x20 == 0 {}
-        self.write_reg(channel, THR_RHR, byte)?;
+    /// Applies software flow control to both directions.
+    ///
+    /// The bridge sends XOFF once its RX FIFO holds 48 bytes and XON once it's back down to 16,
+    /// and holds off transmitting between receiving them.
+    pub fn set_control_flow(&mut self, channel: Channel, control_flow: ControlFlow) -> Result<(), Error<I2C::Error>> {
+        let (mode, xon1, xon2, xoff1, xoff2) = match control_flow {
+            ControlFlow::None => (0b0000, 0, 0, 0, 0),
+            ControlFlow::Xon1Xoff1 { xon1, xoff1 } => (0b1010, xon1, 0, xoff1, 0),
+            ControlFlow::Xon2Xoff2 { xon2, xoff2 } => (0b0101, 0, xon2, 0, xoff2),
+            ControlFlow::Xon12Xoff12 { xon1, xon2, xoff1, xoff2 } => (0b1111, xon1, xon2, xoff1, xoff2),
+        };
 
-        Ok(())
+        self.set_halt_levels(channel)?;
+
+        self.with_enhanced_registers(channel, |this| {
+            this.write_reg(channel, XON1, xon1)?;
+            this.write_reg(channel, XON2, xon2)?;
+            this.write_reg(channel, XOFF1, xoff1)?;
+            this.write_reg(channel, XOFF2, xoff2)?;
+
+            let efr = this.read_reg(channel, EFR)?;
+            this.write_reg(channel, EFR, efr & 0xF0 | EFR_ENHANCED_FUNCTIONS | mode)
+        })
     }
 
-    pub fn write(&mut self, channel: Channel, buf: &[u8]) -> i2c::Result<()> {
-        for byte in buf {
-            self.write_byte(channel, *byte)?;
-        }
+    /// Enables automatic RTS/CTS handshaking.
+    ///
+    /// With `rts` the bridge deasserts RTS once its RX FIFO fills past 48 bytes,
+    /// with `cts` it stops transmitting while CTS is deasserted.
+    pub fn set_hardware_flow_control(&mut self, channel: Channel, rts: bool, cts: bool) -> Result<(), Error<I2C::Error>> {
+        self.set_halt_levels(channel)?;
+
+        self.with_enhanced_registers(channel, |this| {
+            let mut efr = this.read_reg(channel, EFR)? & !(EFR_AUTO_RTS | EFR_AUTO_CTS);
+            if rts {
+                efr |= EFR_AUTO_RTS;
+            }
+            if cts {
+                efr |= EFR_AUTO_CTS;
+            }
+
+            this.write_reg(channel, EFR, efr)
+        })
+    }
+
+    /// Sets the RX FIFO levels that RTS or XOFF halt the sender at and RTS or XON resume it at.
+    ///
+    /// TCR shares its address with MSR and is only reachable with enhanced functions enabled
+    /// and MCR[2] set, which also switches SPR over to TLR. Leaving TLR at zero keeps the FCR trigger levels.
+    fn set_halt_levels(&mut self, channel: Channel) -> Result<(), Error<I2C::Error>> {
+        self.with_enhanced_registers(channel, |this| {
+            let efr = this.read_reg(channel, EFR)?;
+            this.write_reg(channel, EFR, efr | EFR_ENHANCED_FUNCTIONS)
+        })?;
+
+        let mcr = self.read_reg(channel, MCR)?;
+        self.write_reg(channel, MCR, mcr | MCR_TCR_TLR_ENABLE)?;
+        self.write_reg(channel, TCR, ((RESUME_LEVEL / 4) << 4) | (HALT_LEVEL / 4))?;
+        self.write_reg(channel, TLR, 0x00)?;
+        self.write_reg(channel, MCR, mcr & !MCR_TCR_TLR_ENABLE)?;
 
         Ok(())
     }
 
-    pub fn read_byte(&self, channel: Channel) -> i2c::Result<u8> {
-        if self.available(channel)? == 0 {
-            return Err(i2c::Error::Io(io::Error::new(
-                ErrorKind::WouldBlock,
-                "no data ready in the FIFO buffer",
-            )));
-        }
-        let byte = self.read_reg(channel, THR_RHR)?;
+    /// Runs `f` with the enhanced register set (EFR, XON and XOFF) switched in, restoring LCR afterwards.
+    fn with_enhanced_registers<T>(
+        &mut self,
+        channel: Channel,
+        f: impl FnOnce(&mut Self) -> Result<T, Error<I2C::Error>>,
+    ) -> Result<T, Error<I2C::Error>> {
+        let lcr = self.read_reg(channel, LCR)?;
+        self.write_reg(channel, LCR, ENHANCED_ACCESS)?;
 
-        Ok(byte)
+        let result = f(self);
+
+        self.write_reg(channel, LCR, lcr)?;
+
+        result
     }
 
-    /// Reads bytes until a newline is reached.
-    /// This method will block waiting for new input,
-    pub fn read_line(&self, channel: Channel) -> i2c::Result<String> {
-        let mut bytes = Vec::new();
+    /// Writes all of `buf`, as fast as the TX FIFO empties.
+    pub fn write(&mut self, channel: Channel, buf: &[u8]) -> Result<(), Error<I2C::Error>> {
+        let mut remaining = buf;
+        let mut timeout = Timeout::after(WRITE_TIMEOUT);
 
-        loop {
-            if self.available(channel)? > 0 {
-                let byte = self.read_byte(channel)?;
-                if byte == b'\n' {
-                    break;
+        while !remaining.is_empty() {
+            let space = self.space(channel)?;
+            if space == 0 {
+                if timeout.has_expired() {
+                    return Err(Error::TimedOut);
                 }
 
-                bytes.push(self.read_byte(channel)?);
-            } else {
-                // Let the processor know that we are in a spin loop,
-                // but probably not for very long.
-                hint::spin_loop();
+                timeout.wait(&mut self.delay, POLL_INTERVAL);
+                continue;
             }
+
+            let (chunk, rest) = remaining.split_at(space.min(remaining.len()));
+            self.write_fifo(channel, chunk)?;
+            remaining = rest;
+            timeout = Timeout::after(WRITE_TIMEOUT);
         }
 
-        let string = match String::from_utf8(bytes) {
-            Ok(string) => string,
-            Err(err) => {
-                return Err(i2c::Error::Io(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "invalid UTF-8 in data",
-                )))
+        Ok(())
+    }
+
+    /// Waits for everything written to be shifted out onto the line.
+    pub fn flush(&mut self, channel: Channel) -> Result<(), Error<I2C::Error>> {
+        let mut timeout = Timeout::after(WRITE_TIMEOUT);
+
+        while !self.line_status(channel)?.transmitter_empty {
+            if timeout.has_expired() {
+                return Err(Error::TimedOut);
             }
-        };
 
-        return Ok(string);
+            timeout.wait(&mut self.delay, POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    /// Reads as many bytes as are waiting in the RX FIFO, up to the size of `buf`, without blocking.
+    pub fn read_available(&mut self, channel: Channel, buf: &mut [u8]) -> Result<usize, Error<I2C::Error>> {
+        let len = self.available(channel)?.min(buf.len());
+        if len > 0 {
+            self.read_fifo(channel, &mut buf[..len])?;
+        }
+
+        Ok(len)
+    }
+
+    /// Splits the bridge into independent handles for its two channels and GPIO pins.
+    pub fn split(self) -> Parts<I2C, D> {
+        let bridge = Rc::new(RefCell::new(self));
+
+        Parts {
+            a: UartChannel::new(bridge.clone(), Channel::A),
+            b: UartChannel::new(bridge.clone(), Channel::B),
+            pins: array::from_fn(|pin| GpioPin {
+                bridge: bridge.clone(),
+                pin: pin as u8,
+            }),
+        }
+    }
+
+    /// Makes `pin` an output, or an input if `output` is false.
+    ///
+    /// Pins 4-7 double as channel A's modem pins and 0-3 as channel B's,
+    /// so the whole group is switched over to GPIO.
+    pub fn set_gpio_direction(&mut self, pin: u8, output: bool) -> Result<(), Error<I2C::Error>> {
+        let mask = pin_mask(pin)?;
+
+        let modem = if pin >= 4 { IOCONTROL_MODEM_A } else { IOCONTROL_MODEM_B };
+        let iocontrol = self.read_reg(Channel::Both, IOCONTROL)?;
+        if iocontrol & modem > 0 {
+            self.write_reg(Channel::Both, IOCONTROL, iocontrol & !modem)?;
+        }
+
+        self.update_reg(Channel::Both, IODIR, mask, output)
+    }
+
+    /// Level of `pin`, whichever direction it is.
+    pub fn gpio_level(&mut self, pin: u8) -> Result<bool, Error<I2C::Error>> {
+        let mask = pin_mask(pin)?;
+
+        Ok(self.read_reg(Channel::Both, IOSTATE)? & mask > 0)
+    }
+
+    pub fn set_gpio_level(&mut self, pin: u8, high: bool) -> Result<(), Error<I2C::Error>> {
+        let mask = pin_mask(pin)?;
+
+        self.update_reg(Channel::Both, IOSTATE, mask, high)
+    }
+
+    /// Raises [`Interrupt::InputChange`] on both channels when input `pin` changes.
+    pub fn set_gpio_interrupt(&mut self, pin: u8, enabled: bool) -> Result<(), Error<I2C::Error>> {
+        let mask = pin_mask(pin)?;
+
+        self.update_reg(Channel::Both, IOINTENA, mask, enabled)
     }
 
-    pub fn read_with_timeout(&self, channel: Channel, timeout: Duration) -> i2c::Result<u8> {
-        let start = SystemTime::now();
+    /// Levels of all the GPIO pins, bit n being pin n. Reading this clears an input change interrupt.
+    pub fn gpio_state(&mut self) -> Result<u8, Error<I2C::Error>> {
+        self.read_reg(Channel::Both, IOSTATE)
+    }
+
+    /// Chooses which events pull the IRQ line low for `channel`.
+    pub fn configure_interrupts(&mut self, channel: Channel, config: &InterruptConfig) -> Result<(), Error<I2C::Error>> {
+        self.write_reg(channel, IER, config.register())
+    }
 
-        while self.available(channel)? > 0 {
-            if start.elapsed().unwrap() > timeout {
-                return Err(i2c::Error::Io(io::Error::new(
-                    ErrorKind::TimedOut,
-                    "timed out waiting for available bytes",
-                )));
+    /// The highest priority interrupt pending on `channel`, if any.
+    ///
+    /// Each source clears when it is serviced: RX ones by reading the FIFO, THR empty by writing
+    /// to it or reading IIR, line status by reading LSR and input changes by reading IOState.
+    pub fn interrupt(&mut self, channel: Channel) -> Result<Option<Interrupt>, Error<I2C::Error>> {
+        Ok(Interrupt::decode(self.read_reg(channel, IIR)?))
+    }
+
+    /// Interrupts pending on both channels, for working out why the IRQ line went low.
+    pub fn pending_interrupts(&mut self) -> Result<Vec<(Channel, Interrupt), 2>, Error<I2C::Error>> {
+        let mut pending = Vec::new();
+
+        for channel in [Channel::A, Channel::B] {
+            if let Some(interrupt) = self.interrupt(channel)? {
+                // One per channel, so there's always room.
+                let _ = pending.push((channel, interrupt));
             }
         }
 
-        self.read_reg(channel, THR_RHR)
+        Ok(pending)
     }
 
-    pub fn available(&self, channel: Channel) -> i2c::Result<usize> {
+    /// Number of bytes waiting in the RX FIFO.
+    pub fn available(&mut self, channel: Channel) -> Result<usize, Error<I2C::Error>> {
         self.read_reg(channel, RX_LVL)
             .map(|available| available as usize)
     }
 
-    fn read_reg(&self, channel: Channel, reg: u8) -> i2c::Result<u8> {
-        self.i2c.smbus_read_byte(reg << 3 | channel.select())
+    /// Number of bytes that can be written before the TX FIFO is full.
+    pub fn space(&mut self, channel: Channel) -> Result<usize, Error<I2C::Error>> {
+        self.read_reg(channel, TX_LVL)
+            .map(|space| space as usize)
+    }
+
+    pub fn line_status(&mut self, channel: Channel) -> Result<LineStatus, Error<I2C::Error>> {
+        let lsr = self.read_reg(channel, LSR)?;
+
+        Ok(LineStatus {
+            data_ready: lsr & 0x01 > 0,
+            overrun: lsr & 0x02 > 0,
+            parity_error: lsr & 0x04 > 0,
+            framing_error: lsr & 0x08 > 0,
+            break_interrupt: lsr & 0x10 > 0,
+            thr_empty: lsr & 0x20 > 0,
+            transmitter_empty: lsr & 0x40 > 0,
+            fifo_error: lsr & 0x80 > 0,
+        })
+    }
+
+    fn read_reg(&mut self, channel: Channel, reg: u8) -> Result<u8, Error<I2C::Error>> {
+        Ok(self.i2c.read_register(self.address, reg << 3 | channel.select())?)
+    }
+
+    fn write_reg(&mut self, channel: Channel, reg: u8, value: u8) -> Result<(), Error<I2C::Error>> {
+        self.i2c.write_register(self.address, reg << 3 | channel.select(), value)?;
+
+        Ok(())
+    }
+
+    /// Sets or clears the bits in `mask`, leaving the rest of the register alone.
+    fn update_reg(&mut self, channel: Channel, reg: u8, mask: u8, set: bool) -> Result<(), Error<I2C::Error>> {
+        let value = self.read_reg(channel, reg)?;
+        let value = if set { value | mask } else { value & !mask };
+
+        self.write_reg(channel, reg, value)
     }
 
-    fn write_reg(&mut self, channel: Channel, reg: u8, value: u8) -> i2c::Result<()> {
-        self.i2c.write(&[reg << 3 | channel.select(), value])?;
+    /// Burst reads from the RX FIFO. The register pointer doesn't move, so every byte comes from RHR.
+    fn read_fifo(&mut self, channel: Channel, buf: &mut [u8]) -> Result<(), Error<I2C::Error>> {
+        self.i2c.read_registers(self.address, THR_RHR << 3 | channel.select(), buf)?;
 
         Ok(())
     }
 
-    pub fn ping(&mut self) -> i2c::Result<()> {
+    /// Burst writes into the TX FIFO, which must have room for all of `buf`.
+    fn write_fifo(&mut self, channel: Channel, buf: &[u8]) -> Result<(), Error<I2C::Error>> {
+        let mut data = [0; FIFO_SIZE + 1];
+        data[0] = THR_RHR << 3 | channel.select();
+        data[1..=buf.len()].copy_from_slice(buf);
+
+        self.i2c.write(self.address, &data[..=buf.len()])?;
+
+        Ok(())
+    }
+
+    /// Checks the bridge is there by writing to the scratchpad register and reading it back.
+    pub fn ping(&mut self) -> Result<(), Error<I2C::Error>> {
         self.write_reg(Channel::A, SPR, 0x55)?;
 
         if self.read_reg(Channel::A, SPR)? != 0x55 {
-            panic!("failed on A SPR");
+            return Err(Error::NotResponding);
         }
 
         Ok(())
     }
 }
 
+fn pin_mask<E>(pin: u8) -> Result<u8, Error<E>> {
+    if pin >= GPIO_PINS {
+        return Err(Error::InvalidPin(pin));
+    }
+
+    Ok(1 << pin)
+}
+
+/// Events that can raise the IRQ line, set per channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct InterruptConfig {
+    /// The RX FIFO reached its trigger level, or data has been sitting in it for a while.
+    pub receive_data: bool,
+    /// The TX FIFO dropped below its trigger level.
+    pub transmit_empty: bool,
+    /// Overrun, parity, framing or break errors.
+    pub line_status: bool,
+    /// CTS, DSR, RI or CD changed.
+    pub modem_status: bool,
+}
+
+impl InterruptConfig {
+    fn register(&self) -> u8 {
+        (self.modem_status as u8) << 3
+            | (self.line_status as u8) << 2
+            | (self.transmit_empty as u8) << 1
+            | self.receive_data as u8
+    }
+}
+
+/// An interrupt source decoded from IIR, highest priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Interrupt {
+    /// A receive error, details are in [`SC16IS752::line_status`].
+    LineStatus,
+    /// Fewer bytes than the trigger level have been waiting in the RX FIFO for 4 character times.
+    ReceiveTimeout,
+    /// The RX FIFO reached its trigger level.
+    ReceiveData,
+    TransmitEmpty,
+    ModemStatus,
+    /// A GPIO input with its interrupt enabled changed.
+    InputChange,
+    /// An XOFF character was received.
+    Xoff,
+    /// CTS or RTS changed from active to inactive.
+    CtsRts,
+}
+
+impl Interrupt {
+    fn decode(iir: u8) -> Option<Self> {
+        // Bit 0 is clear while an interrupt is pending.
+        if iir & 0x01 > 0 {
+            return None;
+        }
+
+        match iir & 0x3E {
+            0x06 => Some(Interrupt::LineStatus),
+            0x0C => Some(Interrupt::ReceiveTimeout),
+            0x04 => Some(Interrupt::ReceiveData),
+            0x02 => Some(Interrupt::TransmitEmpty),
+            0x00 => Some(Interrupt::ModemStatus),
+            0x30 => Some(Interrupt::InputChange),
+            0x10 => Some(Interrupt::Xoff),
+            0x20 => Some(Interrupt::CtsRts),
+            _ => None,
+        }
+    }
+}
+
+/// Contents of the line status register.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct LineStatus {
+    /// At least one byte is waiting in the RX FIFO.
+    pub data_ready: bool,
+    /// Data arrived while the RX FIFO was full and was lost.
+    pub overrun: bool,
+    pub parity_error: bool,
+    pub framing_error: bool,
+    pub break_interrupt: bool,
+    /// The TX FIFO is empty.
+    pub thr_empty: bool,
+    /// The TX FIFO and shift register are both empty, so everything has been sent.
+    pub transmitter_empty: bool,
+    /// At least one byte in the RX FIFO has a parity, framing or break error.
+    pub fifo_error: bool,
+}
+
+/// Everything a split [`SC16IS752`] is made up of.
+pub struct Parts<I2C, D> {
+    pub a: UartChannel<I2C, D>,
+    pub b: UartChannel<I2C, D>,
+    pub pins: [GpioPin<I2C, D>; GPIO_PINS as usize],
+}
+
+/// One channel of a split [`SC16IS752`], usable as a serial port on its own.
+///
+/// Both channels share the bridge, so each transfer briefly borrows it.
+/// Reads wait forever for data unless given a timeout with [`UartChannel::set_read_timeout`].
+pub struct UartChannel<I2C, D> {
+    bridge: Rc<RefCell<SC16IS752<I2C, D>>>,
+    channel: Channel,
+    read_timeout: Option<Duration>,
+    cancel: Option<CancelToken>,
+}
+
+impl<I2C: I2c, D: DelayNs> UartChannel<I2C, D> {
+    fn new(bridge: Rc<RefCell<SC16IS752<I2C, D>>>, channel: Channel) -> Self {
+        Self {
+            bridge,
+            channel,
+            read_timeout: None,
+            cancel: None,
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    /// Sets how long a read waits for the first byte before failing with [`Error::TimedOut`].
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Lets `cancel` stop a read that is waiting for data, failing it with [`Error::Cancelled`].
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    /// Appends bytes to `buf` up to and including `delimiter`, giving up once `timeout` runs out.
+    /// This is instead of the read timeout, not on top of it.
+    ///
+    /// Whatever was received before timing out or being cancelled is left in `buf`,
+    /// so calling again with the same buffer carries on where it stopped.
+    /// Stops early if `buf` fills up, so check whether it ends with `delimiter`.
+    pub fn read_until<const N: usize>(
+        &mut self,
+        delimiter: u8,
+        buf: &mut Vec<u8, N>,
+        timeout: Duration,
+    ) -> Result<usize, Error<I2C::Error>> {
+        // Borrowing for the whole line would starve the other channel.
+        let start = buf.len();
+        let mut timeout = Timeout::after(timeout);
+
+        while !buf.is_full() {
+            let mut byte = [0];
+            if self.read_with_timeout(&mut byte, &mut timeout)? > 0 {
+                // Can't fail, there's room.
+                let _ = buf.push(byte[0]);
+
+                if byte[0] == delimiter {
+                    break;
+                }
+            }
+
+            // Reads that get something don't check, so a sender that never stops would go on forever.
+            timeout::check(&timeout, self.cancel.as_ref()).map_err(Error::interrupted)?;
+        }
+
+        Ok(buf.len() - start)
+    }
+
+    fn read_timeout(&self) -> Timeout {
+        self.read_timeout.map_or(Timeout::NEVER, Timeout::after)
+    }
+
+    fn read_with_timeout(&mut self, buf: &mut [u8], timeout: &mut Timeout) -> Result<usize, Error<I2C::Error>> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let mut bridge = self.bridge();
+            let len = bridge.read_available(self.channel, buf)?;
+            if len > 0 {
+                let baud_rate = bridge.baud_rates[if self.channel == Channel::B { 1 } else { 0 }];
+                timeout.charge(timeout::transfer_time(len, baud_rate));
+
+                return Ok(len);
+            }
+            drop(bridge);
+
+            timeout::check(timeout, self.cancel.as_ref()).map_err(Error::interrupted)?;
+            timeout.wait(&mut self.bridge().delay, POLL_INTERVAL);
+        }
+    }
+
+    fn bridge(&self) -> RefMut<'_, SC16IS752<I2C, D>> {
+        self.bridge.borrow_mut()
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> embedded_io::ErrorType for UartChannel<I2C, D> {
+    type Error = Error<I2C::Error>;
+}
+
+impl<I2C: I2c, D: DelayNs> embedded_io::Read for UartChannel<I2C, D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut timeout = self.read_timeout();
+        self.read_with_timeout(buf, &mut timeout)
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> ReadReady for UartChannel<I2C, D> {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.bridge().available(self.channel)? > 0)
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> embedded_io::Write for UartChannel<I2C, D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.bridge().write(self.channel, buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.bridge().flush(self.channel)
+    }
+}
+
+/// One of the bridge's GPIO pins, usable through embedded-hal like any other pin.
+///
+/// Pins start out as inputs, call [`GpioPin::set_output`] before driving one.
+pub struct GpioPin<I2C, D> {
+    bridge: Rc<RefCell<SC16IS752<I2C, D>>>,
+    pin: u8,
+}
+
+impl<I2C: I2c, D: DelayNs> GpioPin<I2C, D> {
+    pub fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    pub fn set_output(&mut self) -> Result<(), Error<I2C::Error>> {
+        self.bridge().set_gpio_direction(self.pin, true)
+    }
+
+    pub fn set_input(&mut self) -> Result<(), Error<I2C::Error>> {
+        self.bridge().set_gpio_direction(self.pin, false)
+    }
+
+    pub fn set_interrupt(&mut self, enabled: bool) -> Result<(), Error<I2C::Error>> {
+        self.bridge().set_gpio_interrupt(self.pin, enabled)
+    }
+
+    fn bridge(&self) -> RefMut<'_, SC16IS752<I2C, D>> {
+        self.bridge.borrow_mut()
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> digital::ErrorType for GpioPin<I2C, D> {
+    type Error = Error<I2C::Error>;
+}
+
+impl<I2C: I2c, D: DelayNs> digital::OutputPin for GpioPin<I2C, D> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.bridge().set_gpio_level(self.pin, false)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.bridge().set_gpio_level(self.pin, true)
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> digital::StatefulOutputPin for GpioPin<I2C, D> {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        self.bridge().gpio_level(self.pin)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.bridge().gpio_level(self.pin)?)
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> digital::InputPin for GpioPin<I2C, D> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.bridge().gpio_level(self.pin)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.bridge().gpio_level(self.pin)?)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error("I2C error: {0}")]
+    I2C(#[from] E),
+    #[error("baud rate {0} is too high for the crystal")]
+    InvalidBaudRate(u32),
+    #[error("timed out waiting for available bytes")]
+    TimedOut,
+    #[error("cancelled while waiting for available bytes")]
+    Cancelled,
+    #[error("bridge didn't read back the scratchpad register")]
+    NotResponding,
+    #[error("there is no GPIO pin {0}")]
+    InvalidPin(u8),
+}
+
+impl<E> Error<E> {
+    fn interrupted(value: Interrupted) -> Self {
+        match value {
+            Interrupted::TimedOut => Error::TimedOut,
+            Interrupted::Cancelled => Error::Cancelled,
+        }
+    }
+}
+
+impl<E: fmt::Debug> embedded_io::Error for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::I2C(_) => ErrorKind::Other,
+            Error::InvalidBaudRate(_) => ErrorKind::InvalidInput,
+            Error::TimedOut => ErrorKind::TimedOut,
+            Error::Cancelled => ErrorKind::Interrupted,
+            Error::NotResponding => ErrorKind::NotConnected,
+            Error::InvalidPin(_) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl<E: fmt::Debug> digital::Error for Error<E> {
+    fn kind(&self) -> digital::ErrorKind {
+        digital::ErrorKind::Other
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ControlFlow {
     None,
@@ -291,3 +878,257 @@ pub enum StopLength {
     One,
     Two,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use embedded_io::{Read as _, Write as _};
+
+    use super::*;
+    use crate::bus::mock::{MockBus, MockDelay};
+
+    const ADDRESS: u8 = 0x4D;
+    const CRYSTAL: u32 = 1_843_200;
+
+    /// Sub-address of `reg` on `channel`, as sent on the bus.
+    fn sub(reg: u8, channel: Channel) -> u8 {
+        reg << 3 | channel.select()
+    }
+
+    fn bus() -> MockBus {
+        let mut bus = MockBus::new();
+        // The bridge's register pointer never moves on by itself.
+        bus.add_device(ADDRESS).set_auto_increment(ADDRESS, false);
+        bus
+    }
+
+    fn bridge(bus: MockBus) -> SC16IS752<MockBus, MockDelay> {
+        SC16IS752::with_bus(bus, MockDelay::default(), ADDRESS, 9600, 4800, CRYSTAL, DataLength::D8, Parity::None, StopLength::One).unwrap()
+    }
+
+    #[test]
+    fn init_sets_up_both_channels() {
+        let bridge = bridge(bus());
+
+        let expected: Vec<Vec<u8>> = vec![
+            vec![sub(SPR, Channel::A), 0x55],
+            vec![sub(IOCONTROL, Channel::Both), IOCONTROL_SOFT_RESET],
+            vec![0x10, 0x07],
+            vec![0x12, 0x07],
+            // 1.8432 MHz / 16 / 9600 = 12
+            vec![0x18, 0x80],
+            vec![0x00, 12],
+            vec![0x08, 0],
+            vec![0x18, 0x00],
+            // and 24 for 4800
+            vec![0x1A, 0x80],
+            vec![0x02, 24],
+            vec![0x0A, 0],
+            vec![0x1A, 0x00],
+            // 8N1
+            vec![0x18, 0x03],
+            vec![0x1A, 0x03],
+        ];
+        assert_eq!(bridge.i2c.writes(ADDRESS), expected);
+    }
+
+    #[test]
+    fn line_settings_keep_the_top_lcr_bits() {
+        let mut bridge = bridge(bus());
+        bridge.i2c.set_register(ADDRESS, sub(LCR, Channel::B), 0x43);
+        bridge.i2c.clear_transactions();
+
+        bridge.set_line(Channel::B, DataLength::D7, Parity::Even, StopLength::Two).unwrap();
+
+        assert_eq!(bridge.i2c.writes(ADDRESS), [[0x1A, 0x40 | 0x02 | 0x04 | 0x18]]);
+    }
+
+    #[test]
+    fn baud_rate_accounts_for_the_clock_divisor() {
+        let mut bridge = bridge(bus());
+        bridge.i2c.set_register(ADDRESS, sub(MCR, Channel::A), MCR_CLOCK_DIVISOR);
+        bridge.i2c.clear_transactions();
+
+        bridge.set_baudrate(Channel::A, 1200, CRYSTAL).unwrap();
+
+        assert_eq!(bridge.i2c.writes(ADDRESS)[1..3], [[0x00, 24], [0x08, 0]]);
+        assert!(matches!(bridge.set_baudrate(Channel::A, 1_000_000, CRYSTAL), Err(Error::InvalidBaudRate(1_000_000))));
+    }
+
+    #[test]
+    fn missing_bridge_is_not_responding() {
+        let mut bus = bus();
+        bus.script_reads(ADDRESS, sub(SPR, Channel::A), &[0xff]);
+
+        let result = SC16IS752::with_bus(bus, MockDelay::default(), ADDRESS, 9600, 9600, CRYSTAL, DataLength::D8, Parity::None, StopLength::One);
+        assert!(matches!(result, Err(Error::NotResponding)));
+    }
+
+    #[test]
+    fn writes_go_to_the_channel_fifo() {
+        let mut bridge = bridge(bus());
+        bridge.i2c.set_register(ADDRESS, sub(TX_LVL, Channel::B), 3);
+        bridge.i2c.clear_transactions();
+
+        bridge.write(Channel::B, b"hello").unwrap();
+
+        // Only as much as TX_LVL says there's room for goes at a time.
+        assert_eq!(bridge.i2c.writes(ADDRESS), [b"\x02hel".to_vec(), b"\x02lo".to_vec()]);
+    }
+
+    #[test]
+    fn write_times_out_on_a_full_fifo() {
+        let mut bridge = bridge(bus());
+
+        assert!(matches!(bridge.write(Channel::A, b"x"), Err(Error::TimedOut)));
+        assert_eq!(bridge.delay.waited_ns, WRITE_TIMEOUT.as_nanos() as u64);
+    }
+
+    #[test]
+    fn gpio_direction_frees_the_modem_pins() {
+        let mut bridge = bridge(bus());
+        bridge.i2c.set_register(ADDRESS, sub(IOCONTROL, Channel::Both), IOCONTROL_MODEM_A | IOCONTROL_MODEM_B);
+        bridge.i2c.clear_transactions();
+
+        bridge.set_gpio_direction(5, true).unwrap();
+        bridge.set_gpio_level(5, true).unwrap();
+
+        assert_eq!(
+            bridge.i2c.writes(ADDRESS),
+            [
+                [sub(IOCONTROL, Channel::Both), IOCONTROL_MODEM_B],
+                [sub(IODIR, Channel::Both), 0x20],
+                [sub(IOSTATE, Channel::Both), 0x20],
+            ]
+        );
+        assert!(matches!(bridge.set_gpio_direction(GPIO_PINS, true), Err(Error::InvalidPin(8))));
+    }
+
+    /// A bridge that's been set up for 8N1, with the registers that share an address with EFR cleared.
+    fn configured_bridge() -> SC16IS752<MockBus, MockDelay> {
+        let mut bridge = bridge(bus());
+        bridge.i2c.set_register(ADDRESS, sub(EFR, Channel::A), 0x00)
+            .set_register(ADDRESS, sub(EFR, Channel::B), 0x00)
+            .clear_transactions();
+
+        bridge
+    }
+
+    #[test]
+    fn software_flow_control_sets_halt_levels_and_characters() {
+        let mut bridge = configured_bridge();
+
+        bridge.set_control_flow(Channel::B, ControlFlow::Xon1Xoff1 { xon1: 0x11, xoff1: 0x13 }).unwrap();
+
+        assert_eq!(
+            bridge.i2c.writes(ADDRESS),
+            [
+                // Enhanced functions on, so MCR[2] can be set
+                [0x1A, ENHANCED_ACCESS],
+                [0x12, EFR_ENHANCED_FUNCTIONS],
+                [0x1A, 0x03],
+                // TCR and TLR only while MCR[2] is set: halt at 48 bytes, resume at 16
+                [0x22, MCR_TCR_TLR_ENABLE],
+                [0x32, 0x4C],
+                [0x3A, 0x00],
+                [0x22, 0x00],
+                // XON1, XON2, XOFF1 and XOFF2, then the mode in EFR[3:0]
+                [0x1A, ENHANCED_ACCESS],
+                [0x22, 0x11],
+                [0x2A, 0x00],
+                [0x32, 0x13],
+                [0x3A, 0x00],
+                [0x12, EFR_ENHANCED_FUNCTIONS | 0b1010],
+                [0x1A, 0x03],
+            ]
+        );
+    }
+
+    #[test]
+    fn hardware_flow_control_sets_halt_levels_and_auto_rts_cts() {
+        let mut bridge = configured_bridge();
+
+        bridge.set_hardware_flow_control(Channel::A, true, true).unwrap();
+
+        assert_eq!(
+            bridge.i2c.writes(ADDRESS),
+            [
+                [0x18, ENHANCED_ACCESS],
+                [0x10, EFR_ENHANCED_FUNCTIONS],
+                [0x18, 0x03],
+                [0x20, MCR_TCR_TLR_ENABLE],
+                [0x30, 0x4C],
+                [0x38, 0x00],
+                [0x20, 0x00],
+                [0x18, ENHANCED_ACCESS],
+                [0x10, EFR_AUTO_CTS | EFR_AUTO_RTS | EFR_ENHANCED_FUNCTIONS],
+                [0x18, 0x03],
+            ]
+        );
+
+        bridge.i2c.clear_transactions();
+        bridge.set_hardware_flow_control(Channel::A, false, true).unwrap();
+        assert_eq!(bridge.i2c.writes(ADDRESS)[8], [0x10, EFR_AUTO_CTS | EFR_ENHANCED_FUNCTIONS]);
+    }
+
+    #[test]
+    fn interrupts_are_enabled_and_decoded_per_channel() {
+        let mut bridge = configured_bridge();
+
+        let config = InterruptConfig {
+            receive_data: true,
+            line_status: true,
+            ..InterruptConfig::default()
+        };
+        bridge.configure_interrupts(Channel::B, &config).unwrap();
+        assert_eq!(bridge.i2c.writes(ADDRESS), [[sub(IER, Channel::B), 0x05]]);
+
+        // IIR bit 0 is set while nothing is pending.
+        bridge.i2c.set_register(ADDRESS, sub(IIR, Channel::A), 0x01)
+            .set_register(ADDRESS, sub(IIR, Channel::B), 0xC4);
+        assert_eq!(bridge.pending_interrupts().unwrap(), [(Channel::B, Interrupt::ReceiveData)]);
+
+        bridge.i2c.set_register(ADDRESS, sub(IIR, Channel::A), 0xF0)
+            .set_register(ADDRESS, sub(IIR, Channel::B), 0xCC);
+        assert_eq!(
+            bridge.pending_interrupts().unwrap(),
+            [(Channel::A, Interrupt::InputChange), (Channel::B, Interrupt::ReceiveTimeout)]
+        );
+    }
+
+    #[test]
+    fn channels_read_and_write_through_the_shared_bridge() {
+        let mut bus = bus();
+        bus.set_register(ADDRESS, sub(TX_LVL, Channel::A), 64)
+            .script_reads(ADDRESS, sub(RX_LVL, Channel::A), &[2, 0])
+            .script_reads(ADDRESS, sub(THR_RHR, Channel::A), b"$G");
+        let mut parts = bridge(bus).split();
+
+        parts.a.write_all(b"AT").unwrap();
+
+        let mut buf = [0; 8];
+        assert_eq!(parts.a.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"$G");
+
+        parts.a.set_read_timeout(Some(Duration::from_millis(5)));
+        assert!(matches!(parts.a.read(&mut buf), Err(Error::TimedOut)));
+    }
+
+    #[test]
+    fn read_until_times_out_on_data_that_never_ends() {
+        let mut bus = bus();
+        // Always another byte waiting, and never the delimiter.
+        bus.set_register(ADDRESS, sub(RX_LVL, Channel::A), 64)
+            .set_register(ADDRESS, sub(THR_RHR, Channel::A), b'x');
+        let mut parts = bridge(bus).split();
+
+        let mut line: heapless::Vec<u8, 4096> = heapless::Vec::new();
+        let result = parts.a.read_until(b'\n', &mut line, Duration::from_millis(100));
+
+        assert!(matches!(result, Err(Error::TimedOut)));
+        // About as much as 9600 baud gets through in that time, the rest is kept for next time.
+        assert!((95..=97).contains(&line.len()), "{}", line.len());
+        assert_eq!(parts.a.bridge().delay.waited_ns, 0);
+    }
+}
@@ -0,0 +1,569 @@
+//! Deciding what goes out in each transmit slot.
+//!
+//! Time is cut into fixed-length slots lined up with GPS time, so balloons sharing
+//! a frequency can be given slots that don't overlap. Every slot carries one packet,
+//! picked by priority: a position whenever one is due, then the landing prediction, telemetry and status,
+//! with image packets filling the rest in bursts so a picture goes out in minutes instead of hours.
+//!
+//! With a [`TimeSlot`] set we only transmit inside our own window each minute,
+//! the way slotted trackers share a frequency without stepping on each other.
+
+use std::{
+    collections::hash_map::{DefaultHasher, RandomState},
+    hash::{BuildHasher, Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveTime, Timelike};
+
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PacketKind {
+    Position,
+    /// Predicted landing site.
+    Prediction,
+    Telemetry,
+    Status,
+    Image,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SchedulerConfig {
+    /// Length of a slot, which should fit the longest packet with its TX delay and tail.
+    pub slot_length: Duration,
+    /// Longest time allowed between positions, they take priority over everything else.
+    pub position_interval: Duration,
+    pub prediction_interval: Duration,
+    pub telemetry_interval: Duration,
+    pub status_interval: Duration,
+    /// Image packets sent back to back before giving the channel a rest.
+    pub image_burst: usize,
+    /// Rest after an image burst.
+    pub image_rest: Duration,
+    /// Window to keep transmissions inside, `None` to transmit in any slot.
+    pub time_slot: Option<TimeSlot>,
+}
+
+/// A window at a fixed offset into every period of GPS time, e.g. 20-30 s past each minute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSlot {
+    /// Start of the window from the top of the period.
+    pub offset: Duration,
+    /// Length of the window, nothing gets sent that wouldn't be finished by the end of it.
+    pub length: Duration,
+    pub period: Duration,
+    /// Most the start of a window is randomly pushed back by, so trackers
+    /// with slightly off clocks don't start at exactly the same moment.
+    pub jitter: Duration,
+}
+
+impl TimeSlot {
+    /// A window `offset` into every minute.
+    pub fn new(offset: Duration, length: Duration) -> Self {
+        Self {
+            offset,
+            length,
+            period: Duration::from_secs(60),
+            jitter: Duration::from_millis(500),
+        }
+    }
+
+    /// Checks the window fits in the period and leaves room after the jitter.
+    pub fn is_valid(&self) -> bool {
+        !self.period.is_zero() && self.offset + self.length <= self.period && self.jitter < self.length
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            slot_length: Duration::from_secs(5),
+            position_interval: Duration::from_secs(60),
+            prediction_interval: Duration::from_secs(120),
+            telemetry_interval: Duration::from_secs(600),
+            status_interval: Duration::from_secs(1800),
+            image_burst: 8,
+            image_rest: Duration::from_secs(30),
+            time_slot: None,
+        }
+    }
+}
+
+/// The next packet to send and when to start sending it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    pub kind: PacketKind,
+    pub start: Instant,
+    /// When the transmission has to be over by.
+    pub end: Instant,
+}
+
+/// Maps the local clock onto GPS time of day.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    at: Instant,
+    /// Seconds since midnight UTC at `at`.
+    seconds: f64,
+}
+
+impl Clock {
+    fn seconds_at(&self, instant: Instant) -> f64 {
+        if instant >= self.at {
+            self.seconds + instant.duration_since(self.at).as_secs_f64()
+        } else {
+            self.seconds - self.at.duration_since(instant).as_secs_f64()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    config: SchedulerConfig,
+    clock: Clock,
+    synced: bool,
+    last_position: Option<Instant>,
+    /// Whether there's a landing prediction to send.
+    predicting: bool,
+    last_prediction: Option<Instant>,
+    last_telemetry: Option<Instant>,
+    last_status: Option<Instant>,
+    /// Image packets sent in the current burst.
+    burst: usize,
+    last_image: Option<Instant>,
+    /// End of a time slot window we've given up on.
+    closed_until: Option<Instant>,
+    /// Picks how far each window is pushed back by, the same however often it's looked at
+    /// but different for every tracker.
+    jitter_seed: u64,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            // Until there's a fix slots are lined up with startup instead.
+            clock: Clock {
+                at: Instant::now(),
+                seconds: 0.0,
+            },
+            synced: false,
+            last_position: None,
+            predicting: false,
+            last_prediction: None,
+            last_telemetry: None,
+            last_status: None,
+            burst: 0,
+            last_image: None,
+            closed_until: None,
+            jitter_seed: RandomState::new().hash_one(0),
+        }
+    }
+
+    pub fn config(&self) -> &SchedulerConfig {
+        &self.config
+    }
+
+    pub fn set_position_interval(&mut self, interval: Duration) {
+        self.config.position_interval = interval;
+    }
+
+    /// Starts or stops sending landing predictions.
+    pub fn set_predicting(&mut self, predicting: bool) {
+        self.predicting = predicting;
+    }
+
+    /// Whether slots are lined up with GPS time yet.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Lines the slots up with a GPS fix time, taken as having just been received.
+    pub fn sync(&mut self, time: NaiveTime) {
+        self.sync_at(time, Instant::now());
+    }
+
+    pub fn sync_at(&mut self, time: NaiveTime, at: Instant) {
+        let seconds = time.num_seconds_from_midnight() as f64 + time.nanosecond() as f64 / 1e9;
+
+        self.clock = Clock { at, seconds };
+        self.synced = true;
+    }
+
+    /// GPS time of day at `instant`, `None` before the first fix.
+    pub fn time_of_day(&self, instant: Instant) -> Option<Duration> {
+        self.synced
+            .then(|| Duration::from_secs_f64(self.clock.seconds_at(instant).rem_euclid(SECONDS_PER_DAY)))
+    }
+
+    /// Start and end of the first slot at or after `instant`.
+    ///
+    /// Inside a [`TimeSlot`] window packets go out back to back until it's used up.
+    /// Before the first fix windows are lined up with startup, which at least keeps us to our share of the channel.
+    pub fn slot_at(&self, instant: Instant) -> (Instant, Instant) {
+        let instant = match self.closed_until {
+            Some(closed) => instant.max(closed),
+            None => instant,
+        };
+        let seconds = self.clock.seconds_at(instant);
+
+        let Some(time_slot) = self.config.time_slot else {
+            let slot = self.config.slot_length.as_secs_f64();
+            let boundary = (seconds / slot).ceil() * slot;
+            let start = instant + Duration::from_secs_f64((boundary - seconds).max(0.0));
+
+            return (start, start + self.config.slot_length);
+        };
+
+        let period = time_slot.period.as_secs_f64();
+        let offset = time_slot.offset.as_secs_f64();
+        let into_window = (seconds - offset).rem_euclid(period);
+        // Counting windows from GPS midnight, or startup before there's a fix.
+        let index = ((seconds - offset - into_window) / period).round() as i64;
+
+        if into_window < time_slot.length.as_secs_f64() {
+            let window = instant - Duration::from_secs_f64(into_window);
+            let start = instant.max(window + self.jitter(&time_slot, index));
+
+            return (start, window + time_slot.length);
+        }
+
+        let window = instant + Duration::from_secs_f64(period - into_window);
+
+        (window + self.jitter(&time_slot, index + 1), window + time_slot.length)
+    }
+
+    /// How far the start of window number `index` is pushed back.
+    fn jitter(&self, time_slot: &TimeSlot, index: i64) -> Duration {
+        let max = time_slot.jitter.as_nanos() as u64;
+        if max == 0 {
+            return Duration::ZERO;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (self.jitter_seed, index).hash(&mut hasher);
+
+        Duration::from_nanos(hasher.finish() % max)
+    }
+
+    /// Gives up on the rest of the current window, e.g. when the next packet won't fit in it.
+    pub fn close_window(&mut self, now: Instant) {
+        self.closed_until = Some(self.slot_at(now).1);
+    }
+
+    /// Picks the next packet to send, `images_pending` being whether there's an image part way through.
+    pub fn next(&self, now: Instant, images_pending: bool) -> Slot {
+        let (start, end) = self.slot_at(now);
+        if let Some(kind) = self.due(start, images_pending) {
+            return Slot { kind, start, end };
+        }
+
+        // Nothing yet, wait for whatever comes up first.
+        let mut ready = [
+            (self.ready_at(self.last_position, self.config.position_interval), PacketKind::Position),
+            (self.ready_at(self.last_telemetry, self.config.telemetry_interval), PacketKind::Telemetry),
+            (self.ready_at(self.last_status, self.config.status_interval), PacketKind::Status),
+        ]
+        .to_vec();
+        if self.predicting {
+            ready.push((self.ready_at(self.last_prediction, self.config.prediction_interval), PacketKind::Prediction));
+        }
+        if images_pending {
+            ready.push((self.image_ready_at(), PacketKind::Image));
+        }
+
+        let (at, kind) = ready.into_iter().min().unwrap_or((start, PacketKind::Position));
+        let (start, end) = self.slot_at(at.max(start));
+
+        Slot { kind, start, end }
+    }
+
+    /// Makes a packet due straight away, e.g. a position after a sharp turn.
+    pub fn expedite(&mut self, kind: PacketKind) {
+        match kind {
+            PacketKind::Position => self.last_position = None,
+            PacketKind::Prediction => self.last_prediction = None,
+            PacketKind::Telemetry => self.last_telemetry = None,
+            PacketKind::Status => self.last_status = None,
+            PacketKind::Image => self.last_image = None,
+        }
+    }
+
+    /// Records a packet as sent, whether or not the radio managed to send it.
+    pub fn sent(&mut self, kind: PacketKind, at: Instant) {
+        match kind {
+            PacketKind::Position => self.last_position = Some(at),
+            PacketKind::Prediction => self.last_prediction = Some(at),
+            PacketKind::Telemetry => self.last_telemetry = Some(at),
+            PacketKind::Status => self.last_status = Some(at),
+            PacketKind::Image => {
+                if self.burst >= self.config.image_burst {
+                    self.burst = 0;
+                }
+                self.burst += 1;
+                self.last_image = Some(at);
+            }
+        }
+    }
+
+    fn due(&self, at: Instant, images_pending: bool) -> Option<PacketKind> {
+        if self.ready_at(self.last_position, self.config.position_interval) <= at {
+            return Some(PacketKind::Position);
+        }
+        if self.predicting && self.ready_at(self.last_prediction, self.config.prediction_interval) <= at {
+            return Some(PacketKind::Prediction);
+        }
+        if self.ready_at(self.last_telemetry, self.config.telemetry_interval) <= at {
+            return Some(PacketKind::Telemetry);
+        }
+        if self.ready_at(self.last_status, self.config.status_interval) <= at {
+            return Some(PacketKind::Status);
+        }
+        if images_pending && self.image_ready_at() <= at {
+            return Some(PacketKind::Image);
+        }
+
+        None
+    }
+
+    fn ready_at(&self, last: Option<Instant>, interval: Duration) -> Instant {
+        // Never sent, so it's overdue.
+        last.map_or(self.clock.at, |last| last + interval)
+    }
+
+    fn image_ready_at(&self) -> Instant {
+        match self.last_image {
+            Some(last) if self.burst >= self.config.image_burst => last + self.config.image_rest,
+            Some(last) => last,
+            None => self.clock.at,
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(SchedulerConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts at noon GPS time, which is on a slot boundary.
+    fn scheduler(config: SchedulerConfig) -> (Scheduler, Instant) {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(config);
+        scheduler.sync_at(NaiveTime::from_hms_opt(12, 0, 0).unwrap(), start);
+
+        (scheduler, start)
+    }
+
+    fn assert_at(instant: Instant, expected: Instant) {
+        assert!(instant.max(expected) - instant.min(expected) < Duration::from_millis(1), "{instant:?} != {expected:?}");
+    }
+
+    /// Sends whatever comes up next, returning it and when it went.
+    fn send_next(scheduler: &mut Scheduler, now: Instant, images_pending: bool) -> (PacketKind, Instant) {
+        let slot = scheduler.next(now, images_pending);
+        scheduler.sent(slot.kind, slot.start);
+
+        (slot.kind, slot.start)
+    }
+
+    #[test]
+    fn slots_line_up_with_gps_time() {
+        let (scheduler, start) = scheduler(SchedulerConfig::default());
+
+        let (slot_start, end) = scheduler.slot_at(start + Duration::from_millis(1500));
+        assert_at(slot_start, start + Duration::from_secs(5));
+        assert_at(end, start + Duration::from_secs(10));
+
+        assert_eq!(scheduler.time_of_day(start + Duration::from_secs(90)), Some(Duration::from_secs(12 * 3600 + 90)));
+        assert_eq!(Scheduler::default().time_of_day(start), None);
+    }
+
+    #[test]
+    fn everything_due_goes_out_by_priority() {
+        let (mut scheduler, start) = scheduler(SchedulerConfig::default());
+        scheduler.set_predicting(true);
+
+        let mut now = start;
+        let mut sent = Vec::new();
+        for _ in 0..5 {
+            let (kind, at) = send_next(&mut scheduler, now, true);
+            sent.push(kind);
+            now = at + scheduler.config().slot_length;
+        }
+
+        assert_eq!(sent, [PacketKind::Position, PacketKind::Prediction, PacketKind::Telemetry, PacketKind::Status, PacketKind::Image]);
+    }
+
+    #[test]
+    fn positions_are_paced_by_their_interval_with_images_in_between() {
+        let (mut scheduler, start) = scheduler(SchedulerConfig {
+            image_burst: 100,
+            ..SchedulerConfig::default()
+        });
+
+        let mut now = start;
+        let mut positions = Vec::new();
+        for _ in 0..40 {
+            let (kind, at) = send_next(&mut scheduler, now, true);
+            if kind == PacketKind::Position {
+                positions.push(at);
+            }
+            now = at + Duration::from_secs(5);
+        }
+
+        // Telemetry and status went in the second and third slots, images in the rest.
+        assert_eq!(positions.len(), 4);
+        for (i, at) in positions.into_iter().enumerate() {
+            assert_at(at, start + Duration::from_secs(60) * i as u32);
+        }
+    }
+
+    #[test]
+    fn image_bursts_rest_and_then_start_over() {
+        let (mut scheduler, start) = scheduler(SchedulerConfig::default());
+        // Nothing else is due for a while.
+        for kind in [PacketKind::Position, PacketKind::Telemetry, PacketKind::Status] {
+            scheduler.sent(kind, start + Duration::from_secs(1000));
+        }
+
+        let mut now = start;
+        let mut images = Vec::new();
+        for _ in 0..17 {
+            let (kind, at) = send_next(&mut scheduler, now, true);
+            assert_eq!(kind, PacketKind::Image);
+            images.push(at);
+            now = at + Duration::from_secs(5);
+        }
+
+        // Eight back to back, then a 30s rest rounded up to the next slot.
+        for pair in images[..8].windows(2) {
+            assert_at(pair[1], pair[0] + Duration::from_secs(5));
+        }
+        assert_at(images[8], images[7] + Duration::from_secs(30));
+        for pair in images[8..16].windows(2) {
+            assert_at(pair[1], pair[0] + Duration::from_secs(5));
+        }
+        assert_at(images[16], images[15] + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn waits_for_whatever_is_due_first() {
+        let (mut scheduler, start) = scheduler(SchedulerConfig::default());
+        scheduler.sent(PacketKind::Position, start);
+        scheduler.sent(PacketKind::Telemetry, start);
+        scheduler.sent(PacketKind::Status, start);
+
+        let slot = scheduler.next(start + Duration::from_secs(1), false);
+        assert_eq!(slot.kind, PacketKind::Position);
+        assert_at(slot.start, start + Duration::from_secs(60));
+        assert_at(slot.end, start + Duration::from_secs(65));
+
+        // Predictions only once there's something to predict.
+        scheduler.set_predicting(true);
+        let slot = scheduler.next(start + Duration::from_secs(1), false);
+        assert_eq!(slot.kind, PacketKind::Prediction);
+        assert_at(slot.start, start + Duration::from_secs(5));
+    }
+
+    #[test]
+    fn expedited_packets_are_due_straight_away() {
+        let (mut scheduler, start) = scheduler(SchedulerConfig::default());
+        scheduler.sent(PacketKind::Position, start);
+        scheduler.sent(PacketKind::Telemetry, start);
+        scheduler.sent(PacketKind::Status, start);
+
+        scheduler.expedite(PacketKind::Position);
+
+        let slot = scheduler.next(start + Duration::from_secs(1), true);
+        assert_eq!(slot.kind, PacketKind::Position);
+        assert_at(slot.start, start + Duration::from_secs(5));
+    }
+
+    fn slotted(jitter: Duration) -> SchedulerConfig {
+        SchedulerConfig {
+            time_slot: Some(TimeSlot {
+                jitter,
+                ..TimeSlot::new(Duration::from_secs(20), Duration::from_secs(10))
+            }),
+            ..SchedulerConfig::default()
+        }
+    }
+
+    #[test]
+    fn inside_the_window_packets_go_straight_away() {
+        let (scheduler, start) = scheduler(slotted(Duration::ZERO));
+
+        let now = start + Duration::from_secs(25);
+        let (slot_start, end) = scheduler.slot_at(now);
+        assert_at(slot_start, now);
+        assert_at(end, start + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn outside_the_window_packets_wait_for_the_next_one() {
+        let (scheduler, start) = scheduler(slotted(Duration::ZERO));
+
+        let (slot_start, end) = scheduler.slot_at(start + Duration::from_secs(5));
+        assert_at(slot_start, start + Duration::from_secs(20));
+        assert_at(end, start + Duration::from_secs(30));
+
+        // Just past the end is the next minute's.
+        let (slot_start, end) = scheduler.slot_at(start + Duration::from_secs(30));
+        assert_at(slot_start, start + Duration::from_secs(80));
+        assert_at(end, start + Duration::from_secs(90));
+    }
+
+    #[test]
+    fn each_window_keeps_its_jitter() {
+        let (mut scheduler, start) = scheduler(slotted(Duration::from_millis(500)));
+        scheduler.jitter_seed = 1;
+
+        let (first, end) = scheduler.slot_at(start + Duration::from_secs(5));
+        let jitter = first - (start + Duration::from_secs(20));
+        assert!(jitter < Duration::from_millis(500), "{jitter:?}");
+        assert_at(end, start + Duration::from_secs(30));
+
+        // However often and from wherever it's looked at.
+        for now in [start, start + Duration::from_secs(5), start + Duration::from_secs(19)] {
+            assert_at(scheduler.slot_at(now).0, first);
+            assert_at(scheduler.next(now, false).start, first);
+        }
+        // And once inside it, nothing goes before the jitter is up.
+        assert_at(scheduler.slot_at(start + Duration::from_secs(20)).0, first);
+
+        // Other windows and other trackers get their own.
+        let next_minute = scheduler.slot_at(start + Duration::from_secs(31)).0 - (start + Duration::from_secs(80));
+        scheduler.jitter_seed = 2;
+        let other_tracker = scheduler.slot_at(start + Duration::from_secs(5)).0 - (start + Duration::from_secs(20));
+        assert_ne!(next_minute, jitter);
+        assert_ne!(other_tracker, jitter);
+    }
+
+    #[test]
+    fn closing_the_window_skips_to_the_next_one() {
+        let (mut scheduler, start) = scheduler(slotted(Duration::ZERO));
+        let now = start + Duration::from_secs(28);
+
+        scheduler.close_window(now);
+
+        let slot = scheduler.next(now, false);
+        assert_at(slot.start, start + Duration::from_secs(80));
+        assert_at(slot.end, start + Duration::from_secs(90));
+    }
+
+    #[test]
+    fn windows_line_up_with_startup_until_synced() {
+        let scheduler = Scheduler::new(slotted(Duration::ZERO));
+        let startup = scheduler.clock.at;
+        assert!(!scheduler.is_synced());
+
+        let (slot_start, end) = scheduler.slot_at(startup + Duration::from_secs(45));
+        assert_at(slot_start, startup + Duration::from_secs(80));
+        assert_at(end, startup + Duration::from_secs(90));
+    }
+}
@@ -1,26 +1,104 @@
 //! Communicating with the custom ATTiny85 program over I2C to generate the APRS audio signal.
 
-use rpi_embedded::i2c::{self, I2c};
+use embedded_hal::i2c::I2c;
 
-const GENERATOR_ADDR: u16 = 0x40;
+use crate::ax25;
 
-pub struct SignalGenerator {
-    i2c: I2c,
+const GENERATOR_ADDR: u8 = 0x40;
+/// Most the ATTiny takes in a single write.
+const CHUNK_SIZE: usize = 15;
+
+/// Number of HDLC flags sent before and after each frame.
+const FLAG_SIZE: usize = 20;
+const FLAG: u8 = 0x7e;
+
+/// Bytes added around every frame: flags on both sides and the FCS.
+pub const FRAMING_OVERHEAD: usize = 2 * FLAG_SIZE + ax25::FCS_SIZE;
+
+pub struct SignalGenerator<I2C> {
+    i2c: I2C,
 }
 
-impl SignalGenerator {
-    pub fn new() -> i2c::Result<Self> {
-        let mut i2c = I2c::new()?;
-        i2c.set_slave_address(GENERATOR_ADDR)?;
+impl<I2C: I2c> SignalGenerator<I2C> {
+    pub fn with_bus(i2c: I2C) -> Self {
+        Self { i2c }
+    }
 
-        Ok(Self {i2c})
+    pub fn write(&mut self, buf: &[u8]) -> Result<(), I2C::Error> {
+        self.write_bytes(buf.iter().copied())
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> i2c::Result<()> {
-        for chunk in buf.chunks(15) {
-            self.i2c.write(chunk)?;
+    /// Sends a single AX.25 frame, without flags or FCS.
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<(), I2C::Error> {
+        // The ATTiny just clocks out whatever it is given,
+        // so the flags and FCS have to be added here.
+        let flags = [FLAG; FLAG_SIZE];
+        let fcs = ax25::fcs(frame);
+
+        self.write_bytes(flags.iter().chain(frame).chain(&fcs).chain(&flags).copied())
+    }
+
+    fn write_bytes(&mut self, mut bytes: impl Iterator<Item = u8>) -> Result<(), I2C::Error> {
+        let mut chunk = [0; CHUNK_SIZE];
+
+        loop {
+            let len = chunk.iter_mut().zip(&mut bytes).map(|(slot, byte)| *slot = byte).count();
+            if len == 0 {
+                return Ok(());
+            }
+
+            self.i2c.write(GENERATOR_ADDR, &chunk[..len])?;
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::{MockBus, Transaction};
+
+    fn chunks(bus: &MockBus) -> Vec<Vec<u8>> {
+        bus.transactions()
+            .iter()
+            .map(|transaction| match transaction {
+                Transaction::Write { address: GENERATOR_ADDR, data } => data.clone(),
+                other => panic!("unexpected transfer {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_is_wrapped_in_flags_and_fcs() {
+        let mut bus = MockBus::new();
+        bus.add_device(GENERATOR_ADDR);
+        let mut generator = SignalGenerator::with_bus(&mut bus);
+
+        let frame = b"\x82\xa0\xa4\xa6@@`\x9c`\x86\x82\x98\x98a\x03\xf0test";
+        generator.send_frame(frame).unwrap();
+
+        let chunks = chunks(&bus);
+        assert!(chunks.iter().all(|chunk| !chunk.is_empty() && chunk.len() <= CHUNK_SIZE));
+        // Everything but the last chunk is full.
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() == CHUNK_SIZE));
+
+        let mut expected = vec![FLAG; FLAG_SIZE];
+        expected.extend_from_slice(frame);
+        expected.extend_from_slice(&ax25::fcs(frame));
+        expected.extend_from_slice(&[FLAG; FLAG_SIZE]);
+        assert_eq!(chunks.concat(), expected);
+    }
+
+    #[test]
+    fn write_sends_bytes_as_is() {
+        let mut bus = MockBus::new();
+        bus.add_device(GENERATOR_ADDR);
+        let mut generator = SignalGenerator::with_bus(&mut bus);
+
+        generator.write(&[0xaa; CHUNK_SIZE + 1]).unwrap();
+        assert_eq!(chunks(&bus), [vec![0xaa; CHUNK_SIZE], vec![0xaa]]);
 
-        Ok(())
+        let mut generator = SignalGenerator::with_bus(&mut bus);
+        generator.write(&[]).unwrap();
+        assert_eq!(chunks(&bus).len(), 2);
     }
 }
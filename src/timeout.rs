@@ -0,0 +1,186 @@
+//! Bounding waits in the drivers without needing a clock.
+//!
+//! A [`Timeout`] is a budget that every poll interval spent waiting is charged against,
+//! so all it needs is a [`DelayNs`]. Data coming in is charged by how long it takes on the wire,
+//! so a port that never stops sending still runs it down. Time spent talking over the bus
+//! isn't counted, so a wait can run a little over on a slow bus.
+//!
+//! Waits can also be given a [`CancelToken`] so another thread can make them give up early.
+
+use alloc::sync::Arc;
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use embedded_hal::delay::DelayNs;
+
+/// Time left to wait for something.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeout {
+    /// `None` for waiting forever.
+    remaining: Option<Duration>,
+}
+
+impl Timeout {
+    /// Waits forever. Best kept for things that really can't go missing.
+    pub const NEVER: Timeout = Timeout { remaining: None };
+
+    pub fn after(timeout: Duration) -> Self {
+        Self { remaining: Some(timeout) }
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.remaining == Some(Duration::ZERO)
+    }
+
+    /// Time left, or `None` if there's no limit.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining
+    }
+
+    /// Whichever of the two timeouts runs out first.
+    pub fn min(self, other: Timeout) -> Timeout {
+        match (self.remaining, other.remaining) {
+            (Some(a), Some(b)) => Timeout::after(a.min(b)),
+            (Some(_), None) => self,
+            (None, _) => other,
+        }
+    }
+
+    /// Waits for `interval`, or whatever is left if that's less, and charges it to the timeout.
+    pub fn wait(&mut self, delay: &mut impl DelayNs, interval: Duration) {
+        let interval = self.remaining.map_or(interval, |remaining| remaining.min(interval));
+        delay.delay_us(interval.as_micros() as u32);
+
+        self.charge(interval);
+    }
+
+    /// Charges time spent on something other than waiting, like receiving data.
+    pub fn charge(&mut self, elapsed: Duration) {
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(elapsed);
+        }
+    }
+}
+
+/// How long `bytes` take to arrive over a UART at `baud_rate`, with a start and a stop bit each.
+pub fn transfer_time(bytes: usize, baud_rate: u32) -> Duration {
+    Duration::from_micros(bytes as u64 * 10 * 1_000_000 / baud_rate.max(1) as u64)
+}
+
+impl From<Duration> for Timeout {
+    fn from(timeout: Duration) -> Self {
+        Timeout::after(timeout)
+    }
+}
+
+/// A flag shared between a blocking read and whoever wants to stop it.
+///
+/// Clones share the same flag. Once cancelled it stays that way until [`CancelToken::reset`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Why a wait stopped before it got what it was waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Interrupted {
+    TimedOut,
+    Cancelled,
+}
+
+/// Checks whether a wait should give up, cancellation taking priority over the timeout.
+pub fn check(timeout: &Timeout, cancel: Option<&CancelToken>) -> Result<(), Interrupted> {
+    if cancel.is_some_and(CancelToken::is_cancelled) {
+        return Err(Interrupted::Cancelled);
+    }
+
+    if timeout.has_expired() {
+        return Err(Interrupted::TimedOut);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::MockDelay;
+
+    #[test]
+    fn waits_are_charged_until_it_runs_out() {
+        let mut delay = MockDelay::default();
+        let mut timeout = Timeout::after(Duration::from_millis(25));
+
+        timeout.wait(&mut delay, Duration::from_millis(10));
+        timeout.wait(&mut delay, Duration::from_millis(10));
+        assert_eq!(timeout.remaining(), Some(Duration::from_millis(5)));
+        assert!(!timeout.has_expired());
+
+        // Only what's left gets waited for.
+        timeout.wait(&mut delay, Duration::from_millis(10));
+        assert!(timeout.has_expired());
+        assert_eq!(delay.waited_ns, 25_000_000);
+    }
+
+    #[test]
+    fn received_data_is_charged_without_waiting() {
+        let mut timeout = Timeout::after(Duration::from_millis(100));
+
+        // 10 bits a byte at 9600 baud
+        assert_eq!(transfer_time(96, 9600), Duration::from_millis(100));
+        timeout.charge(transfer_time(48, 9600));
+        assert_eq!(timeout.remaining(), Some(Duration::from_millis(50)));
+
+        timeout.charge(Duration::from_secs(1));
+        assert!(timeout.has_expired());
+    }
+
+    #[test]
+    fn never_runs_out() {
+        let mut delay = MockDelay::default();
+        let mut timeout = Timeout::NEVER;
+
+        timeout.wait(&mut delay, Duration::from_secs(60));
+        timeout.charge(Duration::from_secs(60));
+
+        assert_eq!(timeout.remaining(), None);
+        assert_eq!(check(&timeout, None), Ok(()));
+        assert_eq!(timeout.min(Timeout::after(Duration::from_secs(1))), Timeout::after(Duration::from_secs(1)));
+        assert_eq!(Timeout::after(Duration::from_secs(2)).min(Timeout::after(Duration::from_secs(1))).remaining(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn cancelling_wins_over_the_timeout() {
+        let cancel = CancelToken::new();
+        let expired = Timeout::after(Duration::ZERO);
+
+        assert_eq!(check(&expired, Some(&cancel)), Err(Interrupted::TimedOut));
+
+        cancel.clone().cancel();
+        assert_eq!(check(&expired, Some(&cancel)), Err(Interrupted::Cancelled));
+        assert_eq!(check(&Timeout::NEVER, Some(&cancel)), Err(Interrupted::Cancelled));
+
+        cancel.reset();
+        assert_eq!(check(&Timeout::NEVER, Some(&cancel)), Ok(()));
+    }
+}